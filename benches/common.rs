@@ -9,6 +9,7 @@ pub trait BenchTable {
     fn read_transaction(&self) -> Self::R;
 }
 
+#[allow(clippy::result_unit_err)]
 pub trait BenchWriteTransaction {
     fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), ()>;
 
@@ -99,6 +99,7 @@ fn readwrite_bench(path: &Path) {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(path)
         .unwrap();
 
@@ -161,6 +162,7 @@ fn mmap_bench(path: &Path) {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(path)
         .unwrap();
 
@@ -180,7 +182,7 @@ fn mmap_bench(path: &Path) {
             mut_key.extend_from_slice(&i.to_be_bytes());
             mmap[write_index..(write_index + mut_key.len())].copy_from_slice(&mut_key);
             write_index += mut_key.len();
-            mmap[write_index..(write_index + value.len())].copy_from_slice(&value);
+            mmap[write_index..(write_index + value.len())].copy_from_slice(value);
             write_index += value.len();
         }
     }
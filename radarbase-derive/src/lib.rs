@@ -0,0 +1,135 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, Index, Member};
+
+/// Derives `RadbValue` and `RadbKey` for a struct, so that it can be used as a composite key.
+///
+/// The fields are encoded in declaration order using the same rules as tuple keys: every
+/// field but the last is length-prefixed, and keys are compared field by field with each
+/// field type's own `compare`. So a `struct IndexKey { user_id: u64, ts: u64 }` sorts
+/// exactly like the tuple `(u64, u64)`.
+///
+/// Decoding builds an owned struct, so the view of each field must convert into the field's
+/// type with `Into` (e.g. `&str` into `String`, `&[u8]` into `Vec<u8>`, or an integer into itself).
+#[proc_macro_derive(RadbKey)]
+pub fn derive_radb_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new_spanned(
+                &input,
+                "RadbKey can only be derived for structs",
+            ))
+        }
+    };
+    if fields.is_empty() {
+        return Err(Error::new_spanned(
+            &input,
+            "RadbKey cannot be derived for a struct without fields",
+        ));
+    }
+
+    // the member used to access each field, and a local variable name for its encoded bytes
+    let members: Vec<Member> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        })
+        .collect();
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let vars: Vec<Ident> = (0..members.len())
+        .map(|i| Ident::new(&format!("component{}", i), Span::call_site()))
+        .collect();
+
+    let (init_members, last_member) = members.split_at(members.len() - 1);
+    let (init_types, last_type) = types.split_at(types.len() - 1);
+    let (init_vars, last_var) = vars.split_at(vars.len() - 1);
+    let last_member = &last_member[0];
+    let last_type = last_type[0];
+    let last_var = &last_var[0];
+
+    let construct = match fields {
+        Fields::Named(_) => quote! {
+            #name {
+                #(#members: ::std::convert::Into::into(
+                    <#types as ::radarbase::types::RadbValue>::from_bytes(#vars)
+                ),)*
+            }
+        },
+        Fields::Unnamed(_) => quote! {
+            #name(
+                #(::std::convert::Into::into(
+                    <#types as ::radarbase::types::RadbValue>::from_bytes(#vars)
+                ),)*
+            )
+        },
+        Fields::Unit => unreachable!(),
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::radarbase::types::RadbValue for #name #ty_generics #where_clause {
+            type View = ::radarbase::types::OwnedLifetime<#name #ty_generics>;
+            type ToBytes = ::radarbase::types::OwnedAsBytesLifetime<::std::vec::Vec<u8>>;
+
+            fn from_bytes(
+                data: &[u8],
+            ) -> <Self::View as ::radarbase::types::WithLifetime<'_>>::Out {
+                let rest = data;
+                #(let (#init_vars, rest) = ::radarbase::types::split_component(rest);)*
+                let #last_var = rest;
+                #construct
+            }
+
+            fn as_bytes(
+                &self,
+            ) -> <Self::ToBytes as ::radarbase::types::AsBytesWithLifetime<'_>>::Out {
+                let mut output = ::std::vec::Vec::new();
+                #(
+                    ::radarbase::types::encode_component(
+                        &mut output,
+                        ::std::convert::AsRef::<[u8]>::as_ref(
+                            &<#init_types as ::radarbase::types::RadbValue>::as_bytes(
+                                &self.#init_members
+                            ),
+                        ),
+                    );
+                )*
+                output.extend_from_slice(::std::convert::AsRef::<[u8]>::as_ref(
+                    &<#last_type as ::radarbase::types::RadbValue>::as_bytes(&self.#last_member),
+                ));
+                output
+            }
+        }
+
+        impl #impl_generics ::radarbase::types::RadbKey for #name #ty_generics #where_clause {
+            fn compare(data1: &[u8], data2: &[u8]) -> ::std::cmp::Ordering {
+                let (rest1, rest2) = (data1, data2);
+                #(
+                    let (component1, rest1) = ::radarbase::types::split_component(rest1);
+                    let (component2, rest2) = ::radarbase::types::split_component(rest2);
+                    match <#init_types as ::radarbase::types::RadbKey>::compare(
+                        component1,
+                        component2,
+                    ) {
+                        ::std::cmp::Ordering::Equal => {}
+                        ordering => return ordering,
+                    }
+                )*
+                <#last_type as ::radarbase::types::RadbKey>::compare(rest1, rest2)
+            }
+        }
+    })
+}
@@ -3,16 +3,40 @@ use crate::binarytree::RangeIterState::{
     InitialState, InternalLeft, InternalRight, LeafLeft, LeafRight,
 };
 use crate::page_manager::{Page, PageManager, PageMut};
+use crate::storage::{NodeType, PageUsage};
 use crate::types::RadbKey;
+use crate::{Corruption, Error};
+use std::borrow::Borrow;
 use std::cell::Cell;
-use std::cmp::Ordering;
+use std::cmp::{max, min, Ordering};
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::marker::PhantomData;
-use std::ops::{Bound, RangeBounds};
+use std::ops::{Bound, Range, RangeBounds};
 
 const LEAF: u8 = 1;
 const INTERNAL: u8 = 2;
 
+// The error for a page whose type byte is neither LEAF nor INTERNAL, e.g. in a corrupted file
+fn invalid_node_type(page: &Page, byte: u8) -> Error {
+    Error::Corruption(Corruption::InvalidNodeType {
+        page: page.get_page_number(),
+        byte,
+    })
+}
+
+// Size of the key_size, table_id and value_size fields of an entry
+const ENTRY_HEADER_SIZE: usize = 24;
+
+// Size of the type byte and the greater entry's presence flag, which start a leaf
+const LEAF_HEADER_SIZE: usize = 2;
+
+/// The largest key + value length that can be stored.
+/// A leaf page holds two entries after its header, so each entry must fit in half a page.
+pub(crate) fn max_entry_len(page_size: usize) -> usize {
+    (page_size - LEAF_HEADER_SIZE) / 2 - ENTRY_HEADER_SIZE
+}
+
 // The references within each variant of the RangeIterState<'a> enum (i.e., the Page
 // and parent) must not be dropped before the RangeIterState<'a> itself.
 
@@ -41,8 +65,8 @@ enum RangeIterState<'a> {
 }
 
 impl<'a> RangeIterState<'a> {
-    fn forward_next(self, manager: &'a PageManager) -> Option<RangeIterState> {
-        match self {
+    fn forward_next(self, manager: &'a PageManager) -> Result<Option<RangeIterState<'a>>, Error> {
+        Ok(match self {
             RangeIterState::InitialState(root_page, ..) => match root_page.memory()[0] {
                 // initial state, if it is the leaf node, then we assign it to left leaf
                 LEAF => Some(LeafLeft {
@@ -57,7 +81,7 @@ impl<'a> RangeIterState<'a> {
                     parent: None,
                     reversed: false,
                 }),
-                _ => unreachable!(),
+                byte => return Err(invalid_node_type(&root_page, byte)),
             },
 
             RangeIterState::LeafLeft { page, parent, .. } => Some(LeafRight {
@@ -92,7 +116,7 @@ impl<'a> RangeIterState<'a> {
                         })),
                         reversed: false,
                     }),
-                    _ => unreachable!(),
+                    byte => return Err(invalid_node_type(&child_page, byte)),
                 }
             }
 
@@ -110,14 +134,14 @@ impl<'a> RangeIterState<'a> {
                         parent,
                         reversed: false,
                     }),
-                    _ => unreachable!(),
+                    byte => return Err(invalid_node_type(&child_page, byte)),
                 }
             }
-        }
+        })
     }
 
-    fn backward_next(self, manager: &'a PageManager) -> Option<RangeIterState> {
-        match self {
+    fn backward_next(self, manager: &'a PageManager) -> Result<Option<RangeIterState<'a>>, Error> {
+        Ok(match self {
             RangeIterState::InitialState(root_page, ..) => match root_page.memory()[0] {
                 LEAF => Some(LeafRight {
                     page: root_page,
@@ -129,7 +153,7 @@ impl<'a> RangeIterState<'a> {
                     parent: None,
                     reversed: true,
                 }),
-                _ => unreachable!(),
+                byte => return Err(invalid_node_type(&root_page, byte)),
             },
             RangeIterState::LeafLeft { parent, .. } => parent.map(|x| *x),
             RangeIterState::LeafRight { page, parent, .. } => Some(LeafLeft {
@@ -151,7 +175,7 @@ impl<'a> RangeIterState<'a> {
                         parent,
                         reversed: true,
                     }),
-                    _ => unreachable!(),
+                    byte => return Err(invalid_node_type(&child_page, byte)),
                 }
             }
             RangeIterState::InternalRight { page, parent, .. } => {
@@ -176,14 +200,14 @@ impl<'a> RangeIterState<'a> {
                         })),
                         reversed: true,
                     }),
-                    _ => unreachable!(),
+                    byte => return Err(invalid_node_type(&child_page, byte)),
                 }
             }
-        }
+        })
     }
 
     // this next function will only return the next state
-    fn next(self, manager: &'a PageManager) -> Option<RangeIterState> {
+    fn next(self, manager: &'a PageManager) -> Result<Option<RangeIterState<'a>>, Error> {
         match &self {
             InitialState(_, reversed) => {
                 if *reversed {
@@ -223,28 +247,57 @@ impl<'a> RangeIterState<'a> {
         }
     }
 
-    fn get_entry(&self) -> Option<EntryAccessor> {
+    // The page number and offset of the entry, if this is a leaf
+    fn get_location(&self) -> Option<(u64, usize)> {
+        match self {
+            RangeIterState::LeafLeft { page, .. } => Some((
+                page.get_page_number(),
+                LeafAccessor::new(page).offset_of_lesser(),
+            )),
+            RangeIterState::LeafRight { page, .. } => Some((
+                page.get_page_number(),
+                LeafAccessor::new(page).offset_of_greater(),
+            )),
+            _ => None,
+        }
+    }
+
+    fn get_entry(&self) -> Option<EntryAccessor<'_>> {
         // If it is a leaf, return the entry
         // otherwise, return None
         match self {
-            RangeIterState::LeafLeft { page, .. } => Some(LeafAccessor::new(&page).lesser()),
-            RangeIterState::LeafRight { page, .. } => LeafAccessor::new(&page).greater(),
+            RangeIterState::LeafLeft { page, .. } => Some(LeafAccessor::new(page).lesser()),
+            RangeIterState::LeafRight { page, .. } => LeafAccessor::new(page).greater(),
             _ => None,
         }
     }
 }
 
 // TODO: T should be a RangeBound<&'a K>
-pub struct BinarytreeRangeIter<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> {
+// The bounds are anything that borrows as raw key bytes: `&'a [u8]` by default, or an owned `Vec<u8>`
+pub struct BinarytreeRangeIter<
+    'a,
+    T: RangeBounds<KR>,
+    K: RadbKey + ?Sized,
+    KR: Borrow<[u8]> = &'a [u8],
+> {
     last: Option<RangeIterState<'a>>,
+    // whether the entry that `last` is at has not been yielded yet, after a seek()
+    pending: bool,
+    // the error that ended the iteration
+    error: Option<Error>,
+    root_page: Option<u64>,
     table_id: u64,
     query_range: T,
     reversed: bool,
     manager: &'a PageManager,
     _key_type: PhantomData<K>,
+    _bound_type: PhantomData<KR>,
 }
 
-impl<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> BinarytreeRangeIter<'a, T, K> {
+impl<'a, T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>
+    BinarytreeRangeIter<'a, T, K, KR>
+{
     pub(crate) fn new(
         root_page: Option<Page<'a>>,
         table_id: u64,
@@ -252,12 +305,16 @@ impl<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> BinarytreeRangeIter<'a,
         manager: &'a PageManager,
     ) -> Self {
         Self {
+            root_page: root_page.as_ref().map(|p| p.get_page_number()),
             last: root_page.map(|p| InitialState(p, false)), // key point, initial state
+            pending: false,
+            error: None,
             table_id,
             query_range,
             reversed: false,
             manager,
             _key_type: Default::default(),
+            _bound_type: Default::default(),
         }
     }
 
@@ -268,73 +325,371 @@ impl<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> BinarytreeRangeIter<'a,
         manager: &'a PageManager,
     ) -> Self {
         Self {
+            root_page: root_page.as_ref().map(|p| p.get_page_number()),
             last: root_page.map(|p| InitialState(p, true)),
+            pending: false,
+            error: None,
             table_id,
             query_range,
             reversed: true,
             manager,
             _key_type: Default::default(),
+            _bound_type: Default::default(),
         }
     }
 
-    // TODO: we need generic-associated-types to implement Iterator
-    // This function main focus is to check if the next entry is in the range
-    pub fn next(&mut self) -> Option<EntryAccessor> {
-        if let Some(mut state) = self.last.take() {
-            loop {
-                // this loop ensures that it will only return the leaf node, which will store the entry
-                if let Some(new_state) = state.next(self.manager) {
-                    if let Some(entry) = new_state.get_entry() {
-                        // it is a leaf node, check if it is in the range
-                        // TODO: optimize. This is very inefficient to retrieve and then ignore the values
-                        if self.table_id == entry.table_id()
-                            && bound_contains_key::<T, K>(&self.query_range, entry.key())
-                        {
-                            self.last = Some(new_state);
-                            return self.last.as_ref().map(|s| s.get_entry().unwrap());
-                        } else {
-                            #[allow(clippy::collapsible_else_if)]
-                            if self.reversed {
-                                if let Bound::Included(start) = self.query_range.start_bound() {
-                                    if entry.compare::<K>(self.table_id, *start).is_lt() {
-                                        self.last = None;
-                                        return None;
-                                    }
-                                } else if let Bound::Excluded(start) =
-                                    self.query_range.start_bound()
-                                {
-                                    if entry.compare::<K>(self.table_id, *start).is_le() {
-                                        self.last = None;
-                                        return None;
-                                    }
-                                }
-                            } else {
-                                if let Bound::Included(end) = self.query_range.end_bound() {
-                                    if entry.compare::<K>(self.table_id, *end).is_gt() {
-                                        self.last = None;
-                                        return None;
-                                    }
-                                } else if let Bound::Excluded(end) = self.query_range.end_bound() {
-                                    if entry.compare::<K>(self.table_id, *end).is_ge() {
-                                        self.last = None;
-                                        return None;
-                                    }
-                                }
-                            };
-                            state = new_state;
-                        }
+    // Moves to the next entry, if there is one
+    fn step(&mut self) -> Option<()> {
+        let state = self.last.take()?;
+        match advance::<T, K, KR>(
+            state,
+            std::mem::take(&mut self.pending),
+            self.table_id,
+            &self.query_range,
+            self.reversed,
+            self.manager,
+        ) {
+            Ok(state) => self.last = state,
+            Err(err) => self.error = Some(err),
+        }
+        self.last.as_ref().map(|_| ())
+    }
+
+    // Like `next()`, but returns the page number and offset of the entry
+    pub(crate) fn next_location(&mut self) -> Option<(u64, usize)> {
+        self.step()?;
+        self.last.as_ref().and_then(|state| state.get_location())
+    }
+
+    /// Like `next()`, but returns the key and value of the entry together, borrowed from the mmap
+    pub fn next_key_value(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        self.next().map(|entry| entry.key_value())
+    }
+
+    /// Returns the error that ended the iteration early, such as a corrupted page, if any.
+    /// `next()` returns `None` once an error occurs, so check this to tell an error apart
+    /// from the end of the range
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    // Whether an error ended the iteration, without taking it
+    pub(crate) fn has_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    // For callers that iterate to the end: returns the error that ended the iteration, if any
+    pub(crate) fn finish(&mut self) -> Result<(), Error> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    /// Repositions the iterator, so that `next()` continues from the first entry `>= key`,
+    /// or when reversed from the last entry `<= key`. Entries outside the range are still
+    /// skipped, so seeking past the end of the range exhausts the iterator
+    pub fn seek(&mut self, key: &[u8]) {
+        // drop the current position first, since it borrows pages
+        self.last = None;
+        if let Some(root_page) = self.root_page {
+            match seek_state::<K>(
+                self.manager.get_page(root_page),
+                self.table_id,
+                key,
+                true,
+                self.reversed,
+                self.manager,
+            ) {
+                Ok((state, pending)) => {
+                    self.last = Some(state);
+                    self.pending = pending;
+                }
+                Err(err) => self.error = Some(err),
+            }
+        }
+    }
+}
+
+// Entries borrow from the mmap rather than from the iterator, so that they can be held
+// across calls to `next()`
+impl<'a, T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>> Iterator
+    for BinarytreeRangeIter<'a, T, K, KR>
+{
+    type Item = EntryAccessor<'a>;
+
+    fn next(&mut self) -> Option<EntryAccessor<'a>> {
+        let (page_number, offset) = self.next_location()?;
+        let page = self.manager.get_page(page_number);
+        Some(EntryAccessor::new(&page.into_memory()[offset..]))
+    }
+}
+
+// Moves `state` to the next entry that is in the range, and returns None once the range
+// is exhausted. If `include_current` is set, the entry that `state` is at is considered first
+fn advance<'a, T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
+    mut state: RangeIterState<'a>,
+    mut include_current: bool,
+    table_id: u64,
+    query_range: &T,
+    reversed: bool,
+    manager: &'a PageManager,
+) -> Result<Option<RangeIterState<'a>>, Error> {
+    loop {
+        // this loop ensures that it will only return the leaf node, which will store the entry
+        let new_state = if include_current {
+            include_current = false;
+            state
+        } else {
+            match state.next(manager)? {
+                Some(new_state) => new_state,
+                // we have reached the end of the tree
+                None => return Ok(None),
+            }
+        };
+        if let Some(entry) = new_state.get_entry() {
+            // it is a leaf node, check if it is in the range
+            // TODO: optimize. This is very inefficient to retrieve and then ignore the values
+            if table_id == entry.table_id()
+                && bound_contains_key::<T, K, KR>(query_range, entry.key())
+            {
+                return Ok(Some(new_state));
+            }
+            // stop at the first entry past the end of the range. Without a bound, that is
+            // the first entry of the next table, rather than the end of the whole tree
+            let past_end = if reversed {
+                match query_range.start_bound() {
+                    Bound::Included(start) => entry.compare::<K>(table_id, start.borrow()).is_lt(),
+                    Bound::Excluded(start) => entry.compare::<K>(table_id, start.borrow()).is_le(),
+                    Bound::Unbounded => entry.table_id() < table_id,
+                }
+            } else {
+                match query_range.end_bound() {
+                    Bound::Included(end) => entry.compare::<K>(table_id, end.borrow()).is_gt(),
+                    Bound::Excluded(end) => entry.compare::<K>(table_id, end.borrow()).is_ge(),
+                    Bound::Unbounded => entry.table_id() > table_id,
+                }
+            };
+            if past_end {
+                return Ok(None);
+            }
+        }
+        // otherwise, it is an internal node, or an entry outside of the range, just continue
+        state = new_state;
+    }
+}
+
+// Descends from the root to the first entry after `key`, or when reversed to the last entry
+// before it. Returns the state at that entry and whether the entry still has to be yielded.
+// If it does not, the next state is the entry after it.
+fn seek_state<'a, K: RadbKey + ?Sized>(
+    root: Page<'a>,
+    table: u64,
+    key: &[u8],
+    inclusive: bool,
+    reversed: bool,
+    manager: &'a PageManager,
+) -> Result<(RangeIterState<'a>, bool), Error> {
+    let matches = |entry: &EntryAccessor| {
+        let ordering = entry.compare::<K>(table, key);
+        match (reversed, inclusive) {
+            (false, true) => ordering.is_ge(),
+            (false, false) => ordering.is_gt(),
+            (true, true) => ordering.is_le(),
+            (true, false) => ordering.is_lt(),
+        }
+    };
+
+    let mut page = root;
+    let mut parent: Option<Box<RangeIterState<'a>>> = None;
+    loop {
+        match page.memory()[0] {
+            LEAF => {
+                let accessor = LeafAccessor::new(&page);
+                let lesser = matches(&accessor.lesser());
+                let greater = accessor.greater().is_some_and(|entry| matches(&entry));
+                let (right, found) = match (reversed, lesser, greater) {
+                    (false, true, _) => (false, true),
+                    (false, false, found) => (true, found),
+                    (true, _, true) => (true, true),
+                    (true, found, false) => (false, found),
+                };
+                let state = if right {
+                    LeafRight {
+                        page,
+                        parent,
+                        reversed,
+                    }
+                } else {
+                    LeafLeft {
+                        page,
+                        parent,
+                        reversed,
+                    }
+                };
+                return Ok((state, found));
+            }
+            INTERNAL => {
+                let accessor = InternalAccessor::new(&page);
+                // every key in the lte sub-tree is <= the node's key, and every key in
+                // the gt sub-tree is greater, so one of them can be skipped entirely
+                let ordering = cmp_keys::<K>(accessor.table_id(), accessor.key(), table, key);
+                let (lte, gt) = (accessor.lte_page(), accessor.gt_page());
+                if !reversed {
+                    let descend_lte = if inclusive {
+                        ordering.is_ge()
                     } else {
-                        // otherwise, it is an internal node, just continue
-                        state = new_state;
+                        ordering.is_gt()
+                    };
+                    if descend_lte {
+                        // once the lte sub-tree is done, continue with the gt sub-tree
+                        parent = Some(Box::new(InternalRight {
+                            page,
+                            parent,
+                            reversed,
+                        }));
+                        page = manager.get_page(lte);
+                    } else {
+                        page = manager.get_page(gt);
                     }
+                } else if ordering.is_lt() {
+                    parent = Some(Box::new(InternalLeft {
+                        page,
+                        parent,
+                        reversed,
+                    }));
+                    page = manager.get_page(gt);
                 } else {
-                    // we have reached the end of the tree
-                    self.last = None;
-                    return None;
+                    page = manager.get_page(lte);
                 }
             }
+            byte => return Err(invalid_node_type(&page, byte)),
+        }
+    }
+}
+
+enum CursorPosition<'a> {
+    // before the first entry of the range
+    Start,
+    // at an entry, having last moved in the given direction (true = reversed)
+    At(RangeIterState<'a>, bool),
+    // after the last entry of the range
+    End,
+}
+
+/// A cursor over a range that can move both forward, with `next()`, and backward, with `prev()`.
+///
+/// The cursor starts before the first entry of the range. `next()` returns the entry after
+/// the current one and `prev()` the entry before it, so calling `next()` three times and then
+/// `prev()` yields the first, second, third and then second entry again. Once `next()` has
+/// returned `None` the cursor is past the end, and `prev()` returns the last entry.
+pub struct RangeCursor<'a, T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]> = &'a [u8]> {
+    root_page: Option<u64>,
+    position: CursorPosition<'a>,
+    // the error that stopped the cursor
+    error: Option<Error>,
+    table_id: u64,
+    query_range: T,
+    manager: &'a PageManager,
+    _key_type: PhantomData<K>,
+    _bound_type: PhantomData<KR>,
+}
+
+impl<'a, T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>> RangeCursor<'a, T, K, KR> {
+    pub(crate) fn new(
+        root_page: Option<u64>,
+        table_id: u64,
+        query_range: T,
+        manager: &'a PageManager,
+    ) -> Self {
+        Self {
+            root_page,
+            position: CursorPosition::Start,
+            error: None,
+            table_id,
+            query_range,
+            manager,
+            _key_type: Default::default(),
+            _bound_type: Default::default(),
+        }
+    }
+
+    pub fn next(&mut self) -> Option<EntryAccessor<'_>> {
+        self.step(false)
+    }
+
+    pub fn prev(&mut self) -> Option<EntryAccessor<'_>> {
+        self.step(true)
+    }
+
+    /// Returns the error that stopped the cursor, such as a corrupted page, if any.
+    /// The cursor is moved to the end that it was moving towards when an error occurs
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    fn step(&mut self, reversed: bool) -> Option<EntryAccessor<'_>> {
+        let root_page = self.root_page?;
+        let position = std::mem::replace(&mut self.position, CursorPosition::Start);
+        let state = match position {
+            // continue in the same direction
+            CursorPosition::At(state, last_reversed) if last_reversed == reversed => {
+                Ok((state, false))
+            }
+            // change direction, by seeking to the entry on the other side of the current one
+            CursorPosition::At(state, _) => {
+                let key = state.get_entry().unwrap().key().to_vec();
+                drop(state);
+                seek_state::<K>(
+                    self.manager.get_page(root_page),
+                    self.table_id,
+                    &key,
+                    false,
+                    reversed,
+                    self.manager,
+                )
+            }
+            CursorPosition::Start if !reversed => {
+                Ok((InitialState(self.manager.get_page(root_page), false), false))
+            }
+            CursorPosition::End if reversed => {
+                Ok((InitialState(self.manager.get_page(root_page), true), false))
+            }
+            // already at the end that we are moving towards
+            position => {
+                self.position = position;
+                return None;
+            }
+        };
+
+        let advanced = state.and_then(|(state, include_current)| {
+            advance::<T, K, KR>(
+                state,
+                include_current,
+                self.table_id,
+                &self.query_range,
+                reversed,
+                self.manager,
+            )
+        });
+        let advanced = advanced.unwrap_or_else(|err| {
+            self.error = Some(err);
+            None
+        });
+        match advanced {
+            Some(state) => {
+                self.position = CursorPosition::At(state, reversed);
+                match &self.position {
+                    CursorPosition::At(state, _) => state.get_entry(),
+                    _ => unreachable!(),
+                }
+            }
+            None => {
+                self.position = if reversed {
+                    CursorPosition::Start
+                } else {
+                    CursorPosition::End
+                };
+                None
+            }
         }
-        None
     }
 }
 
@@ -344,36 +699,36 @@ pub trait BinarytreeEntry<'a: 'b, 'b> {
 }
 
 fn cmp_keys<K: RadbKey + ?Sized>(table1: u64, key1: &[u8], table2: u64, key2: &[u8]) -> Ordering {
+    // Every table shares one tree, ordered by table and then by key. So the keys of
+    // different tables never compare equal, even if their bytes do, and `K::compare` is only
+    // used on keys of the same table, which are the only ones that it knows how to order
     match table1.cmp(&table2) {
-        // Why do we need to compare table ids????
-        // two keys is invalid to be compared if they are from different tables???
-        // TODO: check this
         Ordering::Less => Ordering::Less,
         Ordering::Equal => K::compare(key1, key2),
         Ordering::Greater => Ordering::Greater,
     }
 }
 
-fn bound_contains_key<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized>(
+fn bound_contains_key<T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
     range: &T,
     key: &[u8],
 ) -> bool {
     // helper function to check if a key is within a range
     if let Bound::Included(start) = range.start_bound() {
-        if K::compare(key, *start).is_lt() {
+        if K::compare(key, start.borrow()).is_lt() {
             return false;
         }
     } else if let Bound::Excluded(start) = range.start_bound() {
-        if K::compare(key, *start).is_le() {
+        if K::compare(key, start.borrow()).is_le() {
             return false;
         }
     }
     if let Bound::Included(end) = range.end_bound() {
-        if K::compare(key, *end).is_gt() {
+        if K::compare(key, end.borrow()).is_gt() {
             return false;
         }
     } else if let Bound::Excluded(end) = range.end_bound() {
-        if K::compare(key, *end).is_ge() {
+        if K::compare(key, end.borrow()).is_ge() {
             return false;
         }
     }
@@ -381,6 +736,148 @@ fn bound_contains_key<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized>(
     true
 }
 
+// Estimates the number of entries of `table` in `range`, without visiting the whole range.
+//
+// Only the sub-trees that straddle the bounds of the range are descended into. A sub-tree
+// that lies entirely inside the range is assumed to be twice the size of its lte sub-tree,
+// which BinarytreeBuilder always makes complete and at least as large as the gt sub-tree.
+// So for a tree built in one go, the estimate is at least the exact count and at most twice it.
+pub(crate) fn estimate_range_len<T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
+    root: Option<Page>,
+    table: u64,
+    range: &T,
+    manager: &PageManager,
+) -> Result<usize, Error> {
+    match root {
+        Some(page) => estimate_subtree::<T, K, KR>(page, table, range, None, None, manager),
+        None => Ok(0),
+    }
+}
+
+// Every key of the sub-tree is > `lower` and <= `upper`, when they are known
+fn estimate_subtree<T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
+    page: Page,
+    table: u64,
+    range: &T,
+    lower: Option<(u64, &[u8])>,
+    upper: Option<(u64, &[u8])>,
+    manager: &PageManager,
+) -> Result<usize, Error> {
+    match page.memory()[0] {
+        LEAF => {
+            let accessor = LeafAccessor::new(&page);
+            Ok(std::iter::once(accessor.lesser())
+                .chain(accessor.greater())
+                .filter(|entry| {
+                    entry.table_id() == table && bound_contains_key::<T, K, KR>(range, entry.key())
+                })
+                .count())
+        }
+        INTERNAL => {
+            if subtree_in_range::<T, K, KR>(table, range, lower, upper) {
+                return estimate_complete(page, manager);
+            }
+            let accessor = InternalAccessor::new(&page);
+            let key = (accessor.table_id(), accessor.key());
+            let mut count = 0;
+            // the lte sub-tree holds keys <= key, so skip it if key is before the range
+            let lte_before_range = match range.start_bound() {
+                Bound::Included(start) => {
+                    cmp_keys::<K>(key.0, key.1, table, start.borrow()).is_lt()
+                }
+                Bound::Excluded(start) => {
+                    cmp_keys::<K>(key.0, key.1, table, start.borrow()).is_le()
+                }
+                Bound::Unbounded => key.0 < table,
+            };
+            if !lte_before_range {
+                let child = manager.get_page(accessor.lte_page());
+                count +=
+                    estimate_subtree::<T, K, KR>(child, table, range, lower, Some(key), manager)?;
+            }
+            // the gt sub-tree holds keys > key, so skip it if key is at or after the end
+            let gt_after_range = match range.end_bound() {
+                Bound::Included(end) | Bound::Excluded(end) => {
+                    cmp_keys::<K>(key.0, key.1, table, end.borrow()).is_ge()
+                }
+                Bound::Unbounded => key.0 > table,
+            };
+            if !gt_after_range {
+                let child = manager.get_page(accessor.gt_page());
+                count +=
+                    estimate_subtree::<T, K, KR>(child, table, range, Some(key), upper, manager)?;
+            }
+            Ok(count)
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+// Counts the entries of every table in the sub-tree, but stops once `limit` is reached,
+// so that it only visits about `limit` pages
+pub(crate) fn count_entries_up_to(
+    page: Page,
+    limit: usize,
+    manager: &PageManager,
+) -> Result<usize, Error> {
+    match page.memory()[0] {
+        LEAF => Ok(1 + LeafAccessor::new(&page).greater().is_some() as usize),
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            let count = count_entries_up_to(manager.get_page(accessor.lte_page()), limit, manager)?;
+            if count >= limit {
+                Ok(count)
+            } else {
+                Ok(count
+                    + count_entries_up_to(
+                        manager.get_page(accessor.gt_page()),
+                        limit - count,
+                        manager,
+                    )?)
+            }
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+// Whether every key in (lower, upper] is in the range, and belongs to the table
+fn subtree_in_range<T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
+    table: u64,
+    range: &T,
+    lower: Option<(u64, &[u8])>,
+    upper: Option<(u64, &[u8])>,
+) -> bool {
+    let after_start = match (lower, range.start_bound()) {
+        (None, _) => false,
+        (Some((lower_table, lower_key)), Bound::Included(start) | Bound::Excluded(start)) => {
+            cmp_keys::<K>(lower_table, lower_key, table, start.borrow()).is_ge()
+        }
+        (Some((lower_table, _)), Bound::Unbounded) => lower_table >= table,
+    };
+    let before_end = match (upper, range.end_bound()) {
+        (None, _) => false,
+        (Some((upper_table, upper_key)), Bound::Included(end)) => {
+            cmp_keys::<K>(upper_table, upper_key, table, end.borrow()).is_le()
+        }
+        (Some((upper_table, upper_key)), Bound::Excluded(end)) => {
+            cmp_keys::<K>(upper_table, upper_key, table, end.borrow()).is_lt()
+        }
+        (Some((upper_table, _)), Bound::Unbounded) => upper_table <= table,
+    };
+    after_start && before_end
+}
+
+fn estimate_complete(page: Page, manager: &PageManager) -> Result<usize, Error> {
+    match page.memory()[0] {
+        LEAF => Ok(1 + LeafAccessor::new(&page).greater().is_some() as usize),
+        INTERNAL => {
+            let lte = InternalAccessor::new(&page).lte_page();
+            Ok(2 * estimate_complete(manager.get_page(lte), manager)?)
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
 // Provides a simple zero-copy way to access entries
 //
 // Entry format is:
@@ -427,6 +924,50 @@ impl<'a> EntryAccessor<'a> {
     fn compare<K: RadbKey + ?Sized>(&self, table: u64, key: &[u8]) -> Ordering {
         cmp_keys::<K>(self.table_id(), self.key(), table, key)
     }
+
+    /// Returns the key and the value, decoding the entry's lengths only once
+    pub fn key_value(&self) -> (&'a [u8], &'a [u8]) {
+        note_value_read();
+        let key_end = 16 + self.key_len();
+        let value_len =
+            u64::from_be_bytes(self.raw[key_end..(key_end + 8)].try_into().unwrap()) as usize;
+        let value_start = key_end + 8;
+        (
+            &self.raw[16..key_end],
+            &self.raw[value_start..(value_start + value_len)],
+        )
+    }
+}
+
+// Number of values located on this thread, so that tests can check that a walk over the keys
+// never touches a value
+#[cfg(test)]
+thread_local!(static VALUE_READS: Cell<u64> = const { Cell::new(0) });
+
+#[cfg(test)]
+pub(crate) fn value_reads() -> u64 {
+    VALUE_READS.with(|reads| reads.get())
+}
+
+fn note_value_read() {
+    #[cfg(test)]
+    VALUE_READS.with(|reads| reads.set(reads.get() + 1));
+}
+
+/// Returns the `(offset, len)` of the key of the entry at `offset` in the page
+pub(crate) fn key_location(page: &Page, offset: usize) -> (usize, usize) {
+    let entry = EntryAccessor::new(&page.memory()[offset..]);
+    (offset + 16, entry.key_len())
+}
+
+/// Returns the `(offset, len)` of the key and of the value of the entry at `offset` in the page
+pub(crate) fn entry_location(page: &Page, offset: usize) -> ((usize, usize), (usize, usize)) {
+    note_value_read();
+    let entry = EntryAccessor::new(&page.memory()[offset..]);
+    (
+        (offset + 16, entry.key_len()),
+        (offset + entry.value_offset(), entry.value_len()),
+    )
 }
 
 impl<'a: 'b, 'b> BinarytreeEntry<'a, 'b> for EntryAccessor<'a> {
@@ -435,6 +976,7 @@ impl<'a: 'b, 'b> BinarytreeEntry<'a, 'b> for EntryAccessor<'a> {
     }
 
     fn value(&'b self) -> &'a [u8] {
+        note_value_read();
         &self.raw[self.value_offset()..(self.value_offset() + self.value_len())]
     }
 }
@@ -471,6 +1013,7 @@ impl<'a> EntryMutator<'a> {
 //
 // Entry format is:
 // * (1 byte) type: 1 = LEAF
+// * (1 byte) whether greater_entry is present. An explicit flag, so that keys may be empty
 // * (n bytes) lesser_entry
 // * (n bytes) greater_entry: optional
 struct LeafAccessor<'a: 'b, 'b> {
@@ -483,11 +1026,11 @@ impl<'a: 'b, 'b> LeafAccessor<'a, 'b> {
     }
 
     fn offset_of_lesser(&self) -> usize {
-        1
+        LEAF_HEADER_SIZE
     }
 
     fn offset_of_greater(&self) -> usize {
-        1 + self.lesser().raw_len()
+        LEAF_HEADER_SIZE + self.lesser().raw_len()
     }
 
     fn lesser(&self) -> EntryAccessor<'b> {
@@ -495,11 +1038,12 @@ impl<'a: 'b, 'b> LeafAccessor<'a, 'b> {
     }
 
     fn greater(&self) -> Option<EntryAccessor<'b>> {
-        let entry = EntryAccessor::new(&self.page.memory()[self.offset_of_greater()..]);
-        if entry.key_len() == 0 {
+        if self.page.memory()[1] == 0 {
             None
         } else {
-            Some(entry)
+            Some(EntryAccessor::new(
+                &self.page.memory()[self.offset_of_greater()..],
+            ))
         }
     }
 }
@@ -517,21 +1061,21 @@ impl<'a: 'b, 'b> LeafBuilder<'a, 'b> {
     }
 
     fn write_lesser(&mut self, table_id: u64, key: &[u8], value: &[u8]) {
-        let mut entry = EntryMutator::new(&mut self.page.memory_mut()[1..]);
+        let mut entry = EntryMutator::new(&mut self.page.memory_mut()[LEAF_HEADER_SIZE..]);
         entry.write_table_id(table_id);
         entry.write_key(key);
         entry.write_value(value);
     }
 
     fn write_greater(&mut self, entry: Option<(u64, &[u8], &[u8])>) {
-        let offset = 1 + EntryAccessor::new(&self.page.memory()[1..]).raw_len();
-        let mut writer = EntryMutator::new(&mut self.page.memory_mut()[offset..]);
+        let offset = LEAF_HEADER_SIZE
+            + EntryAccessor::new(&self.page.memory()[LEAF_HEADER_SIZE..]).raw_len();
+        self.page.memory_mut()[1] = entry.is_some() as u8;
         if let Some((table_id, key, value)) = entry {
+            let mut writer = EntryMutator::new(&mut self.page.memory_mut()[offset..]);
             writer.write_table_id(table_id);
             writer.write_key(key);
             writer.write_value(value);
-        } else {
-            writer.write_key(&[]);
         }
     }
 }
@@ -545,6 +1089,7 @@ impl<'a: 'b, 'b> LeafBuilder<'a, 'b> {
 // * (key_len bytes) key_data
 // * (8 bytes) lte_page: page number for keys <= key_data
 // * (8 bytes) gt_page: page number for keys > key_data
+// * (8 bytes) height: of the sub-tree, counting a leaf as 1
 struct InternalAccessor<'a: 'b, 'b> {
     page: &'b Page<'a>,
 }
@@ -575,6 +1120,37 @@ impl<'a: 'b, 'b> InternalAccessor<'a, 'b> {
         let offset = 17 + self.key_len() + 8;
         u64::from_be_bytes(self.page.memory()[offset..(offset + 8)].try_into().unwrap())
     }
+
+    fn height(&self) -> u64 {
+        let offset = 17 + self.key_len() + 16;
+        u64::from_be_bytes(self.page.memory()[offset..(offset + 8)].try_into().unwrap())
+    }
+}
+
+// The fixed size fields of an internal node, which the page manager caches
+// so that hot nodes are not decoded again on every lookup
+#[derive(Copy, Clone)]
+pub(crate) struct InternalMetadata {
+    table_id: u64,
+    key_len: usize,
+    lte_page: u64,
+    gt_page: u64,
+}
+
+impl InternalMetadata {
+    fn decode(page: &Page) -> Self {
+        let accessor = InternalAccessor::new(page);
+        InternalMetadata {
+            table_id: accessor.table_id(),
+            key_len: accessor.key_len(),
+            lte_page: accessor.lte_page(),
+            gt_page: accessor.gt_page(),
+        }
+    }
+
+    fn key<'p>(&self, page: &'p Page) -> &'p [u8] {
+        &page.memory()[17..(17 + self.key_len)]
+    }
 }
 
 // Note the caller is responsible for ensuring that the buffer is large enough
@@ -608,16 +1184,167 @@ impl<'a: 'b, 'b> InternalBuilder<'a, 'b> {
         let offset = 17 + self.key_len() + 8;
         self.page.memory_mut()[offset..(offset + 8)].copy_from_slice(&page_number.to_be_bytes());
     }
+
+    fn write_height(&mut self, height: u64) {
+        let offset = 17 + self.key_len() + 16;
+        self.page.memory_mut()[offset..(offset + 8)].copy_from_slice(&height.to_be_bytes());
+    }
+}
+
+// Writes an internal node over two sub-trees, and returns its page number
+fn write_internal(
+    table: u64,
+    key: &[u8],
+    lte_page: u64,
+    gt_page: u64,
+    manager: &PageManager,
+) -> Result<u64, Error> {
+    let height = max(
+        subtree_height(lte_page, manager)?,
+        subtree_height(gt_page, manager)?,
+    ) + 1;
+    let mut page = manager.allocate()?;
+    let mut builder = InternalBuilder::new(&mut page);
+    builder.write_table_and_key(table, key);
+    builder.write_lte_page(lte_page);
+    builder.write_gt_page(gt_page);
+    builder.write_height(height);
+    Ok(page.get_page_number())
+}
+
+// The height of the sub-tree at the page, counting a leaf as 1
+fn subtree_height(page_number: u64, manager: &PageManager) -> Result<u64, Error> {
+    let page = manager.get_page(page_number);
+    match page.memory()[0] {
+        LEAF => Ok(1),
+        INTERNAL => Ok(InternalAccessor::new(&page).height()),
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+// The fields of an internal node, copied so that its page can be released while they are used
+struct InternalNode {
+    table: u64,
+    key: Vec<u8>,
+    lte_page: u64,
+    gt_page: u64,
+}
+
+impl InternalNode {
+    fn read(page_number: u64, manager: &PageManager) -> Self {
+        let page = manager.get_page(page_number);
+        let accessor = InternalAccessor::new(&page);
+        InternalNode {
+            table: accessor.table_id(),
+            key: accessor.key().to_vec(),
+            lte_page: accessor.lte_page(),
+            gt_page: accessor.gt_page(),
+        }
+    }
+}
+
+// Joins two sub-trees under an internal node with the key, which is at least every key of
+// `lte_page` and less than every key of `gt_page`, and returns its page number. The tree is
+// kept balanced as an AVL tree: if one sub-tree is more than one level taller than the other,
+// the shorter one is joined into the nearest sub-tree of about its height along the edge of
+// the taller one, and the nodes above are rotated. The nodes along that edge are copied, and
+// their pages released
+fn join_subtrees(
+    table: u64,
+    key: &[u8],
+    lte_page: u64,
+    gt_page: u64,
+    manager: &PageManager,
+) -> Result<u64, Error> {
+    let lte_height = subtree_height(lte_page, manager)?;
+    let gt_height = subtree_height(gt_page, manager)?;
+    if lte_height > gt_height + 1 {
+        let left = InternalNode::read(lte_page, manager);
+        let joined = join_subtrees(table, key, left.gt_page, gt_page, manager)?;
+        let page_number = rotate(left.table, &left.key, left.lte_page, joined, manager)?;
+        manager.release(lte_page);
+        Ok(page_number)
+    } else if gt_height > lte_height + 1 {
+        let right = InternalNode::read(gt_page, manager);
+        let joined = join_subtrees(table, key, lte_page, right.lte_page, manager)?;
+        let page_number = rotate(right.table, &right.key, joined, right.gt_page, manager)?;
+        manager.release(gt_page);
+        Ok(page_number)
+    } else {
+        write_internal(table, key, lte_page, gt_page, manager)
+    }
+}
+
+// Writes an internal node over two balanced sub-trees, whose heights differ by at most two,
+// rotating it if they differ by two, so that it is balanced too. Returns its page number
+fn rotate(
+    table: u64,
+    key: &[u8],
+    lte_page: u64,
+    gt_page: u64,
+    manager: &PageManager,
+) -> Result<u64, Error> {
+    let lte_height = subtree_height(lte_page, manager)?;
+    let gt_height = subtree_height(gt_page, manager)?;
+    if lte_height > gt_height + 1 {
+        let left = InternalNode::read(lte_page, manager);
+        let page_number =
+            if subtree_height(left.lte_page, manager)? >= subtree_height(left.gt_page, manager)? {
+                let right = write_internal(table, key, left.gt_page, gt_page, manager)?;
+                write_internal(left.table, &left.key, left.lte_page, right, manager)?
+            } else {
+                let middle = InternalNode::read(left.gt_page, manager);
+                let new_left = write_internal(
+                    left.table,
+                    &left.key,
+                    left.lte_page,
+                    middle.lte_page,
+                    manager,
+                )?;
+                let new_right = write_internal(table, key, middle.gt_page, gt_page, manager)?;
+                manager.release(left.gt_page);
+                write_internal(middle.table, &middle.key, new_left, new_right, manager)?
+            };
+        manager.release(lte_page);
+        Ok(page_number)
+    } else if gt_height > lte_height + 1 {
+        let right = InternalNode::read(gt_page, manager);
+        let page_number = if subtree_height(right.gt_page, manager)?
+            >= subtree_height(right.lte_page, manager)?
+        {
+            let left = write_internal(table, key, lte_page, right.lte_page, manager)?;
+            write_internal(right.table, &right.key, left, right.gt_page, manager)?
+        } else {
+            let middle = InternalNode::read(right.lte_page, manager);
+            let new_left = write_internal(table, key, lte_page, middle.lte_page, manager)?;
+            let new_right = write_internal(
+                right.table,
+                &right.key,
+                middle.gt_page,
+                right.gt_page,
+                manager,
+            )?;
+            manager.release(right.lte_page);
+            write_internal(middle.table, &middle.key, new_left, new_right, manager)?
+        };
+        manager.release(gt_page);
+        Ok(page_number)
+    } else {
+        write_internal(table, key, lte_page, gt_page, manager)
+    }
 }
 
 // Returns the page number of the sub-tree with this key deleted, or None if the sub-tree is empty.
-// If key is not found, guaranteed not to modify the tree
+// If key is not found, guaranteed not to modify the tree.
+// A leaf that is emptied is removed, and its parent replaced by its sibling. Two sibling leaves
+// that hold one entry each are also merged, replacing their parent. The nodes above are rotated
+// whenever a sub-tree gets two levels shorter than its sibling, see `join_subtrees`
 pub(crate) fn tree_delete<'a, K: RadbKey + ?Sized>(
     page: Page<'a>,
     table: u64,
     key: &[u8],
     manager: &'a PageManager,
-) -> Option<u64> {
+) -> Result<Option<u64>, Error> {
     let node_mem = page.memory();
     match node_mem[0] {
         LEAF => {
@@ -628,39 +1355,29 @@ pub(crate) fn tree_delete<'a, K: RadbKey + ?Sized>(
                     && greater.compare::<K>(table, key).is_ne()
                 {
                     // Not found
-                    return Some(page.get_page_number());
+                    return Ok(Some(page.get_page_number()));
                 }
                 // Found, create a new leaf with the other key
-                let new_leaf = if accessor.lesser().compare::<K>(table, key).is_eq() {
-                    Leaf(
-                        (
-                            greater.table_id(),
-                            greater.key().to_vec(),
-                            greater.value().to_vec(),
-                        ),
-                        None,
-                    )
+                let other = if accessor.lesser().compare::<K>(table, key).is_eq() {
+                    greater
                 } else {
-                    Leaf(
-                        (
-                            accessor.lesser().table_id(),
-                            accessor.lesser().key().to_vec(),
-                            accessor.lesser().value().to_vec(),
-                        ),
-                        None,
-                    )
+                    accessor.lesser()
                 };
-
-                // TODO: shouldn't need to drop this, but we can't allocate when there are pages in flight
-                drop(page);
-                Some(new_leaf.to_bytes(manager))
+                let mut new_page = manager.allocate()?;
+                let mut builder = LeafBuilder::new(&mut new_page);
+                builder.write_lesser(other.table_id(), other.key(), other.value());
+                builder.write_greater(None);
+                // only once it has been copied, since a released page may be allocated again
+                manager.release(page.get_page_number());
+                Ok(Some(new_page.get_page_number()))
             } else {
                 if accessor.lesser().compare::<K>(table, key).is_eq() {
                     // Deleted the entire left
-                    None
+                    manager.release(page.get_page_number());
+                    Ok(None)
                 } else {
                     // Not found
-                    Some(page.get_page_number())
+                    Ok(Some(page.get_page_number()))
                 }
             }
         }
@@ -673,47 +1390,166 @@ pub(crate) fn tree_delete<'a, K: RadbKey + ?Sized>(
             let mut right_page = accessor.gt_page();
             // TODO: we should recompute our key, since it may now be smaller (if the largest key in the left tree was deleted)
             let our_table = accessor.table_id();
-            let our_key = accessor.key().to_vec();
-            // TODO: shouldn't need to drop this, but we can't allocate when there are pages in flight
-            drop(page);
+            let our_key = accessor.key();
             #[allow(clippy::collapsible_else_if)]
-            if cmp_keys::<K>(table, key, our_table, our_key.as_slice()).is_le() {
+            if cmp_keys::<K>(table, key, our_table, our_key).is_le() {
                 if let Some(page_number) =
-                    tree_delete::<K>(manager.get_page(left_page), table, key, manager)
+                    tree_delete::<K>(manager.get_page(left_page), table, key, manager)?
                 {
                     left_page = page_number;
                 } else {
                     // The entire left sub-tree was deleted, replace ourself with the right tree
-                    return Some(right_page);
+                    manager.release(original_page_number);
+                    return Ok(Some(right_page));
                 }
             } else {
                 if let Some(page_number) =
-                    tree_delete::<K>(manager.get_page(right_page), table, key, manager)
+                    tree_delete::<K>(manager.get_page(right_page), table, key, manager)?
                 {
                     right_page = page_number;
                 } else {
-                    return Some(left_page);
+                    manager.release(original_page_number);
+                    return Ok(Some(left_page));
                 }
             }
 
             // The key was not found, since neither sub-tree changed
             if left_page == original_left_page && right_page == original_right_page {
-                return Some(original_page_number);
+                return Ok(Some(original_page_number));
             }
 
             // MVCC read isolation: (snapshot)
             // If we remove something in the sub-tree, we will allocate spaces
             // for all the affected nodes, actually, which means that the root node
             // will also be a new allocated page, which make us achieve read isolation
-            let mut page = manager.allocate();
-            let mut builder = InternalBuilder::new(&mut page);
-            builder.write_table_and_key(our_table, &our_key);
-            builder.write_lte_page(left_page);
-            builder.write_gt_page(right_page);
+            if let Some(page_number) = merge_sibling_leaves(left_page, right_page, manager)? {
+                manager.release(original_page_number);
+                return Ok(Some(page_number));
+            }
+            // a sub-tree that lost a level is rebalanced against its sibling
+            let page_number = join_subtrees(our_table, our_key, left_page, right_page, manager)?;
+            manager.release(original_page_number);
 
-            Some(page.get_page_number())
+            Ok(Some(page_number))
         }
-        _ => unreachable!(),
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+// Merges two sibling leaves into one, if they hold one entry each, and returns the page number
+// of the merged leaf. Otherwise returns None, and leaves both untouched
+fn merge_sibling_leaves(
+    left_page: u64,
+    right_page: u64,
+    manager: &PageManager,
+) -> Result<Option<u64>, Error> {
+    let left = manager.get_page(left_page);
+    let right = manager.get_page(right_page);
+    if left.memory()[0] != LEAF || right.memory()[0] != LEAF {
+        return Ok(None);
+    }
+    let left = LeafAccessor::new(&left);
+    let right = LeafAccessor::new(&right);
+    if left.greater().is_some() || right.greater().is_some() {
+        return Ok(None);
+    }
+    let (lesser, greater) = (left.lesser(), right.lesser());
+    let mut page = manager.allocate()?;
+    let mut builder = LeafBuilder::new(&mut page);
+    builder.write_lesser(lesser.table_id(), lesser.key(), lesser.value());
+    builder.write_greater(Some((greater.table_id(), greater.key(), greater.value())));
+    manager.release(left_page);
+    manager.release(right_page);
+    Ok(Some(page.get_page_number()))
+}
+
+// Returns the page number of the sub-tree with every entry of the table deleted, or None if the
+// sub-tree is empty. Every key of the sub-tree is greater than a key of the `lower` table, and
+// at most a key of the `upper` table, if they are known. Only the sub-trees that may hold the
+// table are walked, and one that holds nothing else is released whole.
+// If nothing was deleted, guaranteed not to modify the tree. Leaves are merged as in `tree_delete`
+pub(crate) fn tree_delete_table(
+    page: Page,
+    table: u64,
+    lower: Option<u64>,
+    upper: Option<u64>,
+    manager: &PageManager,
+) -> Result<Option<u64>, Error> {
+    let page_number = page.get_page_number();
+    if lower == Some(table) && upper == Some(table) {
+        let mut pages = HashSet::new();
+        collect_pages(page, manager, &mut pages)?;
+        for page in pages {
+            manager.release(page);
+        }
+        return Ok(None);
+    }
+    match page.memory()[0] {
+        LEAF => {
+            let accessor = LeafAccessor::new(&page);
+            let entries: Vec<EntryAccessor> = [Some(accessor.lesser()), accessor.greater()]
+                .into_iter()
+                .flatten()
+                .collect();
+            let len = entries.len();
+            let kept: Vec<EntryAccessor> = entries
+                .into_iter()
+                .filter(|entry| entry.table_id() != table)
+                .collect();
+            if kept.len() == len {
+                return Ok(Some(page_number));
+            }
+            let new_page_number = match kept.first() {
+                Some(entry) => {
+                    let mut new_page = manager.allocate()?;
+                    let mut builder = LeafBuilder::new(&mut new_page);
+                    builder.write_lesser(entry.table_id(), entry.key(), entry.value());
+                    builder.write_greater(None);
+                    Some(new_page.get_page_number())
+                }
+                None => None,
+            };
+            // only once it has been copied, since a released page may be allocated again
+            manager.release(page_number);
+            Ok(new_page_number)
+        }
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            let (left_page, right_page) = (accessor.lte_page(), accessor.gt_page());
+            let our_table = accessor.table_id();
+            // the left sub-tree holds keys up to ours, and the right one the keys after it
+            let left = if table <= our_table {
+                let page = manager.get_page(left_page);
+                tree_delete_table(page, table, lower, Some(our_table), manager)?
+            } else {
+                Some(left_page)
+            };
+            let right = if table >= our_table {
+                let page = manager.get_page(right_page);
+                tree_delete_table(page, table, Some(our_table), upper, manager)?
+            } else {
+                Some(right_page)
+            };
+            let (left, right) = match (left, right) {
+                (Some(left), Some(right)) => (left, right),
+                (remaining, None) | (None, remaining) => {
+                    manager.release(page_number);
+                    return Ok(remaining);
+                }
+            };
+            if left == left_page && right == right_page {
+                return Ok(Some(page_number));
+            }
+            if let Some(merged) = merge_sibling_leaves(left, right, manager)? {
+                manager.release(page_number);
+                return Ok(Some(merged));
+            }
+            // whole sub-trees may have been removed, so the heights may differ by any amount
+            let new_page_number = join_subtrees(our_table, accessor.key(), left, right, manager)?;
+            manager.release(page_number);
+            Ok(Some(new_page_number))
+        }
+        byte => Err(invalid_node_type(&page, byte)),
     }
 }
 
@@ -724,73 +1560,81 @@ pub(crate) fn tree_insert<'a, K: RadbKey + ?Sized>(
     key: &[u8],
     value: &[u8],
     manager: &'a PageManager,
-) -> u64 {
+) -> Result<u64, Error> {
     let node_mem = page.memory();
     match node_mem[0] {
         LEAF => {
             // in a binary search tree (BST), every non-duplicated key-value
             // pair should always be inserted at a leaf node.
 
-            let accessor = LeafAccessor::new(&page);
-            // TODO: this is suboptimal, because it may rebuild the leaf page even if it's not necessary:
-            // e.g. when we insert a second leaf adjacent without modifying this one
-            let mut builder = BinarytreeBuilder::new();
-            builder.add(table, key, value);
-            if accessor.lesser().compare::<K>(table, key).is_ne() {
-                builder.add(
-                    accessor.lesser().table_id(),
-                    accessor.lesser().key(),
-                    accessor.lesser().value(),
-                );
-            }
-            if let Some(entry) = accessor.greater() {
-                if entry.compare::<K>(table, key).is_ne() {
-                    builder.add(entry.table_id(), entry.key(), entry.value());
-                }
-            }
-            // TODO: shouldn't need to drop this, but we can't allocate when there are pages in flight
-            // This guaranteed the MVCC read isolation, since every conflicting page will be dropped.
-            drop(page);
-            builder.build::<K>().to_bytes(manager)
+            let page_number = page.get_page_number();
+            let builder = copy_leaf_with::<K>(page, table, key, value);
+            // the entries have been copied into the builder, so a scratch page can be reused right away
+            manager.release(page_number);
+            builder.build::<K>(table).to_bytes(manager)
         }
         INTERNAL => {
             let accessor = InternalAccessor::new(&page);
             let mut left_page = accessor.lte_page();
             let mut right_page = accessor.gt_page();
             let our_table = accessor.table_id();
-            let our_key = accessor.key().to_vec();
-            // TODO: shouldn't need to drop this, but we can't allocate when there are pages in flight
-            // This guaranteed the MVCC read isolation, since every conflicting page will be dropped.
-            drop(page);
-            if (table, key) <= (our_table, our_key.as_slice()) {
+            let our_key = accessor.key();
+            if cmp_keys::<K>(table, key, our_table, our_key).is_le() {
                 left_page =
-                    tree_insert::<K>(manager.get_page(left_page), table, key, value, manager);
+                    tree_insert::<K>(manager.get_page(left_page), table, key, value, manager)?;
             } else {
                 right_page =
-                    tree_insert::<K>(manager.get_page(right_page), table, key, value, manager);
+                    tree_insert::<K>(manager.get_page(right_page), table, key, value, manager)?;
             }
 
-            // create the new root node
-            let mut page = manager.allocate();
-            let mut builder = InternalBuilder::new(&mut page);
-            builder.write_table_and_key(our_table, &our_key);
-            builder.write_lte_page(left_page);
-            builder.write_gt_page(right_page);
+            // create the new root node, rebalanced if the sub-tree grew a level
+            let new_page_number =
+                join_subtrees(our_table, our_key, left_page, right_page, manager)?;
+            // this node is always copied, so a scratch page can be reused, once its key is written
+            manager.release(page.get_page_number());
 
-            page.get_page_number()
+            Ok(new_page_number)
         }
-        _ => unreachable!(),
+        byte => Err(invalid_node_type(&page, byte)),
     }
 }
 
-/// Returns a tuple of the form `(Page<'a>, usize, usize)` representing the value for
+// Copies the entries of a leaf into a builder, along with the inserted entry, which replaces the
+// entry of the same key. Takes the page, so that it is no longer read once it is released
+fn copy_leaf_with<K: RadbKey + ?Sized>(
+    page: Page,
+    table: u64,
+    key: &[u8],
+    value: &[u8],
+) -> BinarytreeBuilder {
+    let accessor = LeafAccessor::new(&page);
+    // TODO: this is suboptimal, because it may rebuild the leaf page even if it's not necessary:
+    // e.g. when we insert a second leaf adjacent without modifying this one
+    let mut builder = BinarytreeBuilder::new();
+    builder.add(table, key, value);
+    if accessor.lesser().compare::<K>(table, key).is_ne() {
+        builder.add(
+            accessor.lesser().table_id(),
+            accessor.lesser().key(),
+            accessor.lesser().value(),
+        );
+    }
+    if let Some(entry) = accessor.greater() {
+        if entry.compare::<K>(table, key).is_ne() {
+            builder.add(entry.table_id(), entry.key(), entry.value());
+        }
+    }
+    builder
+}
+
+/// Returns a tuple of the form `(Page<'a>, usize)` representing the entry for
 /// a queried key within a binary tree if present.
 ///
 /// The binary tree is composed of Nodes serialized into `Page`s and maintained by a `PageManager`.
 /// This function attempts to locate a key within this tree and if found, returns a tuple where:
-/// - The first element is the `Page` in which the value is located
-/// - The second element is the offset within that page where the value begins
-/// - The third element is the length of the value
+/// - The first element is the `Page` in which the entry is located
+/// - The second element is the offset within that page where the entry begins, which can be
+///   read with `entry_location()`
 ///
 /// Given a key, the function begins at the root of the tree and traverses to the left or right
 /// child depending on whether the key is less or greater than the current node's key. The process
@@ -809,26 +1653,26 @@ pub(crate) fn tree_insert<'a, K: RadbKey + ?Sized>(
 ///
 /// # Returns
 ///
-/// An `Option` that contains a tuple `(Page<'a>, usize, usize)`. If the key is found, it returns `Some`,
-/// with the `Page` containing the value, the offset of the value within the page, and the length of the value.
+/// An `Option` that contains a tuple `(Page<'a>, usize)`. If the key is found, it returns `Some`,
+/// with the `Page` containing the entry and the offset of the entry within the page.
 /// If the key is not found in the tree, it returns `None`.
 ///
-/// # Panics
+/// # Errors
 ///
-/// This function will panic if it encounters a byte in the `Page` memory that does not correspond to a
-/// recognized node type (1 for leaf node or 2 for internal node).
+/// This function returns `Error::Corruption` if it encounters a byte in the `Page` memory that does
+/// not correspond to a recognized node type (1 for leaf node or 2 for internal node).
 pub(crate) fn lookup_in_raw<'a, K: RadbKey + ?Sized>(
     page: Page<'a>,
     table: u64,
     query: &[u8],
     manager: &'a PageManager,
-) -> Option<(Page<'a>, usize, usize)> {
+) -> Result<Option<(Page<'a>, usize)>, Error> {
     let node_mem = page.memory();
     match node_mem[0] {
         LEAF => {
             // Leaf node
             let accessor = LeafAccessor::new(&page);
-            match cmp_keys::<K>(
+            let found = match cmp_keys::<K>(
                 table,
                 query,
                 accessor.lesser().table_id(),
@@ -836,16 +1680,14 @@ pub(crate) fn lookup_in_raw<'a, K: RadbKey + ?Sized>(
             ) {
                 Ordering::Less => None,
                 Ordering::Equal => {
-                    let offset = accessor.offset_of_lesser() + accessor.lesser().value_offset();
-                    let value_len = accessor.lesser().value().len();
-                    Some((page, offset, value_len))
+                    let offset = accessor.offset_of_lesser();
+                    Some((page, offset))
                 }
                 Ordering::Greater => {
                     if let Some(entry) = accessor.greater() {
                         if entry.compare::<K>(table, query).is_eq() {
-                            let offset = accessor.offset_of_greater() + entry.value_offset();
-                            let value_len = entry.value().len();
-                            Some((page, offset, value_len))
+                            let offset = accessor.offset_of_greater();
+                            Some((page, offset))
                         } else {
                             None
                         }
@@ -853,19 +1695,502 @@ pub(crate) fn lookup_in_raw<'a, K: RadbKey + ?Sized>(
                         None
                     }
                 }
+            };
+            Ok(found)
+        }
+        INTERNAL => {
+            let node = manager
+                .internal_metadata(page.get_page_number(), || InternalMetadata::decode(&page));
+            if cmp_keys::<K>(table, query, node.table_id, node.key(&page)).is_le() {
+                lookup_in_raw::<K>(manager.get_page(node.lte_page), table, query, manager)
+            } else {
+                lookup_in_raw::<K>(manager.get_page(node.gt_page), table, query, manager)
             }
         }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+/// Looks up many keys at once, like calling `lookup_in_raw()` for each of them, but descends
+/// into each node at most once. `queries` must be sorted by `K::compare`. At an internal node
+/// the queries are split between its two children, so a run of nearby keys shares the nodes
+/// above them.
+///
+/// Appends the page number and the offset of the entry of each query, in the order of
+/// `queries`, or `None` for a query that is not in the tree
+pub(crate) fn lookup_many_raw<K: RadbKey + ?Sized>(
+    page: Page,
+    table: u64,
+    queries: &[&[u8]],
+    manager: &PageManager,
+    found: &mut Vec<Option<(u64, usize)>>,
+) -> Result<(), Error> {
+    if queries.is_empty() {
+        return Ok(());
+    }
+    let node_mem = page.memory();
+    match node_mem[0] {
+        LEAF => {
+            let accessor = LeafAccessor::new(&page);
+            for query in queries {
+                let offset = if accessor.lesser().compare::<K>(table, query).is_eq() {
+                    Some(accessor.offset_of_lesser())
+                } else {
+                    match accessor.greater() {
+                        Some(entry) if entry.compare::<K>(table, query).is_eq() => {
+                            Some(accessor.offset_of_greater())
+                        }
+                        _ => None,
+                    }
+                };
+                found.push(offset.map(|offset| (page.get_page_number(), offset)));
+            }
+            Ok(())
+        }
+        INTERNAL => {
+            let node = manager
+                .internal_metadata(page.get_page_number(), || InternalMetadata::decode(&page));
+            let key = node.key(&page);
+            let split = queries
+                .partition_point(|query| cmp_keys::<K>(table, query, node.table_id, key).is_le());
+            let (lte, gt) = queries.split_at(split);
+            lookup_many_raw::<K>(manager.get_page(node.lte_page), table, lte, manager, found)?;
+            lookup_many_raw::<K>(manager.get_page(node.gt_page), table, gt, manager, found)
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+/// Finds the entry of the table with the largest key <= `query` (`before == true`), or the
+/// smallest key >= `query`, ordered by `K::compare`.
+///
+/// Returns the page and the offset of the entry, like `lookup_in_raw()`, or `None` if the
+/// query is past the first or last entry of the table
+pub(crate) fn lookup_nearest_raw<'a, K: RadbKey + ?Sized>(
+    page: Page<'a>,
+    table: u64,
+    query: &[u8],
+    before: bool,
+    manager: &'a PageManager,
+) -> Result<Option<(Page<'a>, usize)>, Error> {
+    let position = |entry_table: u64, key: &[u8]| cmp_keys::<K>(table, query, entry_table, key);
+    let found = find_nearest(page, &position, before, manager)?;
+    Ok(found.filter(|(page, offset)| entry_in_table(page, *offset, table)))
+}
+
+/// Finds the first entry of the table, or the last entry if `last` is true, without
+/// comparing any keys
+pub(crate) fn lookup_edge_raw<'a>(
+    page: Page<'a>,
+    table: u64,
+    last: bool,
+    manager: &'a PageManager,
+) -> Result<Option<(Page<'a>, usize)>, Error> {
+    // a position just before, or just after, every entry of the table
+    let position = |entry_table: u64, _: &[u8]| match table.cmp(&entry_table) {
+        Ordering::Equal if last => Ordering::Greater,
+        Ordering::Equal => Ordering::Less,
+        ordering => ordering,
+    };
+    let found = find_nearest(page, &position, last, manager)?;
+    Ok(found.filter(|(page, offset)| entry_in_table(page, *offset, table)))
+}
+
+fn entry_in_table(page: &Page, offset: usize, table: u64) -> bool {
+    EntryAccessor::new(&page.memory()[offset..]).table_id() == table
+}
+
+// Descends to the last entry at or before a position in the tree, or the first entry at or
+// after it. `position` compares the position with the table id and key of an entry
+fn find_nearest<'a>(
+    page: Page<'a>,
+    position: &dyn Fn(u64, &[u8]) -> Ordering,
+    before: bool,
+    manager: &'a PageManager,
+) -> Result<Option<(Page<'a>, usize)>, Error> {
+    match page.memory()[0] {
+        LEAF => {
+            let offset = {
+                let accessor = LeafAccessor::new(&page);
+                let lesser = accessor.lesser();
+                let lesser_matches = if before {
+                    position(lesser.table_id(), lesser.key()).is_ge()
+                } else {
+                    position(lesser.table_id(), lesser.key()).is_le()
+                };
+                let greater_matches = accessor.greater().is_some_and(|greater| {
+                    if before {
+                        position(greater.table_id(), greater.key()).is_ge()
+                    } else {
+                        position(greater.table_id(), greater.key()).is_le()
+                    }
+                });
+                // the greater entry is nearer when looking before the position, and the lesser
+                // entry when looking after it
+                if before && greater_matches {
+                    Some(accessor.offset_of_greater())
+                } else if lesser_matches {
+                    Some(accessor.offset_of_lesser())
+                } else if greater_matches {
+                    Some(accessor.offset_of_greater())
+                } else {
+                    None
+                }
+            };
+            Ok(offset.map(|offset| (page, offset)))
+        }
         INTERNAL => {
             let accessor = InternalAccessor::new(&page);
-            let left_page = accessor.lte_page();
-            let right_page = accessor.gt_page();
-            if cmp_keys::<K>(table, query, accessor.table_id(), accessor.key()).is_le() {
-                lookup_in_raw::<K>(manager.get_page(left_page), table, query, manager)
-            } else {
-                lookup_in_raw::<K>(manager.get_page(right_page), table, query, manager)
+            let lte = accessor.lte_page();
+            let gt = accessor.gt_page();
+            // every key in the gt subtree is after the position, when this is true
+            let in_lte = position(accessor.table_id(), accessor.key()).is_le();
+            match (in_lte, before) {
+                (true, true) => find_nearest(manager.get_page(lte), position, before, manager),
+                (false, false) => find_nearest(manager.get_page(gt), position, before, manager),
+                // the nearest entry may be on either side: if the side of the position has none,
+                // it is at the edge of the other side
+                (true, false) => {
+                    match find_nearest(manager.get_page(lte), position, before, manager)? {
+                        Some(found) => Ok(Some(found)),
+                        None => find_nearest(
+                            manager.get_page(gt),
+                            &|_, _| Ordering::Less,
+                            false,
+                            manager,
+                        ),
+                    }
+                }
+                (false, true) => {
+                    match find_nearest(manager.get_page(gt), position, before, manager)? {
+                        Some(found) => Ok(Some(found)),
+                        None => find_nearest(
+                            manager.get_page(lte),
+                            &|_, _| Ordering::Greater,
+                            true,
+                            manager,
+                        ),
+                    }
+                }
             }
         }
-        _ => unreachable!(),
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+// State of a walk over the tree by `verify_tree()`
+pub(crate) struct VerifyState {
+    pub(crate) visited: HashSet<u64>,
+    pub(crate) errors: Vec<String>,
+    // the last entry seen by the in-order walk
+    last: Option<(u64, Vec<u8>)>,
+    // table whose keys are raw bytes, so their order can be checked.
+    // The key types of other tables are not known, so only their table ids are checked
+    raw_table: u64,
+}
+
+impl VerifyState {
+    pub(crate) fn new(raw_table: u64) -> Self {
+        VerifyState {
+            visited: HashSet::new(),
+            errors: vec![],
+            last: None,
+            raw_table,
+        }
+    }
+
+    fn check_order(&mut self, page_number: u64, table: u64, key: &[u8]) {
+        if let Some((last_table, last_key)) = &self.last {
+            if *last_table > table
+                || (*last_table == table && table == self.raw_table && last_key.as_slice() >= key)
+            {
+                self.errors.push(format!(
+                    "page {}: entry ({}, {:?}) is out of order",
+                    page_number, table, key
+                ));
+            }
+        }
+        self.last = Some((table, key.to_vec()));
+    }
+}
+
+// Checks that an entry starting at `offset` fits in the page, and returns its length
+fn verify_entry_len(memory: &[u8], offset: usize) -> Option<usize> {
+    let read_u64 = |at: usize| -> Option<usize> {
+        memory
+            .get(at..(at + 8))
+            .map(|x| u64::from_be_bytes(x.try_into().unwrap()) as usize)
+    };
+    let key_len = read_u64(offset)?;
+    let value_len = read_u64(offset.checked_add(16)?.checked_add(key_len)?)?;
+    let len = (16 + key_len + 8).checked_add(value_len)?;
+    if offset + len <= memory.len() {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// Renders the sub-tree at `page` as text, one node per line, indented by its depth.
+/// Internal nodes show their separator key, which is the largest key of their lte sub-tree,
+/// and leaves show their entries, with the length of each value
+pub(crate) fn dump_tree(
+    page: Page,
+    depth: usize,
+    manager: &PageManager,
+    out: &mut String,
+) -> Result<(), Error> {
+    let indent = "  ".repeat(depth);
+    match page.memory()[0] {
+        LEAF => {
+            let accessor = LeafAccessor::new(&page);
+            out.push_str(&format!("{}LEAF page {}:", indent, page.get_page_number()));
+            for entry in std::iter::once(accessor.lesser()).chain(accessor.greater()) {
+                out.push_str(&format!(
+                    " (table {}, key \"{}\", {} byte value)",
+                    entry.table_id(),
+                    entry.key().escape_ascii(),
+                    entry.value_len()
+                ));
+            }
+            out.push('\n');
+            Ok(())
+        }
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            out.push_str(&format!(
+                "{}INTERNAL page {}: table {}, key \"{}\"\n",
+                indent,
+                page.get_page_number(),
+                accessor.table_id(),
+                accessor.key().escape_ascii()
+            ));
+            let (lte, gt) = (accessor.lte_page(), accessor.gt_page());
+            dump_tree(manager.get_page(lte), depth + 1, manager, out)?;
+            dump_tree(manager.get_page(gt), depth + 1, manager, out)
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+/// Appends the usage of every page of the sub-tree at `page`, in depth-first order
+pub(crate) fn page_usage(
+    page: Page,
+    manager: &PageManager,
+    out: &mut Vec<PageUsage>,
+) -> Result<(), Error> {
+    let page_size = page.memory().len();
+    match page.memory()[0] {
+        LEAF => {
+            let accessor = LeafAccessor::new(&page);
+            let entries = std::iter::once(accessor.lesser()).chain(accessor.greater());
+            out.push(PageUsage {
+                page_number: page.get_page_number(),
+                node_type: NodeType::Leaf,
+                used_bytes: LEAF_HEADER_SIZE + entries.map(|entry| entry.raw_len()).sum::<usize>(),
+                page_size,
+            });
+            Ok(())
+        }
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            out.push(PageUsage {
+                page_number: page.get_page_number(),
+                node_type: NodeType::Internal,
+                used_bytes: 17 + accessor.key_len() + 24,
+                page_size,
+            });
+            let (lte, gt) = (accessor.lte_page(), accessor.gt_page());
+            page_usage(manager.get_page(lte), manager, out)?;
+            page_usage(manager.get_page(gt), manager, out)
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+/// Adds the pages of the sub-tree to `pages`, skipping any sub-tree whose root is already
+/// there, since versions of the tree share their unchanged sub-trees. Returns the height of
+/// the part that was walked
+pub(crate) fn collect_pages(
+    page: Page,
+    manager: &PageManager,
+    pages: &mut HashSet<u64>,
+) -> Result<usize, Error> {
+    if !pages.insert(page.get_page_number()) {
+        return Ok(0);
+    }
+    match page.memory()[0] {
+        LEAF => Ok(1),
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            let (lte, gt) = (accessor.lte_page(), accessor.gt_page());
+            let lte_height = collect_pages(manager.get_page(lte), manager, pages)?;
+            let gt_height = collect_pages(manager.get_page(gt), manager, pages)?;
+            Ok(1 + max(lte_height, gt_height))
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+/// Selects the `targets` in the sub-tree, in order, along with every page on the path to each
+/// of them, since a parent must be copied to point at a copy of its child. Stops once another
+/// path would take more than `budget` pages, and returns false if it did. Then `path` holds
+/// the pages above the one it stopped at, each with the index of the child that leads there,
+/// 0 for `lte` and 1 for `gt`. Passing those indexes as `resume` skips every page before that
+/// one, except the ones on its path. Relocation copies pages, so they stay valid for the
+/// relocated tree
+pub(crate) fn select_relocations(
+    page: Page,
+    targets: &HashSet<u64>,
+    budget: usize,
+    manager: &PageManager,
+    resume: &[usize],
+    path: &mut Vec<(u64, usize)>,
+    selected: &mut HashSet<u64>,
+) -> Result<bool, Error> {
+    let page_number = page.get_page_number();
+    if targets.contains(&page_number) {
+        let needed = path
+            .iter()
+            .map(|(p, _)| *p)
+            .chain([page_number])
+            .filter(|p| !selected.contains(p))
+            .count();
+        if selected.len() + needed > budget {
+            return Ok(false);
+        }
+        selected.extend(path.iter().map(|(p, _)| *p).chain([page_number]));
+    }
+    match page.memory()[0] {
+        LEAF => {}
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            let first = resume.first().copied().unwrap_or(0);
+            for (index, child) in [accessor.lte_page(), accessor.gt_page()]
+                .into_iter()
+                .enumerate()
+                .skip(first)
+            {
+                let resume = if index == first && !resume.is_empty() {
+                    &resume[1..]
+                } else {
+                    &[]
+                };
+                path.push((page_number, index));
+                let child = manager.get_page(child);
+                if !select_relocations(child, targets, budget, manager, resume, path, selected)? {
+                    return Ok(false);
+                }
+                path.pop();
+            }
+        }
+        byte => return Err(invalid_node_type(&page, byte)),
+    }
+    Ok(true)
+}
+
+/// Copies the selected pages of the sub-tree to newly allocated pages, and returns the page
+/// number of its root. The selection must include the path to each selected page, as made
+/// by `select_relocations`. The copied pages are left in place, for any reader of them
+pub(crate) fn relocate_pages(
+    page: Page,
+    selected: &HashSet<u64>,
+    manager: &PageManager,
+) -> Result<u64, Error> {
+    if !selected.contains(&page.get_page_number()) {
+        return Ok(page.get_page_number());
+    }
+    match page.memory()[0] {
+        LEAF => {
+            let mut new_page = manager.allocate()?;
+            new_page.memory_mut().copy_from_slice(page.memory());
+            Ok(new_page.get_page_number())
+        }
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            let lte = relocate_pages(manager.get_page(accessor.lte_page()), selected, manager)?;
+            let gt = relocate_pages(manager.get_page(accessor.gt_page()), selected, manager)?;
+            let mut new_page = manager.allocate()?;
+            new_page.memory_mut().copy_from_slice(page.memory());
+            let mut builder = InternalBuilder::new(&mut new_page);
+            builder.write_lte_page(lte);
+            builder.write_gt_page(gt);
+            Ok(new_page.get_page_number())
+        }
+        byte => Err(invalid_node_type(&page, byte)),
+    }
+}
+
+/// Walks the sub-tree at `page_number`, recording every page visited and any inconsistency:
+/// pages that are out of bounds or referenced twice, unknown node types, entries that
+/// overflow their page, and entries that are out of order.
+/// Unlike the other tree functions, this never panics on a corrupted tree
+pub(crate) fn verify_tree(page_number: u64, manager: &PageManager, state: &mut VerifyState) {
+    if page_number == 0 || page_number >= manager.get_next_free_page() {
+        state
+            .errors
+            .push(format!("page {} is out of bounds", page_number));
+        return;
+    }
+    if !state.visited.insert(page_number) {
+        state
+            .errors
+            .push(format!("page {} is referenced more than once", page_number));
+        return;
+    }
+
+    let page = manager.get_page(page_number);
+    match page.memory()[0] {
+        LEAF => {
+            let lesser_len = match verify_entry_len(page.memory(), LEAF_HEADER_SIZE) {
+                Some(len) => len,
+                None => {
+                    state
+                        .errors
+                        .push(format!("page {}: lesser entry overflows", page_number));
+                    return;
+                }
+            };
+            let accessor = LeafAccessor::new(&page);
+            let lesser = accessor.lesser();
+            state.check_order(page_number, lesser.table_id(), lesser.key());
+            match page.memory()[1] {
+                0 => return,
+                1 => {}
+                flag => {
+                    state.errors.push(format!(
+                        "page {}: invalid greater entry flag {}",
+                        page_number, flag
+                    ));
+                    return;
+                }
+            }
+            if verify_entry_len(page.memory(), LEAF_HEADER_SIZE + lesser_len).is_none() {
+                state
+                    .errors
+                    .push(format!("page {}: greater entry overflows", page_number));
+                return;
+            }
+            let greater = accessor.greater().unwrap();
+            state.check_order(page_number, greater.table_id(), greater.key());
+        }
+        INTERNAL => {
+            let accessor = InternalAccessor::new(&page);
+            if 17 + accessor.key_len() + 24 > page.memory().len() {
+                state
+                    .errors
+                    .push(format!("page {}: key overflows", page_number));
+                return;
+            }
+            let lte_page = accessor.lte_page();
+            let gt_page = accessor.gt_page();
+            verify_tree(lte_page, manager, state);
+            verify_tree(gt_page, manager, state);
+        }
+        byte => state.errors.push(format!(
+            "page {} has an invalid node type: {}",
+            page_number, byte
+        )),
     }
 }
 
@@ -877,10 +2202,10 @@ pub(crate) enum Node {
 
 impl Node {
     // Returns the page number that the node was written to
-    pub(crate) fn to_bytes(&self, page_manager: &PageManager) -> u64 {
+    pub(crate) fn to_bytes(&self, page_manager: &PageManager) -> Result<u64, Error> {
         match self {
             Node::Leaf(left_val, right_val) => {
-                let mut page = page_manager.allocate();
+                let mut page = page_manager.allocate()?;
                 let mut builder = LeafBuilder::new(&mut page);
                 builder.write_lesser(left_val.0, &left_val.1, &left_val.2);
                 builder.write_greater(
@@ -889,18 +2214,12 @@ impl Node {
                         .map(|(table, key, value)| (*table, key.as_slice(), value.as_slice())),
                 );
 
-                page.get_page_number()
+                Ok(page.get_page_number())
             }
             Node::Internal(left, table, key, right) => {
-                let left_page = left.to_bytes(page_manager);
-                let right_page = right.to_bytes(page_manager);
-                let mut page = page_manager.allocate();
-                let mut builder = InternalBuilder::new(&mut page);
-                builder.write_table_and_key(*table, key);
-                builder.write_lte_page(left_page);
-                builder.write_gt_page(right_page);
-
-                page.get_page_number()
+                let left_page = left.to_bytes(page_manager)?;
+                let right_page = right.to_bytes(page_manager)?;
+                write_internal(*table, key, left_page, right_page, page_manager)
             }
         }
     }
@@ -921,22 +2240,50 @@ impl Node {
 
 pub(crate) struct BinarytreeBuilder {
     pairs: Vec<(u64, Vec<u8>, Vec<u8>)>,
+    fill_factor: f32,
 }
 
 impl BinarytreeBuilder {
     pub(crate) fn new() -> BinarytreeBuilder {
-        BinarytreeBuilder { pairs: vec![] }
+        BinarytreeBuilder::with_fill_factor(1.0)
+    }
+
+    /// A builder that fills its leaves to about `fill_factor`, which must be in (0, 1].
+    ///
+    /// A leaf holds up to two entries, and inserting into a full leaf splits it into three
+    /// pages, so leaving slack makes later inserts cheaper, at the cost of a larger tree.
+    /// Below 0.5, every leaf holds a single entry
+    pub(crate) fn with_fill_factor(fill_factor: f32) -> BinarytreeBuilder {
+        assert!(fill_factor > 0.0 && fill_factor <= 1.0);
+        BinarytreeBuilder {
+            pairs: vec![],
+            fill_factor,
+        }
     }
 
     pub(crate) fn add(&mut self, table: u64, key: &[u8], value: &[u8]) {
         self.pairs.push((table, key.to_vec(), value.to_vec()));
     }
 
+    /// Whether no pair has been added, so that there is no tree to build
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// The number of bytes that `build()` then `Node::to_bytes()` allocate for the pairs added
+    /// so far. Every leaf and internal node takes a page, and joining the leaves pairwise into
+    /// a tree adds one internal node less than there are leaves
+    pub(crate) fn estimated_size(&self, page_size: usize) -> usize {
+        estimated_tree_size(self.pairs.len(), self.fill_factor, page_size)
+    }
+
     /// Builds a balanced binary tree from the provided key-value pairs.
     ///
     /// This function operates by first sorting the pairs by key to ensure balance, then
     /// constructs the tree by creating leaves from pairs of elements and combining them
-    /// into internal nodes. If there is an odd number of elements, the last one is handled separately.
+    /// into internal nodes. If there is an odd number of elements, the last one is handled separately,
+    /// and an odd last node of a level is joined into the last internal node of the next level,
+    /// so that the heights of sibling sub-trees differ by at most one.
     ///
     /// The tree is built in a bottom-up manner, i.e., leaves are created first and then
     /// internal nodes are created by combining these leaves. This process continues until
@@ -957,6 +2304,10 @@ impl BinarytreeBuilder {
     /// and the process starts over for the next pair of nodes.
     /// This continues until we only have one node left, which is the root of the tree.
     ///
+    /// Only the keys of `table` are ordered with `K::compare`. The key type of any other table
+    /// is unknown here, so its entries are only ordered by table id and otherwise keep the order
+    /// in which they were added. Callers must add them in their existing tree order.
+    ///
     /// # Panics
     ///
     /// This function will panic if the `pairs` vector is empty, as it's not possible to build
@@ -968,31 +2319,25 @@ impl BinarytreeBuilder {
     /// # Returns
     ///
     /// This function returns the root `Node` of the constructed tree.
-    pub(crate) fn build<K: RadbKey + ?Sized>(mut self) -> Node {
+    pub(crate) fn build<K: RadbKey + ?Sized>(self, table: u64) -> Node {
+        #[cfg(feature = "rayon")]
+        if self.pairs.len() >= PARALLEL_BUILD_THRESHOLD {
+            return self.build_parallel::<K>(table);
+        }
+        self.build_sequential::<K>(table)
+    }
+
+    fn build_sequential<K: RadbKey + ?Sized>(mut self, table: u64) -> Node {
         // we want a balanced tree, so we sort the pairs by key
         assert!(!self.pairs.is_empty());
-        self.pairs.sort_by(|(table1, key1, _), (table2, key2, _)| {
-            cmp_keys::<K>(*table1, key1, *table2, key2)
-        });
+        // sort_by() is stable, so the entries of other tables stay in their original order
+        self.pairs
+            .sort_by(|pair1, pair2| compare_pairs::<K>(table, pair1, pair2));
         let mut leaves = vec![];
 
         // create leaves from pairs of elements
-        for group in self.pairs.chunks(2) {
-            let leaf = if group.len() == 1 {
-                Leaf((group[0].0, group[0].1.to_vec(), group[0].2.to_vec()), None)
-            } else {
-                assert_eq!(group.len(), 2);
-                if (group[0].0, &group[0].1) == (group[1].0, &group[1].1) {
-                    // This cannot happend, since we implement the overwriting feature
-                    // put the panic here to make sure we don't have bugs in the future
-                    panic!("duplicate key: {:?}", group[0].0);
-                }
-                Leaf(
-                    (group[0].0, group[0].1.to_vec(), group[0].2.to_vec()),
-                    Some((group[1].0, group[1].1.to_vec(), group[1].2.to_vec())),
-                )
-            };
-            leaves.push(leaf);
+        for group in leaf_groups(self.pairs.len(), self.fill_factor) {
+            leaves.push(make_leaf(&self.pairs[group]));
         }
 
         let mut bottom = leaves;
@@ -1005,17 +2350,19 @@ impl BinarytreeBuilder {
                 // state machine: if we have a previous node, create an internal node
                 // and reset the state, otherwise store the current node in the state
                 if let Some(previous_node) = maybe_previous_node.take() {
-                    // pick a key for the internal node
-                    let (table, key) = previous_node.get_max_key();
-                    let internal = Internal(Box::new(previous_node), table, key, Box::new(node));
-                    internals.push(internal)
+                    internals.push(join_nodes(previous_node, node))
                 } else {
                     maybe_previous_node.set(Some(node));
                 }
             }
 
+            // an odd last node is joined into the last internal node, rather than carried up
+            // to a level whose nodes are taller, so that siblings differ by at most one level
             if let Some(previous_node) = maybe_previous_node.take() {
-                internals.push(previous_node);
+                match internals.pop() {
+                    Some(internal) => internals.push(join_nodes(internal, previous_node)),
+                    None => internals.push(previous_node),
+                }
             }
 
             bottom = internals
@@ -1023,6 +2370,113 @@ impl BinarytreeBuilder {
 
         bottom.pop().unwrap()
     }
+
+    // Builds the same tree as build_sequential(), but sorts the pairs and joins the nodes
+    // of each level of the tree on the rayon thread pool
+    #[cfg(feature = "rayon")]
+    fn build_parallel<K: RadbKey + ?Sized>(mut self, table: u64) -> Node {
+        use rayon::prelude::*;
+
+        assert!(!self.pairs.is_empty());
+        // par_sort_by() is stable too
+        self.pairs
+            .par_sort_by(|pair1, pair2| compare_pairs::<K>(table, pair1, pair2));
+        let mut bottom: Vec<Node> = leaf_groups(self.pairs.len(), self.fill_factor)
+            .into_par_iter()
+            .map(|group| make_leaf(&self.pairs[group]))
+            .collect();
+
+        // each level pairs up the nodes of the level below, and joins an odd last node into
+        // the last pair, as build_sequential() does
+        while bottom.len() > 1 {
+            let odd = bottom.len() % 2 == 1;
+            bottom = bottom
+                .into_par_iter()
+                .chunks(2)
+                .map(|mut group| {
+                    let node = group.pop().unwrap();
+                    match group.pop() {
+                        Some(previous_node) => join_nodes(previous_node, node),
+                        None => node,
+                    }
+                })
+                .collect();
+            if odd {
+                let node = bottom.pop().unwrap();
+                let internal = bottom.pop().unwrap();
+                bottom.push(join_nodes(internal, node));
+            }
+        }
+
+        bottom.pop().unwrap()
+    }
+}
+
+// Inputs smaller than this are built sequentially, since threads would only add overhead
+#[cfg(feature = "rayon")]
+const PARALLEL_BUILD_THRESHOLD: usize = 16 * 1024;
+
+// The order in which BinarytreeBuilder sorts its pairs
+fn compare_pairs<K: RadbKey + ?Sized>(
+    table: u64,
+    (table1, key1, _): &(u64, Vec<u8>, Vec<u8>),
+    (table2, key2, _): &(u64, Vec<u8>, Vec<u8>),
+) -> Ordering {
+    if table1 == table2 && *table1 != table {
+        Ordering::Equal
+    } else {
+        cmp_keys::<K>(*table1, key1, *table2, key2)
+    }
+}
+
+/// The number of bytes that a tree of `len` entries takes, when it is built with the fill
+/// factor. See `BinarytreeBuilder::estimated_size`
+pub(crate) fn estimated_tree_size(len: usize, fill_factor: f32, page_size: usize) -> usize {
+    let leaves = leaf_groups(len, fill_factor).len();
+    (2 * leaves).saturating_sub(1) * page_size
+}
+
+// Splits the sorted pairs into the runs of one or two that the leaves are built from
+fn leaf_groups(len: usize, fill_factor: f32) -> Vec<Range<usize>> {
+    let per_leaf = (2.0 * fill_factor).clamp(1.0, 2.0);
+    let mut groups = Vec::with_capacity((len as f32 / per_leaf) as usize + 1);
+    // entries owed to the leaves so far, so that two-entry leaves are spread evenly
+    let mut budget = 0.0;
+    let mut start = 0;
+    while start < len {
+        budget += per_leaf;
+        let size = if budget >= 2.0 { 2 } else { 1 };
+        budget -= size as f32;
+        let end = min(start + size, len);
+        groups.push(start..end);
+        start = end;
+    }
+    groups
+}
+
+// Creates a leaf from one or two sorted pairs
+fn make_leaf(group: &[(u64, Vec<u8>, Vec<u8>)]) -> Node {
+    if group.len() == 1 {
+        Leaf((group[0].0, group[0].1.to_vec(), group[0].2.to_vec()), None)
+    } else {
+        assert_eq!(group.len(), 2);
+        if (group[0].0, &group[0].1) == (group[1].0, &group[1].1) {
+            // This cannot happend, since we implement the overwriting feature
+            // put the panic here to make sure we don't have bugs in the future
+            panic!("duplicate key: {:?}", group[0].0);
+        }
+        Leaf(
+            (group[0].0, group[0].1.to_vec(), group[0].2.to_vec()),
+            Some((group[1].0, group[1].1.to_vec(), group[1].2.to_vec())),
+        )
+    }
+}
+
+// Creates an internal node over two adjacent sub-trees
+fn join_nodes(previous_node: Node, node: Node) -> Node {
+    // pick a key for the internal node
+    let (table, key) = previous_node.get_max_key();
+    Internal(Box::new(previous_node), table, key, Box::new(node))
 }
 
 #[cfg(test)]
@@ -1047,6 +2501,69 @@ mod test {
         builder.add(1, b"hello3", b"world3");
         builder.add(1, b"hello", b"world");
 
-        assert_eq!(expected, builder.build::<[u8]>());
+        assert_eq!(expected, builder.build::<[u8]>(1));
+    }
+
+    #[test]
+    fn estimated_size() {
+        use crate::page_manager::{Mapping, PageManager};
+        use memmap2::MmapMut;
+
+        let page_size = 4096;
+        for (len, fill_factor) in [
+            (0, 1.0),
+            (1, 1.0),
+            (2, 1.0),
+            (3, 1.0),
+            (1000, 1.0),
+            (999, 0.7),
+        ] {
+            let mut builder = BinarytreeBuilder::with_fill_factor(fill_factor);
+            for i in 0..len as u64 {
+                builder.add(1, &i.to_be_bytes(), b"value");
+            }
+            let estimate = builder.estimated_size(page_size);
+            if len == 0 {
+                assert_eq!(estimate, 0);
+                continue;
+            }
+
+            let mut mmap = MmapMut::map_anon(2048 * page_size).unwrap();
+            PageManager::initialize(&mut mmap[0..PageManager::state_size()]);
+            let size = mmap.len();
+            let manager = PageManager::restore(Mapping::ReadWrite(mmap), None, size, page_size, 0);
+            let before = manager.get_next_free_page();
+            builder.build::<[u8]>(1).to_bytes(&manager).unwrap();
+            let allocated = (manager.get_next_free_page() - before) as usize * page_size;
+            assert_eq!(estimate, allocated, "{} pairs", len);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_build() {
+        use rand::Rng;
+
+        let mut rng = rand::thread_rng();
+        for len in [1, 2, 3, 1000, 40_001] {
+            let mut sequential = BinarytreeBuilder::new();
+            let mut parallel = BinarytreeBuilder::new();
+            for i in 0..len {
+                // entries of other tables keep the order they were added in
+                let table = rng.gen_range(0..3u64);
+                let key = if table == 1 {
+                    rng.gen::<[u8; 16]>().to_vec()
+                } else {
+                    (i as u64).to_be_bytes().to_vec()
+                };
+                let value = rng.gen::<[u8; 8]>();
+                sequential.add(table, &key, &value);
+                parallel.add(table, &key, &value);
+            }
+            assert_eq!(
+                sequential.build_sequential::<[u8]>(1),
+                parallel.build_parallel::<[u8]>(1)
+            );
+        }
     }
 }
@@ -58,7 +58,10 @@
  *
  */
 
+use std::borrow::Borrow;
 use std::fmt::Debug;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
 
 const B: usize = 3; // minimum degree
 
@@ -71,7 +74,40 @@ pub struct BTree<K: Ord + Clone + Debug, V: Clone + Debug> {
 pub struct Node<K: Ord + Clone + Debug, V: Clone + Debug> {
     keys: Vec<K>,
     values: Vec<V>,
-    children: Vec<Box<Node<K, V>>>,
+    children: Vec<Node<K, V>>,
+}
+
+/// Size and shape of a `BTree`, returned by `BTree::stats`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BTreeStats {
+    /// Number of key-value pairs
+    pub entry_count: usize,
+    /// Number of nodes, including the leaves
+    pub node_count: usize,
+    /// Number of nodes without children
+    pub leaf_count: usize,
+    /// Number of levels of nodes, 0 for an empty tree. Every leaf is at this depth
+    pub height: usize,
+    /// Bytes allocated for the nodes and their key, value and child vectors.
+    /// Memory owned by the keys and values themselves (e.g. the contents of a `String`) is not included
+    pub estimated_bytes: usize,
+}
+
+/// A position in a `BTree`, returned by `BTree::cursor`, that can be moved in either direction.
+/// A new cursor is before the first entry
+pub struct Cursor<'a, K: Ord + Clone + Debug, V: Clone + Debug> {
+    root: Option<&'a Node<K, V>>,
+    // The nodes from the root down to the current entry. Each ancestor is paired with the index
+    // of the child that was descended into, and the last node with the index of the current key
+    path: Vec<(&'a Node<K, V>, usize)>,
+    // Where the cursor is when the path is empty: past the last entry, or before the first
+    at_end: bool,
+}
+
+impl<K: Ord + Clone + Debug, V: Clone + Debug> Default for BTree<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
@@ -85,34 +121,22 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
         }
     }
 
-    pub fn traverse(&self) -> Vec<(K, V)> 
-    where
-        K: Clone,
-        V: Clone,
-    {
-        let mut kv_pairs = Vec::new();
-        if let Some(root) = &self.root {
-            Self::dfs(&**root, &mut kv_pairs);
-        }
-        kv_pairs
+    /// Copies every key-value pair, in key order
+    #[deprecated(note = "use `entries()` to walk the tree without copying, or `to_vec()`")]
+    pub fn traverse(&self) -> Vec<(K, V)> {
+        self.to_vec()
     }
 
-    // Add the dfs() method as an associated function
-    fn dfs(node: &Node<K, V>, kv_pairs: &mut Vec<(K, V)>) 
-    where
-        K: Clone,
-        V: Clone,
-    {
-        for i in 0..node.keys.len() {
-            if let Some(child) = node.children.get(i) {
-                Self::dfs(child, kv_pairs);
-            }
-            kv_pairs.push((node.keys[i].clone(), node.values[i].clone()));
-        }
+    /// Iterates over every key-value pair in key order, borrowing them from the tree
+    pub fn entries(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.cursor()
+    }
 
-        if let Some(child) = node.children.last() {
-            Self::dfs(child, kv_pairs);
-        }
+    /// Copies every key-value pair, in key order. Use `entries()` unless owned copies are needed
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.entries()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
     }
 
     pub fn insert(&mut self, key: K, value: V) {
@@ -124,7 +148,7 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
             if root.is_full() { // it has 2 * B - 1 keys
                 // split it before inserting
                 let mut new_root = Box::new(Node::new());
-                new_root.children.push(root.clone()); 
+                new_root.children.push((**root).clone());
                 new_root.split_child(0);
                 new_root.insert_non_full(key.clone(), value.clone());
                 self.root = Some(new_root);
@@ -139,15 +163,23 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
         }
     }
 
-    pub fn delete(&mut self, key: &K) -> Option<V> {
+    // Lookups take any borrowed form of the key, like `BTreeMap`, e.g. a `&str` for `String`
+    // keys. It must order the same as the key itself
+    pub fn delete<Q: Ord + Debug + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
         println!("Deleting {:?} from root", key);
+        // The delete rebalances the nodes on its way down, before it knows whether the key is
+        // there, so look it up first to leave the tree untouched when it is absent
+        self.search(key)?;
         if let Some(root) = &mut self.root {
             let deleted_value = root.delete(key);
             if root.keys.is_empty() {
                 if root.children.is_empty() {
                     self.root = None;
                 } else {
-                    self.root = Some(root.children.remove(0));
+                    self.root = Some(Box::new(root.children.remove(0)));
                 }
             }
             deleted_value
@@ -156,11 +188,58 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
         }
     }
 
-    pub fn search(&self, key: &K) -> Option<&V> {
+    pub fn search<Q: Ord + Debug + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
         // Search for a key and return the associated value if found
         self.root.as_ref().and_then(|root| root.search(key))
     }
 
+    // Removes every entry whose key falls in the range, returning how many were removed
+    // Takes a range of owned keys, since a borrowed form could not be inferred for `..`
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        // collect the keys first, since each delete may restructure the tree under a walk
+        let mut keys = Vec::new();
+        if let Some(root) = &self.root {
+            root.collect_range(&range, &mut keys);
+        }
+        for key in &keys {
+            self.delete::<K>(key);
+        }
+        keys.len()
+    }
+
+    pub fn stats(&self) -> BTreeStats {
+        let mut stats = BTreeStats::default();
+        if let Some(root) = &self.root {
+            // the root is boxed, while every other node is stored inline in its parent's children
+            stats.estimated_bytes = size_of::<Node<K, V>>();
+            root.collect_stats(1, &mut stats);
+        }
+        stats
+    }
+
+    // Checks the properties listed at the top of this file, and that every leaf is at the same
+    // depth, returning a description of the first violation found
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if let Some(root) = &self.root {
+            if root.keys.is_empty() {
+                return Err("the root has no keys".to_string());
+            }
+            let mut leaf_depth = None;
+            root.check_invariants(None, None, 1, true, &mut leaf_depth)?;
+        }
+        Ok(())
+    }
+
+    pub fn cursor(&self) -> Cursor<'_, K, V> {
+        Cursor {
+            root: self.root.as_deref(),
+            path: Vec::new(),
+            at_end: false,
+        }
+    }
 
     pub fn print_tree(&self) {
         if let Some(ref root) = self.root {
@@ -171,6 +250,146 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
     }
 }
 
+impl<'a, K: Ord + Clone + Debug, V: Clone + Debug> Cursor<'a, K, V> {
+    /// Moves to the first entry with a key >= `key`, and returns it. If every key is less,
+    /// the cursor is moved past the last entry
+    pub fn seek<Q: Ord + ?Sized>(&mut self, key: &Q) -> Option<(&'a K, &'a V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.path.clear();
+        self.at_end = true;
+        let mut node = self.root?;
+        loop {
+            match node.find(key) {
+                Ok(index) => {
+                    self.path.push((node, index));
+                    break;
+                }
+                Err(index) => {
+                    self.path.push((node, index));
+                    if node.children.is_empty() {
+                        // the key would be inserted here, before the key at index (if any)
+                        self.ascend_forward();
+                        break;
+                    }
+                    node = &node.children[index];
+                }
+            }
+        }
+        self.entry()
+    }
+
+    /// Moves to the previous entry and returns it, or `None` if the cursor moved before the
+    /// first entry
+    pub fn prev(&mut self) -> Option<(&'a K, &'a V)> {
+        match self.path.last_mut() {
+            Some((node, index)) => {
+                if node.children.is_empty() {
+                    if *index > 0 {
+                        *index -= 1;
+                    } else {
+                        self.ascend_backward();
+                    }
+                } else {
+                    // the predecessor is the rightmost entry of the left subtree
+                    let child = &node.children[*index];
+                    self.descend(child, true);
+                }
+            }
+            None if !self.at_end => return None,
+            None => {
+                let root = self.root?;
+                self.descend(root, true);
+            }
+        }
+        self.entry()
+    }
+
+    pub fn key(&self) -> Option<&'a K> {
+        self.entry().map(|(key, _)| key)
+    }
+
+    pub fn value(&self) -> Option<&'a V> {
+        self.entry().map(|(_, value)| value)
+    }
+
+    fn entry(&self) -> Option<(&'a K, &'a V)> {
+        self.path
+            .last()
+            .map(|(node, index)| (&node.keys[*index], &node.values[*index]))
+    }
+
+    // Pushes the path to the leftmost (or rightmost) entry of the subtree
+    fn descend(&mut self, mut node: &'a Node<K, V>, rightmost: bool) {
+        while !node.children.is_empty() {
+            let index = if rightmost {
+                node.children.len() - 1
+            } else {
+                0
+            };
+            self.path.push((node, index));
+            node = &node.children[index];
+        }
+        let index = if rightmost { node.keys.len() - 1 } else { 0 };
+        self.path.push((node, index));
+    }
+
+    // Called when the last node of the path has no key at its index: climbs to the first
+    // ancestor whose key follows the subtree that was finished
+    fn ascend_forward(&mut self) {
+        while let Some((node, index)) = self.path.last() {
+            if *index < node.keys.len() {
+                return;
+            }
+            self.path.pop();
+        }
+        self.at_end = true;
+    }
+
+    // Called when the cursor moves before the first key of a leaf: climbs to the first ancestor
+    // with a key before the subtree
+    fn ascend_backward(&mut self) {
+        self.path.pop();
+        while let Some((_, index)) = self.path.last_mut() {
+            if *index > 0 {
+                *index -= 1;
+                return;
+            }
+            self.path.pop();
+        }
+        self.at_end = false;
+    }
+}
+
+/// Iterating a cursor moves it forward from its position
+impl<'a, K: Ord + Clone + Debug, V: Clone + Debug> Iterator for Cursor<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    /// Moves to the next entry and returns it, or `None` if the cursor moved past the last entry
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.path.last_mut() {
+            Some((node, index)) => {
+                if node.children.is_empty() {
+                    *index += 1;
+                    self.ascend_forward();
+                } else {
+                    // the successor is the leftmost entry of the right subtree
+                    *index += 1;
+                    let child = &node.children[*index];
+                    self.descend(child, false);
+                }
+            }
+            None if self.at_end => return None,
+            None => {
+                let root = self.root?;
+                self.descend(root, false);
+            }
+        }
+        self.entry()
+    }
+}
+
 impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
     // Helper methods for B-tree operations (insert, delete, search, etc.)
     // Methods like split, merge, and other utility methods will be implemented here
@@ -203,6 +422,128 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         }
     }
 
+    // Single pass over the subtree, at the given depth (the root is at depth 1)
+    fn collect_stats(&self, depth: usize, stats: &mut BTreeStats) {
+        stats.entry_count += self.keys.len();
+        stats.node_count += 1;
+        stats.height = stats.height.max(depth);
+        stats.estimated_bytes += self.keys.capacity() * size_of::<K>()
+            + self.values.capacity() * size_of::<V>()
+            + self.children.capacity() * size_of::<Node<K, V>>();
+        if self.children.is_empty() {
+            stats.leaf_count += 1;
+        }
+        for child in &self.children {
+            child.collect_stats(depth + 1, stats);
+        }
+    }
+
+    // Checks the subtree, whose keys must all lie strictly between the bounds
+    fn check_invariants(
+        &self,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        depth: usize,
+        is_root: bool,
+        leaf_depth: &mut Option<usize>,
+    ) -> Result<(), String> {
+        if self.keys.len() != self.values.len() {
+            return Err(format!(
+                "node {:?} has {} values",
+                self.keys,
+                self.values.len()
+            ));
+        }
+        if self.keys.len() > 2 * B - 1 {
+            return Err(format!(
+                "node {:?} has more than {} keys",
+                self.keys,
+                2 * B - 1
+            ));
+        }
+        if !is_root && self.keys.len() < B - 1 {
+            return Err(format!(
+                "node {:?} has fewer than {} keys",
+                self.keys,
+                B - 1
+            ));
+        }
+        if self.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!(
+                "the keys of node {:?} are not ascending",
+                self.keys
+            ));
+        }
+        let below = lower.is_some_and(|lower| self.keys.first().is_some_and(|key| key <= lower));
+        let above = upper.is_some_and(|upper| self.keys.last().is_some_and(|key| key >= upper));
+        if below || above {
+            return Err(format!(
+                "node {:?} is not between {:?} and {:?}",
+                self.keys, lower, upper
+            ));
+        }
+
+        if self.children.is_empty() {
+            match *leaf_depth {
+                Some(expected) if expected != depth => {
+                    return Err(format!(
+                        "leaf {:?} is at depth {}, but another is at {}",
+                        self.keys, depth, expected
+                    ))
+                }
+                _ => *leaf_depth = Some(depth),
+            }
+            return Ok(());
+        }
+        if self.children.len() != self.keys.len() + 1 {
+            return Err(format!(
+                "node {:?} has {} children",
+                self.keys,
+                self.children.len()
+            ));
+        }
+        for (i, child) in self.children.iter().enumerate() {
+            let child_lower = if i == 0 {
+                lower
+            } else {
+                Some(&self.keys[i - 1])
+            };
+            let child_upper = self.keys.get(i).or(upper);
+            child.check_invariants(child_lower, child_upper, depth + 1, false, leaf_depth)?;
+        }
+        Ok(())
+    }
+
+    // In-order walk of the keys in the range, skipping children that lie entirely outside it
+    fn collect_range<R: RangeBounds<K>>(&self, range: &R, keys: &mut Vec<K>) {
+        for i in 0..=self.keys.len() {
+            // child i holds the keys between keys[i - 1] and keys[i]
+            let below_start = i < self.keys.len()
+                && match range.start_bound() {
+                    Bound::Included(start) => self.keys[i].lt(start),
+                    Bound::Excluded(start) => self.keys[i].le(start),
+                    Bound::Unbounded => false,
+                };
+            let above_end = i > 0
+                && match range.end_bound() {
+                    Bound::Included(end) => self.keys[i - 1].gt(end),
+                    Bound::Excluded(end) => self.keys[i - 1].ge(end),
+                    Bound::Unbounded => false,
+                };
+            if above_end {
+                return;
+            }
+            if !below_start {
+                if let Some(child) = self.children.get(i) {
+                    child.collect_range(range, keys);
+                }
+            }
+            if i < self.keys.len() && range.contains(&self.keys[i]) {
+                keys.push(self.keys[i].clone());
+            }
+        }
+    }
+
     fn is_full(&self) -> bool {
         self.keys.len() >= 2 * B - 1
     }
@@ -215,7 +556,7 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         let split_value = self.children[index].values[B - 1].clone();
 
         // 2. Create new node to store the keys and values to right of the middle key
-        let mut right = Box::new(Node::new());
+        let mut right = Node::new();
 
         // 3. Remove they keys and values to the right of the middle key from the original node
         // (greater part)
@@ -270,16 +611,30 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                 self.split_child(index); // split the current index
 
                 // After splitting, check if the new key should go to the right child
-                if self.keys[index].lt(&key) {
-                    index += 1;
+                match self.keys[index].cmp(&key) {
+                    std::cmp::Ordering::Less => index += 1,
+                    // the key was the middle key of the child, which has moved up into this node
+                    std::cmp::Ordering::Equal => return,
+                    std::cmp::Ordering::Greater => {}
                 }
             }
             self.children[index].insert_non_full(key, value);
         }
     }
 
-    fn search(&self, key: &K) -> Option<&V> {
-        match self.keys.binary_search(key) {
+    // Binary searches this node's keys, by their borrowed form
+    fn find<Q: Ord + ?Sized>(&self, key: &Q) -> Result<usize, usize>
+    where
+        K: Borrow<Q>,
+    {
+        self.keys.binary_search_by(|probe| probe.borrow().cmp(key))
+    }
+
+    fn search<Q: Ord + Debug + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        match self.find(key) {
             Ok(index) => Some(&self.values[index]),
             Err(index) => {
                 if self.children.is_empty() {
@@ -292,9 +647,12 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         }
     }
 
-    pub fn delete(&mut self, key: &K) -> Option<V> {
+    pub fn delete<Q: Ord + Debug + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
         println!("Deleting key '{:?}' from node: {:?}", key, self.keys);
-        match self.keys.binary_search(&key) {
+        match self.find(key) {
             Ok(index) => {
                 println!("Found key at index: {:?}", index);
                 if self.children.is_empty() {
@@ -302,7 +660,7 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                     // Then we just simply remove the key and value
                     println!("Case 1: The key '{:?}' is on the leaf node, remove it directly.", key);
                     self.keys.remove(index);
-                    return Some(self.values.remove(index));
+                    Some(self.values.remove(index))
                 } else {
                     // Case 2: The key is in the current node and it's an internal node
                     // To maintain the B-Tree properties, we cannot just remove the key and its
@@ -322,9 +680,11 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                          */
                         let (pred_key, pred_value) = self.children[index].find_predecessor();
                         println!("Case 2a: The key '{:?}' is deleted since it is on the internal node", key);
+                        // the recursive delete returns the predecessor's value, which has moved up
                         self.keys[index] = pred_key.clone();
-                        self.values[index] = pred_value.clone();
-                        return self.children[index].delete(&pred_key); // recursive
+                        let value = std::mem::replace(&mut self.values[index], pred_value);
+                        self.children[index].delete::<K>(&pred_key); // recursive
+                        Some(value)
                     } else if self.children[index + 1].keys.len() >= B {
                         // Case 2b: If the left child doesn't have enough keys, we check if the
                         // right child has at least B keys. If it does, we find the successor of 
@@ -334,8 +694,9 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         let (succ_key, succ_value) = self.children[index + 1].find_successor();
                         println!("Case 2b: The key '{:?}' is deleted since it is on the internal node", key);
                         self.keys[index] = succ_key.clone();
-                        self.values[index] = succ_value.clone();
-                        return self.children[index + 1].delete(&succ_key); // recursive
+                        let value = std::mem::replace(&mut self.values[index], succ_value);
+                        self.children[index + 1].delete::<K>(&succ_key); // recursive
+                        Some(value)
                     } else {
                         // Case 2c: If both the left and right children have less than B keys
                         // we merge the current node with the left child and then recursively
@@ -367,7 +728,7 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
 				  and we move our left and right sibling together", key);
                         self.merge_with_left(index+1); 
                         self.children.remove(index+1);
-                        return self.children[index].delete(key);
+                        self.children[index].delete(key)
                     }
                 }
             }
@@ -378,17 +739,19 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                 if self.children.is_empty() {
                     // Case 3a: If the current node is a leaf node, then the key is not in the tree
                     println!("Case 3a: If the current node is a leaf node, then the key is not in the tree, NOT FOUND");
-                    return None;
+                    None
                 } else {
                     // Case 3b: If the current node is an internal node, we need to ensure that the
                     // child node at the target index has at least B keys before recursively
                     // deleting the key from that child.
                     if self.children[index].keys.len() < B {
-                        if index > 0 && self.children[index - 1].keys.len() >= B {
+                        if index > 0
+                            && self.children[index - 1].keys.len() >= B
+                            && self.borrow_from_left(index)
+                        {
                             // Case 3b1: If the left sibling (at index-1) exists and has at least B
                             // keys, borrow a key from the left sibling
                             println!("Case 3b1: If the left sibling (at index-1) exists and has at least B keys, borrow a key from the left sibling");
-                            self.borrow_from_left(index);
                             let borrowed_key = self.keys.remove(index);
                             let borrowed_value = self.values.remove(index);
                             self.children[index].keys.insert(0, borrowed_key);
@@ -412,8 +775,9 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         } else {
                             // Case 3b4: if the left sibling doesn't exist, merge the child with the right sibling
                             println!("Case 3b4: if the left sibling doesn't exist, merge the child with the right sibling");
-                            self.merge_with_right(index);
-                            self.children.remove(index+1);
+                            if self.merge_with_right(index) {
+                                self.children.remove(index + 1);
+                            }
                         }
                     }
 
@@ -427,8 +791,13 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
 
 
     // helper functions
-    fn borrow_from_left(&mut self, index: usize) {
+    fn borrow_from_left(&mut self, index: usize) -> bool {
         // Borrow a key from the left sibling, assuming that the left sibling has more than B-1 keys
+        // Returns false, and changes nothing, if there is no left sibling with a key to lend
+
+        if index == 0 || index >= self.children.len() || self.children[index - 1].keys.is_empty() {
+            return false;
+        }
 
         // since it is the right most child, it will not violate the navigational property
 
@@ -444,6 +813,7 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
             let left_sibling_child = left_sibling.children.pop().unwrap();
             self.children[index].children.insert(0, left_sibling_child);
         }
+        true
     }
 
     fn borrow_from_right(&mut self, index: usize) {
@@ -496,8 +866,12 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         }
     }
 
-    fn merge_with_right(&mut self, index: usize) {
+    fn merge_with_right(&mut self, index: usize) -> bool {
         // Merge the current node with the right sibling, assuming that the right sibling has B-1 keys
+        // Returns false, and changes nothing, if there is no right sibling
+        if index >= self.keys.len() || index + 1 >= self.children.len() {
+            return false;
+        }
         let parent_key = self.keys.remove(index);
         let parent_value = self.values.remove(index);
 
@@ -519,7 +893,7 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         if !right_sibling.children.is_empty() {
             current_node.children.append(&mut right_sibling.children);
         }
-
+        true
     }
 
     fn find_predecessor(&self) -> (K, V) {
@@ -0,0 +1,121 @@
+use crate::{Corruption, Error};
+
+/// Compression applied to the values of a table. Keys are never compressed,
+/// so that they keep their order
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Options that a table is opened with.
+///
+/// The values of a compressed table are stored with a tag naming their codec, so a table
+/// may be reopened with a different codec, but a compressed table must never be opened
+/// with `Compression::None`, or vice versa.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TableOptions {
+    pub compression: Compression,
+}
+
+// Tags that prefix every value of a compressed table
+const RAW: u8 = 0;
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 2;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+// The codec is recorded in the catalog entry of each table by its tag, with RAW for a table
+// that is not compressed
+pub(crate) fn codec_tag(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => RAW,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => LZ4,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => ZSTD,
+    }
+}
+
+pub(crate) fn codec_from_tag(tag: u8) -> Result<Compression, Error> {
+    match tag {
+        RAW => Ok(Compression::None),
+        #[cfg(feature = "lz4")]
+        LZ4 => Ok(Compression::Lz4),
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(Compression::Zstd),
+        tag => Err(Error::Corruption(Corruption::CompressionTag(tag))),
+    }
+}
+
+// Bytes that compression adds to a value in the worst case, when it is stored raw
+pub(crate) fn overhead(compression: Compression) -> usize {
+    if compression == Compression::None {
+        0
+    } else {
+        1
+    }
+}
+
+// Returns the tag and compressed data, or None if the value is not compressed
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn encode(compression: Compression, value: &[u8]) -> Result<Option<(u8, Vec<u8>)>, Error> {
+    match compression {
+        Compression::None => Ok(None),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(Some((LZ4, lz4_flex::compress_prepend_size(value)))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Some((ZSTD, zstd::bulk::compress(value, ZSTD_LEVEL)?))),
+    }
+}
+
+/// Encodes a value for storage. Values that do not shrink are stored raw
+pub(crate) fn compress(compression: Compression, value: &[u8]) -> Result<Vec<u8>, Error> {
+    let (tag, compressed) = match encode(compression, value)? {
+        Some(encoded) => encoded,
+        None => return Ok(value.to_vec()),
+    };
+
+    let mut output = Vec::with_capacity(1 + compressed.len().min(value.len()));
+    if compressed.len() < value.len() {
+        output.push(tag);
+        output.extend_from_slice(&compressed);
+    } else {
+        output.push(RAW);
+        output.extend_from_slice(value);
+    }
+    Ok(output)
+}
+
+// The error for a value that its codec fails to decompress
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn invalid_value(codec: Compression, err: impl std::fmt::Display) -> Error {
+    Error::Corruption(Corruption::CompressedValue {
+        codec,
+        message: err.to_string(),
+    })
+}
+
+/// Decodes a value of a compressed table
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.is_empty() {
+        return Err(Error::Corruption(Corruption::EmptyCompressedValue));
+    }
+    match data[0] {
+        RAW => Ok(data[1..].to_vec()),
+        #[cfg(feature = "lz4")]
+        LZ4 => lz4_flex::decompress_size_prepended(&data[1..])
+            .map_err(|err| invalid_value(Compression::Lz4, err)),
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::stream::decode_all(&data[1..])
+            .map_err(|err| invalid_value(Compression::Zstd, err)),
+        tag => Err(Error::Corruption(Corruption::CompressionTag(tag))),
+    }
+}
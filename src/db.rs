@@ -1,44 +1,476 @@
-use crate::storage::Storage;
+use crate::binarytree::max_entry_len;
+use crate::compression::TableOptions;
+use crate::page_manager::{Mapping, DEFAULT_NODE_CACHE_SIZE};
+use crate::storage::{
+    check_magic, stored_page_size, CompactProgress, DbStats, PageUsage, Storage, VerifyReport,
+    HEADER_SIZE, MAGICNUMBER,
+};
 use crate::table::Table;
-use crate::types::RadbKey;
-use crate::Error;
+use crate::transactions::ReadOnlyTransaction;
+use crate::types::{RadbKey, RadbValue};
+use crate::{Corruption, Error};
 
-use memmap2::MmapMut;
-use std::fs::OpenOptions;
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use std::cmp::min;
+use std::fs::{OpenOptions, TryLockError};
+use std::io;
+use std::io::Read;
 use std::path::Path;
 
+// The fewest pages that a fixed size database can have: the metadata page, the leaf of the
+// committed tree, and the leaf that the first insert writes in its place
+const MIN_PAGES: usize = 3;
+
 pub struct Database {
     storage: Storage,
 }
 
 impl Database {
-    /// Opens the specified file as a radarbase database (radb).
+    /// Opens the specified file as a radarbase database (radb), with the default options.
     ///
     /// * if the file does not exist, or is an empty file, a new database will be initialized in it
-    /// * if the file is a valid redb database, it will be opened
-    /// * otherwise this function will return an error
+    /// * if the file is a valid radarbase database, it will be opened
+    /// * otherwise this function will return `Error::InvalidDatabase`, without modifying the file
+    ///
+    /// # Safety
+    ///
+    /// The file is memory-mapped, so the caller must ensure that it is not concurrently
+    /// modified by another process or another `Database` instance while it is open.
     pub unsafe fn open(path: &Path) -> Result<Database, Error> {
+        Self::builder().open(path)
+    }
+
+    /// Opens the specified file as a radarbase database, like `open`, but with a maximum size
+    /// of `size` bytes, rounded up to a multiple of the OS page size, instead of the default
+    /// of 16GiB. This much address space is reserved by the mmap, see
+    /// `DatabaseBuilder::set_max_size`. The size must be at least three pages: the metadata
+    /// page, and room for a table with one entry, since a commit writes the new tree before it
+    /// frees the old one
+    ///
+    /// # Safety
+    ///
+    /// See [`Database::open`]
+    pub unsafe fn open_with_size(path: &Path, size: usize) -> Result<Database, Error> {
+        let page_size = page_size::get();
+        let size = size.div_ceil(page_size) * page_size;
+        if size < MIN_PAGES * page_size {
+            return Err(invalid_option(format!(
+                "size {} is smaller than {} pages",
+                size, MIN_PAGES
+            )));
+        }
+        let mut builder = Self::builder();
+        let initial_size = min(builder.initial_size, size);
+        builder.set_initial_size(initial_size).set_max_size(size);
+        builder.open(path)
+    }
+
+    /// Opens the specified file as a radarbase database, with the default options, like `open`,
+    /// but without `unsafe`. The file is locked for as long as the database is open, and
+    /// `Error::AlreadyOpen` is returned if another `Database` opened with `create` holds it.
+    ///
+    /// The lock is advisory, so it only excludes databases that are opened this way. Opening
+    /// the file with `open`, or modifying it with other programs, while it is locked is still
+    /// undefined behavior
+    pub fn create(path: &Path) -> Result<Database, Error> {
+        Self::builder().create(path)
+    }
+
+    /// Opens the specified file as a read-only radarbase database.
+    ///
+    /// Tables can be read, but creating tables or beginning a write transaction returns an error.
+    /// The file only needs to be readable, so this works on read-only media.
+    ///
+    /// # Safety
+    ///
+    /// See [`Database::open`]
+    pub unsafe fn open_read_only(path: &Path) -> Result<Database, Error> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let mmap = Mmap::map(&file)?;
+        let storage = Storage::new(
+            Mapping::ReadOnly(mmap),
+            Some(file),
+            file_len,
+            page_size::get(),
+        )?;
+        Ok(Database { storage })
+    }
+
+    /// Creates a database in anonymous memory, of the given size, rounded down to a multiple
+    /// of the OS page size. It is not backed by any file, so its contents are lost when it is
+    /// dropped. Like for `open_with_size`, the size must be at least three pages
+    pub fn open_in_memory(size: usize) -> Result<Database, Error> {
+        let page_size = page_size::get();
+        let size = size - size % page_size;
+        if size < MIN_PAGES * page_size {
+            return Err(invalid_option(format!(
+                "size {} is smaller than {} pages",
+                size, MIN_PAGES
+            )));
+        }
+        let mmap = MmapMut::map_anon(size)?;
+        let storage = Storage::new(Mapping::ReadWrite(mmap), None, size, page_size)?;
+        Ok(Database { storage })
+    }
+
+    pub fn builder() -> DatabaseBuilder {
+        DatabaseBuilder::new()
+    }
+
+    pub fn open_table<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        name: &[u8],
+    ) -> Result<Table<'_, K, V>, Error> {
+        self.open_table_with_options(name, TableOptions::default())
+    }
+
+    /// Opens the table with the given name, creating it if it does not exist.
+    /// See [`TableOptions`] for the options that must match across opens
+    pub fn open_table_with_options<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        name: &[u8],
+        options: TableOptions,
+    ) -> Result<Table<'_, K, V>, Error> {
+        let id = self
+            .storage
+            .get_or_create_table(name, options.compression)?;
+        Table::new(id, &self.storage, options.compression)
+    }
+
+    /// Returns the names of all the tables in the database, in the byte order of the names.
+    /// A table is listed once it is opened, even if nothing has been written to it
+    pub fn list_tables(&self) -> Result<Vec<Vec<u8>>, Error> {
+        self.storage.list_tables()
+    }
+
+    /// Deletes the table with the given name, and all of its entries.
+    /// Returns `false` if there was no such table, and `Error::WriteConflict` while a write
+    /// transaction is live
+    pub fn delete_table(&self, name: &[u8]) -> Result<bool, Error> {
+        let deleted = self.storage.delete_table(name)?;
+        if deleted {
+            self.storage.fsync()?;
+        }
+        Ok(deleted)
+    }
+
+    /// Flushes all committed data to disk, including commits made with `Durability::Eventual`.
+    ///
+    /// Dropping the `Database` also flushes it, but `Drop` cannot report errors, so call this
+    /// to know that the data is durable
+    pub fn flush(&self) -> Result<(), Error> {
+        self.storage.fsync()
+    }
+
+    /// Runs `f`, flushing all the transactions that it commits to disk once at the end,
+    /// rather than once per commit. Only the flushes are grouped: each commit still applies
+    /// its changes to the tree, as usual, and they are visible as soon as it returns, but
+    /// none of them are durable until `batch` returns. The batch ends even if `f` panics
+    pub fn batch<T, F: FnOnce() -> Result<T, Error>>(&self, f: F) -> Result<T, Error> {
+        let batch = self.storage.begin_batch();
+        let result = f();
+        // flush whatever was committed, even if f failed part way
+        batch.end()?;
+        result
+    }
+
+    #[cfg(test)]
+    pub(crate) fn flush_count(&self) -> u64 {
+        self.storage.flush_count()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn node_cache_hits(&self) -> u64 {
+        self.storage.node_cache_hits()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn reader_count(&self, version: u64) -> usize {
+        self.storage.reader_count(version)
+    }
+
+    /// Size of the pages that the tree is stored in, which is fixed when the database is created
+    pub fn page_size(&self) -> usize {
+        self.storage.get_page_size()
+    }
+
+    /// The largest combined length of a key and its value that fits into a leaf page,
+    /// after the page and entry headers. Longer entries are rejected with `Error::KeyTooLarge`
+    /// or `Error::ValueTooLarge`. A compressed table needs one more byte per value
+    pub fn usable_leaf_capacity(&self) -> usize {
+        max_entry_len(self.page_size())
+    }
+
+    /// The latest committed version, which is the number of commits that the database has
+    /// had. A read transaction opened now reports this version
+    pub fn current_version(&self) -> u64 {
+        self.storage.current_version()
+    }
+
+    /// Holds the current version of the database, so that it can still be read with `read_at`
+    /// after later commits, and returns the version.
+    ///
+    /// Checkpoints are only held in memory, until they are released or the database is closed.
+    /// The pages of the version are not reused while it is held, and `compact_and_shrink`
+    /// fails with `Error::CheckpointHeld`. A version may be checkpointed several times, and
+    /// is held until each of them is released
+    pub fn checkpoint(&self) -> u64 {
+        self.storage.checkpoint()
+    }
+
+    /// Releases one checkpoint of the version. Returns `false` if none was held
+    pub fn release_checkpoint(&self, version: u64) -> bool {
+        self.storage.release_checkpoint(version)
+    }
+
+    /// Opens a read transaction on the table, as it was at the given checkpoint.
+    /// Returns `Error::CheckpointNotFound` if no checkpoint of that version is held, and
+    /// `Error::TableNotFound` if the table did not exist then
+    pub fn read_at<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        name: &[u8],
+        version: u64,
+    ) -> Result<ReadOnlyTransaction<'_, K, V>, Error> {
+        let root_page = self.storage.checkpoint_root(version)?;
+        match self.storage.get_table_at(name, root_page)? {
+            Some((id, compression)) => Ok(ReadOnlyTransaction::new_at(
+                id,
+                &self.storage,
+                compression,
+                version,
+                root_page,
+            )),
+            None => Err(Error::TableNotFound(
+                String::from_utf8_lossy(name).into_owned(),
+            )),
+        }
+    }
+
+    /// Rewrites all the entries into the start of the file, and truncates the file after them.
+    ///
+    /// Commits reuse the pages of old versions that nothing reads anymore, but the file never
+    /// shrinks, and free pages are scattered over it. This reclaims that space. It takes
+    /// `&mut self`, since the pages that open tables and transactions read from are overwritten.
+    /// Returns `Error::CheckpointHeld` while any checkpoint is held
+    pub fn compact_and_shrink(&mut self) -> Result<(), Error> {
+        self.storage.compact_and_shrink()
+    }
+
+    /// Compacts the database incrementally, so that it can be spread over many calls, e.g.
+    /// from an event loop. Each call moves at most `budget` pages of the tree nearer the start
+    /// of the file, then truncates the file after the last page in use.
+    ///
+    /// Unlike `compact_and_shrink`, open transactions stay valid, since pages that a read
+    /// transaction or checkpoint may read are never overwritten. Returns `Error::WriteConflict`
+    /// while a write transaction is live. Moving a page means copying the nodes above it too,
+    /// so a budget smaller than the height of the tree may return
+    /// `Error::CompactBudgetTooSmall`. Calls pick up where the previous one stopped, unless
+    /// a commit, a new read transaction or a checkpoint came in between
+    pub fn compact_step(&self, budget: usize) -> Result<CompactProgress, Error> {
+        self.storage.compact_step(budget)
+    }
+
+    /// Compacts the database in one go, moving the tree to the start of the file and truncating
+    /// the file after it, as far as the open read transactions and checkpoints allow.
+    ///
+    /// Like `compact_step`, and unlike `compact_and_shrink`, it takes `&self`, and open
+    /// transactions stay valid. Returns `Error::WriteConflict` while a write transaction is live
+    pub fn compact(&self) -> Result<(), Error> {
+        self.storage.compact()
+    }
+
+    /// Reports how much space the database uses, and how many entries each table holds
+    pub fn stats(&self) -> Result<DbStats, Error> {
+        self.storage.stats()
+    }
+
+    /// Reports how many bytes of each page of the committed tree are used, as each node takes
+    /// a whole page however small it is. Pages of old versions of the tree are not included
+    pub fn page_utilization(&self) -> Result<Vec<PageUsage>, Error> {
+        self.storage.page_utilization()
+    }
+
+    /// Walks the whole tree and checks that it is internally consistent.
+    /// Inconsistencies are listed in the report, rather than returned as an error
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        Ok(self.storage.verify())
+    }
+
+    /// Opens the table with the given name, without creating it if it does not exist.
+    /// The table is opened with the compression that it was created with
+    pub fn open_existing_table<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        name: &[u8],
+    ) -> Result<Table<'_, K, V>, Error> {
+        match self.storage.get_table(name)? {
+            Some((id, compression)) => Table::new(id, &self.storage, compression),
+            None => Err(Error::TableNotFound(
+                String::from_utf8_lossy(name).into_owned(),
+            )),
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // errors cannot be reported from here, use flush() to observe them
+        let _ = self.storage.fsync();
+    }
+}
+
+pub struct DatabaseBuilder {
+    initial_size: usize,
+    max_size: usize,
+    page_size: usize,
+    fill_factor: f32,
+    node_cache_size: usize,
+}
+
+impl DatabaseBuilder {
+    fn new() -> Self {
+        DatabaseBuilder {
+            initial_size: 1024 * 1024,
+            max_size: 16 * 1024 * 1024 * 1024,
+            page_size: page_size::get(),
+            fill_factor: 1.0,
+            node_cache_size: DEFAULT_NODE_CACHE_SIZE,
+        }
+    }
+
+    /// Size that the database file is created with. It is grown as needed, up to `max_size`
+    pub fn set_initial_size(&mut self, size: usize) -> &mut Self {
+        self.initial_size = size;
+        self
+    }
+
+    /// Maximum size of the database file. This much address space is reserved by the mmap
+    pub fn set_max_size(&mut self, size: usize) -> &mut Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Size of the pages that the tree is stored in. Must be a multiple of the OS page size.
+    /// Only used when creating a new database
+    pub fn set_page_size(&mut self, size: usize) -> &mut Self {
+        self.page_size = size;
+        self
+    }
+
+    /// How full the leaves of the tree are packed when it is rebuilt, by a commit that is large
+    /// compared to the database or by compaction. Must be greater than 0 and at most 1,
+    /// the default. A lower fill factor leaves room for later inserts, which then allocate
+    /// fewer pages, while 1 builds the smallest tree for read-mostly data
+    pub fn set_fill_factor(&mut self, fill_factor: f32) -> &mut Self {
+        self.fill_factor = fill_factor;
+        self
+    }
+
+    /// Number of internal nodes of the tree that are kept decoded in memory, so that lookups
+    /// which repeatedly pass through the same nodes skip decoding them. 0 disables the cache
+    pub fn set_node_cache_size(&mut self, entries: usize) -> &mut Self {
+        self.node_cache_size = entries;
+        self
+    }
+
+    /// Opens the specified file as a radarbase database, with these options.
+    ///
+    /// # Safety
+    ///
+    /// See [`Database::open`]
+    pub unsafe fn open(&self, path: &Path) -> Result<Database, Error> {
+        self.open_file(path, false)
+    }
+
+    /// Opens the specified file as a radarbase database, with these options, while holding
+    /// a lock on it. See [`Database::create`]
+    pub fn create(&self, path: &Path) -> Result<Database, Error> {
+        // Safety: the lock excludes every other database that is opened with `create`,
+        // and the file must not be modified otherwise, as documented on Database::create
+        unsafe { self.open_file(path, true) }
+    }
+
+    unsafe fn open_file(&self, path: &Path, lock: bool) -> Result<Database, Error> {
+        if self.page_size == 0 || !self.page_size.is_multiple_of(page_size::get()) {
+            return Err(invalid_option(format!(
+                "page size {} is not a multiple of the OS page size {}",
+                self.page_size,
+                page_size::get()
+            )));
+        }
+        if !(self.fill_factor > 0.0 && self.fill_factor <= 1.0) {
+            return Err(invalid_option(format!(
+                "fill factor {} is not in (0, 1]",
+                self.fill_factor
+            )));
+        }
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)?;
+        // the lock is released when the file is closed, along with the database
+        if lock {
+            file.try_lock().map_err(|err| match err {
+                TryLockError::WouldBlock => Error::AlreadyOpen,
+                TryLockError::Error(err) => Error::Io(err),
+            })?;
+        }
 
-        // TODO: make this configurable
-        let mut db_size = 16 * 1024 * 1024 * 1024;
-        // Ensure that db_size is a multiple of page size, which is required by mmap
-        // page_size::get() to retrieve the memory page size of the current system.
-        db_size -= db_size % page_size::get();
-        file.set_len(db_size as u64)?;
+        let mut file_len = file.metadata()?.len() as usize;
+        // check an existing file before growing it, so that a foreign file is left untouched.
+        // An existing database keeps its own page size, which the sizes are rounded to
+        let mut page_size = self.page_size;
+        if file_len > 0 {
+            let mut header = vec![0; min(file_len, HEADER_SIZE)];
+            (&file).read_exact(&mut header)?;
+            check_magic(&header[..min(file_len, MAGICNUMBER.len())])?;
+            if let Some(stored) = stored_page_size(&header) {
+                if stored == 0 || !stored.is_multiple_of(page_size::get()) {
+                    return Err(Error::Corruption(Corruption::PageSizeNotOsMultiple {
+                        page_size: stored,
+                        os_page_size: page_size::get(),
+                    }));
+                }
+                page_size = stored;
+            }
+        }
 
-        let mmap = MmapMut::map_mut(&file)?;
-        let storage = Storage::new(mmap)?;
+        // Both sizes must be a multiple of page size, which is required by mmap
+        let initial_size = self.initial_size - self.initial_size % page_size;
+        let max_size = self.max_size - self.max_size % page_size;
+        if initial_size == 0 {
+            return Err(invalid_option(format!(
+                "initial size {} is smaller than a page",
+                self.initial_size
+            )));
+        }
+        if initial_size > max_size {
+            return Err(invalid_option(format!(
+                "initial size {} is larger than max size {}",
+                self.initial_size, self.max_size
+            )));
+        }
+        if file_len > max_size {
+            return Err(invalid_option(format!(
+                "database file of {} bytes is larger than max size {}",
+                file_len, self.max_size
+            )));
+        }
+        if file_len < initial_size {
+            file.set_len(initial_size as u64)?;
+            file_len = initial_size;
+        }
+
+        let mmap = MmapOptions::new().len(max_size).map_mut(&file)?;
+        let storage = Storage::new(Mapping::ReadWrite(mmap), Some(file), file_len, page_size)?
+            .with_fill_factor(self.fill_factor)
+            .with_node_cache_size(self.node_cache_size);
         Ok(Database { storage })
     }
+}
 
-    pub fn open_table<K: RadbKey + ?Sized>(&self, name: &[u8]) -> Result<Table<K>, Error> {
-        assert!(!name.is_empty());
-        let id = self.storage.get_or_create_table(name)?;
-        Table::new(id, &self.storage)
-    }
+fn invalid_option(msg: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, msg))
 }
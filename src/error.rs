@@ -1,8 +1,39 @@
+use crate::Compression;
+use std::fmt::{Display, Formatter};
 use std::io;
 
 #[derive(Debug)]
 pub enum Error {
-    Corrupted(String),
+    /// The database file, or an export, is invalid or has been corrupted
+    Corruption(Corruption),
+    /// The file is not a radarbase database
+    InvalidDatabase,
+    /// The key, of the given length, does not fit into a page
+    KeyTooLarge(usize),
+    /// The value, of the given length, does not fit into a page together with its key
+    ValueTooLarge(usize),
+    /// No table with the given name exists
+    TableNotFound(String),
+    /// No checkpoint of the given version is held, see `Database::checkpoint`
+    CheckpointNotFound(u64),
+    /// `Database::compact_and_shrink` would overwrite the versions that checkpoints hold
+    CheckpointHeld,
+    /// The budget, in pages, of `Database::compact_step` is too small to move the next page
+    /// along with the nodes above it
+    CompactBudgetTooSmall(usize),
+    /// The savepoint was invalidated by rolling back to an older one, or was taken by another
+    /// transaction, see `WriteTransaction::savepoint`
+    InvalidSavepoint,
+    /// The database file has no space left for new pages
+    OutOfSpace,
+    /// The database was opened read-only
+    ReadOnly,
+    /// Every table id has been used
+    TableIdExhausted,
+    /// Another write transaction is already in progress
+    WriteConflict,
+    /// The database file is locked by another database, see `Database::create`
+    AlreadyOpen,
     Io(io::Error),
 }
 
@@ -11,3 +42,131 @@ impl From<io::Error> for Error {
         Error::Io(err)
     }
 }
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Corruption(corruption) => write!(f, "database corrupted: {}", corruption),
+            Error::InvalidDatabase => write!(f, "not a radarbase database"),
+            Error::KeyTooLarge(len) => write!(f, "key of {} bytes is too large", len),
+            Error::ValueTooLarge(len) => write!(f, "value of {} bytes is too large", len),
+            Error::TableNotFound(name) => write!(f, "table {:?} not found", name),
+            Error::CheckpointNotFound(version) => {
+                write!(f, "no checkpoint of version {} is held", version)
+            }
+            Error::CheckpointHeld => {
+                write!(f, "cannot compact the database while checkpoints are held")
+            }
+            Error::CompactBudgetTooSmall(budget) => write!(
+                f,
+                "a budget of {} pages is too small to move a page along with its path",
+                budget
+            ),
+            Error::InvalidSavepoint => write!(f, "savepoint is no longer valid"),
+            Error::OutOfSpace => write!(f, "database is out of space"),
+            Error::ReadOnly => write!(f, "database is read-only"),
+            Error::TableIdExhausted => write!(f, "no table ids are left"),
+            Error::WriteConflict => write!(f, "another write transaction is in progress"),
+            Error::AlreadyOpen => write!(f, "database is already open"),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// What is invalid about a corrupted database file or export, see `Error::Corruption`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// The page of the tree has a type byte that is neither a leaf nor an internal node
+    InvalidNodeType { page: u64, byte: u8 },
+    /// The file was written by an unknown version of the file format
+    UnsupportedFormatVersion(u8),
+    /// The page size in the header is too small, or does not divide the file length
+    InvalidPageSize(usize),
+    /// The page size in the header is not a multiple of the OS page size, so the file cannot
+    /// be mapped on this machine
+    PageSizeNotOsMultiple {
+        page_size: usize,
+        os_page_size: usize,
+    },
+    /// The header lists more free pages than the metadata page holds
+    FreePageCount(u64),
+    /// A free page in the header is the metadata page, or past the allocated pages
+    FreePageOutOfBounds(u64),
+    /// The catalog entry of a table, of the given length, is invalid
+    CatalogEntry(usize),
+    /// A value, or the catalog entry of a table, names a codec that is unknown or was not
+    /// compiled in
+    CompressionTag(u8),
+    /// A value of a compressed table is empty, so it lacks its codec tag
+    EmptyCompressedValue,
+    /// A value of a compressed table cannot be decompressed by its codec
+    CompressedValue { codec: Compression, message: String },
+    /// The stream does not start like an export
+    NotAnExport,
+    /// The export ends before its end marker, or in the middle of an entry
+    ExportTruncated,
+    /// An entry of the export is longer than memory can hold
+    ExportEntryTooLarge,
+    /// The export holds a different number of entries than its end marker records
+    ExportEntryCount { found: u64, expected: u64 },
+    /// An entry of the export has an unknown tag
+    ExportEntryTag(u8),
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corruption::InvalidNodeType { page, byte } => {
+                write!(f, "page {} has invalid node type {}", page, byte)
+            }
+            Corruption::UnsupportedFormatVersion(version) => {
+                write!(f, "unsupported file format version {}", version)
+            }
+            Corruption::InvalidPageSize(page_size) => write!(f, "invalid page size {}", page_size),
+            Corruption::PageSizeNotOsMultiple {
+                page_size,
+                os_page_size,
+            } => write!(
+                f,
+                "page size {} is not a multiple of the OS page size {}",
+                page_size, os_page_size
+            ),
+            Corruption::FreePageCount(count) => write!(
+                f,
+                "free page count {} does not fit into the metadata page",
+                count
+            ),
+            Corruption::FreePageOutOfBounds(page) => {
+                write!(f, "free page {} is out of bounds", page)
+            }
+            Corruption::CatalogEntry(len) => {
+                write!(f, "catalog entry of {} bytes is invalid", len)
+            }
+            Corruption::CompressionTag(tag) => {
+                write!(f, "invalid or disabled compression tag: {}", tag)
+            }
+            Corruption::EmptyCompressedValue => write!(f, "compressed value is empty"),
+            Corruption::CompressedValue { codec, message } => {
+                write!(f, "invalid {:?} value: {}", codec, message)
+            }
+            Corruption::NotAnExport => write!(f, "not a radarbase export"),
+            Corruption::ExportTruncated => write!(f, "export is truncated"),
+            Corruption::ExportEntryTooLarge => write!(f, "export entry is too large"),
+            Corruption::ExportEntryCount { found, expected } => write!(
+                f,
+                "export contains {} entries, but expected {}",
+                found, expected
+            ),
+            Corruption::ExportEntryTag(tag) => write!(f, "invalid export entry tag: {}", tag),
+        }
+    }
+}
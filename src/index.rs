@@ -0,0 +1,181 @@
+use crate::binarytree::BinarytreeEntry;
+use crate::storage::{AccessGuard, Storage};
+use crate::table::Table;
+use crate::transactions::{Durability, WriteTransaction};
+use crate::types::{encode_component, RadbKey, RadbValue, WithLifetime};
+use crate::Error;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::RangeFrom;
+
+// Returns the encoded value to index an entry by, from the entry's encoded value. Wraps the
+// typed extractor passed to `Index::new`
+type Extractor<'a> = dyn Fn(&[u8]) -> Vec<u8> + 'a;
+
+/// A secondary index over a primary table, which maps a value extracted from each entry back to
+/// the keys of the entries that it was extracted from.
+///
+/// The index is stored in a second table, keyed by the composite of the indexed value and the
+/// primary key, so that one indexed value can map to many primary keys. The extractor is passed
+/// the decoded value of an entry, and returns the value to index it by, which is encoded as an
+/// `IK`.
+///
+/// The index is only maintained by writes made through [`Index::begin_write`], so both tables
+/// should be empty when the index is created, and the primary table should not be written
+/// to directly afterwards.
+pub struct Index<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized, IK: RadbValue + ?Sized> {
+    primary: Table<'mmap, K, V>,
+    index: Table<'mmap, [u8]>,
+    extract: Box<Extractor<'mmap>>,
+    _indexed_type: PhantomData<IK>,
+}
+
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized, IK: RadbValue + ?Sized>
+    Index<'mmap, K, V, IK>
+{
+    pub fn new<R: Borrow<IK>, F: Fn(&V) -> R + 'mmap>(
+        primary: Table<'mmap, K, V>,
+        index: Table<'mmap, [u8]>,
+        extract: F,
+    ) -> Index<'mmap, K, V, IK>
+    where
+        for<'a> <V::View as WithLifetime<'a>>::Out: Borrow<V>,
+    {
+        let extract = move |value: &[u8]| {
+            let indexed = extract(V::from_bytes(value).borrow());
+            let indexed = indexed.borrow().as_bytes();
+            indexed.as_ref().to_vec()
+        };
+        Index {
+            primary,
+            index,
+            extract: Box::new(extract),
+            _indexed_type: Default::default(),
+        }
+    }
+
+    /// The primary table, for reads. Writes must go through `begin_write`
+    pub fn primary(&self) -> &Table<'mmap, K, V> {
+        &self.primary
+    }
+
+    /// Begins a write transaction on the primary table, whose changes are applied to the index
+    /// in the same commit
+    pub fn begin_write(&self) -> Result<IndexedWriteTransaction<'_, 'mmap, K, V>, Error> {
+        let storage = self.index.storage();
+        let index_table_id = self.index.table_id();
+        Ok(IndexedWriteTransaction {
+            primary: self.primary.begin_write()?,
+            storage,
+            index_table_id,
+            extract: &*self.extract,
+            added: HashSet::new(),
+            removed: HashSet::new(),
+        })
+    }
+
+    /// Returns the keys of every entry of the primary table that is indexed by `value`, in the
+    /// byte order of the keys
+    pub fn lookup(&self, value: &IK) -> Result<std::vec::IntoIter<AccessGuard<'static, K>>, Error> {
+        let prefix = index_prefix(value.as_bytes().as_ref());
+        let read_txn = self.index.read_transaction()?;
+        let mut iter = read_txn.get_range_owned::<RangeFrom<Vec<u8>>>(prefix.clone()..)?;
+        let mut keys = vec![];
+        for entry in iter.by_ref() {
+            match entry.key().strip_prefix(prefix.as_slice()) {
+                Some(primary_key) => keys.push(AccessGuard::owned(primary_key.to_vec())),
+                None => break,
+            }
+        }
+        if let Some(err) = iter.take_error() {
+            return Err(err);
+        }
+        Ok(keys.into_iter())
+    }
+}
+
+/// A write transaction on the primary table of an [`Index`], which stages the matching changes
+/// to the index
+pub struct IndexedWriteTransaction<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> {
+    primary: WriteTransaction<'mmap, K, V>,
+    storage: &'mmap Storage,
+    index_table_id: u64,
+    extract: &'a Extractor<'mmap>,
+    // keys of the index table
+    added: HashSet<Vec<u8>>,
+    removed: HashSet<Vec<u8>>,
+}
+
+impl<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized>
+    IndexedWriteTransaction<'a, 'mmap, K, V>
+{
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.primary.set_durability(durability);
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        self.unindex(key)?;
+        self.primary.insert(key, value)?;
+        let index_key = self.index_key(key, value.as_bytes().as_ref());
+        self.removed.remove(&index_key);
+        self.added.insert(index_key);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<bool, Error> {
+        self.unindex(key)?;
+        self.primary.remove(key)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<AccessGuard<'_, V>>, Error> {
+        self.primary.get(key)
+    }
+
+    /// Commits the primary table, and applies the index changes in the same commit, so that
+    /// both become visible, and durable, together
+    pub fn commit(self) -> Result<(), Error> {
+        let added: HashMap<Vec<u8>, Vec<u8>> =
+            self.added.into_iter().map(|key| (key, vec![])).collect();
+        let storage = self.storage;
+        let index_table_id = self.index_table_id;
+        let removed = self.removed;
+        self.primary.commit_with(|| {
+            storage.bulk_insert::<[u8]>(index_table_id, &added)?;
+            for key in removed.iter() {
+                storage.remove::<[u8]>(index_table_id, key)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn abort(self) -> Result<(), Error> {
+        self.primary.abort()
+    }
+
+    // Stages the removal of the index entry of the current value of `key`, if it has one
+    fn unindex(&mut self, key: &K) -> Result<(), Error> {
+        let current = match self.primary.get(key)? {
+            Some(value) => value.to_vec(),
+            None => return Ok(()),
+        };
+        let index_key = self.index_key(key, &current);
+        self.added.remove(&index_key);
+        self.removed.insert(index_key);
+        Ok(())
+    }
+
+    fn index_key(&self, key: &K, value: &[u8]) -> Vec<u8> {
+        let mut index_key = index_prefix(&(self.extract)(value));
+        index_key.extend_from_slice(key.as_bytes().as_ref());
+        index_key
+    }
+}
+
+// Index keys are the indexed value, length-prefixed like the first component of a tuple key,
+// followed by the primary key. So all the keys of one indexed value share this prefix
+fn index_prefix(value: &[u8]) -> Vec<u8> {
+    let mut prefix = vec![];
+    encode_component(&mut prefix, value);
+    prefix
+}
@@ -1,10 +1,15 @@
+// Lets the code generated by `#[derive(RadbKey)]` refer to `::radarbase` from within this crate
+extern crate self as radarbase;
+
+mod compression;
 mod db;
 mod error;
+mod index;
 mod page_manager;
 mod storage;
 mod table;
 mod transactions;
-mod types;
+pub mod types;
 pub mod btree; // testing
 
 /// This module provides an implementation of a binary tree.
@@ -21,9 +26,16 @@ pub mod btree; // testing
 ///
 mod binarytree;
 
-pub use db::Database;
-pub use error::Error;
-pub use storage::AccessGuard;
+pub use compression::{Compression, TableOptions};
+pub use db::{Database, DatabaseBuilder};
+pub use error::{Corruption, Error};
+pub use index::{Index, IndexedWriteTransaction};
+pub use radarbase_derive::RadbKey;
+pub use storage::{AccessGuard, CompactProgress, DbStats, NodeType, PageUsage, VerifyReport};
 pub use table::Table;
-pub use btree::BTree;
-pub use transactions::{ReadOnlyTransaction, WriteTransaction};
+pub use btree::{BTree, BTreeStats};
+pub use transactions::{
+    Change, ChangeSet, Durability, Entry, ExcludingPrefixIter, KeyIter, MergeJoin, OccupiedEntry,
+    ReadOnlyTransaction, Savepoint, TableIter, TypedRangeIter, VacantEntry, WriteTransaction,
+};
+pub use types::{RadbKey, RadbValue};
@@ -1,37 +1,72 @@
+use crate::binarytree::InternalMetadata;
 use crate::Error;
-use memmap2::MmapMut;
-use std::cell::{Ref, RefCell, RefMut};
+use memmap2::{Mmap, MmapMut};
+use std::cell::{Cell, RefCell};
+use std::cmp::{max, min};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use std::rc::Rc;
 
 pub(crate) const DB_METADATA_PAGE: u64 = 0;
+// Number of internal nodes that are cached by default
+pub(crate) const DEFAULT_NODE_CACHE_SIZE: usize = 64;
 
+// Pages point into the mmap, rather than borrowing it, so that a page can be read while
+// others are allocated and written. No page is ever read and written at once: a write only
+// goes to the metadata page, which is never held, or to an allocated page. That is either
+// new, or was freed once no version of the tree that uses it can be read: each read
+// transaction and checkpoint holds its version, and the values read from a transaction
+// borrow it. See `PageManager::release` and `Storage::reclaim` for when a page is freed.
+// Debug builds check this with `PageBorrows`
 pub struct Page<'a> {
-    mem: Ref<'a, [u8]>,
+    ptr: *const u8,
+    len: usize,
     page_number: u64,
+    #[cfg(debug_assertions)]
+    _borrow: PageBorrow,
+    _mmap: PhantomData<&'a [u8]>,
 }
 
 impl<'a> Page<'a> {
     pub(crate) fn memory(&self) -> &[u8] {
-        &self.mem
+        // Safety: the page is within the mmap, which outlives 'a, and is not being written
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
     }
 
     pub(crate) fn get_page_number(&self) -> u64 {
         self.page_number
     }
+
+    /// Like `memory()`, but borrows from the mmap rather than from the page. The memory is no
+    /// longer checked by `PageBorrows` once the page is dropped
+    pub(crate) fn into_memory(self) -> &'a [u8] {
+        // Safety: as for memory()
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
 }
 
 pub(crate) struct PageMut<'a> {
-    mem: RefMut<'a, [u8]>,
+    ptr: *mut u8,
+    len: usize,
     page_number: u64,
+    #[cfg(debug_assertions)]
+    _borrow: PageBorrow,
+    _mmap: PhantomData<&'a mut [u8]>,
 }
 
 impl<'a> PageMut<'a> {
     pub(crate) fn memory(&self) -> &[u8] {
-        &self.mem
+        // Safety: as for Page, and the page is only written through this PageMut
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
     }
 
     pub(crate) fn memory_mut(&mut self) -> &mut [u8] {
-        &mut self.mem
+        // Safety: as for memory()
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 
     pub(crate) fn get_page_number(&self) -> u64 {
@@ -39,11 +74,192 @@ impl<'a> PageMut<'a> {
     }
 }
 
+// The pages that a live Page or PageMut points to, each with its number of Pages, or -1 while
+// a PageMut writes it. Debug builds check that a page is never written while it is read, e.g.
+// because it was released and allocated again while a Page of it was still alive
+#[cfg(debug_assertions)]
+#[derive(Default)]
+struct PageBorrows(RefCell<HashMap<u64, isize>>);
+
+#[cfg(debug_assertions)]
+impl PageBorrows {
+    fn borrow(self: &Rc<Self>, page_number: u64) -> PageBorrow {
+        let mut borrows = self.0.borrow_mut();
+        let count = borrows.entry(page_number).or_default();
+        assert!(
+            *count >= 0,
+            "page {} is read while it is written",
+            page_number
+        );
+        *count += 1;
+        PageBorrow {
+            borrows: self.clone(),
+            page_number,
+        }
+    }
+
+    fn borrow_mut(self: &Rc<Self>, page_number: u64) -> PageBorrow {
+        let mut borrows = self.0.borrow_mut();
+        assert!(
+            !borrows.contains_key(&page_number),
+            "page {} is written while it is borrowed",
+            page_number
+        );
+        borrows.insert(page_number, -1);
+        PageBorrow {
+            borrows: self.clone(),
+            page_number,
+        }
+    }
+
+    fn release(&self, page_number: u64) {
+        let mut borrows = self.0.borrow_mut();
+        let count = borrows.get_mut(&page_number).unwrap();
+        if *count > 1 {
+            *count -= 1;
+        } else {
+            borrows.remove(&page_number);
+        }
+    }
+}
+
+// The borrow of a page by a Page or PageMut, which ends when it is dropped. It does not borrow
+// the PageManager itself, so that dropping a Page never needs the PageManager to be alive
+#[cfg(debug_assertions)]
+struct PageBorrow {
+    borrows: Rc<PageBorrows>,
+    page_number: u64,
+}
+
+#[cfg(debug_assertions)]
+impl Drop for PageBorrow {
+    fn drop(&mut self) {
+        self.borrows.release(self.page_number);
+    }
+}
+
+// The memory that the database is mapped into
+pub(crate) enum Mapping {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl Mapping {
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        if let Mapping::ReadWrite(mmap) = self {
+            mmap.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Mapping::ReadWrite(mmap) => mmap,
+            Mapping::ReadOnly(mmap) => mmap,
+        }
+    }
+}
+
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Mapping::ReadWrite(mmap) => mmap,
+            // Callers must check for a read-only database before writing
+            Mapping::ReadOnly(_) => unreachable!("write to a read-only database"),
+        }
+    }
+}
+
+// A small least-recently-used cache of decoded internal nodes, keyed by page number.
+// The capacity is small, so the least recently used node is found with a linear scan
+struct NodeCache {
+    capacity: usize,
+    // each node, with the tick at which it was last used
+    nodes: HashMap<u64, (InternalMetadata, u64)>,
+    tick: u64,
+    hits: u64,
+}
+
+impl NodeCache {
+    fn new(capacity: usize) -> Self {
+        NodeCache {
+            capacity,
+            nodes: HashMap::with_capacity(capacity),
+            tick: 0,
+            hits: 0,
+        }
+    }
+
+    fn get(&mut self, page_number: u64) -> Option<InternalMetadata> {
+        self.tick += 1;
+        let (node, last_used) = self.nodes.get_mut(&page_number)?;
+        *last_used = self.tick;
+        self.hits += 1;
+        Some(*node)
+    }
+
+    fn insert(&mut self, page_number: u64, node: InternalMetadata) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.nodes.len() >= self.capacity {
+            let (&evicted, _) = self
+                .nodes
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .unwrap();
+            self.nodes.remove(&evicted);
+        }
+        self.nodes.insert(page_number, (node, self.tick));
+    }
+}
+
+// The pages allocated within a `with_scratch` scope. No reader can see the tree while the
+// scope is open, so the pages of intermediate trees that were built in the scope and then
+// copied again, e.g. by consecutive inserts into the same path, can be reused for later pages
+#[derive(Default)]
+struct Scratch {
+    allocated: HashSet<u64>,
+    free: Vec<u64>,
+    // the length of `PageManager::superseded` when the scope was opened
+    superseded_len: usize,
+}
+
 pub(crate) struct PageManager {
     next_free_page: RefCell<u64>, // the next free page number that not yet been allocated
-    mmap: RefCell<MmapMut>,
+    mmap: Mapping,
+    // The start of the mmap, which stays at the same address for as long as mmap is alive
+    base: *mut u8,
+    // The mmap may be larger than the file. The file is grown as pages are allocated,
+    // since touching a page beyond the end of the file is an error (SIGBUS).
+    // An anonymous mmap has no file, and its full length is usable
+    file: Option<File>,
+    file_len: Cell<usize>,
+    page_size: usize,
+    // Pages are only ever written through get_page_mut(), which evicts them from the cache
+    node_cache: RefCell<NodeCache>,
+    scratch: RefCell<Option<Scratch>>,
+    // Pages below next_free_page that no readable version of the tree uses, which are
+    // allocated again, lowest first, before the file grows
+    free: RefCell<BTreeSet<u64>>,
+    // Pages of a committed tree that a scratch scope released, since the tree that it built
+    // no longer uses them. They are only freed once nothing reads the committed tree
+    superseded: RefCell<Vec<u64>>,
+    #[cfg(debug_assertions)]
+    borrows: Rc<PageBorrows>,
 }
 
+// Safety: base points into mmap, which moves along with the PageManager, so it stays valid on
+// another thread. Every Page and PageMut borrows the PageManager, and neither is Send, so none
+// is alive while the PageManager moves between threads, and nothing else shares the Rc of
+// `PageBorrows`. Within the thread that owns it, the
+// pages are only aliased as described for Page, which `PageBorrows` checks in debug builds
+unsafe impl Send for PageManager {}
+
 impl PageManager {
     pub(crate) const fn state_size() -> usize {
         8
@@ -54,60 +270,340 @@ impl PageManager {
     }
 
     /// Restore the page manager from the given memory map.
-    pub(crate) fn restore(mmap: MmapMut, state_offset: usize) -> Self {
+    pub(crate) fn restore(
+        mmap: Mapping,
+        file: Option<File>,
+        file_len: usize,
+        page_size: usize,
+        state_offset: usize,
+    ) -> Self {
         let next_free_page = u64::from_be_bytes(
             mmap[state_offset..(state_offset + Self::state_size())]
                 .try_into()
                 .unwrap(),
         );
+        let base = match &mmap {
+            Mapping::ReadWrite(mmap) => mmap.as_ptr() as *mut u8,
+            Mapping::ReadOnly(mmap) => mmap.as_ptr() as *mut u8,
+        };
         PageManager {
             next_free_page: RefCell::new(next_free_page),
-            mmap: RefCell::new(mmap),
+            mmap,
+            base,
+            file,
+            file_len: Cell::new(file_len),
+            page_size,
+            node_cache: RefCell::new(NodeCache::new(DEFAULT_NODE_CACHE_SIZE)),
+            scratch: RefCell::new(None),
+            free: RefCell::new(BTreeSet::new()),
+            superseded: RefCell::new(vec![]),
+            #[cfg(debug_assertions)]
+            borrows: Rc::default(),
+        }
+    }
+
+    /// Sets how many decoded internal nodes are cached. 0 disables the cache
+    pub(crate) fn set_node_cache_size(&mut self, capacity: usize) {
+        self.node_cache = RefCell::new(NodeCache::new(capacity));
+    }
+
+    /// Returns the internal node of the given page, decoding it with `decode` if it is not cached
+    pub(crate) fn internal_metadata(
+        &self,
+        page_number: u64,
+        decode: impl FnOnce() -> InternalMetadata,
+    ) -> InternalMetadata {
+        let mut cache = self.node_cache.borrow_mut();
+        if let Some(node) = cache.get(page_number) {
+            return node;
         }
+        let node = decode();
+        cache.insert(page_number, node);
+        node
+    }
+
+    #[cfg(test)]
+    pub(crate) fn node_cache_hits(&self) -> u64 {
+        self.node_cache.borrow().hits
+    }
+
+    pub(crate) fn get_page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub(crate) fn get_next_free_page(&self) -> u64 {
+        *self.next_free_page.borrow()
+    }
+
+    pub(crate) fn get_mapped_len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub(crate) fn get_file_len(&self) -> usize {
+        self.file_len.get()
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        matches!(self.mmap, Mapping::ReadOnly(_))
     }
 
     pub(crate) fn fsync(&self) -> Result<(), Error> {
-        self.mmap.borrow().flush()?;
+        self.mmap.flush()?;
 
         Ok(())
     }
 
     /// Returns a reference to the page with the specified number.
-    pub(crate) fn get_page(&self, page_number: u64) -> Page {
+    pub(crate) fn get_page(&self, page_number: u64) -> Page<'_> {
         assert!(page_number < *self.next_free_page.borrow());
-        let start = page_number as usize * page_size::get();
-        let end = start + page_size::get();
+        let start = page_number as usize * self.page_size;
+        assert!(start + self.page_size <= self.mmap.len());
+        #[cfg(debug_assertions)]
+        let borrow = self.borrows.borrow(page_number);
 
         Page {
-            mem: Ref::map(self.mmap.borrow(), |m| &m[start..end]),
+            // Safety: the page is within the mmap, as checked above
+            ptr: unsafe { self.base.add(start) },
+            len: self.page_size,
             page_number,
+            #[cfg(debug_assertions)]
+            _borrow: borrow,
+            _mmap: PhantomData,
         }
     }
 
-    pub(crate) fn get_metapage_mut(&self) -> PageMut {
+    pub(crate) fn get_metapage_mut(&self) -> PageMut<'_> {
         self.get_page_mut(DB_METADATA_PAGE)
     }
 
     /// Returns a mutable reference to the page with the specified number.
-    fn get_page_mut(&self, page_number: u64) -> PageMut {
+    fn get_page_mut(&self, page_number: u64) -> PageMut<'_> {
         assert!(page_number < *self.next_free_page.borrow());
-        let start = page_number as usize * page_size::get();
-        let end = start + page_size::get();
+        // the page may be overwritten, e.g. once it has been reclaimed and allocated again
+        self.node_cache.borrow_mut().nodes.remove(&page_number);
+        let start = page_number as usize * self.page_size;
+        assert!(start + self.page_size <= self.mmap.len());
+        // Callers must check for a read-only database before writing
+        assert!(!self.is_read_only(), "write to a read-only database");
+        #[cfg(debug_assertions)]
+        let borrow = self.borrows.borrow_mut(page_number);
 
         PageMut {
-            mem: RefMut::map(self.mmap.borrow_mut(), |m| &mut m[start..end]),
+            // Safety: the page is within the mmap, as checked above
+            ptr: unsafe { self.base.add(start) },
+            len: self.page_size,
             page_number,
+            #[cfg(debug_assertions)]
+            _borrow: borrow,
+            _mmap: PhantomData,
         }
     }
 
-    pub(crate) fn allocate(&self) -> PageMut {
+    /// Allocates a page, which is reused from the scratch arena if one was released there,
+    /// or else from the free pages. Returns `Error::OutOfSpace` if no page is left in the mmap,
+    /// and `Error::Io` if the file cannot be grown
+    pub(crate) fn allocate(&self) -> Result<PageMut<'_>, Error> {
+        if let Some(scratch) = self.scratch.borrow_mut().as_mut() {
+            if let Some(page_number) = scratch.free.pop() {
+                scratch.allocated.insert(page_number);
+                return Ok(self.get_page_mut(page_number));
+            }
+        }
+        let reused = self.free.borrow_mut().pop_first();
+        if let Some(page_number) = reused {
+            if let Some(scratch) = self.scratch.borrow_mut().as_mut() {
+                scratch.allocated.insert(page_number);
+            }
+            return Ok(self.get_page_mut(page_number));
+        }
         let page_number = *self.next_free_page.borrow();
+        if (page_number as usize + 1) * self.page_size > self.mmap.len() {
+            return Err(Error::OutOfSpace);
+        }
+        // grow the file first, so that nothing is allocated if that fails, e.g. on a full disk
+        let end = (page_number as usize + 1) * self.page_size;
+        if end > self.file_len.get() {
+            if let Some(file) = &self.file {
+                // Double the file, so that it is only grown a logarithmic number of times
+                let mmap_len = self.mmap.len();
+                let new_len = max(end, min(2 * self.file_len.get(), mmap_len));
+                file.set_len(new_len as u64)?;
+                self.file_len.set(new_len);
+            }
+        }
         *self.next_free_page.borrow_mut() += 1;
+        if let Some(scratch) = self.scratch.borrow_mut().as_mut() {
+            scratch.allocated.insert(page_number);
+        }
+
+        Ok(self.get_page_mut(page_number))
+    }
+
+    /// Runs `f` with a scratch arena, in which a page that was allocated within `f` can be
+    /// released once it is no longer referenced, and is then reused by a later allocation.
+    /// If `f` succeeds, released pages that are left over are freed, and the released pages
+    /// of committed trees are left for `take_superseded`. The caller must ensure that no
+    /// reader sees the tree until `f` returns. A nested call joins the outer scope
+    pub(crate) fn with_scratch<T>(&self, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+        if self.scratch.borrow().is_some() {
+            return f();
+        }
+        self.with_free_pages(vec![], f)
+    }
+
+    /// Runs `f` with a scratch arena, as `with_scratch`, in which allocations first take the
+    /// given free pages, lowest first. The caller must ensure that no free page is referenced
+    pub(crate) fn with_free_pages<T>(
+        &self,
+        mut free: Vec<u64>,
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        assert!(self.scratch.borrow().is_none());
+        free.sort_unstable_by(|a, b| b.cmp(a));
+        *self.scratch.borrow_mut() = Some(Scratch {
+            allocated: HashSet::new(),
+            free,
+            superseded_len: self.superseded.borrow().len(),
+        });
+        let result = f();
+        let scratch = self.scratch.borrow_mut().take().unwrap();
+        // after a failure, a released page may still be referenced by the root
+        if result.is_ok() {
+            self.add_free_pages(scratch.free);
+        } else {
+            self.superseded
+                .borrow_mut()
+                .truncate(scratch.superseded_len);
+        }
+        result
+    }
 
-        self.get_page_mut(page_number)
+    /// Returns the pages of committed trees that scratch scopes released, see `release`
+    pub(crate) fn take_superseded(&self) -> Vec<u64> {
+        std::mem::take(&mut *self.superseded.borrow_mut())
+    }
+
+    /// Frees pages, so that they are allocated again. Free pages at the end of the allocated
+    /// pages are given back, so that the file can shrink. The caller must ensure that no
+    /// freed page is referenced
+    pub(crate) fn add_free_pages(&self, pages: impl IntoIterator<Item = u64>) {
+        let mut free = self.free.borrow_mut();
+        free.extend(pages);
+        let mut next_free_page = self.next_free_page.borrow_mut();
+        while free.last() == Some(&(*next_free_page - 1)) {
+            free.pop_last();
+            *next_free_page -= 1;
+        }
+    }
+
+    /// Replaces the free pages, e.g. once compaction has found every unused page
+    pub(crate) fn set_free_pages(&self, pages: impl IntoIterator<Item = u64>) {
+        self.free.borrow_mut().clear();
+        self.add_free_pages(pages);
+    }
+
+    /// The free pages, lowest first
+    pub(crate) fn free_pages(&self) -> Vec<u64> {
+        self.free.borrow().iter().copied().collect()
+    }
+
+    pub(crate) fn free_page_count(&self) -> usize {
+        self.free.borrow().len()
+    }
+
+    /// Releases a page that is no longer referenced, so that it can be reused, if it was
+    /// allocated in the current scratch arena. Any other page may still be read, and is kept,
+    /// but recorded as superseded, so that it can be freed once the tree that replaced it is
+    /// committed and nothing reads the old one. A released page may be handed out by the next
+    /// allocation, so the caller must be done reading it
+    pub(crate) fn release(&self, page_number: u64) {
+        if let Some(scratch) = self.scratch.borrow_mut().as_mut() {
+            if scratch.allocated.remove(&page_number) {
+                scratch.free.push(page_number);
+            } else {
+                self.superseded.borrow_mut().push(page_number);
+            }
+        }
+    }
+
+    /// Frees every page, except the metadata page.
+    /// The caller must ensure that no freed page is referenced
+    pub(crate) fn reset_allocator(&self) {
+        *self.next_free_page.borrow_mut() = DB_METADATA_PAGE + 1;
+        self.free.borrow_mut().clear();
+        self.superseded.borrow_mut().clear();
+        self.node_cache.borrow_mut().nodes.clear();
+    }
+
+    /// Frees every page from `next_free_page` on, if it is below the next free page.
+    /// The caller must ensure that no freed page is referenced
+    pub(crate) fn truncate(&self, next_free_page: u64) {
+        let mut current = self.next_free_page.borrow_mut();
+        *current = min(*current, max(next_free_page, DB_METADATA_PAGE + 1));
+        self.free.borrow_mut().retain(|page| page < &*current);
+    }
+
+    /// Truncates the file to the allocated pages. Does nothing for an anonymous mmap
+    pub(crate) fn shrink(&self) -> Result<(), Error> {
+        if let Some(file) = &self.file {
+            let len = *self.next_free_page.borrow() as usize * self.page_size;
+            if len < self.file_len.get() {
+                file.set_len(len as u64)?;
+                self.file_len.set(len);
+            }
+        }
+        Ok(())
     }
 
     pub(crate) fn store_state(&self, output: &mut [u8]) {
         output.copy_from_slice(&self.next_free_page.borrow().to_be_bytes());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::page_manager::{Mapping, PageManager};
+    use memmap2::MmapMut;
+
+    fn manager(pages: usize) -> PageManager {
+        let page_size = 4096;
+        let mut mmap = MmapMut::map_anon(pages * page_size).unwrap();
+        PageManager::initialize(&mut mmap[0..PageManager::state_size()]);
+        let size = mmap.len();
+        PageManager::restore(Mapping::ReadWrite(mmap), None, size, page_size, 0)
+    }
+
+    #[test]
+    fn reallocate_released_page() {
+        let manager = manager(8);
+        manager
+            .with_scratch(|| {
+                let page_number = manager.allocate()?.get_page_number();
+                // the page is only borrowed until the end of the statement
+                let len = manager.get_page(page_number).memory().len();
+                assert_eq!(len, manager.get_page_size());
+                manager.release(page_number);
+                assert_eq!(manager.allocate()?.get_page_number(), page_number);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    // a released page may be allocated again, so it must no longer be read
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "is written while it is borrowed")]
+    fn reallocate_borrowed_page() {
+        let manager = manager(8);
+        manager
+            .with_scratch(|| {
+                let page_number = manager.allocate()?.get_page_number();
+                let page = manager.get_page(page_number);
+                manager.release(page_number);
+                manager.allocate()?;
+                drop(page);
+                Ok(())
+            })
+            .unwrap();
+    }
+}
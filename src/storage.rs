@@ -1,38 +1,206 @@
 use crate::binarytree::{
-    lookup_in_raw, tree_delete, tree_insert, BinarytreeBuilder, BinarytreeEntry,
-    BinarytreeRangeIter,
+    collect_pages, count_entries_up_to, dump_tree, entry_location, estimate_range_len,
+    estimated_tree_size, key_location, lookup_edge_raw, lookup_in_raw, lookup_many_raw,
+    lookup_nearest_raw, page_usage, relocate_pages, select_relocations, tree_delete,
+    tree_delete_table, tree_insert, verify_tree, BinarytreeBuilder, BinarytreeEntry,
+    BinarytreeRangeIter, RangeCursor, VerifyState,
 };
-use crate::page_manager::{Page, PageManager, DB_METADATA_PAGE};
-use crate::types::RadbKey;
-use crate::Error;
-use memmap2::MmapMut;
-use std::collections::HashMap;
+use crate::compression::{codec_from_tag, codec_tag, Compression};
+use crate::page_manager::{Mapping, Page, PageManager, DB_METADATA_PAGE};
+use crate::types::{RadbKey, RadbValue, WithLifetime};
+use crate::{Corruption, Error};
+use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
-use std::ops::{RangeBounds, RangeFull};
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::ops::{Deref, RangeBounds, RangeFull};
 
-const MAGICNUMBER: [u8; 4] = [b'r', b'a', b'd', b'b'];
-const ALLOCATOR_STATE_OFFSET: usize = MAGICNUMBER.len();
-const ROOT_PAGE_OFFSET: usize = ALLOCATOR_STATE_OFFSET + PageManager::state_size();
-const DB_METADATA_SIZE: usize = ROOT_PAGE_OFFSET;
+pub(crate) const MAGICNUMBER: [u8; 4] = [b'r', b'a', b'd', b'b'];
+// Version of the file format, to be bumped by any incompatible change to it
+const FORMAT_VERSION_OFFSET: usize = MAGICNUMBER.len();
+const FORMAT_VERSION: u8 = 6;
+const ALLOCATOR_STATE_OFFSET: usize = FORMAT_VERSION_OFFSET + 1;
+const DB_METADATA_SIZE: usize = HeaderField::Version.offset() + 8;
+// The free pages follow the header, each a big-endian u64, in as much of the metadata page as
+// they fit into. Any more are not stored, and are only reclaimed by compaction
+const FREE_LIST_OFFSET: usize = DB_METADATA_SIZE;
 
-// The table of name -> table_id mappings
+// The fields of the header that follow the allocator state, each a big-endian u64.
+// All reads and writes of them go through `read()` and `write()`, so that the layout is only
+// defined here, and a change to it comes with a new FORMAT_VERSION
+#[derive(Clone, Copy)]
+enum HeaderField {
+    // Page number of the root of the tree, or 0 if it is empty. Written by every commit
+    RootPage,
+    PageSize,
+    // The id that the next table created will get. Ids are never reused, even once a table is deleted
+    NextTableId,
+    // Number of free pages that are stored after the header
+    FreePageCount,
+    // The version of the committed tree, which is the number of commits that the database
+    // has had. Written by every commit, along with the root
+    Version,
+}
+
+impl HeaderField {
+    const fn offset(self) -> usize {
+        let first = ALLOCATOR_STATE_OFFSET + PageManager::state_size();
+        match self {
+            HeaderField::RootPage => first,
+            HeaderField::PageSize => first + 8,
+            HeaderField::NextTableId => first + 16,
+            HeaderField::FreePageCount => first + 24,
+            HeaderField::Version => first + 32,
+        }
+    }
+
+    fn read(self, header: &[u8]) -> u64 {
+        let offset = self.offset();
+        u64::from_be_bytes(header[offset..(offset + 8)].try_into().unwrap())
+    }
+
+    fn write(self, header: &mut [u8], value: u64) {
+        let offset = self.offset();
+        header[offset..(offset + 8)].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+// The table of name -> table_id mappings. The id of each table, a big-endian u64, is
+// followed by the tag of the codec that the table was created with
 const TABLE_TABLE_ID: u64 = 0;
 
+fn encode_table_entry(id: u64, compression: Compression) -> [u8; 9] {
+    let mut entry = [0; 9];
+    entry[0..8].copy_from_slice(&id.to_be_bytes());
+    entry[8] = codec_tag(compression);
+    entry
+}
+
+fn decode_table_entry(entry: &[u8]) -> Result<(u64, Compression), Error> {
+    if entry.len() != 9 {
+        return Err(Error::Corruption(Corruption::CatalogEntry(entry.len())));
+    }
+    let id = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+    Ok((id, codec_from_tag(entry[8])?))
+}
+
 pub(crate) struct Storage {
     mem: PageManager,
+    // Number of nested `Database::batch` scopes. While in a batch, commits are not flushed
+    batch_depth: Cell<usize>,
+    // Whether a write transaction is live. Writers are serialized, so that one cannot
+    // clobber the changes of another
+    write_active: Cell<bool>,
+    // Number of times the mmap has been flushed, so that tests can observe group commits
+    flushes: Cell<u64>,
+    // How full the leaves of a rebuilt tree are
+    fill_factor: f32,
+    // Versions that are held by `Database::checkpoint`, with how many checkpoints hold each,
+    // and its root
+    checkpoints: RefCell<BTreeMap<u64, (usize, Option<u64>)>>,
+    // Versions read by live `ReadOnlyTransaction`s, with how many transactions read each,
+    // and its root. Pages reachable from them must not be reclaimed until the transactions
+    // are dropped
+    readers: RefCell<BTreeMap<u64, (usize, u64)>>,
+    // The last version that has been flushed to disk
+    durable_version: Cell<u64>,
+    // The pages that each commit superseded, oldest first, with the version it made.
+    // A tree that a commit superseded pages of can still be read by readers, by checkpoints
+    // and, until it is flushed, from disk after a crash. So the pages are only freed once
+    // every version that is held, and the durable one, are the version it made or later
+    pending: RefCell<Vec<(u64, Vec<u64>)>>,
+    // The root of the tree that a write transaction is changing, while `with_working_root`
+    // runs. Changes to the tree go to it rather than to the header, so they stay uncommitted
+    working_root: Cell<Option<Option<u64>>>,
+    // Where the last `compact_step` stopped, so that the next one picks up from there
+    compaction: RefCell<Option<CompactState>>,
+}
+
+// The pages that an incremental compaction found, and how far it got. It only holds for the
+// version that the last step committed, and for the roots that were held then
+struct CompactState {
+    version: u64,
+    held_roots: Vec<u64>,
+    held: HashSet<u64>,
+    live: HashSet<u64>,
+    // the pages of the live tree are moved to the free pages up to this one
+    threshold: u64,
+    // the child indexes that lead to the page where the last step stopped
+    cursor: Vec<usize>,
+}
+
+/// Number of bytes at the start of the file that hold the database metadata
+pub(crate) const HEADER_SIZE: usize = DB_METADATA_SIZE;
+
+/// The page size stored in the header of an initialized database, or None if the header
+/// is too short or not initialized
+pub(crate) fn stored_page_size(header: &[u8]) -> Option<usize> {
+    if header.len() < HEADER_SIZE || header[0..MAGICNUMBER.len()] != MAGICNUMBER {
+        return None;
+    }
+    Some(HeaderField::PageSize.read(header) as usize)
+}
+
+// Reads the free pages that are stored after the header
+fn read_free_pages(header: &[u8], page_size: usize) -> Result<Vec<u64>, Error> {
+    let count = HeaderField::FreePageCount.read(header) as usize;
+    if count > free_list_capacity(page_size) {
+        return Err(Error::Corruption(Corruption::FreePageCount(count as u64)));
+    }
+    Ok(header[FREE_LIST_OFFSET..(FREE_LIST_OFFSET + count * 8)]
+        .chunks_exact(8)
+        .map(|page| u64::from_be_bytes(page.try_into().unwrap()))
+        .collect())
+}
+
+// The number of free pages that fit into the metadata page after the header
+fn free_list_capacity(page_size: usize) -> usize {
+    (page_size - FREE_LIST_OFFSET) / 8
+}
+
+/// Checks the first bytes of a file. They must be either the magic number, or zeroes if the
+/// database has not been initialized yet, so that a file of some other format is never
+/// overwritten
+pub(crate) fn check_magic(header: &[u8]) -> Result<(), Error> {
+    if header == MAGICNUMBER || header.iter().all(|b| *b == 0) {
+        Ok(())
+    } else {
+        Err(Error::InvalidDatabase)
+    }
 }
 
 impl Storage {
-    pub(crate) fn new(mut mmap: MmapMut) -> Result<Storage, Error> {
+    /// `page_size` is only used when initializing a new database. An existing database
+    /// keeps the page size that it was created with
+    pub(crate) fn new(
+        mut mmap: Mapping,
+        file: Option<File>,
+        file_len: usize,
+        page_size: usize,
+    ) -> Result<Storage, Error> {
         // Ensure that the database metadata fits into the first page
-        assert!(page_size::get() >= DB_METADATA_SIZE);
+        assert!(page_size >= DB_METADATA_SIZE);
 
+        check_magic(&mmap[0..MAGICNUMBER.len()])?;
         if mmap[0..MAGICNUMBER.len()] != MAGICNUMBER {
+            if let Mapping::ReadOnly(_) = mmap {
+                return Err(Error::InvalidDatabase);
+            }
+            mmap[FORMAT_VERSION_OFFSET] = FORMAT_VERSION;
             PageManager::initialize(
                 &mut mmap
                     [ALLOCATOR_STATE_OFFSET..(ALLOCATOR_STATE_OFFSET + PageManager::state_size())],
             );
-            mmap[ROOT_PAGE_OFFSET..(ROOT_PAGE_OFFSET + 8)].copy_from_slice(&0u64.to_be_bytes());
+            HeaderField::RootPage.write(&mut mmap, 0);
+            HeaderField::PageSize.write(&mut mmap, page_size as u64);
+            HeaderField::NextTableId.write(&mut mmap, TABLE_TABLE_ID + 1);
+            HeaderField::FreePageCount.write(&mut mmap, 0);
+            HeaderField::Version.write(&mut mmap, 0);
             mmap.flush()?;
             // Write the magic number only after the data structure is initialized and written to disk
             // to ensure that it's crash safe
@@ -40,31 +208,612 @@ impl Storage {
             mmap.flush()?;
         }
 
+        if mmap[FORMAT_VERSION_OFFSET] != FORMAT_VERSION {
+            return Err(Error::Corruption(Corruption::UnsupportedFormatVersion(
+                mmap[FORMAT_VERSION_OFFSET],
+            )));
+        }
+
+        let page_size = HeaderField::PageSize.read(&mmap) as usize;
+        if page_size < DB_METADATA_SIZE || !file_len.is_multiple_of(page_size) {
+            return Err(Error::Corruption(Corruption::InvalidPageSize(page_size)));
+        }
+        // a database created on a machine with smaller OS pages cannot be mapped here
+        if !page_size.is_multiple_of(page_size::get()) {
+            return Err(Error::Corruption(Corruption::PageSizeNotOsMultiple {
+                page_size,
+                os_page_size: page_size::get(),
+            }));
+        }
+
+        let free_pages = read_free_pages(&mmap, page_size)?;
+        let mem = PageManager::restore(mmap, file, file_len, page_size, ALLOCATOR_STATE_OFFSET);
+        if let Some(page) = free_pages
+            .iter()
+            .find(|page| **page == DB_METADATA_PAGE || **page >= mem.get_next_free_page())
+        {
+            return Err(Error::Corruption(Corruption::FreePageOutOfBounds(*page)));
+        }
+        mem.add_free_pages(free_pages);
+        let version = HeaderField::Version.read(mem.get_page(DB_METADATA_PAGE).memory());
+
         Ok(Storage {
-            mem: PageManager::restore(mmap, ALLOCATOR_STATE_OFFSET),
+            mem,
+            batch_depth: Cell::new(0),
+            write_active: Cell::new(false),
+            flushes: Cell::new(0),
+            fill_factor: 1.0,
+            checkpoints: RefCell::new(BTreeMap::new()),
+            readers: RefCell::new(BTreeMap::new()),
+            durable_version: Cell::new(version),
+            pending: RefCell::new(vec![]),
+            working_root: Cell::new(None),
+            compaction: RefCell::new(None),
         })
     }
 
-    pub(crate) fn get_or_create_table(&self, name: &[u8]) -> Result<u64, Error> {
+    /// Sets how full the leaves are built when the tree is rebuilt, e.g. by a large commit
+    /// or by compaction. See `BinarytreeBuilder::with_fill_factor`
+    pub(crate) fn with_fill_factor(mut self, fill_factor: f32) -> Storage {
+        self.fill_factor = fill_factor;
+        self
+    }
+
+    /// Sets how many decoded internal nodes are cached, to speed up repeated lookups
+    pub(crate) fn with_node_cache_size(mut self, capacity: usize) -> Storage {
+        self.mem.set_node_cache_size(capacity);
+        self
+    }
+
+    pub(crate) fn get_page_size(&self) -> usize {
+        self.mem.get_page_size()
+    }
+
+    /// Returns an error if the database was opened read-only
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        if self.mem.is_read_only() {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Checks that the tree of the builder fits after the allocated pages, so that a rebuild
+    // fails with `Error::OutOfSpace` before writing anything
+    fn check_space(&self, builder: &BinarytreeBuilder) -> Result<(), Error> {
+        let used = self.mem.get_next_free_page() as usize * self.mem.get_page_size();
+        let needed = builder.estimated_size(self.mem.get_page_size());
+        if used + needed > self.mem.get_mapped_len() {
+            Err(Error::OutOfSpace)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The id of the table, and the compression that it was created with
+    pub(crate) fn get_table(&self, name: &[u8]) -> Result<Option<(u64, Compression)>, Error> {
+        self.get_table_at(name, self.get_root_page_number())
+    }
+
+    // Looks the table up in the version of the database with the given root
+    pub(crate) fn get_table_at(
+        &self,
+        name: &[u8],
+        root_page: Option<u64>,
+    ) -> Result<Option<(u64, Compression)>, Error> {
+        match self.get::<[u8], [u8]>(TABLE_TABLE_ID, name, root_page)? {
+            Some(found) => Ok(Some(decode_table_entry(&found)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The version of the committed tree. Every commit makes a new version, which is greater
+    /// than every earlier one, so a version is never reused for another state
+    pub(crate) fn current_version(&self) -> u64 {
+        let metapage = self.mem.get_page(DB_METADATA_PAGE);
+        HeaderField::Version.read(metapage.memory())
+    }
+
+    /// Holds the current version, so that it can be read with `read_at`, and returns it
+    pub(crate) fn checkpoint(&self) -> u64 {
+        let version = self.current_version();
+        self.checkpoints
+            .borrow_mut()
+            .entry(version)
+            .or_insert((0, self.get_root_page_number()))
+            .0 += 1;
+        version
+    }
+
+    /// Releases one checkpoint of the version. Returns whether one was held
+    pub(crate) fn release_checkpoint(&self, version: u64) -> bool {
+        let mut checkpoints = self.checkpoints.borrow_mut();
+        let held = match checkpoints.get_mut(&version) {
+            Some((count, _)) => {
+                *count -= 1;
+                if *count == 0 {
+                    checkpoints.remove(&version);
+                }
+                true
+            }
+            None => false,
+        };
+        drop(checkpoints);
+        self.reclaim();
+        held
+    }
+
+    /// The root page of a checkpoint
+    pub(crate) fn checkpoint_root(&self, version: u64) -> Result<Option<u64>, Error> {
+        self.checkpoints
+            .borrow()
+            .get(&version)
+            .map(|(_, root)| *root)
+            .ok_or(Error::CheckpointNotFound(version))
+    }
+
+    /// Records that a read transaction reads the given version, whose tree has the given root.
+    /// An empty tree has no pages to hold
+    pub(crate) fn register_reader(&self, version: u64, root_page: Option<u64>) {
+        if let Some(root_page) = root_page {
+            self.readers
+                .borrow_mut()
+                .entry(version)
+                .or_insert((0, root_page))
+                .0 += 1;
+        }
+    }
+
+    /// Records that a read transaction registered with `register_reader` was dropped
+    pub(crate) fn unregister_reader(&self, version: u64, root_page: Option<u64>) {
+        if root_page.is_some() {
+            let mut readers = self.readers.borrow_mut();
+            let (count, _) = readers.get_mut(&version).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                readers.remove(&version);
+            }
+        }
+        self.reclaim();
+    }
+
+    // Frees the pages that no version that can still be read uses. Nothing is freed while a
+    // write transaction is live, since it may read from a version that is not held
+    fn reclaim(&self) {
+        if self.write_active.get() {
+            return;
+        }
+        let oldest = self
+            .readers
+            .borrow()
+            .keys()
+            .copied()
+            .chain(
+                self.checkpoints
+                    .borrow()
+                    .iter()
+                    .filter(|(_, (_, root))| root.is_some())
+                    .map(|(version, _)| *version),
+            )
+            .fold(self.durable_version.get(), min);
+        let mut pending = self.pending.borrow_mut();
+        let reclaimed = pending
+            .iter()
+            .take_while(|(commit, _)| *commit <= oldest)
+            .count();
+        for (_, pages) in pending.drain(..reclaimed) {
+            self.mem.add_free_pages(pages);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn reader_count(&self, version: u64) -> usize {
+        self.readers
+            .borrow()
+            .get(&version)
+            .map_or(0, |(count, _)| *count)
+    }
+
+    /// Returns the id of the table, creating it with the given compression if it does not exist
+    pub(crate) fn get_or_create_table(
+        &self,
+        name: &[u8],
+        compression: Compression,
+    ) -> Result<u64, Error> {
         // if the table already exists, return its id
-        if let Some(found) = self.get::<[u8]>(TABLE_TABLE_ID, name, self.get_root_page_number())? {
-            return Ok(u64::from_be_bytes(found.as_ref().try_into().unwrap()));
+        if let Some((id, _)) = self.get_table(name)? {
+            return Ok(id);
+        }
+        if self.mem.is_read_only() {
+            return Err(Error::TableNotFound(
+                String::from_utf8_lossy(name).into_owned(),
+            ));
         }
 
         // otherwise, create a new table
-        let mut iter = self.get_range_reversed::<RangeFull, [u8]>(
+        let new_id = self.get_next_table_id();
+        if new_id == u64::MAX {
+            return Err(Error::TableIdExhausted);
+        }
+        let entry = encode_table_entry(new_id, compression);
+        self.with_scratch(|| self.insert::<[u8]>(TABLE_TABLE_ID, name, &entry))?;
+        self.set_next_table_id(new_id + 1);
+        Ok(new_id)
+    }
+
+    fn get_next_table_id(&self) -> u64 {
+        let metapage = self.mem.get_page(DB_METADATA_PAGE);
+        HeaderField::NextTableId.read(metapage.memory())
+    }
+
+    fn set_next_table_id(&self, table_id: u64) {
+        let mut meta = self.mem.get_metapage_mut();
+        HeaderField::NextTableId.write(meta.memory_mut(), table_id);
+    }
+
+    /// Returns the names of all tables
+    pub(crate) fn list_tables(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut iter = self.get_range::<RangeFull, [u8], &[u8]>(
             TABLE_TABLE_ID,
             ..,
             self.get_root_page_number(),
         )?;
-        let largest_id = iter
-            .next()
-            .map(|x| u64::from_be_bytes(x.value().try_into().unwrap()))
-            .unwrap_or(TABLE_TABLE_ID);
-        drop(iter);
-        let new_id = largest_id + 1;
-        self.insert::<[u8]>(TABLE_TABLE_ID, name, &new_id.to_be_bytes())?;
-        Ok(new_id)
+        let mut names = vec![];
+        for entry in iter.by_ref() {
+            names.push(entry.key().to_vec());
+        }
+        iter.finish()?;
+        Ok(names)
+    }
+
+    pub(crate) fn stats(&self) -> Result<DbStats, Error> {
+        let root_page = self.get_root_page_number();
+        let mut table_entries = vec![];
+        let mut iter = self.get_range::<RangeFull, [u8], &[u8]>(TABLE_TABLE_ID, .., root_page)?;
+        for entry in iter.by_ref() {
+            let (id, _) = decode_table_entry(entry.value())?;
+            table_entries.push((entry.key().to_vec(), self.len(id, root_page)?));
+        }
+        iter.finish()?;
+        // starting from no pages, the whole tree is walked, so this is its full height
+        let tree_height = match root_page {
+            Some(root) => collect_pages(self.mem.get_page(root), &self.mem, &mut HashSet::new())?,
+            None => 0,
+        };
+
+        Ok(DbStats {
+            next_free_page: self.mem.get_next_free_page(),
+            free_pages: self.mem.free_page_count(),
+            page_size: self.mem.get_page_size(),
+            mapped_bytes: self.mem.get_mapped_len(),
+            file_bytes: self.mem.get_file_len(),
+            table_count: table_entries.len(),
+            table_entries,
+            tree_height,
+        })
+    }
+
+    /// Removes the table and all of its entries.
+    /// Returns a boolean indicating if the table existed
+    pub(crate) fn delete_table(&self, name: &[u8]) -> Result<bool, Error> {
+        // a write transaction would commit its changes to the table on top of the deletion
+        self.begin_write()?;
+        let result = self.delete_table_locked(name);
+        self.end_write();
+        result
+    }
+
+    fn delete_table_locked(&self, name: &[u8]) -> Result<bool, Error> {
+        let id = match self.get_table(name)? {
+            Some((id, _)) => id,
+            None => return Ok(false),
+        };
+        // Only the parts of the tree that hold the table's catalog entry and its contents are
+        // walked, as in `clear_table`
+        let root = self.with_working_root(self.get_root_page_number(), || {
+            self.remove::<[u8]>(TABLE_TABLE_ID, name)?;
+            if let Some(root) = self.get_root_page() {
+                let new_root = tree_delete_table(root, id, None, None, &self.mem)?;
+                self.set_root_page(new_root);
+            }
+            Ok(())
+        })?;
+        self.set_committed_root(root, self.take_superseded());
+        Ok(true)
+    }
+
+    /// Removes all of the table's entries, but keeps the table itself. Only the parts of the
+    /// tree that hold the table are walked, and the paths to them copied
+    pub(crate) fn clear_table(&self, table_id: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        // the catalog entry is kept, so the tree is never empty
+        if let Some(root) = self.get_root_page() {
+            let new_root = tree_delete_table(root, table_id, None, None, &self.mem)?;
+            self.set_root_page(new_root);
+        }
+        Ok(())
+    }
+
+    // Releases every page of the tree that changes apply to, once it has been copied into
+    // a builder, so that the rebuilt tree replaces it. See `PageManager::release`
+    fn release_tree(&self) -> Result<(), Error> {
+        let mut pages = HashSet::new();
+        if let Some(root) = self.get_root_page() {
+            collect_pages(root, &self.mem, &mut pages)?;
+        }
+        for page in pages {
+            self.mem.release(page);
+        }
+        Ok(())
+    }
+
+    // Copies every entry of the database into a builder
+    fn copy_entries(&self) -> Result<BinarytreeBuilder, Error> {
+        let mut builder = BinarytreeBuilder::with_fill_factor(self.fill_factor);
+        let mut tables_iter = BinarytreeRangeIter::<RangeFull, [u8]>::new(
+            self.get_root_page(),
+            TABLE_TABLE_ID,
+            ..,
+            &self.mem,
+        );
+        for table_entry in tables_iter.by_ref() {
+            let (id, _) = decode_table_entry(table_entry.value())?;
+            builder.add(
+                table_entry.table_id(),
+                table_entry.key(),
+                table_entry.value(),
+            );
+            let mut iter = BinarytreeRangeIter::<RangeFull, [u8]>::new(
+                self.get_root_page(),
+                id,
+                ..,
+                &self.mem,
+            );
+            for x in iter.by_ref() {
+                builder.add(x.table_id(), x.key(), x.value());
+            }
+            iter.finish()?;
+        }
+        tables_iter.finish()?;
+        Ok(builder)
+    }
+
+    /// Rewrites the live tree into the pages at the start of the file, and shrinks the file
+    /// to fit. Every page after them, such as the pages of old versions of the tree, is freed.
+    ///
+    /// The caller must ensure that no page of the current tree is referenced, e.g. by a read
+    /// transaction, since they are overwritten
+    pub(crate) fn compact_and_shrink(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        // compaction overwrites the pages of old versions
+        if !self.checkpoints.borrow().is_empty() {
+            return Err(Error::CheckpointHeld);
+        }
+        let builder = self.copy_entries()?;
+        if builder.is_empty() {
+            self.pending.borrow_mut().clear();
+            self.set_root_page(None);
+            self.mem.reset_allocator();
+            self.fsync()?;
+            return self.mem.shrink();
+        }
+        self.check_space(&builder)?;
+        let tree = builder.build::<[u8]>(TABLE_TABLE_ID);
+        // The live tree may occupy any page, so first commit a copy of it after all the
+        // allocated pages. Once that is durable, every page before the copy is unused and the
+        // tree can be written there, without a crash ever leaving the root invalid
+        let end = self.mem.get_next_free_page();
+        let free = self.mem.free_pages();
+        self.mem.set_free_pages([]);
+        let copy_root = match tree.to_bytes(&self.mem) {
+            Ok(root) => root,
+            Err(err) => {
+                // nothing references the pages of a partial copy
+                self.mem.truncate(end);
+                self.mem.set_free_pages(free);
+                return Err(err);
+            }
+        };
+        self.set_root_page(Some(copy_root));
+        self.fsync()?;
+        self.pending.borrow_mut().clear();
+        // The final tree takes the pages before the copy, lowest first. It may need more of
+        // them than there are, e.g. if the fill factor packs it less densely than the old tree,
+        // and then the rest are allocated after the copy
+        self.mem.set_free_pages(DB_METADATA_PAGE + 1..end);
+        let root = match tree.to_bytes(&self.mem) {
+            Ok(root) => root,
+            Err(err) => {
+                // the copy is still committed, so free every other page
+                self.truncate_unused(&self.live_pages()?, &HashSet::new())?;
+                return Err(err);
+            }
+        };
+        self.set_root_page(Some(root));
+        // frees the copy, and gives back the pages after the final tree
+        self.truncate_unused(&self.live_pages()?, &HashSet::new())?;
+        Ok(())
+    }
+
+    /// Moves at most `budget` pages of the live tree into unused pages nearer the start of the
+    /// file, then frees the unused pages at the end of it. Pages that live read transactions
+    /// or checkpoints may read are neither moved nor reused.
+    ///
+    /// The tree is done once it fits in its own number of pages, plus its height, of the
+    /// pages that could be reused. The slack lets each step move a page along with its path.
+    /// A step resumes the walk of the tree where the previous one stopped, unless a commit or
+    /// a new holder of pages came in between
+    pub(crate) fn compact_step(&self, budget: usize) -> Result<CompactProgress, Error> {
+        // a write transaction may have flushed its changes to pages that look unused
+        self.begin_write()?;
+        let result = self.compact_step_locked(budget);
+        self.end_write();
+        result
+    }
+
+    /// Runs compaction steps, with no budget, until the tree is as compact as the current
+    /// readers and checkpoints allow. See `compact_step`
+    pub(crate) fn compact(&self) -> Result<(), Error> {
+        self.begin_write()?;
+        let result = loop {
+            match self.compact_step_locked(usize::MAX) {
+                Ok(CompactProgress::Pending) => continue,
+                Ok(CompactProgress::Done) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.end_write();
+        result
+    }
+
+    fn compact_step_locked(&self, budget: usize) -> Result<CompactProgress, Error> {
+        let mut held_roots = self
+            .readers
+            .borrow()
+            .values()
+            .map(|(_, root)| *root)
+            .chain(
+                self.checkpoints
+                    .borrow()
+                    .values()
+                    .filter_map(|(_, root)| *root),
+            )
+            .collect::<Vec<u64>>();
+        held_roots.sort_unstable();
+        // a commit in between may have freed or reused the pages, and a new reader holds some
+        let state = self.compaction.borrow_mut().take().filter(|state| {
+            state.version == self.current_version() && state.held_roots == held_roots
+        });
+        let mut state = match state {
+            Some(state) => state,
+            None => match self.start_compaction(held_roots)? {
+                Some(state) => state,
+                None => return Ok(CompactProgress::Done),
+            },
+        };
+        let root = self.get_root_page_number().unwrap();
+
+        let free: Vec<u64> = (DB_METADATA_PAGE + 1..=state.threshold)
+            .filter(|p| !state.live.contains(p) && !state.held.contains(p))
+            .collect();
+        let targets: HashSet<u64> = state
+            .live
+            .iter()
+            .copied()
+            .filter(|p| *p > state.threshold && !state.held.contains(p))
+            .collect();
+        let mut path = vec![];
+        let mut selected = HashSet::new();
+        let finished = select_relocations(
+            self.mem.get_page(root),
+            &targets,
+            min(budget, free.len()),
+            &self.mem,
+            &state.cursor,
+            &mut path,
+            &mut selected,
+        )?;
+        if selected.is_empty() && !finished {
+            *self.compaction.borrow_mut() = Some(state);
+            return Err(Error::CompactBudgetTooSmall(budget));
+        }
+        if !selected.is_empty() {
+            let new_root = self.mem.with_free_pages(free, || {
+                relocate_pages(self.mem.get_page(root), &selected, &self.mem)
+            })?;
+            // the pages that were moved are freed by `truncate_unused`, which flushes the
+            // new root before any of them is reused
+            self.set_committed_root(Some(new_root), vec![]);
+            state.live.retain(|p| !selected.contains(p));
+            // only walks the copies, since the other pages are already there
+            collect_pages(self.mem.get_page(new_root), &self.mem, &mut state.live)?;
+        }
+        self.truncate_unused(&state.live, &state.held)?;
+        if finished {
+            return Ok(CompactProgress::Done);
+        }
+        state.version = self.current_version();
+        state.cursor = path.into_iter().map(|(_, index)| index).collect();
+        *self.compaction.borrow_mut() = Some(state);
+        Ok(CompactProgress::Pending)
+    }
+
+    // Finds the pages of the live tree and of the held roots, and the threshold that the tree
+    // is moved below, see `compact_step`. If the tree is already below it, frees the unused
+    // pages and returns None
+    fn start_compaction(&self, held_roots: Vec<u64>) -> Result<Option<CompactState>, Error> {
+        // A commit that was not flushed leaves the last durable tree in pages that look unused,
+        // so flush it, before those pages are overwritten
+        self.fsync()?;
+        let mut held = HashSet::new();
+        for root in &held_roots {
+            collect_pages(self.mem.get_page(*root), &self.mem, &mut held)?;
+        }
+        let mut live = HashSet::new();
+        let root = match self.get_root_page_number() {
+            Some(root) => root,
+            None => {
+                self.truncate_unused(&live, &held)?;
+                return Ok(None);
+            }
+        };
+        let height = collect_pages(self.mem.get_page(root), &self.mem, &mut live)?;
+
+        // the pages that the live tree could occupy, i.e. its own and unused ones. There are
+        // `height` more of these up to the threshold than there are movable pages above it,
+        // which is enough for at least one of them and its path
+        let movable = live.difference(&held).count();
+        let threshold = (DB_METADATA_PAGE + 1..self.mem.get_next_free_page())
+            .filter(|p| !held.contains(p))
+            .nth(movable + height - 1);
+        match threshold {
+            Some(threshold) => Ok(Some(CompactState {
+                version: self.current_version(),
+                held_roots,
+                held,
+                live,
+                threshold,
+                cursor: vec![],
+            })),
+            None => {
+                self.truncate_unused(&live, &held)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Frees the pages after the last one that the committed tree, whose pages are `live`, or
+    // a holder of `held` uses. Every unused page before it is freed too, and the pages that
+    // only holders use are freed once they are released, as if the last commit had
+    // superseded them
+    fn truncate_unused(&self, live: &HashSet<u64>, held: &HashSet<u64>) -> Result<(), Error> {
+        let last = live
+            .iter()
+            .chain(held.iter())
+            .copied()
+            .max()
+            .unwrap_or(DB_METADATA_PAGE);
+        self.mem.truncate(last + 1);
+        self.mem.set_free_pages(
+            (DB_METADATA_PAGE + 1..last).filter(|p| !live.contains(p) && !held.contains(p)),
+        );
+        let superseded: Vec<u64> = held.difference(live).copied().collect();
+        *self.pending.borrow_mut() = if superseded.is_empty() {
+            vec![]
+        } else {
+            vec![(self.current_version(), superseded)]
+        };
+        self.fsync()?;
+        self.mem.shrink()
+    }
+
+    // The pages of the committed tree
+    fn live_pages(&self) -> Result<HashSet<u64>, Error> {
+        let mut live = HashSet::new();
+        if let Some(root) = self.get_root_page_number() {
+            collect_pages(self.mem.get_page(root), &self.mem, &mut live)?;
+        }
+        Ok(live)
     }
 
     pub(crate) fn insert<K: RadbKey + ?Sized>(
@@ -76,30 +825,62 @@ impl Storage {
         let new_root = if let Some(root) = self.get_root_page() {
             // If there is already a root page, insert into the existing tree
             // quite inefficiently, only if the tree is contain less node
-            tree_insert::<K>(root, table_id, key, value, &self.mem)
+            tree_insert::<K>(root, table_id, key, value, &self.mem)?
         } else {
             // If there is no root page, create a new tree
             // (only happends when first inserting)
             let mut builder = BinarytreeBuilder::new();
             builder.add(table_id, key, value);
-            builder.build::<K>().to_bytes(&self.mem)
+            builder.build::<K>(table_id).to_bytes(&self.mem)?
         };
         self.set_root_page(Some(new_root));
         Ok(())
     }
 
+    // The number of entries in the tree with the given root, if a commit of `len` entries into
+    // it rebuilds the tree, rather than inserting them one by one
+    fn rebuild_len(&self, len: usize, root: Option<u64>) -> Result<Option<usize>, Error> {
+        // Assume that rewriting half the tree is about the same cost as building a completely new one.
+        // A rebuild copies every table, so compare against the whole tree, and only count as
+        // far as needed, so that a small commit does not scan a large database
+        let limit = len.saturating_mul(2);
+        let tree_len = root.map_or(Ok(0), |root| {
+            count_entries_up_to(self.mem.get_page(root), limit, &self.mem)
+        })?;
+        Ok(if tree_len >= limit {
+            None
+        } else {
+            Some(tree_len)
+        })
+    }
+
+    /// The number of bytes that a commit of `len` entries into the tree with the given root
+    /// allocates, if it rebuilds the tree, see `bulk_insert`. Entries that replace a value
+    /// are counted twice, so it may overestimate
+    pub(crate) fn estimated_rebuild_size(
+        &self,
+        len: usize,
+        root: Option<u64>,
+    ) -> Result<Option<usize>, Error> {
+        Ok(self.rebuild_len(len, root)?.map(|tree_len| {
+            estimated_tree_size(tree_len + len, self.fill_factor, self.mem.get_page_size())
+        }))
+    }
+
     pub(crate) fn bulk_insert<K: RadbKey + ?Sized>(
         &self,
         table_id: u64,
-        entries: HashMap<Vec<u8>, Vec<u8>>,
+        entries: &HashMap<Vec<u8>, Vec<u8>>,
     ) -> Result<(), Error> {
-        // Assume that rewriting half the tree is about the same cost as building a completely new one
-        if entries.len() <= self.len(table_id, self.get_root_page_number())? / 2 {
+        if self
+            .rebuild_len(entries.len(), self.get_root_page_number())?
+            .is_none()
+        {
             for (key, value) in entries.iter() {
                 self.insert::<K>(table_id, key, value)?;
             }
         } else {
-            let mut builder = BinarytreeBuilder::new();
+            let mut builder = BinarytreeBuilder::with_fill_factor(self.fill_factor);
             // Copy all the existing entries
             let mut tables_iter = BinarytreeRangeIter::<RangeFull, [u8]>::new(
                 self.get_root_page(),
@@ -107,8 +888,8 @@ impl Storage {
                 ..,
                 &self.mem,
             );
-            while let Some(table_entry) = tables_iter.next() {
-                let id = u64::from_be_bytes(table_entry.value().try_into().unwrap());
+            for table_entry in tables_iter.by_ref() {
+                let (id, _) = decode_table_entry(table_entry.value())?;
                 // Copy the table entry
                 builder.add(
                     table_entry.table_id(),
@@ -122,17 +903,21 @@ impl Storage {
                     ..,
                     &self.mem,
                 );
-                while let Some(x) = iter.next() {
+                for x in iter.by_ref() {
                     if table_id != x.table_id() || !entries.contains_key(x.key()) {
                         builder.add(x.table_id(), x.key(), x.value());
                     }
                 }
+                iter.finish()?;
             }
+            tables_iter.finish()?;
             for (key, value) in entries {
-                builder.add(table_id, &key, &value);
+                builder.add(table_id, key, value);
             }
 
-            let new_root = builder.build::<K>().to_bytes(&self.mem);
+            self.check_space(&builder)?;
+            self.release_tree()?;
+            let new_root = builder.build::<K>(table_id).to_bytes(&self.mem)?;
             self.set_root_page(Some(new_root));
         }
         Ok(())
@@ -150,17 +935,27 @@ impl Storage {
         while iter.next().is_some() {
             count += 1;
         }
+        iter.finish()?;
         Ok(count)
     }
 
+    pub(crate) fn estimate_len<T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
+        &self,
+        table: u64,
+        range: &T,
+        root_page: Option<u64>,
+    ) -> Result<usize, Error> {
+        estimate_range_len::<T, K, KR>(
+            root_page.map(|p| self.mem.get_page(p)),
+            table,
+            range,
+            &self.mem,
+        )
+    }
+
     pub(crate) fn get_root_page_number(&self) -> Option<u64> {
         let metapage = self.mem.get_page(DB_METADATA_PAGE);
-        let mmap = metapage.memory();
-        let root_page_number = u64::from_be_bytes(
-            mmap[ROOT_PAGE_OFFSET..(ROOT_PAGE_OFFSET + 8)]
-                .try_into()
-                .unwrap(),
-        );
+        let root_page_number = HeaderField::RootPage.read(metapage.memory());
         if root_page_number == 0 {
             None
         } else {
@@ -168,53 +963,342 @@ impl Storage {
         }
     }
 
-    fn get_root_page(&self) -> Option<Page> {
-        self.get_root_page_number().map(|p| self.mem.get_page(p))
+    // The root of the tree that changes apply to: the working root, if there is one
+    fn get_root_page(&self) -> Option<Page<'_>> {
+        let root = match self.working_root.get() {
+            Some(root) => root,
+            None => self.get_root_page_number(),
+        };
+        root.map(|p| self.mem.get_page(p))
     }
 
     fn set_root_page(&self, root_page: Option<u64>) {
+        if self.working_root.get().is_some() {
+            self.working_root.set(Some(root_page));
+            return;
+        }
+        self.set_committed_root(root_page, self.mem.take_superseded());
+    }
+
+    /// Makes `root_page` the root of the tree that readers see, without flushing it to disk.
+    /// `superseded` are the pages of the previous tree that the new one no longer uses, which
+    /// are freed once nothing can read the previous tree
+    pub(crate) fn set_committed_root(&self, root_page: Option<u64>, superseded: Vec<u64>) {
+        let version = self.current_version() + 1;
         let mut meta = self.mem.get_metapage_mut();
-        let mmap = meta.memory_mut();
-        mmap[ROOT_PAGE_OFFSET..(ROOT_PAGE_OFFSET + 8)]
-            .copy_from_slice(&root_page.unwrap_or(0).to_be_bytes());
+        HeaderField::RootPage.write(meta.memory_mut(), root_page.unwrap_or(0));
+        HeaderField::Version.write(meta.memory_mut(), version);
+        if !superseded.is_empty() {
+            self.pending.borrow_mut().push((version, superseded));
+        }
     }
 
-    pub(crate) fn fsync(&self) -> Result<(), Error> {
+    /// Returns the pages of the committed tree that the last scratch scope superseded.
+    /// See `PageManager::release`
+    pub(crate) fn take_superseded(&self) -> Vec<u64> {
+        self.mem.take_superseded()
+    }
+
+    /// Runs `f`, which changes the tree, on the tree at `root` instead of the committed one,
+    /// and returns the root of the changed tree. Nothing is committed: the tree only becomes
+    /// visible once it is passed to `set_committed_root`. Pages are reused as in `with_scratch`
+    pub(crate) fn with_working_root(
+        &self,
+        root: Option<u64>,
+        f: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<Option<u64>, Error> {
+        self.working_root.set(Some(root));
+        let result = self.with_scratch(f);
+        let root = self.working_root.take().unwrap();
+        result.map(|()| root)
+    }
+
+    /// Writes the allocator state to the metadata page, without flushing it to disk
+    pub(crate) fn store_state(&self) {
         let mut meta = self.mem.get_metapage_mut();
         let mmap = meta.memory_mut();
 
         self.mem.store_state(
             &mut mmap[ALLOCATOR_STATE_OFFSET..(ALLOCATOR_STATE_OFFSET + PageManager::state_size())],
         );
+        // The committed tree uses none of the pending pages either, and nothing else reads
+        // them once the database is reopened
+        let mut free_pages = self.mem.free_pages();
+        free_pages.extend(self.pending.borrow().iter().flat_map(|(_, pages)| pages));
+        free_pages.sort_unstable();
+        free_pages.truncate(free_list_capacity(self.mem.get_page_size()));
+        HeaderField::FreePageCount.write(mmap, free_pages.len() as u64);
+        for (i, page) in free_pages.iter().enumerate() {
+            let offset = FREE_LIST_OFFSET + i * 8;
+            mmap[offset..(offset + 8)].copy_from_slice(&page.to_be_bytes());
+        }
+    }
 
-        drop(meta);
+    pub(crate) fn fsync(&self) -> Result<(), Error> {
+        // a read-only database has nothing to flush
+        if self.mem.is_read_only() {
+            return Ok(());
+        }
+        self.store_state();
         self.mem.fsync()?;
+        self.flushes.set(self.flushes.get() + 1);
+        self.durable_version.set(self.current_version());
+        self.reclaim();
+        Ok(())
+    }
+
+    /// Makes a commit durable, unless it is part of a batch, which is flushed once it ends
+    pub(crate) fn fsync_commit(&self) -> Result<(), Error> {
+        if self.batch_depth.get() > 0 {
+            self.store_state();
+            Ok(())
+        } else {
+            self.fsync()
+        }
+    }
+
+    /// Takes the write lock, which is released by `end_write`
+    pub(crate) fn begin_write(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        if self.write_active.replace(true) {
+            return Err(Error::WriteConflict);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn end_write(&self) {
+        self.write_active.set(false);
+        self.reclaim();
+    }
+
+    /// Runs `f`, which changes the tree, reusing the pages of the intermediate trees that it
+    /// builds and then supersedes. See `PageManager::with_scratch`
+    pub(crate) fn with_scratch<T>(&self, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+        self.mem.with_scratch(f)
+    }
+
+    /// Begins a batch, which lasts until the returned scope is ended or dropped
+    pub(crate) fn begin_batch(&self) -> BatchScope<'_> {
+        self.batch_depth.set(self.batch_depth.get() + 1);
+        BatchScope {
+            storage: self,
+            ended: false,
+        }
+    }
+
+    // Flushes all the commits of the batch, once the outermost batch ends
+    fn end_batch(&self) -> Result<(), Error> {
+        self.batch_depth.set(self.batch_depth.get() - 1);
+        if self.batch_depth.get() == 0 {
+            self.fsync()?;
+        }
         Ok(())
     }
 
-    pub(crate) fn get<K: RadbKey + ?Sized>(
+    #[cfg(test)]
+    pub(crate) fn flush_count(&self) -> u64 {
+        self.flushes.get()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn node_cache_hits(&self) -> u64 {
+        self.mem.node_cache_hits()
+    }
+
+    pub(crate) fn verify(&self) -> VerifyReport {
+        let mut state = VerifyState::new(TABLE_TABLE_ID);
+        if let Some(root) = self.get_root_page_number() {
+            verify_tree(root, &self.mem, &mut state);
+        }
+        // a free page may be allocated again, so the tree must not use it
+        for page in self.mem.free_pages() {
+            if state.visited.contains(&page) {
+                state
+                    .errors
+                    .push(format!("free page {} is used by the tree", page));
+            }
+        }
+        // every page except the metadata page should be reachable, or free, but pages of
+        // old versions of the tree are only freed once nothing reads them
+        let allocated = self.mem.get_next_free_page() - 1;
+        VerifyReport {
+            pages_visited: state.visited.len() as u64,
+            unreachable_pages: allocated - state.visited.len() as u64,
+            errors: state.errors,
+        }
+    }
+
+    /// How many bytes of each page of the committed tree are used, see `page_utilization()`
+    pub(crate) fn page_utilization(&self) -> Result<Vec<PageUsage>, Error> {
+        let mut usage = vec![];
+        if let Some(root) = self.get_root_page() {
+            page_usage(root, &self.mem, &mut usage)?;
+        }
+        Ok(usage)
+    }
+
+    /// Renders the tree with the given root as text, see `dump_tree()`
+    pub(crate) fn dump_tree(&self, root_page_number: Option<u64>) -> Result<String, Error> {
+        let mut out = String::new();
+        match root_page_number {
+            Some(root) => dump_tree(self.mem.get_page(root), 0, &self.mem, &mut out)?,
+            None => out.push_str("empty\n"),
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn get<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
         &self,
         table_id: u64,
         key: &[u8],
         root_page_number: Option<u64>,
-    ) -> Result<Option<AccessGuard>, Error> {
+    ) -> Result<Option<AccessGuard<'_, V>>, Error> {
         if let Some(root_page) = root_page_number.map(|p| self.mem.get_page(p)) {
-            if let Some((page, offset, len)) =
-                lookup_in_raw::<K>(root_page, table_id, key, &self.mem)
+            if let Some((page, offset)) = lookup_in_raw::<K>(root_page, table_id, key, &self.mem)? {
+                let (_, (value_offset, value_len)) = entry_location(&page, offset);
+                return Ok(Some(AccessGuard::page_backed(
+                    page,
+                    value_offset,
+                    value_len,
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Looks up each of the keys, returning the values in the order of `keys`.
+    /// The keys are sorted first, so that the tree is walked once for all of them
+    pub(crate) fn get_multi<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        table_id: u64,
+        keys: &[&[u8]],
+        root_page_number: Option<u64>,
+    ) -> Result<Vec<Option<AccessGuard<'_, V>>>, Error> {
+        let root_page = match root_page_number {
+            Some(root_page) => self.mem.get_page(root_page),
+            None => return Ok(keys.iter().map(|_| None).collect()),
+        };
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|a, b| K::compare(keys[*a], keys[*b]));
+        let sorted: Vec<&[u8]> = order.iter().map(|i| keys[*i]).collect();
+        let mut found = Vec::with_capacity(keys.len());
+        lookup_many_raw::<K>(root_page, table_id, &sorted, &self.mem, &mut found)?;
+
+        let mut results: Vec<Option<AccessGuard<'_, V>>> = keys.iter().map(|_| None).collect();
+        for (i, location) in order.into_iter().zip(found) {
+            if let Some((page_number, offset)) = location {
+                let page = self.mem.get_page(page_number);
+                let (_, (value_offset, value_len)) = entry_location(&page, offset);
+                results[i] = Some(AccessGuard::page_backed(page, value_offset, value_len));
+            }
+        }
+        Ok(results)
+    }
+
+    /// Checks whether the table has an entry for the key, without reading its value
+    pub(crate) fn contains_key<K: RadbKey + ?Sized>(
+        &self,
+        table_id: u64,
+        key: &[u8],
+        root_page_number: Option<u64>,
+    ) -> Result<bool, Error> {
+        match root_page_number.map(|p| self.mem.get_page(p)) {
+            Some(root_page) => {
+                Ok(lookup_in_raw::<K>(root_page, table_id, key, &self.mem)?.is_some())
+            }
+            None => Ok(false),
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn get_key_value<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        table_id: u64,
+        key: &[u8],
+        root_page_number: Option<u64>,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        if let Some(root_page) = root_page_number.map(|p| self.mem.get_page(p)) {
+            if let Some((page, offset)) = lookup_in_raw::<K>(root_page, table_id, key, &self.mem)? {
+                return Ok(Some(self.entry_guards(page, offset)));
+            }
+        }
+        Ok(None)
+    }
+
+    // The entry with the largest key <= key, or with the smallest key >= key
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn get_nearest<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        table_id: u64,
+        key: &[u8],
+        before: bool,
+        root_page_number: Option<u64>,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        if let Some(root_page) = root_page_number.map(|p| self.mem.get_page(p)) {
+            if let Some((page, offset)) =
+                lookup_nearest_raw::<K>(root_page, table_id, key, before, &self.mem)?
             {
-                return Ok(Some(AccessGuard::PageBacked(page, offset, len)));
+                return Ok(Some(self.entry_guards(page, offset)));
             }
         }
         Ok(None)
     }
 
+    // The first entry of the table, or the last entry
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn get_edge<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        table_id: u64,
+        last: bool,
+        root_page_number: Option<u64>,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        if let Some(root_page) = root_page_number.map(|p| self.mem.get_page(p)) {
+            if let Some((page, offset)) = lookup_edge_raw(root_page, table_id, last, &self.mem)? {
+                return Ok(Some(self.entry_guards(page, offset)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Guards over the key and the value of the entry at the given page number and offset,
+    /// e.g. as returned by `BinarytreeRangeIter::next_location`
+    pub(crate) fn entry_at<K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &self,
+        (page_number, offset): (u64, usize),
+    ) -> (AccessGuard<'_, K>, AccessGuard<'_, V>) {
+        self.entry_guards(self.mem.get_page(page_number), offset)
+    }
+
+    /// The key of the entry at a location returned by `BinarytreeRangeIter::next_location`,
+    /// which unlike `entry_at` never locates the value
+    pub(crate) fn key_at(&self, (page_number, offset): (u64, usize)) -> &[u8] {
+        let page = self.mem.get_page(page_number);
+        let (key_offset, key_len) = key_location(&page, offset);
+        &page.into_memory()[key_offset..(key_offset + key_len)]
+    }
+
+    // Guards over the key and the value of the entry at offset in the page
+    fn entry_guards<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+        &'a self,
+        page: Page<'a>,
+        offset: usize,
+    ) -> (AccessGuard<'a, K>, AccessGuard<'a, V>) {
+        let ((key_offset, key_len), (value_offset, value_len)) = entry_location(&page, offset);
+        // each guard holds its own reference to the page
+        let key_page = self.mem.get_page(page.get_page_number());
+        (
+            AccessGuard::page_backed(key_page, key_offset, key_len),
+            AccessGuard::page_backed(page, value_offset, value_len),
+        )
+    }
+
     // Create an iterator over the given range
-    pub(crate) fn get_range<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized>(
+    pub(crate) fn get_range<'a, T: RangeBounds<KR>, K: RadbKey + ?Sized, KR: Borrow<[u8]>>(
         &'a self,
         table_id: u64,
         range: T,
         root_page: Option<u64>,
-    ) -> Result<BinarytreeRangeIter<T, K>, Error> {
+    ) -> Result<BinarytreeRangeIter<'a, T, K, KR>, Error> {
         Ok(BinarytreeRangeIter::new(
             root_page.map(|p| self.mem.get_page(p)),
             table_id,
@@ -222,12 +1306,33 @@ impl Storage {
             &self.mem,
         ))
     }
-    pub(crate) fn get_range_reversed<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized>(
+
+    // Create a cursor over the given range, which can move in both directions
+    pub(crate) fn get_range_cursor<
+        'a,
+        T: RangeBounds<KR>,
+        K: RadbKey + ?Sized,
+        KR: Borrow<[u8]>,
+    >(
+        &'a self,
+        table_id: u64,
+        range: T,
+        root_page: Option<u64>,
+    ) -> Result<RangeCursor<'a, T, K, KR>, Error> {
+        Ok(RangeCursor::new(root_page, table_id, range, &self.mem))
+    }
+
+    pub(crate) fn get_range_reversed<
+        'a,
+        T: RangeBounds<KR>,
+        K: RadbKey + ?Sized,
+        KR: Borrow<[u8]>,
+    >(
         &'a self,
         table_id: u64,
         range: T,
         root_page: Option<u64>,
-    ) -> Result<BinarytreeRangeIter<T, K>, Error> {
+    ) -> Result<BinarytreeRangeIter<'a, T, K, KR>, Error> {
         Ok(BinarytreeRangeIter::new_reversed(
             root_page.map(|p| self.mem.get_page(p)),
             table_id,
@@ -244,7 +1349,7 @@ impl Storage {
     ) -> Result<bool, Error> {
         if let Some(root_page) = self.get_root_page() {
             let old_root = root_page.get_page_number();
-            let new_root = tree_delete::<K>(root_page, table_id, key, &self.mem);
+            let new_root = tree_delete::<K>(root_page, table_id, key, &self.mem)?;
             self.set_root_page(new_root);
             return Ok(old_root == new_root.unwrap_or(0));
         }
@@ -252,17 +1357,197 @@ impl Storage {
     }
 }
 
-pub enum AccessGuard<'a> {
+/// A batch begun by `Storage::begin_batch`. Dropping it ends the batch too, e.g. when the
+/// closure of `Database::batch` panics, so that later commits are flushed again, but any
+/// error of the flush is then lost, as in `Database::drop`
+pub(crate) struct BatchScope<'a> {
+    storage: &'a Storage,
+    ended: bool,
+}
+
+impl<'a> BatchScope<'a> {
+    /// Ends the batch, flushing its commits if it is the outermost one
+    pub(crate) fn end(mut self) -> Result<(), Error> {
+        self.ended = true;
+        self.storage.end_batch()
+    }
+}
+
+impl<'a> Drop for BatchScope<'a> {
+    fn drop(&mut self) {
+        if !self.ended {
+            let _ = self.storage.end_batch();
+        }
+    }
+}
+
+/// Summary of a check of the database's consistency, returned by `Database::verify`
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// Number of pages reachable from the root of the tree
+    pub pages_visited: u64,
+    /// Number of allocated pages that are not reachable, such as free pages, and pages of old
+    /// versions of the tree
+    pub unreachable_pages: u64,
+    /// Description of each inconsistency found
+    pub errors: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Returns true if no inconsistency was found
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Space usage of the database, returned by `Database::stats`
+#[derive(Debug)]
+pub struct DbStats {
+    /// The next page to be allocated, once no free page is left
+    pub next_free_page: u64,
+    /// Number of pages below `next_free_page` that no version of the tree that can still be
+    /// read uses, which later commits reuse
+    pub free_pages: usize,
+    /// Size of a page, in bytes
+    pub page_size: usize,
+    /// Number of bytes of address space that the database is mapped into
+    pub mapped_bytes: usize,
+    /// Current length of the database file, in bytes
+    pub file_bytes: usize,
+    /// Number of tables
+    pub table_count: usize,
+    /// The name and number of live entries of each table
+    pub table_entries: Vec<(Vec<u8>, usize)>,
+    /// Number of levels of the tree that holds every table, along its deepest path, or 0 while
+    /// it is empty
+    pub tree_height: usize,
+}
+
+/// Whether `Database::compact_step` has more work to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactProgress {
+    /// More pages can be moved by another step
+    Pending,
+    /// The tree is as compact as a step can make it, while the current readers and checkpoints
+    /// are held
+    Done,
+}
+
+/// The kind of node that a page holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeType {
+    /// Holds up to two entries
+    Leaf,
+    /// Holds a separator key, and the page numbers of its two children
+    Internal,
+}
+
+/// How much of one page of the tree is used, returned by `Database::page_utilization`
+#[derive(Debug)]
+pub struct PageUsage {
+    pub page_number: u64,
+    pub node_type: NodeType,
+    /// Number of bytes that the node takes. The rest of the page is unused
+    pub used_bytes: usize,
+    /// Size of the page, in bytes
+    pub page_size: usize,
+}
+
+enum AccessGuardMemory<'a> {
     // Either a reference to the mmap or a reference to the local data in memory
     PageBacked(Page<'a>, usize, usize),
     Local(&'a [u8]),
+    // A value that was decoded, e.g. decompressed, or copied to outlive its transaction
+    Owned(Vec<u8>),
+}
+
+pub struct AccessGuard<'a, V: RadbValue + ?Sized = [u8]> {
+    memory: AccessGuardMemory<'a>,
+    // how far the value has been read through the `Read` impl
+    read_position: usize,
+    _value_type: PhantomData<V>,
+}
+
+impl<'a, V: RadbValue + ?Sized> AccessGuard<'a, V> {
+    pub(crate) fn page_backed(page: Page<'a>, offset: usize, len: usize) -> Self {
+        AccessGuard {
+            memory: AccessGuardMemory::PageBacked(page, offset, len),
+            read_position: 0,
+            _value_type: Default::default(),
+        }
+    }
+
+    pub(crate) fn local(data: &'a [u8]) -> Self {
+        AccessGuard {
+            memory: AccessGuardMemory::Local(data),
+            read_position: 0,
+            _value_type: Default::default(),
+        }
+    }
+
+    pub(crate) fn owned(data: Vec<u8>) -> Self {
+        AccessGuard {
+            memory: AccessGuardMemory::Owned(data),
+            read_position: 0,
+            _value_type: Default::default(),
+        }
+    }
+
+    /// Decodes the value
+    pub fn to_value(&self) -> <V::View as WithLifetime<'_>>::Out {
+        V::from_bytes(self.as_ref())
+    }
+
+    /// Copies the raw bytes of the value
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_ref().to_vec()
+    }
 }
 
-impl<'a> AsRef<[u8]> for AccessGuard<'a> {
+impl<'a, V: RadbValue + ?Sized> AsRef<[u8]> for AccessGuard<'a, V> {
     fn as_ref(&self) -> &[u8] {
-        match self {
-            AccessGuard::PageBacked(page, offset, len) => &page.memory()[*offset..(*offset + *len)],
-            AccessGuard::Local(data_ref) => data_ref,
+        match &self.memory {
+            AccessGuardMemory::PageBacked(page, offset, len) => {
+                &page.memory()[*offset..(*offset + *len)]
+            }
+            AccessGuardMemory::Local(data_ref) => data_ref,
+            AccessGuardMemory::Owned(data) => data,
+        }
+    }
+}
+
+impl<'a, V: RadbValue + ?Sized> Deref for AccessGuard<'a, V> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+/// Streams the value, e.g. into `std::io::copy`. Reading does not change what `as_ref`
+/// and `to_vec` return
+impl<'a, V: RadbValue + ?Sized> Read for AccessGuard<'a, V> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut remaining = &self.as_ref()[self.read_position..];
+        let len = remaining.read(buf)?;
+        self.read_position += len;
+        Ok(len)
+    }
+}
+
+// Number of leading bytes shown by the Debug impl
+const DEBUG_PREVIEW_LEN: usize = 16;
+
+impl<'a, V: RadbValue + ?Sized> Debug for AccessGuard<'a, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let data = self.as_ref();
+        write!(f, "AccessGuard {{ len: {}, data: ", data.len())?;
+        for byte in data.iter().take(DEBUG_PREVIEW_LEN) {
+            write!(f, "{:02x}", byte)?;
+        }
+        if data.len() > DEBUG_PREVIEW_LEN {
+            write!(f, "...")?;
         }
+        write!(f, " }}")
     }
 }
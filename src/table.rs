@@ -1,62 +1,1725 @@
+use crate::compression::Compression;
 use crate::error::Error;
 use crate::storage::Storage;
 use crate::transactions::WriteTransaction;
-use crate::types::RadbKey;
+use crate::types::{RadbKey, RadbValue};
 use crate::ReadOnlyTransaction;
 use std::marker::PhantomData;
 
-pub struct Table<'mmap, K: RadbKey + ?Sized> {
+pub struct Table<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized = [u8]> {
     storage: &'mmap Storage,
     table_id: u64,
+    compression: Compression,
     _key_type: PhantomData<K>,
+    _value_type: PhantomData<V>,
 }
 
-impl<'mmap, K: RadbKey + ?Sized> Table<'mmap, K> {
-    pub(crate) fn new(table_id: u64, storage: &'mmap Storage) -> Result<Table<'mmap, K>, Error> {
+/// A table handle can be cloned, and each clone used to start transactions. Writes are still
+/// exclusive: only one write transaction may be live per database, whichever handle began it
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Clone for Table<'mmap, K, V> {
+    fn clone(&self) -> Self {
+        Table {
+            storage: self.storage,
+            table_id: self.table_id,
+            compression: self.compression,
+            _key_type: Default::default(),
+            _value_type: Default::default(),
+        }
+    }
+}
+
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Table<'mmap, K, V> {
+    pub(crate) fn new(
+        table_id: u64,
+        storage: &'mmap Storage,
+        compression: Compression,
+    ) -> Result<Table<'mmap, K, V>, Error> {
         Ok(Table {
             storage,
             table_id,
+            compression,
             _key_type: Default::default(),
+            _value_type: Default::default(),
+        })
+    }
+
+    /// Only one write transaction may be live per database. Returns `Error::WriteConflict`
+    /// if another one has not yet been committed, aborted or dropped
+    pub fn begin_write(&self) -> Result<WriteTransaction<'mmap, K, V>, Error> {
+        self.storage.begin_write()?;
+        Ok(WriteTransaction::new(
+            self.table_id,
+            self.storage,
+            self.compression,
+        ))
+    }
+
+    /// Removes every entry of the table, and commits. The table stays open, and empty
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut write_txn = self.begin_write()?;
+        write_txn.clear()?;
+        write_txn.commit()
+    }
+
+    pub(crate) fn storage(&self) -> &'mmap Storage {
+        self.storage
+    }
+
+    pub(crate) fn table_id(&self) -> u64 {
+        self.table_id
+    }
+
+    pub fn read_transaction(&'_ self) -> Result<ReadOnlyTransaction<'mmap, K, V>, Error> {
+        Ok(ReadOnlyTransaction::new(
+            self.table_id,
+            self.storage,
+            self.compression,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binarytree::{value_reads, BinarytreeEntry, BinarytreeRangeIter};
+    use crate::types::{
+        AsBytesWithLifetime, RadbKey, RadbValue, RefAsBytesLifetime, RefLifetime, WithLifetime,
+    };
+    use crate::{Change, CompactProgress, Database, Error, Table};
+    use std::cmp::Ordering;
+    use std::ops::{Bound, RangeBounds};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn len() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        write_txn.insert(b"hello2", b"world2").unwrap();
+        write_txn.insert(b"hi", b"world").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 3);
+    }
+
+    #[test]
+    fn len_string_keys() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<String> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&"hello".to_string(), b"world").unwrap();
+        write_txn.insert(&"hello2".to_string(), b"world2").unwrap();
+        write_txn.insert(&"hi".to_string(), b"world").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 3);
+        assert_eq!(
+            b"world2",
+            read_txn
+                .get(&"hello2".to_string())
+                .unwrap()
+                .unwrap()
+                .as_ref()
+        );
+    }
+
+    #[test]
+    fn tuple_keys() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<(u64, String)> = db.open_table(b"x").unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&(1, "b".to_string()), b"1b").unwrap();
+        write_txn.insert(&(1, "a".to_string()), b"1a").unwrap();
+        write_txn.insert(&(2, "a".to_string()), b"2a").unwrap();
+        write_txn.commit().unwrap();
+        // a longer first component must still sort by value, not by its length prefix
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&(0, "zz".to_string()), b"0zz").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(
+            b"1a",
+            read_txn
+                .get(&(1, "a".to_string()))
+                .unwrap()
+                .unwrap()
+                .as_ref()
+        );
+        let mut iter = read_txn.get_range(..).unwrap();
+        for (id, name) in [(0, "zz"), (1, "a"), (1, "b"), (2, "a")] {
+            let entry = iter.next().unwrap();
+            assert_eq!(<(u64, String)>::from_bytes(entry.key()), (id, name));
+        }
+        assert!(iter.next().is_none());
+
+        let table: Table<(String, u64)> = db.open_table(b"y").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&("b".to_string(), 1), b"b1").unwrap();
+        write_txn.insert(&("ab".to_string(), 2), b"ab2").unwrap();
+        write_txn.insert(&("a".to_string(), 3), b"a3").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let mut iter = read_txn.get_range(..).unwrap();
+        for (name, id) in [("a", 3), ("ab", 2), ("b", 1)] {
+            let entry = iter.next().unwrap();
+            assert_eq!(<(String, u64)>::from_bytes(entry.key()), (name, id));
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn iter_key_value() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..50u8 {
+            write_txn.insert(&[i], &vec![i; i as usize]).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let start = [10u8];
+        let end = [40u8];
+        let mut iter = read_txn.get_range(&start[..]..&end[..]).unwrap();
+        let mut pairs = vec![];
+        while let Some((key, value)) = iter.next_key_value() {
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+        assert_eq!(pairs.len(), 30);
+        for (key, value) in pairs {
+            assert_eq!(value, read_txn.get(&key).unwrap().unwrap().to_vec());
+        }
+    }
+
+    #[test]
+    fn iter() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in [5u64, 1, 300, 2, 4] {
+            write_txn.insert(&i, b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let mut iter = read_txn.iter().unwrap();
+        for i in [1u64, 2, 4, 5, 300] {
+            let entry = iter.next().unwrap();
+            assert_eq!(u64::from_bytes(entry.key()), i);
+            assert_eq!(entry.value(), b"value");
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn owned_range() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..10u8 {
+            write_txn.insert(&[i], b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let mut iter = read_txn.get_range_owned(vec![3]..vec![7]).unwrap();
+        for i in 3..7u8 {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+
+        let mut iter = read_txn.get_range_owned(vec![8]..).unwrap();
+        for i in 8..10u8 {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn seek() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..100u64 {
+            write_txn.insert(&i, b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let mut iter = read_txn.iter().unwrap();
+        iter.seek(&50u64.to_be_bytes());
+        for i in 50..55u64 {
+            assert_eq!(u64::from_bytes(iter.next().unwrap().key()), i);
+        }
+        // seek backward, and to every position
+        for target in (0..100u64).rev() {
+            iter.seek(&target.to_be_bytes());
+            assert_eq!(u64::from_bytes(iter.next().unwrap().key()), target);
+        }
+        iter.seek(&99u64.to_be_bytes());
+        iter.next().unwrap();
+        assert!(iter.next().is_none());
+        iter.seek(&100u64.to_be_bytes());
+        assert!(iter.next().is_none());
+
+        // the range still applies after a seek
+        let start = 10u64.to_be_bytes();
+        let end = 20u64.to_be_bytes();
+        let mut iter = read_txn
+            .get_range(start.as_slice()..end.as_slice())
+            .unwrap();
+        iter.seek(&5u64.to_be_bytes());
+        assert_eq!(u64::from_bytes(iter.next().unwrap().key()), 10);
+        iter.seek(&19u64.to_be_bytes());
+        assert_eq!(u64::from_bytes(iter.next().unwrap().key()), 19);
+        assert!(iter.next().is_none());
+
+        let mut iter = read_txn
+            .get_range_reversed(start.as_slice()..end.as_slice())
+            .unwrap();
+        iter.seek(&15u64.to_be_bytes());
+        for i in (10..=15u64).rev() {
+            assert_eq!(u64::from_bytes(iter.next().unwrap().key()), i);
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn estimate_count() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..1000u64 {
+            write_txn.insert(&i, b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let estimate = read_txn.estimate_count(..).unwrap();
+        assert!((1000..=2000).contains(&estimate), "{}", estimate);
+
+        for (start, end) in [
+            (0u64, 1000u64),
+            (0, 10),
+            (5, 6),
+            (100, 900),
+            (333, 777),
+            (990, 2000),
+        ] {
+            let start = start.to_be_bytes();
+            let end = end.to_be_bytes();
+            let range = start.as_slice()..end.as_slice();
+            let mut exact = 0;
+            let mut iter = read_txn.get_range(range.clone()).unwrap();
+            while iter.next().is_some() {
+                exact += 1;
+            }
+            let estimate = read_txn.estimate_count(range).unwrap();
+            assert!(
+                exact <= estimate && estimate <= 2 * exact,
+                "{} {}",
+                exact,
+                estimate
+            );
+        }
+        let start = 500u64.to_be_bytes();
+        assert_eq!(
+            read_txn
+                .estimate_count(start.as_slice()..start.as_slice())
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn range_cursor() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..100u64 {
+            write_txn.insert(&i, b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let start = 10u64.to_be_bytes();
+        let end = 20u64.to_be_bytes();
+        let mut cursor = read_txn
+            .range_cursor(start.as_slice()..end.as_slice())
+            .unwrap();
+        assert!(cursor.prev().is_none());
+        let mut keys = vec![];
+        for step in [true, true, true, false, true, true, false, false, false] {
+            let entry = if step { cursor.next() } else { cursor.prev() };
+            keys.push(u64::from_bytes(entry.unwrap().key()));
+        }
+        assert_eq!(keys, [10, 11, 12, 11, 12, 13, 12, 11, 10]);
+        assert!(cursor.prev().is_none());
+        assert_eq!(u64::from_bytes(cursor.next().unwrap().key()), 10);
+
+        // scroll off the end of the range, and back
+        for i in 11..20u64 {
+            assert_eq!(u64::from_bytes(cursor.next().unwrap().key()), i);
+        }
+        assert!(cursor.next().is_none());
+        assert!(cursor.next().is_none());
+        assert_eq!(u64::from_bytes(cursor.prev().unwrap().key()), 19);
+        assert_eq!(u64::from_bytes(cursor.prev().unwrap().key()), 18);
+        assert_eq!(u64::from_bytes(cursor.next().unwrap().key()), 19);
+
+        // every entry is reachable by turning around at any position
+        let mut cursor = read_txn.range_cursor(..).unwrap();
+        for i in 0..99u64 {
+            assert_eq!(u64::from_bytes(cursor.next().unwrap().key()), i);
+            assert_eq!(u64::from_bytes(cursor.next().unwrap().key()), i + 1);
+            assert_eq!(u64::from_bytes(cursor.prev().unwrap().key()), i);
+        }
+    }
+
+    #[test]
+    fn node_cache() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        // insert one at a time, so that the tree is deep rather than built balanced
+        for i in 0..200u64 {
+            let mut write_txn = table.begin_write().unwrap();
+            write_txn.insert(&i, b"value").unwrap();
+            write_txn.commit().unwrap();
+        }
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"value", read_txn.get(&100).unwrap().unwrap().as_ref());
+        let hits = db.node_cache_hits();
+        for _ in 0..10 {
+            assert_eq!(b"value", read_txn.get(&100).unwrap().unwrap().as_ref());
+        }
+        // every internal node on the path is cached after the first lookup
+        assert!(db.node_cache_hits() >= hits + 10 * 2);
+
+        // a commit overwrites no cached page, and lookups see its changes
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&100, b"changed").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"changed", read_txn.get(&100).unwrap().unwrap().as_ref());
+    }
+
+    #[test]
+    fn node_cache_compaction() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let mut db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        for i in 0..100u64 {
+            let mut write_txn = table.begin_write().unwrap();
+            write_txn.insert(&i, &i.to_be_bytes()).unwrap();
+            write_txn.commit().unwrap();
+        }
+        let read_txn = table.read_transaction().unwrap();
+        for i in 0..100u64 {
+            read_txn.get(&i).unwrap().unwrap();
+        }
+        drop(read_txn);
+
+        // compaction overwrites the pages of the cached nodes
+        db.compact_and_shrink().unwrap();
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        for i in 0..100u64 {
+            assert_eq!(
+                &i.to_be_bytes(),
+                read_txn.get(&i).unwrap().unwrap().as_ref()
+            );
+        }
+    }
+
+    #[test]
+    fn batch() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let flushes = db.flush_count();
+        db.batch(|| {
+            for i in 0..50u64 {
+                let mut write_txn = table.begin_write()?;
+                write_txn.insert(&i, b"value")?;
+                write_txn.commit()?;
+            }
+            Ok(())
         })
+        .unwrap();
+        assert_eq!(db.flush_count(), flushes + 1);
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 50);
+
+        // without a batch, every commit is flushed
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&50, b"value").unwrap();
+        write_txn.commit().unwrap();
+        assert_eq!(db.flush_count(), flushes + 2);
+
+        // a batch that panics ends too, and is flushed
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.batch(|| -> Result<(), Error> {
+                let mut write_txn = table.begin_write()?;
+                write_txn.insert(&51, b"value")?;
+                write_txn.commit()?;
+                panic!("in a batch")
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(db.flush_count(), flushes + 3);
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&52, b"value").unwrap();
+        write_txn.commit().unwrap();
+        assert_eq!(db.flush_count(), flushes + 4);
+    }
+
+    #[test]
+    fn compact_steps_resume() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        for i in 0..200u64 {
+            let mut write_txn = table.begin_write().unwrap();
+            write_txn.insert(&i, b"value").unwrap();
+            write_txn.commit().unwrap();
+        }
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..150u64 {
+            write_txn.remove(&i).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        // the first step finds the pages, and flushes before and after moving them
+        let flushes = db.flush_count();
+        assert_eq!(db.compact_step(8).unwrap(), CompactProgress::Pending);
+        assert_eq!(db.flush_count(), flushes + 2);
+        // the next ones pick up where it stopped, and only flush the moved pages
+        let flushes = db.flush_count();
+        assert_eq!(db.compact_step(8).unwrap(), CompactProgress::Pending);
+        assert_eq!(db.flush_count(), flushes + 1);
+
+        // a commit in between starts over
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&1000, b"value").unwrap();
+        write_txn.commit().unwrap();
+        let flushes = db.flush_count();
+        db.compact_step(8).unwrap();
+        assert_eq!(db.flush_count(), flushes + 2);
+        db.compact().unwrap();
+        assert!(db.verify().unwrap().is_ok());
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 51);
+        for i in 150..200u64 {
+            assert_eq!(b"value", read_txn.get(&i).unwrap().unwrap().as_ref());
+        }
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn check_compression(compression: crate::Compression) {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let options = crate::TableOptions { compression };
+        let table: Table<u64> = db.open_table_with_options(b"x", options).unwrap();
+        let value = "{\"name\": \"radarbase\", \"kind\": \"json\"}".repeat(40);
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..10u64 {
+            write_txn.insert(&i, value.as_bytes()).unwrap();
+        }
+        // incompressible values are stored as is
+        write_txn.insert(&10, b"x").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        for i in 0..10u64 {
+            assert_eq!(
+                value.as_bytes(),
+                read_txn.get(&i).unwrap().unwrap().as_ref()
+            );
+        }
+        assert_eq!(b"x", read_txn.get(&10).unwrap().unwrap().as_ref());
+
+        let mut stored = 0;
+        let iter = read_txn.iter().unwrap();
+        for entry in iter {
+            stored += entry.value().len();
+        }
+        assert!(stored < 10 * value.len());
+
+        // a table opened without options is read with the compression it was created with
+        let existing: Table<u64> = db.open_existing_table(b"x").unwrap();
+        let read_txn = existing.read_transaction().unwrap();
+        assert_eq!(
+            value.as_bytes(),
+            read_txn.get(&3).unwrap().unwrap().as_ref()
+        );
+
+        // and so is a checkpoint of it
+        let checkpoint = db.checkpoint();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&3, b"changed").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = db.read_at::<u64, [u8]>(b"x", checkpoint).unwrap();
+        assert_eq!(
+            value.as_bytes(),
+            read_txn.get(&3).unwrap().unwrap().as_ref()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn lz4_compression() {
+        check_compression(crate::Compression::Lz4);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn zstd_compression() {
+        check_compression(crate::Compression::Zstd);
+    }
+
+    #[test]
+    fn update() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+
+        // append to a list, which is created on first use
+        let append = |item: u8| {
+            move |current: Option<&[u8]>| {
+                let mut list = current.map(|x| x.to_vec()).unwrap_or_default();
+                list.push(item);
+                Some(list)
+            }
+        };
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.update(b"list", append(1)).unwrap();
+        write_txn.update(b"list", append(2)).unwrap();
+        write_txn.commit().unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.update(b"list", append(3)).unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(&[1, 2, 3], read_txn.get(b"list").unwrap().unwrap().as_ref());
+
+        // pop from the list, and delete it once it is empty
+        let pop = |current: Option<&[u8]>| {
+            let mut list = current.unwrap().to_vec();
+            list.pop();
+            if list.is_empty() {
+                None
+            } else {
+                Some(list)
+            }
+        };
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.update(b"list", pop).unwrap();
+        write_txn.update(b"list", pop).unwrap();
+        assert_eq!(&[1], write_txn.get(b"list").unwrap().unwrap().as_ref());
+        write_txn.update(b"list", pop).unwrap();
+        // the removal is seen by a later update in the same transaction
+        write_txn
+            .update(b"list", |current| {
+                assert!(current.is_none());
+                None
+            })
+            .unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert!(read_txn.get(b"list").unwrap().is_none());
+        assert!(read_txn.is_empty().unwrap());
+    }
+
+    #[test]
+    fn append_value() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.append_value(b"log", b"first,").unwrap();
+        write_txn.append_value(b"log", b"second,").unwrap();
+        write_txn.append_value(b"log", b"third").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(
+            b"first,second,third",
+            read_txn.get(b"log").unwrap().unwrap().as_ref()
+        );
+        drop(read_txn);
+
+        // appends to the committed value, but not to one that is staged for removal
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.append_value(b"log", b",fourth").unwrap();
+        assert_eq!(
+            b"first,second,third,fourth",
+            write_txn.get(b"log").unwrap().unwrap().as_ref()
+        );
+        write_txn.remove(b"log").unwrap();
+        write_txn.append_value(b"log", b"fifth").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"fifth", read_txn.get(b"log").unwrap().unwrap().as_ref());
+        drop(read_txn);
+
+        // the combined value must still fit into a page
+        let mut write_txn = table.begin_write().unwrap();
+        let half = vec![0; db.usable_leaf_capacity() / 2];
+        write_txn.append_value(b"big", &half).unwrap();
+        assert!(matches!(
+            write_txn.append_value(b"big", &half),
+            Err(Error::ValueTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn reserve_many() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&3, b"old").unwrap();
+        let keys: Vec<(Vec<u8>, usize)> = (0..10u64)
+            .map(|i| (i.to_be_bytes().to_vec(), i as usize))
+            .collect();
+        let buffers = write_txn.reserve_many(&keys).unwrap();
+        assert_eq!(buffers.len(), 10);
+        for (i, buffer) in buffers.into_iter().enumerate() {
+            assert_eq!(buffer.len(), i);
+            buffer.fill(i as u8);
+        }
+
+        // nothing is reserved if any key is invalid
+        let duplicates = vec![(vec![20], 1), (vec![21], 1), (vec![20], 1)];
+        assert!(matches!(
+            write_txn.reserve_many(&duplicates),
+            Err(Error::Io(_))
+        ));
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 10);
+        for i in 0..10u64 {
+            let value = read_txn.get(&i).unwrap().unwrap();
+            assert_eq!(value.as_ref(), vec![i as u8; i as usize].as_slice());
+        }
+    }
+
+    #[test]
+    fn rename_key() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"a", b"1").unwrap();
+        write_txn.insert(b"c", b"3").unwrap();
+        assert!(write_txn.rename_key(b"a", b"b").unwrap());
+        assert!(!write_txn.rename_key(b"a", b"d").unwrap());
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert!(read_txn.get(b"a").unwrap().is_none());
+        assert_eq!(b"1", read_txn.get(b"b").unwrap().unwrap().as_ref());
+
+        // a committed value, over an existing key
+        let mut write_txn = table.begin_write().unwrap();
+        assert!(write_txn.rename_key(b"b", b"c").unwrap());
+        assert!(write_txn.rename_key(b"c", b"c").unwrap());
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert!(read_txn.get(b"b").unwrap().is_none());
+        assert_eq!(b"1", read_txn.get(b"c").unwrap().unwrap().as_ref());
+        assert_eq!(read_txn.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn clone_read_transaction() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let clone = read_txn.clone();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"changed").unwrap();
+        write_txn.insert(b"hello2", b"world2").unwrap();
+        write_txn.commit().unwrap();
+
+        for txn in [&read_txn, &clone] {
+            assert_eq!(b"world", txn.get(b"hello").unwrap().unwrap().as_ref());
+            assert!(txn.get(b"hello2").unwrap().is_none());
+            assert_eq!(txn.len().unwrap(), 1);
+        }
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(
+            b"changed",
+            read_txn.get(b"hello").unwrap().unwrap().as_ref()
+        );
+    }
+
+    #[test]
+    fn merge_join() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let names: Table<u64, str> = db.open_table(b"names").unwrap();
+        let ages: Table<u64, u64> = db.open_table(b"ages").unwrap();
+        let mut write_txn = names.begin_write().unwrap();
+        for (id, name) in [(1, "a"), (2, "b"), (4, "d"), (6, "f"), (7, "g")] {
+            write_txn.insert(&id, name).unwrap();
+        }
+        write_txn.commit().unwrap();
+        let mut write_txn = ages.begin_write().unwrap();
+        for id in [0u64, 2, 3, 4, 7, 8] {
+            write_txn.insert(&id, &(id * 10)).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let names_txn = names.read_transaction().unwrap();
+        let ages_txn = ages.read_transaction().unwrap();
+        let joined: Vec<(u64, String, u64)> = names_txn
+            .merge_join(&ages_txn)
+            .unwrap()
+            .map(|(id, name, age)| (id.to_value(), name.to_value().to_string(), age.to_value()))
+            .collect();
+        assert_eq!(
+            joined,
+            vec![
+                (2, "b".to_string(), 20),
+                (4, "d".to_string(), 40),
+                (7, "g".to_string(), 70)
+            ]
+        );
+
+        let empty: Table<u64, u64> = db.open_table(b"empty").unwrap();
+        let empty_txn = empty.read_transaction().unwrap();
+        assert_eq!(names_txn.merge_join(&empty_txn).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn preview() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..4u64 {
+            write_txn.insert(&i, &[i as u8]).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&10, b"new").unwrap();
+        write_txn.insert(&1, b"changed").unwrap();
+        write_txn.remove(&2).unwrap();
+        write_txn.remove(&20).unwrap();
+        write_txn.insert(&3, b"staged").unwrap();
+        write_txn.remove(&3).unwrap();
+        let key = |i: u64| i.to_be_bytes().to_vec();
+        assert_eq!(
+            write_txn.preview().unwrap(),
+            vec![
+                (key(1), Change::Overwrite(vec![1])),
+                (key(2), Change::Remove(vec![2])),
+                (key(3), Change::Remove(vec![3])),
+                (key(10), Change::Insert),
+            ]
+        );
+
+        write_txn.clear().unwrap();
+        write_txn.insert(&0, b"kept").unwrap();
+        assert_eq!(
+            write_txn.preview().unwrap(),
+            vec![
+                (key(0), Change::Overwrite(vec![0])),
+                (key(1), Change::Remove(vec![1])),
+                (key(2), Change::Remove(vec![2])),
+                (key(3), Change::Remove(vec![3])),
+            ]
+        );
+        write_txn.abort().unwrap();
+        assert!(table.begin_write().unwrap().preview().unwrap().is_empty());
+    }
+
+    #[test]
+    fn get_owned() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8], str> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"committed", "hello").unwrap();
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"staged", "world").unwrap();
+        let staged = write_txn.get_owned(b"staged").unwrap().unwrap();
+        let committed = write_txn.get_owned(b"committed").unwrap().unwrap();
+        assert!(write_txn.get_owned(b"missing").unwrap().is_none());
+        write_txn.abort().unwrap();
+
+        assert_eq!(staged.to_value(), "world");
+        assert_eq!(committed.to_value(), "hello");
+        assert_eq!(b"world", staged.as_ref());
+    }
+
+    #[test]
+    fn contains_key() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"committed", b"1").unwrap();
+        write_txn.insert(b"removed", b"2").unwrap();
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"staged", b"3").unwrap();
+        write_txn.remove(b"removed").unwrap();
+        assert!(write_txn.contains_key(b"committed").unwrap());
+        assert!(write_txn.contains_key(b"staged").unwrap());
+        assert!(!write_txn.contains_key(b"removed").unwrap());
+        assert!(!write_txn.contains_key(b"missing").unwrap());
+        write_txn.commit().unwrap();
+
+        let write_txn = table.begin_write().unwrap();
+        assert!(write_txn.contains_key(b"staged").unwrap());
+        assert!(!write_txn.contains_key(b"removed").unwrap());
+    }
+
+    #[test]
+    fn clear() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let other: Table<[u8]> = db.open_table(b"y").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..100u64 {
+            write_txn.insert(&i.to_be_bytes(), b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+        let mut write_txn = other.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        write_txn.commit().unwrap();
+
+        table.clear().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 0);
+        assert!(read_txn.get(&0u64.to_be_bytes()).unwrap().is_none());
+        let read_txn = other.read_transaction().unwrap();
+        assert_eq!(b"world", read_txn.get(b"hello").unwrap().unwrap().as_ref());
+
+        // entries staged after the truncation are kept
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"before", b"1").unwrap();
+        write_txn.clear().unwrap();
+        assert!(write_txn.get(b"before").unwrap().is_none());
+        write_txn.insert(b"after", b"2").unwrap();
+        write_txn.commit().unwrap();
+
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 1);
+        assert_eq!(b"2", read_txn.get(b"after").unwrap().unwrap().as_ref());
+        assert!(db.list_tables().unwrap().contains(&b"x".to_vec()));
+
+        // clearing a small table copies the paths to its entries, not the whole tree
+        let large: Table<u64> = db.open_table(b"z").unwrap();
+        let mut write_txn = large.begin_write().unwrap();
+        for i in 0..1000u64 {
+            write_txn.insert(&i, b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+        let pages = db.stats().unwrap().next_free_page;
+        other.clear().unwrap();
+        assert!(db.stats().unwrap().next_free_page < pages + 50);
+        assert!(other.read_transaction().unwrap().is_empty().unwrap());
+        assert_eq!(large.read_transaction().unwrap().len().unwrap(), 1000);
+        assert_eq!(table.read_transaction().unwrap().len().unwrap(), 1);
+        assert!(db.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn get_mut() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"committed", b"hello").unwrap();
+        write_txn.insert(b"removed", b"hello").unwrap();
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"staged", b"world").unwrap();
+        write_txn.get_mut(b"staged").unwrap().unwrap()[0] = b'W';
+        write_txn.get_mut(b"committed").unwrap().unwrap()[0] = b'H';
+        // reads within the transaction see the change
+        assert_eq!(
+            b"Hello",
+            write_txn.get(b"committed").unwrap().unwrap().as_ref()
+        );
+        write_txn.remove(b"removed").unwrap();
+        assert!(write_txn.get_mut(b"removed").unwrap().is_none());
+        assert!(write_txn.get_mut(b"missing").unwrap().is_none());
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"World", read_txn.get(b"staged").unwrap().unwrap().as_ref());
+        assert_eq!(
+            b"Hello",
+            read_txn.get(b"committed").unwrap().unwrap().as_ref()
+        );
+        assert!(read_txn.get(b"removed").unwrap().is_none());
+        assert!(read_txn.get(b"missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn typed_values() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<String, u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&"hello".to_string(), &1).unwrap();
+        write_txn.insert(&"hi".to_string(), &u64::MAX).unwrap();
+        assert_eq!(
+            1,
+            write_txn
+                .get(&"hello".to_string())
+                .unwrap()
+                .unwrap()
+                .to_value()
+        );
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(
+            1,
+            read_txn
+                .get(&"hello".to_string())
+                .unwrap()
+                .unwrap()
+                .to_value()
+        );
+        assert_eq!(
+            u64::MAX,
+            read_txn.get(&"hi".to_string()).unwrap().unwrap().to_value()
+        );
+        assert!(read_txn.get(&"missing".to_string()).unwrap().is_none());
+
+        let table: Table<u64, String> = db.open_table(b"y").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(&1, &"world".to_string()).unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!("world", read_txn.get(&1).unwrap().unwrap().to_value());
+    }
+
+    #[test]
+    fn access_guard() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        write_txn.insert(b"big", &[0xab; 100]).unwrap();
+        // local data, not yet committed
+        let guard = write_txn.get(b"hello").unwrap().unwrap();
+        assert_eq!(guard.len(), 5);
+        assert_eq!(&guard[1..3], b"or");
+        drop(guard);
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let guard = read_txn.get(b"hello").unwrap().unwrap();
+        assert_eq!(guard.len(), 5);
+        assert_eq!(guard[0], b'w');
+        assert_eq!(&guard[1..3], b"or");
+        assert_eq!(guard.to_vec(), b"world".to_vec());
+        assert_eq!(
+            format!("{:?}", guard),
+            "AccessGuard { len: 5, data: 776f726c64 }"
+        );
+
+        let guard = read_txn.get(b"big").unwrap().unwrap();
+        assert_eq!(guard.to_vec(), vec![0xab; 100]);
+        assert_eq!(
+            format!("{:?}", guard),
+            format!("AccessGuard {{ len: 100, data: {}... }}", "ab".repeat(16))
+        );
+    }
+
+    #[test]
+    fn get_key_value() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<str, [u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert("hello", b"world").unwrap();
+        write_txn.insert("hi", b"there").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let (key, value) = read_txn.get_key_value("hello").unwrap().unwrap();
+        assert_eq!(key.as_ref(), b"hello");
+        assert_eq!(key.to_value(), "hello");
+        assert_eq!(value.as_ref(), b"world");
+        assert!(read_txn.get_key_value("help").unwrap().is_none());
+    }
+
+    #[test]
+    fn floor_ceiling() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for key in [10u64, 20, 30] {
+            write_txn.insert(&key, &key.to_be_bytes()).unwrap();
+        }
+        write_txn.commit().unwrap();
+        // entries of the neighbouring tables must not be returned
+        for name in [b"w", b"y"] {
+            let other: Table<u64> = db.open_table(name).unwrap();
+            let mut write_txn = other.begin_write().unwrap();
+            for key in [0u64, 25, 100] {
+                write_txn.insert(&key, b"other").unwrap();
+            }
+            write_txn.commit().unwrap();
+        }
+
+        let read_txn = table.read_transaction().unwrap();
+        let (key, value) = read_txn.floor(&25).unwrap().unwrap();
+        assert_eq!(key.to_value(), 20);
+        assert_eq!(value.as_ref(), 20u64.to_be_bytes());
+        assert_eq!(read_txn.ceiling(&25).unwrap().unwrap().0.to_value(), 30);
+        assert_eq!(read_txn.floor(&20).unwrap().unwrap().0.to_value(), 20);
+        assert_eq!(read_txn.ceiling(&20).unwrap().unwrap().0.to_value(), 20);
+        assert_eq!(read_txn.ceiling(&5).unwrap().unwrap().0.to_value(), 10);
+        assert_eq!(read_txn.floor(&35).unwrap().unwrap().0.to_value(), 30);
+        assert!(read_txn.floor(&5).unwrap().is_none());
+        assert!(read_txn.ceiling(&35).unwrap().is_none());
+        drop((key, value));
+
+        // a larger tree, written by several commits
+        let table: Table<u64> = db.open_table(b"z").unwrap();
+        for chunk in 0..4u64 {
+            let mut write_txn = table.begin_write().unwrap();
+            for key in (chunk..100).step_by(4) {
+                write_txn.insert(&(key * 10), b"value").unwrap();
+            }
+            write_txn.commit().unwrap();
+        }
+        let read_txn = table.read_transaction().unwrap();
+        for query in 0..=990u64 {
+            let floor = read_txn.floor(&query).unwrap().unwrap().0.to_value();
+            assert_eq!(floor, query / 10 * 10);
+            let ceiling = read_txn.ceiling(&query).unwrap().unwrap().0.to_value();
+            assert_eq!(ceiling, query.div_ceil(10) * 10);
+        }
+        assert!(read_txn.ceiling(&991).unwrap().is_none());
+    }
+
+    #[test]
+    fn first_last() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert!(read_txn.first().unwrap().is_none());
+        assert!(read_txn.last().unwrap().is_none());
+
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..10u64 {
+            write_txn.insert(&i, &i.to_be_bytes()).unwrap();
+        }
+        write_txn.commit().unwrap();
+        // entries of the neighbouring tables must not be returned
+        for name in [b"w", b"y"] {
+            let other: Table<u64> = db.open_table(name).unwrap();
+            let mut write_txn = other.begin_write().unwrap();
+            write_txn.insert(&100, b"other").unwrap();
+            write_txn.commit().unwrap();
+        }
+
+        let read_txn = table.read_transaction().unwrap();
+        let (key, value) = read_txn.first().unwrap().unwrap();
+        assert_eq!(key.to_value(), 0);
+        assert_eq!(value.as_ref(), 0u64.to_be_bytes());
+        let (key, value) = read_txn.last().unwrap().unwrap();
+        assert_eq!(key.to_value(), 9);
+        assert_eq!(value.as_ref(), 9u64.to_be_bytes());
+        drop(read_txn);
+
+        // a deeper tree, whose edges are trimmed one commit at a time. It is grown by commits
+        // that are small compared to it, so that they insert into the tree rather than
+        // rebuild it, in a scattered order, so that the inserts spread over the whole tree
+        for chunk in (0..500u64).collect::<Vec<u64>>().chunks(5) {
+            let mut write_txn = table.begin_write().unwrap();
+            for i in chunk {
+                write_txn.insert(&(i * 211 % 500 + 10), b"deep").unwrap();
+            }
+            write_txn.commit().unwrap();
+        }
+        for i in 0..20u64 {
+            let read_txn = table.read_transaction().unwrap();
+            assert_eq!(read_txn.first().unwrap().unwrap().0.to_value(), i);
+            assert_eq!(read_txn.last().unwrap().unwrap().0.to_value(), 509 - i);
+            drop(read_txn);
+            let mut write_txn = table.begin_write().unwrap();
+            write_txn.remove(&i).unwrap();
+            write_txn.remove(&(509 - i)).unwrap();
+            write_txn.commit().unwrap();
+        }
+    }
+
+    #[test]
+    fn table_not_found() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        match db.open_existing_table::<[u8], [u8]>(b"x") {
+            Err(Error::TableNotFound(name)) => assert_eq!(name, "x"),
+            _ => panic!("expected TableNotFound"),
+        }
+
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        write_txn.commit().unwrap();
+
+        let table: Table<[u8]> = db.open_existing_table(b"x").unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"world", read_txn.get(b"hello").unwrap().unwrap().as_ref());
+    }
+
+    #[test]
+    fn drop_without_commit() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        write_txn.commit().unwrap();
+        let version = db.current_version();
+        let next_free_page = db.stats().unwrap().next_free_page;
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world2").unwrap();
+        write_txn.insert(b"hello2", b"world2").unwrap();
+        write_txn.remove(b"hello").unwrap();
+        drop(write_txn);
+
+        assert_eq!(db.current_version(), version);
+        assert_eq!(db.stats().unwrap().next_free_page, next_free_page);
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"world", read_txn.get(b"hello").unwrap().unwrap().as_ref());
+        assert!(read_txn.get(b"hello2").unwrap().is_none());
+        assert_eq!(read_txn.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn count_range() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..100u8 {
+            write_txn.insert(&[i], &[i; 50]).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let reads = value_reads();
+        let (ten, twenty) = ([10u8], [20u8]);
+        assert_eq!(read_txn.count_range(&ten[..]..&twenty[..]).unwrap(), 10);
+        assert_eq!(read_txn.count_range(&ten[..]..=&twenty[..]).unwrap(), 11);
+        let exclusive = (Bound::Excluded(&ten[..]), Bound::Excluded(&twenty[..]));
+        assert_eq!(read_txn.count_range(exclusive).unwrap(), 9);
+        assert_eq!(read_txn.count_range(..&ten[..]).unwrap(), 10);
+        assert_eq!(read_txn.count_range(&twenty[..]..).unwrap(), 80);
+        assert_eq!(read_txn.count_range(..).unwrap(), 100);
+        assert_eq!(read_txn.count_range(&twenty[..]..&ten[..]).unwrap(), 0);
+        assert_eq!(value_reads(), reads);
+    }
+
+    #[test]
+    fn keys_only() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64, [u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..10u64 {
+            write_txn.insert(&i, &[i as u8; 100]).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let reads = value_reads();
+        let mut iter = read_txn.keys(..).unwrap();
+        let keys: Vec<u64> = iter
+            .by_ref()
+            .map(|key| u64::from_be_bytes(key.try_into().unwrap()))
+            .collect();
+        assert!(iter.take_error().is_none());
+        assert_eq!(keys, (0..10).collect::<Vec<u64>>());
+        let seven = 7u64.to_be_bytes();
+        let keys: Vec<&[u8]> = read_txn.keys(seven.as_slice()..).unwrap().collect();
+        assert_eq!(keys, [7u64, 8, 9].map(|i| i.to_be_bytes()));
+        assert_eq!(value_reads(), reads);
+
+        // whereas the entries themselves locate each value
+        for _ in &read_txn {}
+        assert_eq!(value_reads(), reads + 10);
+    }
+
+    #[test]
+    fn remove_reports_presence() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"committed", b"value").unwrap();
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"staged", b"value").unwrap();
+        assert!(write_txn.remove(b"committed").unwrap());
+        assert!(write_txn.remove(b"staged").unwrap());
+        assert!(!write_txn.remove(b"absent").unwrap());
+        // reads within the transaction see the removals
+        assert!(write_txn.get(b"committed").unwrap().is_none());
+        assert!(write_txn.get(b"staged").unwrap().is_none());
+        // already removed in this transaction
+        assert!(!write_txn.remove(b"committed").unwrap());
+        assert!(!write_txn.remove(b"staged").unwrap());
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        assert!(!write_txn.remove(b"committed").unwrap());
+        write_txn.clear().unwrap();
+        write_txn.insert(b"staged", b"value").unwrap();
+        assert!(write_txn.remove(b"staged").unwrap());
+        write_txn.abort().unwrap();
+    }
+
+    #[test]
+    fn clear_in_transaction() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for key in [b"a", b"b", b"c"] {
+            write_txn.insert(key, b"committed").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"d", b"staged").unwrap();
+        write_txn.clear().unwrap();
+        write_txn.insert(b"e", b"kept").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 1);
+        assert_eq!(read_txn.get(b"e").unwrap().unwrap().as_ref(), b"kept");
+    }
+
+    #[test]
+    fn savepoints() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..10 {
+            write_txn.insert(&i, &i).unwrap();
+        }
+        let first = write_txn.savepoint();
+        for i in 10..20 {
+            write_txn.insert(&i, &i).unwrap();
+        }
+        write_txn.remove(&0).unwrap();
+        write_txn.rollback_to(first).unwrap();
+        assert_eq!(write_txn.len().unwrap(), 10);
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 10);
+        assert_eq!(read_txn.get(&0).unwrap().unwrap().to_value(), 0);
+        assert!(read_txn.get(&10).unwrap().is_none());
+        drop(read_txn);
+
+        // nested savepoints, across a flush
+        let mut write_txn = table.begin_write().unwrap();
+        let outer = write_txn.savepoint();
+        write_txn.insert(&0, &100).unwrap();
+        write_txn.flush_staged().unwrap();
+        let inner = write_txn.savepoint();
+        write_txn.clear().unwrap();
+        let innermost = write_txn.savepoint();
+        write_txn.rollback_to(inner).unwrap();
+        assert_eq!(write_txn.len().unwrap(), 10);
+        assert_eq!(write_txn.get(&0).unwrap().unwrap().to_value(), 100);
+        // rolling back to `inner` invalidated the newer savepoint
+        assert!(matches!(
+            write_txn.rollback_to(innermost),
+            Err(Error::InvalidSavepoint)
+        ));
+        write_txn.rollback_to(outer).unwrap();
+        assert_eq!(write_txn.get(&0).unwrap().unwrap().to_value(), 0);
+
+        // a savepoint of another transaction is rejected
+        write_txn.insert(&1, &101).unwrap();
+        write_txn.commit().unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        let foreign = write_txn.savepoint();
+        write_txn.abort().unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        assert!(matches!(
+            write_txn.rollback_to(foreign),
+            Err(Error::InvalidSavepoint)
+        ));
+        drop(write_txn);
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 10);
+        assert_eq!(read_txn.get(&0).unwrap().unwrap().to_value(), 0);
+        assert_eq!(read_txn.get(&1).unwrap().unwrap().to_value(), 101);
+    }
+
+    #[test]
+    fn replace() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        assert_eq!(write_txn.replace(b"hello", b"world").unwrap(), None);
+        // overwrite a value staged by the same transaction
+        assert_eq!(
+            write_txn.replace(b"hello", b"world2").unwrap(),
+            Some(b"world".to_vec())
+        );
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        // overwrite a committed value
+        assert_eq!(
+            write_txn.replace(b"hello", b"world3").unwrap(),
+            Some(b"world2".to_vec())
+        );
+        write_txn.remove(b"hello").unwrap();
+        assert_eq!(write_txn.replace(b"hello", b"world4").unwrap(), None);
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.get(b"hello").unwrap().unwrap().as_ref(), b"world4");
     }
 
-    pub fn begin_write(&'_ mut self) -> Result<WriteTransaction<'mmap, K>, Error> {
-        Ok(WriteTransaction::new(self.table_id, self.storage))
+    #[test]
+    fn entry_api() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"committed", &[1]).unwrap();
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        // insert if absent
+        assert_eq!(
+            write_txn.entry(b"new").unwrap().or_insert(&[1]).unwrap(),
+            [1]
+        );
+        assert_eq!(
+            write_txn.entry(b"new").unwrap().or_insert(&[2]).unwrap(),
+            [1]
+        );
+        assert_eq!(
+            write_txn
+                .entry(b"committed")
+                .unwrap()
+                .or_insert(&[2])
+                .unwrap(),
+            [1]
+        );
+        let lazy = write_txn
+            .entry(b"committed")
+            .unwrap()
+            .or_insert_with(|| panic!("the key has a value"))
+            .unwrap();
+        assert_eq!(lazy, [1]);
+        assert_eq!(
+            write_txn
+                .entry(b"lazy")
+                .unwrap()
+                .or_insert_with(|| vec![3])
+                .unwrap(),
+            [3]
+        );
+
+        // modify existing, both committed and staged, counting from 1
+        for key in [b"committed".as_slice(), b"new", b"counter", b"counter"] {
+            write_txn
+                .entry(key)
+                .unwrap()
+                .and_modify(|value| value[0] += 1)
+                .unwrap()
+                .or_insert(&[1])
+                .unwrap();
+        }
+        write_txn.remove(b"lazy").unwrap();
+        assert!(matches!(
+            write_txn.entry(b"lazy").unwrap(),
+            crate::Entry::Vacant(_)
+        ));
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 3);
+        for (key, value) in [(b"committed".as_slice(), 2), (b"new", 2), (b"counter", 2)] {
+            assert_eq!(read_txn.get(key).unwrap().unwrap().as_ref(), [value]);
+        }
     }
 
-    pub fn read_transaction(&'_ self) -> Result<ReadOnlyTransaction<'mmap, K>, Error> {
-        Ok(ReadOnlyTransaction::new(self.table_id, self.storage))
+    #[test]
+    fn pop_first_and_last() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let entry = |i: u64| (i.to_be_bytes().to_vec(), vec![i as u8]);
+        let mut write_txn = table.begin_write().unwrap();
+        for i in [10u64, 20, 30, 40] {
+            write_txn.insert(&i, &[i as u8]).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        assert_eq!(write_txn.pop_first().unwrap(), Some(entry(10)));
+        // a staged key before the committed ones is popped first
+        write_txn.insert(&5, &[5]).unwrap();
+        assert_eq!(write_txn.pop_first().unwrap(), Some(entry(5)));
+        assert_eq!(write_txn.pop_first().unwrap(), Some(entry(20)));
+        write_txn.insert(&50, &[50]).unwrap();
+        assert_eq!(write_txn.pop_last().unwrap(), Some(entry(50)));
+        assert_eq!(write_txn.pop_last().unwrap(), Some(entry(40)));
+        // a staged overwrite of a committed key is popped with its staged value
+        write_txn.insert(&30, &[3]).unwrap();
+        assert_eq!(
+            write_txn.pop_last().unwrap(),
+            Some((30u64.to_be_bytes().to_vec(), vec![3]))
+        );
+        assert_eq!(write_txn.pop_first().unwrap(), None);
+        assert_eq!(write_txn.pop_last().unwrap(), None);
+        write_txn.insert(&60, &[60]).unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 1);
+        assert_eq!(read_txn.get(&60).unwrap().unwrap().as_ref(), [60]);
+        drop(read_txn);
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.clear().unwrap();
+        assert_eq!(write_txn.pop_first().unwrap(), None);
+        write_txn.abort().unwrap();
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::binarytree::BinarytreeEntry;
-    use crate::types::{RadbKey, RefLifetime, WithLifetime};
-    use crate::{Database, Table};
-    use std::cmp::Ordering;
-    use tempfile::NamedTempFile;
+    #[test]
+    fn write_transaction_len() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        assert!(write_txn.is_empty().unwrap());
+        write_txn.insert(b"a", b"1").unwrap();
+        write_txn.insert(b"b", b"1").unwrap();
+        write_txn.insert(b"c", b"1").unwrap();
+        assert_eq!(write_txn.len().unwrap(), 3);
+        write_txn.commit().unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        assert_eq!(write_txn.len().unwrap(), 3);
+        // overwriting a committed key, or a staged one, adds nothing
+        write_txn.insert(b"a", b"2").unwrap();
+        write_txn.insert(b"d", b"1").unwrap();
+        write_txn.insert(b"d", b"2").unwrap();
+        assert_eq!(write_txn.len().unwrap(), 4);
+        // removing an absent key removes nothing
+        write_txn.remove(b"b").unwrap();
+        write_txn.remove(b"d").unwrap();
+        write_txn.remove(b"missing").unwrap();
+        assert_eq!(write_txn.len().unwrap(), 2);
+        // nor does removing a key twice, and a removed key can be inserted again
+        write_txn.remove(b"b").unwrap();
+        write_txn.insert(b"b", b"2").unwrap();
+        assert_eq!(write_txn.len().unwrap(), 3);
+        write_txn.flush_staged().unwrap();
+        write_txn.insert(b"e", b"1").unwrap();
+        assert_eq!(write_txn.len().unwrap(), 4);
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 4);
+        drop(read_txn);
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.clear().unwrap();
+        assert!(write_txn.is_empty().unwrap());
+        write_txn.insert(b"a", b"3").unwrap();
+        assert_eq!(write_txn.len().unwrap(), 1);
+        write_txn.abort().unwrap();
+    }
 
     #[test]
-    fn len() {
+    fn write_conflict() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table2: Table<[u8]> = db.open_table(b"y").unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        assert!(matches!(table2.begin_write(), Err(Error::WriteConflict)));
+        write_txn.commit().unwrap();
+
+        // the lock is released on commit, and on abort
+        let write_txn = table2.begin_write().unwrap();
+        assert!(matches!(table.begin_write(), Err(Error::WriteConflict)));
+        write_txn.abort().unwrap();
+        let write_txn = table.begin_write().unwrap();
+        drop(write_txn);
+        table2.begin_write().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(b"world", read_txn.get(b"hello").unwrap().unwrap().as_ref());
+    }
+
+    #[test]
+    fn version() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+
+        let before = table.read_transaction().unwrap();
+        assert_eq!(before.version(), db.current_version());
         let mut write_txn = table.begin_write().unwrap();
         write_txn.insert(b"hello", b"world").unwrap();
-        write_txn.insert(b"hello2", b"world2").unwrap();
-        write_txn.insert(b"hi", b"world").unwrap();
+        // uncommitted writes are not a new version
+        assert_eq!(before.version(), db.current_version());
+        write_txn.commit().unwrap();
+
+        let after = table.read_transaction().unwrap();
+        assert_ne!(before.version(), after.version());
+        assert_eq!(after.version(), db.current_version());
+        // the earlier transaction stays pinned to its snapshot
+        assert!(before.get(b"hello").unwrap().is_none());
+        assert_eq!(b"world", after.get(b"hello").unwrap().unwrap().as_ref());
+
+        // reads do not change the version
+        let again = table.read_transaction().unwrap();
+        assert_eq!(after.version(), again.version());
+        drop((before, after, again));
+
+        // every commit makes a greater version, even once the pages of the old versions are
+        // reused, so a released checkpoint's version never names another state
+        let checkpoint = db.checkpoint();
+        assert!(db.release_checkpoint(checkpoint));
+        for i in 0..50u8 {
+            let mut write_txn = table.begin_write().unwrap();
+            write_txn.insert(b"hello", &[i]).unwrap();
+            write_txn.commit().unwrap();
+        }
+        assert_eq!(db.current_version(), checkpoint + 50);
+        assert!(matches!(
+            db.read_at::<[u8], [u8]>(b"x", checkpoint),
+            Err(Error::CheckpointNotFound(_))
+        ));
+        let version = db.current_version();
+        drop(db);
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        assert_eq!(db.current_version(), version);
+    }
+
+    #[test]
+    fn entry_too_large() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        let big = vec![0u8; page_size::get()];
+        assert!(matches!(
+            write_txn.insert(&big, b"value"),
+            Err(Error::KeyTooLarge(len)) if len == big.len()
+        ));
+        assert!(matches!(
+            write_txn.insert(b"hello", &big),
+            Err(Error::ValueTooLarge(len)) if len == big.len()
+        ));
+        assert!(write_txn.get(b"hello").unwrap().is_none());
+    }
+
+    #[derive(crate::RadbKey)]
+    struct IndexKey {
+        user_id: u64,
+        ts: u64,
+    }
+
+    #[test]
+    fn derived_key() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<IndexKey> = db.open_table(b"x").unwrap();
+
+        let mut write_txn = table.begin_write().unwrap();
+        for (user_id, ts) in [(2, 1), (1, 300), (300, 0), (1, 2), (2, 0)] {
+            write_txn
+                .insert(&IndexKey { user_id, ts }, b"value")
+                .unwrap();
+        }
         write_txn.commit().unwrap();
+
         let read_txn = table.read_transaction().unwrap();
-        assert_eq!(read_txn.len().unwrap(), 3);
+        let mut iter = read_txn.get_range(..).unwrap();
+        for expected in [(1, 2), (1, 300), (2, 0), (2, 1), (300, 0)] {
+            let entry = iter.next().unwrap();
+            let key = IndexKey::from_bytes(entry.key());
+            assert_eq!((key.user_id, key.ts), expected);
+        }
+        assert!(iter.next().is_none());
+
+        let start = IndexKey { user_id: 2, ts: 0 }.as_bytes();
+        let end = IndexKey {
+            user_id: 2,
+            ts: u64::MAX,
+        }
+        .as_bytes();
+        let mut iter = read_txn
+            .get_range(start.as_slice()..=end.as_slice())
+            .unwrap();
+        for expected in [0, 1] {
+            let entry = iter.next().unwrap();
+            assert_eq!(IndexKey::from_bytes(entry.key()).ts, expected);
+        }
+        assert!(iter.next().is_none());
     }
 
     #[test]
     fn multiple_tables() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"1").unwrap();
-        let mut table2: Table<[u8]> = db.open_table(b"2").unwrap();
+        let table: Table<[u8]> = db.open_table(b"1").unwrap();
+        let table2: Table<[u8]> = db.open_table(b"2").unwrap();
 
         let mut write_txn = table.begin_write().unwrap();
         write_txn.insert(b"hello", b"world").unwrap();
@@ -80,7 +1743,7 @@ mod test {
     fn is_empty() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
         let read_txn = table.read_transaction().unwrap();
         assert!(read_txn.is_empty().unwrap());
         let mut write_txn = table.begin_write().unwrap();
@@ -94,7 +1757,7 @@ mod test {
     fn abort() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
         let read_txn = table.read_transaction().unwrap();
         assert!(read_txn.is_empty().unwrap());
         let mut write_txn = table.begin_write().unwrap();
@@ -118,7 +1781,7 @@ mod test {
     fn insert_overwrite() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
         let mut write_txn = table.begin_write().unwrap();
         write_txn.insert(b"hello", b"world").unwrap();
         write_txn.commit().unwrap();
@@ -139,7 +1802,7 @@ mod test {
     fn insert_reserve() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
         let mut write_txn = table.begin_write().unwrap();
         let value = b"world";
         let reserved = write_txn.insert_reserve(b"hello", value.len()).unwrap();
@@ -153,7 +1816,7 @@ mod test {
     fn delete() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         let mut write_txn = table.begin_write().unwrap();
         write_txn.insert(b"hello", b"world").unwrap();
@@ -184,7 +1847,7 @@ mod test {
 
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         let mut write_txn = table.begin_write().unwrap();
         write_txn.insert(b"hello", b"world").unwrap();
@@ -207,7 +1870,7 @@ mod test {
         // the version that was current when they started.
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         // first write transaction
         let mut write_txn = table.begin_write().unwrap();
@@ -249,7 +1912,7 @@ mod test {
     fn read_isolation2() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         // write transaction 1
         let mut write_txn = table.begin_write().unwrap();
@@ -296,7 +1959,7 @@ mod test {
         // TODO: Support read isolation in updating
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         // first write transaction - create complex tree
         let mut write_txn = table.begin_write().unwrap();
@@ -343,7 +2006,7 @@ mod test {
     fn range_query() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         let mut write_txn = table.begin_write().unwrap();
         for i in 0..10u8 {
@@ -369,7 +2032,7 @@ mod test {
     fn range_query_reversed() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
         let mut write_txn = table.begin_write().unwrap();
         for i in 0..10u8 {
@@ -391,28 +2054,31 @@ mod test {
         assert!(iter.next().is_none());
     }
 
-    #[test]
-    fn custom_ordering() {
-        struct ReverseKey(Vec<u8>);
-        impl RadbKey for ReverseKey {
-            type View = RefLifetime<[u8]>;
+    struct ReverseKey(Vec<u8>);
+    impl RadbValue for ReverseKey {
+        type View = RefLifetime<[u8]>;
+        type ToBytes = RefAsBytesLifetime<[u8]>;
 
-            fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime>::Out {
-                data
-            }
+        fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
+            data
+        }
 
-            fn as_bytes(&self) -> &[u8] {
-                &self.0
-            }
+        fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
+            &self.0
+        }
+    }
 
-            fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
-                data2.cmp(data1)
-            }
+    impl RadbKey for ReverseKey {
+        fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+            data2.cmp(data1)
         }
+    }
 
+    #[test]
+    fn custom_ordering() {
         let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
         let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-        let mut table: Table<ReverseKey> = db.open_table(b"x").unwrap();
+        let table: Table<ReverseKey> = db.open_table(b"x").unwrap();
 
         let mut write_txn = table.begin_write().unwrap();
         for i in 0..10u8 {
@@ -434,4 +2100,322 @@ mod test {
         }
         assert!(iter.next().is_none());
     }
+
+    #[test]
+    fn custom_ordering_floor_ceiling() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<ReverseKey> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in [2u8, 4, 6] {
+            write_txn.insert(&ReverseKey(vec![i]), b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        // ReverseKey is used, so the floor of 5 is the next larger byte
+        let read_txn = table.read_transaction().unwrap();
+        let (key, _) = read_txn.floor(&ReverseKey(vec![5])).unwrap().unwrap();
+        assert_eq!(key.as_ref(), &[6]);
+        let (key, _) = read_txn.ceiling(&ReverseKey(vec![5])).unwrap().unwrap();
+        assert_eq!(key.as_ref(), &[4]);
+        assert!(read_txn.floor(&ReverseKey(vec![7])).unwrap().is_none());
+        assert!(read_txn.ceiling(&ReverseKey(vec![1])).unwrap().is_none());
+    }
+
+    #[test]
+    fn custom_ordering_reversed() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<ReverseKey> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..10u8 {
+            write_txn.insert(&ReverseKey(vec![i]), b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+        // entries of a neighbouring table must not be mistaken for the end of the range
+        let other: Table<[u8]> = db.open_table(b"y").unwrap();
+        let mut write_txn = other.begin_write().unwrap();
+        for i in 0..10u8 {
+            write_txn.insert(&[i], b"other").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        let start = vec![7u8]; // ReverseKey is used, so 7 < 3
+        let end = vec![3u8];
+        let mut iter = read_txn
+            .get_range_reversed(start.as_slice()..=end.as_slice())
+            .unwrap();
+        for i in 3..=7u8 {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+
+        let mut iter = read_txn
+            .get_range_reversed(start.as_slice()..end.as_slice())
+            .unwrap();
+        for i in 4..=7u8 {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+
+        let mut iter = read_txn.get_range_reversed(..end.as_slice()).unwrap();
+        for i in 4..10u8 {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+
+        let mut iter = read_txn.get_range_reversed(start.as_slice()..).unwrap();
+        for i in 0..=7u8 {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+
+        // and the same ranges forward
+        let mut iter = read_txn.get_range(..end.as_slice()).unwrap();
+        for i in (4..10u8).rev() {
+            assert_eq!(&[i], iter.next().unwrap().key());
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn unbounded_ranges() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        // tables on both sides of the one that is scanned
+        let mut tables: Vec<Table<[u8]>> = vec![];
+        for name in [b"a", b"b", b"c"] {
+            let table: Table<[u8]> = db.open_table(name).unwrap();
+            let mut write_txn = table.begin_write().unwrap();
+            for i in 0..20u8 {
+                write_txn.insert(&[i], name).unwrap();
+            }
+            write_txn.commit().unwrap();
+            tables.push(table);
+        }
+
+        let read_txn = tables[1].read_transaction().unwrap();
+        fn collect<'a, T: RangeBounds<&'a [u8]>>(
+            iter: BinarytreeRangeIter<'a, T, [u8]>,
+        ) -> Vec<u8> {
+            let mut keys = vec![];
+            for entry in iter {
+                assert_eq!(entry.value(), b"b");
+                keys.push(entry.key()[0]);
+            }
+            keys
+        }
+        let start = vec![5u8];
+        let end = vec![15u8];
+        assert_eq!(
+            collect(read_txn.get_range(..).unwrap()),
+            (0..20).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(read_txn.get_range(start.as_slice()..).unwrap()),
+            (5..20).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(read_txn.get_range(..end.as_slice()).unwrap()),
+            (0..15).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(read_txn.get_range_reversed(..).unwrap()),
+            (0..20).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(read_txn.get_range_reversed(start.as_slice()..).unwrap()),
+            (5..20).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(read_txn.get_range_reversed(..=end.as_slice()).unwrap()),
+            (0..=15).rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ranges_at_table_boundaries() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        // every table has the same keys, including the smallest and a large one, so that the
+        // ends of each table sit right next to the same key bytes in its neighbours
+        let keys: [&[u8]; 5] = [b"", b"a", b"hello", b"hello\0", b"\xff"];
+        let mut tables: Vec<Table<[u8]>> = vec![];
+        for name in [b"1", b"2", b"3"] {
+            let table: Table<[u8]> = db.open_table(name).unwrap();
+            let mut write_txn = table.begin_write().unwrap();
+            for key in keys {
+                write_txn.insert(key, name).unwrap();
+            }
+            write_txn.commit().unwrap();
+            tables.push(table);
+        }
+
+        let read_txn = tables[1].read_transaction().unwrap();
+        fn collect<'a, T: RangeBounds<&'a [u8]>>(
+            iter: BinarytreeRangeIter<'a, T, [u8]>,
+        ) -> Vec<Vec<u8>> {
+            let mut found = vec![];
+            for entry in iter {
+                assert_eq!(entry.value(), b"2");
+                found.push(entry.key().to_vec());
+            }
+            found
+        }
+        let bounds = |key: &'static [u8]| [Bound::Included(key), Bound::Excluded(key)];
+        let mut all_bounds = vec![Bound::Unbounded];
+        for key in keys.iter().chain([&&b"b"[..], &&b"\xff\xff"[..]]) {
+            all_bounds.extend(bounds(key));
+        }
+        for start in all_bounds.iter() {
+            for end in all_bounds.iter() {
+                let range = (*start, *end);
+                let expected: Vec<Vec<u8>> = keys
+                    .iter()
+                    .filter(|key| range.contains(*key))
+                    .map(|key| key.to_vec())
+                    .collect();
+                assert_eq!(collect(read_txn.get_range(range).unwrap()), expected);
+                let reversed: Vec<Vec<u8>> = expected.into_iter().rev().collect();
+                assert_eq!(
+                    collect(read_txn.get_range_reversed(range).unwrap()),
+                    reversed,
+                    "{:?}",
+                    range
+                );
+            }
+        }
+
+        // seeking past either end of the table does not land in a neighbouring table
+        let mut iter = read_txn.get_range(..).unwrap();
+        iter.seek(b"\xff\xff");
+        assert!(iter.next().is_none());
+        let mut iter = read_txn.get_range_reversed(..).unwrap();
+        iter.seek(b"");
+        assert_eq!(iter.next().unwrap().key(), b"");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn readers_pin_their_version() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for i in 0..100u64 {
+            write_txn.insert(&i, &i).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let old = table.read_transaction().unwrap();
+        let version = old.version();
+        let clone = old.clone();
+        assert_eq!(db.reader_count(version), 2);
+
+        // overwrite every entry, one commit at a time, which supersedes every page of the
+        // version that the old readers hold
+        for i in 0..100u64 {
+            let mut write_txn = table.begin_write().unwrap();
+            write_txn.insert(&i, &(i + 1000)).unwrap();
+            write_txn.remove(&((i + 50) % 100)).unwrap();
+            write_txn.commit().unwrap();
+        }
+        assert_ne!(db.current_version(), version);
+        assert_eq!(db.reader_count(version), 2);
+        for i in 0..100u64 {
+            assert_eq!(old.get(&i).unwrap().unwrap().to_value(), i);
+        }
+        assert_eq!(old.len().unwrap(), 100);
+
+        drop(old);
+        assert_eq!(db.reader_count(version), 1);
+        assert_eq!(clone.get(&7).unwrap().unwrap().to_value(), 7);
+        drop(clone);
+        assert_eq!(db.reader_count(version), 0);
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(db.reader_count(read_txn.version()), 1);
+        assert_eq!(read_txn.len().unwrap(), 50);
+    }
+
+    #[test]
+    fn dump_tree() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let empty = table.read_transaction().unwrap().dump_tree().unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        for key in [&b"apple"[..], b"banana", b"cherry\n"] {
+            write_txn.insert(key, b"value").unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        let dump = table.read_transaction().unwrap().dump_tree().unwrap();
+        // only the table's name is in the tree before the commit
+        assert!(empty.starts_with("LEAF page "));
+        assert!(empty.ends_with(": (table 0, key \"x\", 9 byte value)\n"));
+        // the table's name, in the catalog, and its three entries make two full leaves
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("INTERNAL page "));
+        assert!(lines[0].ends_with("table 1, key \"apple\""));
+        assert!(lines[1].starts_with("  LEAF page "));
+        assert!(lines[1].contains("(table 0, key \"x\", 9 byte value)"));
+        assert!(lines[1].contains("(table 1, key \"apple\", 5 byte value)"));
+        assert!(lines[2].starts_with("  LEAF page "));
+        assert!(lines[2].contains("(table 1, key \"banana\", 5 byte value)"));
+        // keys are escaped, so each node stays on one line
+        assert!(lines[2].contains("(table 1, key \"cherry\\n\", 5 byte value)"));
+    }
+
+    #[test]
+    fn cloned_table() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let clone = table.clone();
+        let mut write_txn = clone.begin_write().unwrap();
+        write_txn.insert(b"hello", b"world").unwrap();
+        // the clones share the database's single writer
+        assert!(matches!(table.begin_write(), Err(Error::WriteConflict)));
+        write_txn.commit().unwrap();
+
+        for handle in [&table, &clone] {
+            let read_txn = handle.read_transaction().unwrap();
+            assert_eq!(read_txn.get(b"hello").unwrap().unwrap().as_ref(), b"world");
+        }
+        table.clear().unwrap();
+        assert!(clone.read_transaction().unwrap().is_empty().unwrap());
+    }
+
+    #[test]
+    fn empty_keys_and_values() {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<[u8]> = db.open_table(b"").unwrap();
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.insert(b"", b"value").unwrap();
+        write_txn.insert(b"key", b"").unwrap();
+        write_txn.commit().unwrap();
+
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.get(b"").unwrap().unwrap().as_ref(), b"value");
+        assert!(read_txn.get(b"key").unwrap().unwrap().is_empty());
+        // the table's empty name and its empty key share the first leaf
+        let mut iter = read_txn.get_range(..).unwrap();
+        assert_eq!(iter.next().unwrap().key(), b"");
+        assert_eq!(iter.next().unwrap().key(), b"key");
+        assert!(iter.next().is_none());
+        drop(iter);
+        drop(read_txn);
+        assert_eq!(db.list_tables().unwrap(), vec![Vec::<u8>::new()]);
+        assert!(db.verify().unwrap().is_ok());
+
+        let mut write_txn = table.begin_write().unwrap();
+        write_txn.remove(b"").unwrap();
+        write_txn.commit().unwrap();
+        let read_txn = table.read_transaction().unwrap();
+        assert!(read_txn.get(b"").unwrap().is_none());
+        assert_eq!(read_txn.len().unwrap(), 1);
+    }
 }
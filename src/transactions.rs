@@ -1,117 +1,1572 @@
-use crate::binarytree::BinarytreeRangeIter;
-use crate::error::Error;
+use crate::binarytree::{
+    max_entry_len, BinarytreeEntry, BinarytreeRangeIter, EntryAccessor, RangeCursor,
+};
+use crate::compression::{compress, decompress, overhead, Compression};
+use crate::error::{Corruption, Error};
 use crate::storage::{AccessGuard, Storage};
-use crate::types::RadbKey;
+use crate::types::{RadbKey, RadbValue, WithLifetime};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Write};
 use std::marker::PhantomData;
-use std::ops::RangeBounds;
+use std::ops::{Bound, RangeBounds, RangeFrom, RangeFull, RangeTo};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 
-pub struct WriteTransaction<'mmap, K: RadbKey + ?Sized> {
+// Export format is:
+// * (8 bytes) EXPORT_MAGIC
+// * for each entry: (1 byte) EXPORT_ENTRY, (8 bytes) key_len, key, (8 bytes) value_len, value
+// * (1 byte) EXPORT_END, then (8 bytes) the number of entries
+// all lengths are 64-bit big-endian unsigned. The end marker and count make a truncated
+// export detectable
+const EXPORT_MAGIC: [u8; 8] = *b"radbexp1";
+const EXPORT_ENTRY: u8 = 1;
+const EXPORT_END: u8 = 0;
+
+// Savepoint ids are unique across transactions, so that a savepoint can't be rolled back to
+// in a transaction other than the one that took it
+static NEXT_SAVEPOINT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Commits are flushed to disk before `commit` returns
+    Immediate,
+    /// Commits are only written to the mmap, and reach the disk when the OS writes them back,
+    /// or when `Database::flush` is called. Faster, but a crash may lose recent commits.
+    /// The pages of the versions that the commits replace are only reused once they are flushed
+    Eventual,
+}
+
+/// How committing a transaction changes one key, as reported by `WriteTransaction::preview`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The key has no committed value, and is inserted
+    Insert,
+    /// The key's committed value, which is replaced by the staged value
+    Overwrite(Vec<u8>),
+    /// The key's committed value, which is removed
+    Remove(Vec<u8>),
+}
+
+/// The keys that a transaction changes on commit, in key order
+pub type ChangeSet = Vec<(Vec<u8>, Change)>;
+
+/// The staged changes of a `WriteTransaction` at some point, which `rollback_to` restores
+pub struct Savepoint {
+    id: u64,
+    added: HashMap<Vec<u8>, Vec<u8>>,
+    removed: HashSet<Vec<u8>>,
+    truncated: bool,
+    flushed: Option<(Option<u64>, Option<u64>)>,
+    superseded: usize,
+}
+
+/// A set of changes to a table, which are staged in memory and only applied to the database
+/// by `commit`. Dropping the transaction without committing it discards the changes, like
+/// `abort`, and leaves the database untouched. `commit` and `abort` consume the transaction,
+/// so it can never be committed twice, or committed after being aborted
+pub struct WriteTransaction<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized = [u8]> {
     storage: &'mmap Storage,
     table_id: u64,
+    compression: Compression,
+    durability: Durability,
     added: HashMap<Vec<u8>, Vec<u8>>,
     removed: HashSet<Vec<u8>>,
+    // whether every committed entry is removed on commit, before the staged changes are applied
+    truncated: bool,
+    // Once `flush_staged` has applied changes, the committed root that they were applied to,
+    // and the root of the resulting tree, which is private to the transaction until commit
+    flushed: Option<(Option<u64>, Option<u64>)>,
+    // The pages of the committed tree, and of the trees that `flush_staged` built, that the
+    // flushed changes no longer use. They are freed once the transaction commits
+    superseded: Vec<u64>,
+    // ids of the savepoints that can still be rolled back to, oldest first
+    savepoints: Vec<u64>,
     _key_type: PhantomData<K>,
+    _value_type: PhantomData<V>,
 }
 
-impl<'mmap, K: RadbKey + ?Sized> WriteTransaction<'mmap, K> {
-    pub(crate) fn new(table_id: u64, storage: &'mmap Storage) -> WriteTransaction<'mmap, K> {
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> WriteTransaction<'mmap, K, V> {
+    pub(crate) fn new(
+        table_id: u64,
+        storage: &'mmap Storage,
+        compression: Compression,
+    ) -> WriteTransaction<'mmap, K, V> {
         WriteTransaction {
             storage,
             table_id,
+            compression,
+            durability: Durability::Immediate,
             added: HashMap::new(),
             removed: HashSet::new(),
+            truncated: false,
+            flushed: None,
+            superseded: vec![],
+            savepoints: vec![],
             _key_type: Default::default(),
+            _value_type: Default::default(),
         }
     }
 
-    pub fn insert(&mut self, key: &K, value: &[u8]) -> Result<(), Error> {
-        self.removed.remove(key.as_bytes());
-        self.added.insert(key.as_bytes().to_vec(), value.to_vec());
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        check_entry_len(
+            self.storage,
+            key.as_bytes().as_ref(),
+            value.as_bytes().as_ref().len() + overhead(self.compression),
+        )?;
+        self.removed.remove(key.as_bytes().as_ref());
+        self.added.insert(
+            key.as_bytes().as_ref().to_vec(),
+            value.as_bytes().as_ref().to_vec(),
+        );
         Ok(())
     }
 
+    /// Inserts like `insert`, and returns the value that `key` had before, staged by this
+    /// transaction or committed
+    pub fn replace(&mut self, key: &K, value: &V) -> Result<Option<Vec<u8>>, Error> {
+        let key_bytes = key.as_bytes();
+        let key_bytes = key_bytes.as_ref();
+        let previous = if let Some(previous) = self.added.get(key_bytes) {
+            Some(previous.clone())
+        } else if self.removed.contains(key_bytes) {
+            None
+        } else {
+            self.get(key)?.map(|previous| previous.to_vec())
+        };
+        self.insert(key, value)?;
+        Ok(previous)
+    }
+
     /// change the in-memory (mmap) data structure
     pub fn commit(self) -> Result<(), Error> {
-        self.storage.bulk_insert::<K>(self.table_id, self.added)?;
-        for key in self.removed.iter() {
-            self.storage.remove::<K>(self.table_id, key)?;
+        self.commit_with(|| Ok(()))
+    }
+
+    /// Commits like `commit`, and applies the changes of `f` to other tables in the same
+    /// commit. `f` runs after the staged changes are applied, on the same uncommitted tree,
+    /// so either all of the changes are committed, or none are
+    pub(crate) fn commit_with(
+        mut self,
+        f: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let root = match self.flushed {
+            Some((base, root)) => {
+                // the flushed tree was built on the committed tree, so it would discard
+                // anything committed since, such as a table created by `open_table`
+                if self.storage.get_root_page_number() != base {
+                    return Err(Error::WriteConflict);
+                }
+                root
+            }
+            None => self.storage.get_root_page_number(),
+        };
+        let root = self.apply_staged(root, f)?;
+        self.storage
+            .set_committed_root(root, std::mem::take(&mut self.superseded));
+        match self.durability {
+            Durability::Immediate => self.storage.fsync_commit()?,
+            Durability::Eventual => self.storage.store_state(),
+        }
+        Ok(())
+    }
+
+    /// Applies the staged changes to the tree, and clears them, so that the memory they take
+    /// is freed while the transaction goes on. Nothing is committed or flushed to disk: the
+    /// changes stay visible only to this transaction, which can still be aborted.
+    ///
+    /// The pages of the applied changes are not reclaimed if the transaction is aborted.
+    /// Committing returns `Error::WriteConflict` if anything else, such as `open_table`
+    /// creating a table, has committed since the first flush
+    pub fn flush_staged(&mut self) -> Result<(), Error> {
+        let (base, root) = match self.flushed {
+            Some(flushed) => flushed,
+            None => {
+                let committed = self.storage.get_root_page_number();
+                (committed, committed)
+            }
+        };
+        let root = self.apply_staged(root, || Ok(()))?;
+        self.flushed = Some((base, root));
+        Ok(())
+    }
+
+    /// Takes a savepoint of the staged changes, which `rollback_to` restores. Savepoints nest:
+    /// rolling back to one invalidates every savepoint that was taken after it.
+    ///
+    /// The savepoint holds a copy of the staged changes, so taking one after `flush_staged`
+    /// is cheaper. Rolling back past a flush restores the tree as it was flushed then, but the
+    /// pages of the later flushes are not reclaimed, as when the transaction is aborted
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = NEXT_SAVEPOINT.fetch_add(1, AtomicOrdering::Relaxed);
+        self.savepoints.push(id);
+        Savepoint {
+            id,
+            added: self.added.clone(),
+            removed: self.removed.clone(),
+            truncated: self.truncated,
+            flushed: self.flushed,
+            superseded: self.superseded.len(),
         }
-        self.storage.fsync()?;
+    }
+
+    /// Discards every change made since `savepoint` was taken. Returns
+    /// `Error::InvalidSavepoint` if the savepoint was taken by another transaction, or was
+    /// invalidated by rolling back to an older savepoint
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), Error> {
+        let position = self
+            .savepoints
+            .iter()
+            .position(|id| *id == savepoint.id)
+            .ok_or(Error::InvalidSavepoint)?;
+        self.savepoints.truncate(position);
+        self.added = savepoint.added;
+        self.removed = savepoint.removed;
+        self.truncated = savepoint.truncated;
+        self.flushed = savepoint.flushed;
+        // the trees that were flushed since are discarded, so their pages stay in use
+        self.superseded.truncate(savepoint.superseded);
         Ok(())
     }
 
+    // Applies the staged changes to the tree at `root`, and returns the root of the new tree,
+    // which is not committed. The changes are only cleared once they are applied, so that
+    // after an error, e.g. `Error::OutOfSpace`, they are still staged, and a later commit
+    // applies them or fails too. `f` makes further changes to the same tree
+    fn apply_staged(
+        &mut self,
+        root: Option<u64>,
+        f: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<Option<u64>, Error> {
+        // values are staged uncompressed, so that insert_reserve() can hand out a buffer
+        let compressed = if self.compression == Compression::None {
+            None
+        } else {
+            Some(
+                self.added
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), compress(self.compression, value)?)))
+                    .collect::<Result<HashMap<_, _>, Error>>()?,
+            )
+        };
+        let added = compressed.as_ref().unwrap_or(&self.added);
+        // no reader can see the tree until it is committed
+        let root = self.storage.with_working_root(root, || {
+            if self.truncated {
+                self.storage.clear_table(self.table_id)?;
+            }
+            self.storage.bulk_insert::<K>(self.table_id, added)?;
+            for key in self.removed.iter() {
+                self.storage.remove::<K>(self.table_id, key)?;
+            }
+            f()
+        })?;
+        self.superseded.extend(self.storage.take_superseded());
+        self.added = HashMap::new();
+        self.removed = HashSet::new();
+        self.truncated = false;
+        Ok(root)
+    }
+
+    // The root of the tree that the transaction reads values from, when they are not staged
+    fn read_root(&self) -> Option<u64> {
+        match self.flushed {
+            Some((_, root)) => root,
+            None => self.storage.get_root_page_number(),
+        }
+    }
+
     /// Reserve space to insert a key-value pair (without knowing the value yet)
     /// The returned reference will have length equal to value_length
     pub fn insert_reserve(&mut self, key: &K, value_length: usize) -> Result<&mut [u8], Error> {
-        self.removed.remove(key.as_bytes());
+        check_entry_len(
+            self.storage,
+            key.as_bytes().as_ref(),
+            value_length + overhead(self.compression),
+        )?;
+        self.removed.remove(key.as_bytes().as_ref());
         self.added
-            .insert(key.as_bytes().to_vec(), vec![0; value_length]);
-        Ok(self.added.get_mut(key.as_bytes()).unwrap())
+            .insert(key.as_bytes().as_ref().to_vec(), vec![0; value_length]);
+        Ok(self.added.get_mut(key.as_bytes().as_ref()).unwrap())
+    }
+
+    /// Returns a writer that streams the value of `key`, replacing any current value, so that
+    /// e.g. a file can be copied in with `std::io::copy`. The value must still fit into a page:
+    /// a write past that limit fails with an error that wraps `Error::ValueTooLarge`
+    pub fn value_writer(&mut self, key: &K) -> Result<impl Write + '_, Error> {
+        let key_bytes = key.as_bytes();
+        let key_bytes = key_bytes.as_ref();
+        check_entry_len(self.storage, key_bytes, overhead(self.compression))?;
+        let max_len = max_entry_len(self.storage.get_page_size())
+            - key_bytes.len()
+            - overhead(self.compression);
+        self.removed.remove(key_bytes);
+        self.added.insert(key_bytes.to_vec(), vec![]);
+        Ok(ValueWriter {
+            buffer: self.added.get_mut(key_bytes).unwrap(),
+            max_len,
+        })
+    }
+
+    /// Like `insert_reserve`, but reserves a zeroed buffer for each of many keys at once.
+    /// The buffers are returned in the same order as the keys, which must be distinct
+    pub fn reserve_many(
+        &mut self,
+        keys_and_lengths: &[(Vec<u8>, usize)],
+    ) -> Result<Vec<&mut [u8]>, Error> {
+        // validate everything first, so that nothing is staged on error
+        let mut positions = HashMap::with_capacity(keys_and_lengths.len());
+        for (i, (key, value_length)) in keys_and_lengths.iter().enumerate() {
+            check_entry_len(self.storage, key, value_length + overhead(self.compression))?;
+            if positions.insert(key.as_slice(), i).is_some() {
+                return Err(Error::Io(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "a key was reserved more than once",
+                )));
+            }
+        }
+
+        for (key, value_length) in keys_and_lengths {
+            self.removed.remove(key);
+            self.added.insert(key.clone(), vec![0; *value_length]);
+        }
+        // the buffers are disjoint entries of the map, so they can all be borrowed at once
+        let mut buffers: Vec<(usize, &mut [u8])> = self
+            .added
+            .iter_mut()
+            .filter_map(|(key, value)| {
+                positions
+                    .get(key.as_slice())
+                    .map(|i| (*i, value.as_mut_slice()))
+            })
+            .collect();
+        buffers.sort_unstable_by_key(|(i, _)| *i);
+        Ok(buffers.into_iter().map(|(_, buffer)| buffer).collect())
     }
 
     /// Get a value from the transaction. If the value is not in the data,
     /// it will be fetched from the mmap disk storage.
-    pub fn get(&self, key: &K) -> Result<Option<AccessGuard>, Error> {
-        if let Some(value) = self.added.get(key.as_bytes()) {
-            return Ok(Some(AccessGuard::Local(value)));
+    pub fn get(&self, key: &K) -> Result<Option<AccessGuard<'_, V>>, Error> {
+        if let Some(value) = self.added.get(key.as_bytes().as_ref()) {
+            return Ok(Some(AccessGuard::local(value)));
         }
-        self.storage.get::<K>(
-            self.table_id,
-            key.as_bytes(),
-            self.storage.get_root_page_number(),
-        )
+        if self.removed.contains(key.as_bytes().as_ref()) || self.truncated {
+            return Ok(None);
+        }
+        let found =
+            self.storage
+                .get::<K, V>(self.table_id, key.as_bytes().as_ref(), self.read_root())?;
+        decode_value(self.compression, found)
+    }
+
+    /// Reports how committing the transaction would change each key, against the committed
+    /// values. Removing a key that has no committed value changes nothing, so it is not listed.
+    /// After `flush_staged`, the values that it applied count as committed
+    pub fn preview(&self) -> Result<ChangeSet, Error> {
+        let root_page = self.read_root();
+        let committed = |key: &[u8]| -> Result<Option<Vec<u8>>, Error> {
+            let found = self.storage.get::<K, [u8]>(self.table_id, key, root_page)?;
+            Ok(decode_value(self.compression, found)?.map(|value| value.to_vec()))
+        };
+
+        let mut changes = vec![];
+        for key in self.added.keys() {
+            let change = match committed(key)? {
+                Some(value) => Change::Overwrite(value),
+                None => Change::Insert,
+            };
+            changes.push((key.clone(), change));
+        }
+        if self.truncated {
+            // every committed key that is not staged again is removed
+            let mut iter =
+                self.storage
+                    .get_range::<RangeFull, K, &[u8]>(self.table_id, .., root_page)?;
+            for entry in iter.by_ref() {
+                if !self.added.contains_key(entry.key()) {
+                    let value = if self.compression == Compression::None {
+                        entry.value().to_vec()
+                    } else {
+                        decompress(entry.value())?
+                    };
+                    changes.push((entry.key().to_vec(), Change::Remove(value)));
+                }
+            }
+            iter.finish()?;
+        } else {
+            for key in self.removed.iter() {
+                if let Some(value) = committed(key)? {
+                    changes.push((key.clone(), Change::Remove(value)));
+                }
+            }
+        }
+        changes.sort_by(|(key1, _), (key2, _)| K::compare(key1, key2));
+        Ok(changes)
+    }
+
+    /// The number of bytes that committing the staged inserts allocates, if the commit rebuilds
+    /// the tree in one go, as it does when they are many compared to the entries of the whole
+    /// database. Returns None if the commit inserts them one by one instead.
+    ///
+    /// A rebuild that does not fit after the allocated pages, see `DbStats`, fails with
+    /// `Error::OutOfSpace` before writing anything, so this lets a caller check for space
+    /// up front. It may overestimate, e.g. for inserts that replace a value
+    pub fn estimated_rebuild_size(&self) -> Result<Option<usize>, Error> {
+        self.storage
+            .estimated_rebuild_size(self.added.len(), self.read_root())
+    }
+
+    /// Like `get`, but copies the value, so that it can be held after the transaction ends
+    pub fn get_owned(&self, key: &K) -> Result<Option<AccessGuard<'static, V>>, Error> {
+        Ok(self
+            .get(key)?
+            .map(|value| AccessGuard::owned(value.to_vec())))
+    }
+
+    /// The number of entries that the table will have once this transaction is committed,
+    /// counting both the committed entries and the staged changes
+    pub fn len(&self) -> Result<usize, Error> {
+        if self.truncated {
+            return Ok(self.added.len());
+        }
+        let root = self.read_root();
+        let mut len = self.storage.len(self.table_id, root)?;
+        // a staged key only changes the count if it is new, or if its removal is of a committed one
+        for key in self.added.keys() {
+            if !self.storage.contains_key::<K>(self.table_id, key, root)? {
+                len += 1;
+            }
+        }
+        for key in self.removed.iter() {
+            if self.storage.contains_key::<K>(self.table_id, key, root)? {
+                len -= 1;
+            }
+        }
+        Ok(len)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Checks whether `key` has a value, staged or committed, without reading the value.
+    /// A key that is staged for removal has none, even if it is committed
+    pub fn contains_key(&self, key: &K) -> Result<bool, Error> {
+        let key_bytes = key.as_bytes();
+        let key_bytes = key_bytes.as_ref();
+        if self.added.contains_key(key_bytes) {
+            return Ok(true);
+        }
+        if self.removed.contains(key_bytes) || self.truncated {
+            return Ok(false);
+        }
+        self.storage
+            .contains_key::<K>(self.table_id, key_bytes, self.read_root())
+    }
+
+    /// Replaces the value of `key` with the result of `f`, which is passed the current value.
+    /// If `f` returns `None`, the key is removed
+    pub fn update<F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>>(
+        &mut self,
+        key: &K,
+        f: F,
+    ) -> Result<(), Error> {
+        let key_bytes = key.as_bytes();
+        let key_bytes = key_bytes.as_ref();
+        let new_value = if let Some(value) = self.added.get(key_bytes) {
+            f(Some(value))
+        } else if self.removed.contains(key_bytes) {
+            f(None)
+        } else {
+            let current = self.get(key)?;
+            f(current.as_deref())
+        };
+
+        if let Some(value) = new_value {
+            check_entry_len(
+                self.storage,
+                key_bytes,
+                value.len() + overhead(self.compression),
+            )?;
+            self.removed.remove(key_bytes);
+            self.added.insert(key_bytes.to_vec(), value);
+        } else {
+            self.added.remove(key_bytes);
+            self.removed.insert(key_bytes.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Appends `suffix` to the value of `key`, staged or committed. A key without a value is
+    /// created, with `suffix` as its value
+    pub fn append_value(&mut self, key: &K, suffix: &[u8]) -> Result<(), Error> {
+        self.update(key, |current| {
+            let mut value = current.map(|x| x.to_vec()).unwrap_or_default();
+            value.extend_from_slice(suffix);
+            Some(value)
+        })
+    }
+
+    /// Returns the value of `key`, to be modified in place. A committed value is first copied
+    /// into the transaction, so that the change is applied on commit
+    pub fn get_mut(&mut self, key: &K) -> Result<Option<&mut [u8]>, Error> {
+        let key_bytes = key.as_bytes();
+        let key_bytes = key_bytes.as_ref();
+        if !self.stage_for_update(key_bytes)? {
+            return Ok(None);
+        }
+        Ok(self
+            .added
+            .get_mut(key_bytes)
+            .map(|value| value.as_mut_slice()))
+    }
+
+    /// Returns the entry of `key`, to insert a value only if it has none, or to modify its
+    /// value in place, with a single lookup of the committed value
+    pub fn entry(&mut self, key: &K) -> Result<Entry<'_, 'mmap, K, V>, Error> {
+        let key = key.as_bytes().as_ref().to_vec();
+        if self.added.contains_key(&key) {
+            return Ok(Entry::Occupied(OccupiedEntry {
+                txn: self,
+                key,
+                committed: None,
+            }));
+        }
+        Ok(match self.committed_value(&key)? {
+            Some(value) => Entry::Occupied(OccupiedEntry {
+                txn: self,
+                key,
+                committed: Some(value),
+            }),
+            None => Entry::Vacant(VacantEntry { txn: self, key }),
+        })
+    }
+
+    // Copies the committed value of the key into `added`, unless a value is staged already,
+    // so that it can be modified in place. Returns false if the key has no value
+    fn stage_for_update(&mut self, key: &[u8]) -> Result<bool, Error> {
+        if self.added.contains_key(key) {
+            return Ok(true);
+        }
+        match self.committed_value(key)? {
+            Some(value) => {
+                self.added.insert(key.to_vec(), value);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // The committed value of a key that has no staged value, unless it is staged for removal
+    fn committed_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if self.removed.contains(key) || self.truncated {
+            return Ok(None);
+        }
+        let found = self
+            .storage
+            .get::<K, [u8]>(self.table_id, key, self.read_root())?;
+        Ok(decode_value(self.compression, found)?.map(|value| value.to_vec()))
+    }
+
+    /// Moves the value of `from`, staged or committed, to `to`, overwriting any value of `to`.
+    /// Returns false, and changes nothing, if `from` has no value
+    pub fn rename_key(&mut self, from: &K, to: &K) -> Result<bool, Error> {
+        let from_bytes = from.as_bytes();
+        let from_bytes = from_bytes.as_ref();
+        let to_bytes = to.as_bytes();
+        let to_bytes = to_bytes.as_ref();
+        let value = if let Some(value) = self.added.get(from_bytes) {
+            value.clone()
+        } else if self.removed.contains(from_bytes) {
+            return Ok(false);
+        } else {
+            match self.get(from)? {
+                Some(value) => value.to_vec(),
+                None => return Ok(false),
+            }
+        };
+        if from_bytes == to_bytes {
+            return Ok(true);
+        }
+        check_entry_len(
+            self.storage,
+            to_bytes,
+            value.len() + overhead(self.compression),
+        )?;
+
+        self.added.remove(from_bytes);
+        self.removed.insert(from_bytes.to_vec());
+        self.removed.remove(to_bytes);
+        self.added.insert(to_bytes.to_vec(), value);
+        Ok(true)
+    }
+
+    /// Stages the removal of `key`, and returns whether it was present, either staged by this
+    /// transaction or committed
+    pub fn remove(&mut self, key: &K) -> Result<bool, Error> {
+        let present = self.contains_key(key)?;
+        self.added.remove(key.as_bytes().as_ref());
+        self.removed.insert(key.as_bytes().as_ref().to_vec());
+        Ok(present)
+    }
+
+    /// Removes the entry with the smallest key, staged or committed, and returns its key and
+    /// value, or `None` if the table is empty
+    #[allow(clippy::type_complexity)]
+    pub fn pop_first(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        self.pop_edge(false)
+    }
+
+    /// Removes the entry with the largest key, staged or committed, and returns its key and
+    /// value, or `None` if the table is empty
+    #[allow(clippy::type_complexity)]
+    pub fn pop_last(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        self.pop_edge(true)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn pop_edge(&mut self, last: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        // orders keys so that the edge being popped is the least
+        let order = |key1: &[u8], key2: &[u8]| {
+            let ordering = K::compare(key1, key2);
+            if last {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        };
+        let mut edge = self
+            .added
+            .iter()
+            .min_by(|(key1, _), (key2, _)| order(key1, key2))
+            .map(|(key, value)| (key.clone(), value.clone()));
+
+        if !self.truncated {
+            let root = self.read_root();
+            let mut iter = if last {
+                self.storage
+                    .get_range_reversed::<RangeFull, K, &[u8]>(self.table_id, .., root)?
+            } else {
+                self.storage
+                    .get_range::<RangeFull, K, &[u8]>(self.table_id, .., root)?
+            };
+            // the first committed key that has not been removed, unless a staged key comes first
+            let committed = iter
+                .by_ref()
+                .find(|entry| !self.removed.contains(entry.key()))
+                .filter(|entry| match &edge {
+                    Some((key, _)) => order(entry.key(), key).is_lt(),
+                    None => true,
+                });
+            iter.finish()?;
+            if let Some(entry) = committed {
+                let value = if self.compression == Compression::None {
+                    entry.value().to_vec()
+                } else {
+                    decompress(entry.value())?
+                };
+                edge = Some((entry.key().to_vec(), value));
+            }
+        }
+
+        if let Some((key, _)) = &edge {
+            self.added.remove(key);
+            self.removed.insert(key.clone());
+        }
+        Ok(edge)
     }
 
-    pub fn remove(&mut self, key: &K) -> Result<(), Error> {
-        self.added.remove(key.as_bytes());
-        self.removed.insert(key.as_bytes().to_vec());
+    /// Removes every entry of the table, both staged and committed. The table itself is kept,
+    /// and entries inserted afterwards in this transaction are committed as usual. On commit,
+    /// the parts of the tree that only hold the table's entries are dropped whole, rather than
+    /// each entry being staged for removal one by one
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.added.clear();
+        self.removed.clear();
+        self.truncated = true;
         Ok(())
     }
 
+    /// Removes every entry of the table, see `clear()`
+    #[deprecated(note = "use `clear()`")]
+    pub fn truncate_table(&mut self) {
+        // clear() cannot fail
+        let _ = self.clear();
+    }
+
+    /// Stages every entry of a table exported with `ReadOnlyTransaction::export_to`. If the
+    /// export is invalid or cannot be read, none of its entries are staged
+    pub fn import_from<R: Read>(&mut self, r: &mut R) -> Result<(), Error> {
+        let mut magic = [0; EXPORT_MAGIC.len()];
+        read_export(r, &mut magic)?;
+        if magic != EXPORT_MAGIC {
+            return Err(Error::Corruption(Corruption::NotAnExport));
+        }
+
+        // staged only once the whole export has been read
+        let mut imported = vec![];
+        loop {
+            let mut tag = [0];
+            read_export(r, &mut tag)?;
+            match tag[0] {
+                EXPORT_ENTRY => {
+                    let key = read_export_bytes(r)?;
+                    let value = read_export_bytes(r)?;
+                    check_entry_len(self.storage, &key, value.len() + overhead(self.compression))?;
+                    imported.push((key, value));
+                }
+                EXPORT_END => {
+                    let mut count = [0; 8];
+                    read_export(r, &mut count)?;
+                    if u64::from_be_bytes(count) != imported.len() as u64 {
+                        return Err(Error::Corruption(Corruption::ExportEntryCount {
+                            found: imported.len() as u64,
+                            expected: u64::from_be_bytes(count),
+                        }));
+                    }
+                    for (key, value) in imported {
+                        self.removed.remove(&key);
+                        self.added.insert(key, value);
+                    }
+                    return Ok(());
+                }
+                tag => return Err(Error::Corruption(Corruption::ExportEntryTag(tag))),
+            }
+        }
+    }
+
     pub fn abort(self) -> Result<(), Error> {
         Ok(())
     }
 }
 
-pub struct ReadOnlyTransaction<'mmap, K: RadbKey + ?Sized> {
+/// The entry of a key in a `WriteTransaction`, returned by `WriteTransaction::entry`
+pub enum Entry<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> {
+    Occupied(OccupiedEntry<'a, 'mmap, K, V>),
+    Vacant(VacantEntry<'a, 'mmap, K, V>),
+}
+
+impl<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Entry<'a, 'mmap, K, V> {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Inserts `value` if the key has none, and returns the key's value
+    pub fn or_insert(self, value: &V) -> Result<&'a mut [u8], Error> {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(value),
+        }
+    }
+
+    /// Like `or_insert`, but only calls `f`, which returns the encoded value, if the key has
+    /// no value
+    pub fn or_insert_with<F: FnOnce() -> Vec<u8>>(self, f: F) -> Result<&'a mut [u8], Error> {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert_bytes(f()),
+        }
+    }
+
+    /// Modifies the value in place, if the key has one
+    pub fn and_modify<F: FnOnce(&mut [u8])>(self, f: F) -> Result<Self, Error> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut()?);
+                Ok(Entry::Occupied(entry))
+            }
+            vacant => Ok(vacant),
+        }
+    }
+}
+
+/// The entry of a key that has a value, staged or committed
+pub struct OccupiedEntry<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> {
+    txn: &'a mut WriteTransaction<'mmap, K, V>,
+    key: Vec<u8>,
+    // The committed value found by `entry`, until it is staged. None if a value was staged
+    committed: Option<Vec<u8>>,
+}
+
+impl<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> OccupiedEntry<'a, 'mmap, K, V> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the value, to be modified in place. A committed value is first copied into the
+    /// transaction, as in `WriteTransaction::get_mut`
+    pub fn get_mut(&mut self) -> Result<&mut [u8], Error> {
+        if let Some(value) = self.committed.take() {
+            self.txn.added.insert(self.key.clone(), value);
+        }
+        Ok(self.txn.added.get_mut(&self.key).unwrap())
+    }
+
+    /// Like `get_mut`, but the value borrows from the transaction rather than the entry
+    pub fn into_mut(self) -> Result<&'a mut [u8], Error> {
+        let staged = self.txn.added.entry(self.key).or_default();
+        if let Some(value) = self.committed {
+            *staged = value;
+        }
+        Ok(staged)
+    }
+}
+
+/// The entry of a key that has no value
+pub struct VacantEntry<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> {
+    txn: &'a mut WriteTransaction<'mmap, K, V>,
+    key: Vec<u8>,
+}
+
+impl<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> VacantEntry<'a, 'mmap, K, V> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Stages `value` for the key, and returns it
+    pub fn insert(self, value: &V) -> Result<&'a mut [u8], Error> {
+        self.insert_bytes(value.as_bytes().as_ref().to_vec())
+    }
+
+    fn insert_bytes(self, value: Vec<u8>) -> Result<&'a mut [u8], Error> {
+        let txn = self.txn;
+        check_entry_len(
+            txn.storage,
+            &self.key,
+            value.len() + overhead(txn.compression),
+        )?;
+        txn.removed.remove(&self.key);
+        let staged = txn.added.entry(self.key).or_default();
+        *staged = value;
+        Ok(staged)
+    }
+}
+
+// Nothing is committed before commit, so dropping only needs to release the write lock
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Drop for WriteTransaction<'mmap, K, V> {
+    fn drop(&mut self) {
+        self.storage.end_write();
+    }
+}
+
+// Decompresses a value read from a compressed table
+fn decode_value<V: RadbValue + ?Sized>(
+    compression: Compression,
+    found: Option<AccessGuard<'_, V>>,
+) -> Result<Option<AccessGuard<'_, V>>, Error> {
+    match found {
+        Some(guard) if compression != Compression::None => {
+            Ok(Some(AccessGuard::owned(decompress(&guard)?)))
+        }
+        found => Ok(found),
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn decode_entry<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized>(
+    compression: Compression,
+    found: Option<(AccessGuard<'a, K>, AccessGuard<'a, V>)>,
+) -> Result<Option<(AccessGuard<'a, K>, AccessGuard<'a, V>)>, Error> {
+    match found {
+        Some((key, value)) => {
+            let value = decode_value(compression, Some(value))?.unwrap();
+            Ok(Some((key, value)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The entries whose keys are in both of two tables, returned by
+/// `ReadOnlyTransaction::merge_join`. Yields the key, then the value in each table
+pub struct MergeJoin<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized, V2: RadbValue + ?Sized> {
+    left: BinarytreeRangeIter<'a, RangeFull, K>,
+    right: BinarytreeRangeIter<'a, RangeFull, K>,
+    left_storage: &'a Storage,
+    right_storage: &'a Storage,
+    left_compression: Compression,
+    right_compression: Compression,
+    // the error that ended the join
+    error: Option<Error>,
+    _value_type: PhantomData<V>,
+    _other_value_type: PhantomData<V2>,
+}
+
+impl<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized, V2: RadbValue + ?Sized>
+    MergeJoin<'a, K, V, V2>
+{
+    /// Returns the error that ended the join early, such as a corrupted page, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    // Finds the next key that is in both tables, returning the location of its entry in each
+    fn next_match(&mut self) -> Option<((u64, usize), (u64, usize))> {
+        let mut left = self.left.next_location()?;
+        let mut right = self.right.next_location()?;
+        loop {
+            let (left_key, _) = self.left_storage.entry_at::<K, [u8]>(left);
+            let (right_key, _) = self.right_storage.entry_at::<K, [u8]>(right);
+            match K::compare(&left_key, &right_key) {
+                Ordering::Less => left = self.left.next_location()?,
+                Ordering::Greater => right = self.right.next_location()?,
+                Ordering::Equal => return Some((left, right)),
+            }
+        }
+    }
+}
+
+impl<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized, V2: RadbValue + ?Sized> Iterator
+    for MergeJoin<'a, K, V, V2>
+{
+    type Item = (AccessGuard<'a, K>, AccessGuard<'a, V>, AccessGuard<'a, V2>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        let found = self.next_match();
+        if let Some(err) = self.left.take_error().or_else(|| self.right.take_error()) {
+            self.error = Some(err);
+            return None;
+        }
+        let (left, right) = found?;
+        let (key, left_value) = self.left_storage.entry_at::<K, V>(left);
+        let (_, right_value) = self.right_storage.entry_at::<K, V2>(right);
+        // decode_value() returns a value for every value it is given
+        let decoded = decode_value(self.left_compression, Some(left_value)).and_then(|left| {
+            let right = decode_value(self.right_compression, Some(right_value))?;
+            Ok((left.unwrap(), right.unwrap()))
+        });
+        match decoded {
+            Ok((left_value, right_value)) => Some((key, left_value, right_value)),
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+}
+
+/// Every entry of a table, in key order, returned by iterating over a `&ReadOnlyTransaction`.
+/// Yields the key and the value of each entry, which borrow from the database
+pub struct TableIter<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized> {
+    // None if the iteration could not start, with the reason in error
+    inner: Option<BinarytreeRangeIter<'a, RangeFull, K>>,
+    storage: &'a Storage,
+    compression: Compression,
+    // the error that ended the iteration
+    error: Option<Error>,
+    _value_type: PhantomData<V>,
+}
+
+impl<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized> TableIter<'a, K, V> {
+    /// Returns the error that ended the iteration early, such as a corrupted page, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error
+            .take()
+            .or_else(|| self.inner.as_mut()?.take_error())
+    }
+}
+
+impl<'a, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Iterator for TableIter<'a, K, V> {
+    type Item = (AccessGuard<'a, K>, AccessGuard<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        let location = self.inner.as_mut()?.next_location()?;
+        let (key, value) = self.storage.entry_at::<K, V>(location);
+        match decode_value(self.compression, Some(value)) {
+            // decode_value() returns a value for every value it is given
+            Ok(value) => Some((key, value.unwrap())),
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+}
+
+impl<'a, 'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> IntoIterator
+    for &'a ReadOnlyTransaction<'mmap, K, V>
+{
+    type Item = (AccessGuard<'a, K>, AccessGuard<'a, V>);
+    type IntoIter = TableIter<'a, K, V>;
+
+    fn into_iter(self) -> TableIter<'a, K, V> {
+        let (inner, error) = match self.storage.get_range(self.table_id, .., self.root_page) {
+            Ok(inner) => (Some(inner), None),
+            Err(err) => (None, Some(err)),
+        };
+        TableIter {
+            inner,
+            storage: self.storage,
+            compression: self.compression,
+            error,
+            _value_type: Default::default(),
+        }
+    }
+}
+
+/// The keys of the entries in a range, returned by `ReadOnlyTransaction::keys`. Values are
+/// never located, let alone read or decompressed
+pub struct KeyIter<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> {
+    inner: BinarytreeRangeIter<'a, T, K>,
+    storage: &'a Storage,
+}
+
+impl<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> KeyIter<'a, T, K> {
+    /// Returns the error that ended the iteration early, such as a corrupted page, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.inner.take_error()
+    }
+}
+
+impl<'a, T: RangeBounds<&'a [u8]>, K: RadbKey + ?Sized> Iterator for KeyIter<'a, T, K> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let location = self.inner.next_location()?;
+        Some(self.storage.key_at(location))
+    }
+}
+
+/// The entries of a table except those whose keys start with a prefix, returned by
+/// `ReadOnlyTransaction::scan_excluding_prefix`. Yields the entries before the prefix,
+/// then the entries after it, in ascending key order
+pub struct ExcludingPrefixIter<'a, K: RadbKey + ?Sized> {
+    below: BinarytreeRangeIter<'a, RangeTo<Vec<u8>>, K, Vec<u8>>,
+    // None if no key can follow the keys with the prefix
+    after: Option<BinarytreeRangeIter<'a, RangeFrom<Vec<u8>>, K, Vec<u8>>>,
+    in_below: bool,
+}
+
+impl<'a, K: RadbKey + ?Sized> Iterator for ExcludingPrefixIter<'a, K> {
+    type Item = EntryAccessor<'a>;
+
+    fn next(&mut self) -> Option<EntryAccessor<'a>> {
+        if self.in_below {
+            if let Some(entry) = self.below.next() {
+                return Some(entry);
+            }
+            // stop at an error, rather than skipping to the second range
+            if self.below.has_error() {
+                return None;
+            }
+            self.in_below = false;
+        }
+        self.after.as_mut()?.next()
+    }
+}
+
+impl<'a, K: RadbKey + ?Sized> ExcludingPrefixIter<'a, K> {
+    /// Returns the error that ended the iteration early, such as a corrupted page, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.below
+            .take_error()
+            .or_else(|| self.after.as_mut()?.take_error())
+    }
+}
+
+type TypedRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// The decoded entries of a range of a table, returned by `ReadOnlyTransaction::range_typed`.
+/// Yields owned keys and values, in the order of the table's keys
+pub struct TypedRangeIter<'a, K: RadbKey + ToOwned + ?Sized, V: RadbValue + ToOwned + ?Sized> {
+    inner: BinarytreeRangeIter<'a, TypedRange, K, Vec<u8>>,
+    compression: Compression,
+    // the error that ended the iteration
+    error: Option<Error>,
+    _value_type: PhantomData<V>,
+}
+
+impl<'a, K: RadbKey + ToOwned + ?Sized, V: RadbValue + ToOwned + ?Sized> TypedRangeIter<'a, K, V> {
+    /// Returns the error that ended the iteration early, such as a corrupted page, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take().or_else(|| self.inner.take_error())
+    }
+}
+
+impl<'a, K: RadbKey + ToOwned + ?Sized, V: RadbValue + ToOwned + ?Sized> Iterator
+    for TypedRangeIter<'a, K, V>
+where
+    for<'b> <K::View as WithLifetime<'b>>::Out: Into<K::Owned>,
+    for<'b> <V::View as WithLifetime<'b>>::Out: Into<V::Owned>,
+{
+    type Item = (K::Owned, V::Owned);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        let (key, value) = self.inner.next_key_value()?;
+        let key = K::from_bytes(key).into();
+        if self.compression == Compression::None {
+            return Some((key, V::from_bytes(value).into()));
+        }
+        match decompress(value) {
+            Ok(value) => Some((key, V::from_bytes(&value).into())),
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+}
+
+// The smallest key that is greater than every key that starts with `prefix`,
+// or None if there is no such key, when the prefix is empty or all 0xFF
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|byte| *byte != 0xFF)?;
+    let mut successor = prefix[..=last].to_vec();
+    successor[last] += 1;
+    Some(successor)
+}
+
+// Parameters of the 64-bit FNV-1a hash, used by `ReadOnlyTransaction::content_hash`.
+// Unlike the hashers in std, its output is specified, so it never changes
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// Fills buf, reporting the end of the stream as a truncated export
+fn read_export<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    r.read_exact(buf).map_err(|err| {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            Error::Corruption(Corruption::ExportTruncated)
+        } else {
+            Error::Io(err)
+        }
+    })
+}
+
+fn read_export_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 8];
+    read_export(r, &mut len)?;
+    let len: usize = u64::from_be_bytes(len)
+        .try_into()
+        .map_err(|_| Error::Corruption(Corruption::ExportEntryTooLarge))?;
+    let mut data = vec![];
+    r.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(Error::Corruption(Corruption::ExportTruncated));
+    }
+    Ok(data)
+}
+
+// Appends to a value staged by `WriteTransaction::value_writer`
+struct ValueWriter<'a> {
+    buffer: &'a mut Vec<u8>,
+    max_len: usize,
+}
+
+impl<'a> Write for ValueWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.buffer.len() + buf.len();
+        if len > self.max_len {
+            return Err(std::io::Error::other(Error::ValueTooLarge(len)));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn check_entry_len(storage: &Storage, key: &[u8], value_len: usize) -> Result<(), Error> {
+    let max_len = max_entry_len(storage.get_page_size());
+    if key.len() > max_len {
+        Err(Error::KeyTooLarge(key.len()))
+    } else if key.len() + value_len > max_len {
+        Err(Error::ValueTooLarge(value_len))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads one version of a table, which later commits don't change. The pages of the version
+/// are reused once no transaction reads it, so the values that are read from it borrow the
+/// transaction, and cannot outlive it. Copy a value, e.g. with `to_vec`, to keep it longer
+pub struct ReadOnlyTransaction<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized = [u8]> {
     storage: &'mmap Storage,
+    version: u64,
     root_page: Option<u64>,
     table_id: u64,
+    compression: Compression,
     _key_type: PhantomData<K>,
+    _value_type: PhantomData<V>,
 }
 
-impl<'mmap, K: RadbKey + ?Sized> ReadOnlyTransaction<'mmap, K> {
-    pub(crate) fn new(table_id: u64, storage: &'mmap Storage) -> ReadOnlyTransaction<'mmap, K> {
-        let root_page = storage.get_root_page_number();
+/// A clone shares the version that the transaction was opened at, so the same consistent
+/// snapshot can be handed to several readers, even as later commits are made.
+/// It is implemented by hand, since `K` and `V` need not be `Clone`
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Clone for ReadOnlyTransaction<'mmap, K, V> {
+    fn clone(&self) -> Self {
+        self.storage.register_reader(self.version, self.root_page);
         ReadOnlyTransaction {
+            storage: self.storage,
+            version: self.version,
+            root_page: self.root_page,
+            table_id: self.table_id,
+            compression: self.compression,
+            _key_type: Default::default(),
+            _value_type: Default::default(),
+        }
+    }
+}
+
+// The version that the transaction read may be reclaimed once no transaction reads it
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> Drop for ReadOnlyTransaction<'mmap, K, V> {
+    fn drop(&mut self) {
+        self.storage.unregister_reader(self.version, self.root_page);
+    }
+}
+
+impl<'mmap, K: RadbKey + ?Sized, V: RadbValue + ?Sized> ReadOnlyTransaction<'mmap, K, V> {
+    pub(crate) fn new(
+        table_id: u64,
+        storage: &'mmap Storage,
+        compression: Compression,
+    ) -> ReadOnlyTransaction<'mmap, K, V> {
+        Self::new_at(
+            table_id,
             storage,
+            compression,
+            storage.current_version(),
+            storage.get_root_page_number(),
+        )
+    }
+
+    // A transaction that reads the given version of the database, whose tree has the given root
+    pub(crate) fn new_at(
+        table_id: u64,
+        storage: &'mmap Storage,
+        compression: Compression,
+        version: u64,
+        root_page: Option<u64>,
+    ) -> ReadOnlyTransaction<'mmap, K, V> {
+        storage.register_reader(version, root_page);
+        ReadOnlyTransaction {
+            storage,
+            version,
             root_page,
             table_id,
+            compression,
             _key_type: Default::default(),
+            _value_type: Default::default(),
         }
     }
 
-    pub fn get(&self, key: &K) -> Result<Option<AccessGuard<'mmap>>, Error> {
+    /// The version of the database that this transaction reads. Versions count the commits
+    /// that the database has had, so every commit produces a new, greater version, even if
+    /// it changes nothing, and two versions never name the same state
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<AccessGuard<'_, V>>, Error> {
+        let found =
+            self.storage
+                .get::<K, V>(self.table_id, key.as_bytes().as_ref(), self.root_page)?;
+        decode_value(self.compression, found)
+    }
+
+    /// Looks up many keys at once, returning their values in the order of `keys`.
+    /// This is faster than calling `get` for each key, since the keys are sorted and the tree
+    /// is walked once for all of them, rather than from the root for each one
+    pub fn get_multi(&self, keys: &[&K]) -> Result<Vec<Option<AccessGuard<'_, V>>>, Error> {
+        let encoded: Vec<_> = keys.iter().map(|key| key.as_bytes()).collect();
+        let encoded: Vec<&[u8]> = encoded.iter().map(|key| key.as_ref()).collect();
         self.storage
-            .get::<K>(self.table_id, key.as_bytes(), self.root_page)
+            .get_multi::<K, V>(self.table_id, &encoded, self.root_page)?
+            .into_iter()
+            .map(|found| decode_value(self.compression, found))
+            .collect()
+    }
+
+    /// Like `get`, but also returns the key of the entry that was found
+    #[allow(clippy::type_complexity)]
+    pub fn get_key_value(
+        &self,
+        key: &K,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        let found = self.storage.get_key_value::<K, V>(
+            self.table_id,
+            key.as_bytes().as_ref(),
+            self.root_page,
+        )?;
+        decode_entry(self.compression, found)
+    }
+
+    /// Walks this table and `other` in key order together, yielding the key of every entry that
+    /// is in both tables, with its value in each. Both tables are scanned once, so this takes
+    /// time linear in their sizes. The tables may belong to different databases
+    pub fn merge_join<'a, V2: RadbValue + ?Sized>(
+        &'a self,
+        other: &'a ReadOnlyTransaction<'mmap, K, V2>,
+    ) -> Result<MergeJoin<'a, K, V, V2>, Error> {
+        Ok(MergeJoin {
+            left: self.storage.get_range(self.table_id, .., self.root_page)?,
+            right: other
+                .storage
+                .get_range(other.table_id, .., other.root_page)?,
+            left_storage: self.storage,
+            right_storage: other.storage,
+            left_compression: self.compression,
+            right_compression: other.compression,
+            error: None,
+            _value_type: Default::default(),
+            _other_value_type: Default::default(),
+        })
+    }
+
+    /// Renders the tree that this transaction reads as indented text, one node per line, for
+    /// debugging. The tree holds every table, so the entries of other tables are shown too
+    pub fn dump_tree(&self) -> Result<String, Error> {
+        self.storage.dump_tree(self.root_page)
+    }
+
+    /// Returns the entry with the largest key that is less than or equal to `key`, or `None`
+    /// if every key in the table is greater
+    #[allow(clippy::type_complexity)]
+    pub fn floor(
+        &self,
+        key: &K,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        self.get_nearest(key, true)
+    }
+
+    /// Returns the entry with the smallest key that is greater than or equal to `key`, or `None`
+    /// if every key in the table is less
+    #[allow(clippy::type_complexity)]
+    pub fn ceiling(
+        &self,
+        key: &K,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        self.get_nearest(key, false)
+    }
+
+    /// Returns the entry with the smallest key, or `None` if the table is empty
+    #[allow(clippy::type_complexity)]
+    pub fn first(&self) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        let found = self
+            .storage
+            .get_edge::<K, V>(self.table_id, false, self.root_page)?;
+        decode_entry(self.compression, found)
+    }
+
+    /// Returns the entry with the largest key, or `None` if the table is empty
+    #[allow(clippy::type_complexity)]
+    pub fn last(&self) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        let found = self
+            .storage
+            .get_edge::<K, V>(self.table_id, true, self.root_page)?;
+        decode_entry(self.compression, found)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn get_nearest(
+        &self,
+        key: &K,
+        before: bool,
+    ) -> Result<Option<(AccessGuard<'_, K>, AccessGuard<'_, V>)>, Error> {
+        let found = self.storage.get_nearest::<K, V>(
+            self.table_id,
+            key.as_bytes().as_ref(),
+            before,
+            self.root_page,
+        )?;
+        decode_entry(self.compression, found)
     }
 
+    /// Range iterators yield values as they are stored, so the values of a compressed
+    /// table are compressed
     pub fn get_range<'a, T: RangeBounds<&'a [u8]>>(
         &'a self,
         range: T,
-    ) -> Result<BinarytreeRangeIter<T, K>, Error> {
+    ) -> Result<BinarytreeRangeIter<'a, T, K>, Error> {
+        self.storage.get_range(self.table_id, range, self.root_page)
+    }
+
+    /// Like `get_range`, but yields only the keys, which borrow from the database. Cheaper
+    /// when the values are not needed, e.g. to list or count keys, since no value is touched
+    pub fn keys<'a, T: RangeBounds<&'a [u8]>>(
+        &'a self,
+        range: T,
+    ) -> Result<KeyIter<'a, T, K>, Error> {
+        Ok(KeyIter {
+            inner: self.get_range(range)?,
+            storage: self.storage,
+        })
+    }
+
+    /// Like `get_range`, but takes owned bounds, such as `vec![3]..vec![7]`,
+    /// so that they do not need to outlive the call
+    pub fn get_range_owned<T: RangeBounds<Vec<u8>>>(
+        &self,
+        range: T,
+    ) -> Result<BinarytreeRangeIter<'_, T, K, Vec<u8>>, Error> {
         self.storage.get_range(self.table_id, range, self.root_page)
     }
 
+    /// Like `get_range`, but takes typed bounds, such as `10..20` on a table of `u32`, and
+    /// yields each entry decoded into an owned key and value. Entries are ordered by
+    /// `K::compare`, as in `get_range`, and the values of a compressed table are decompressed
+    pub fn range_typed<KR: Borrow<K>, T: RangeBounds<KR>>(
+        &self,
+        range: T,
+    ) -> Result<TypedRangeIter<'_, K, V>, Error>
+    where
+        K: ToOwned,
+        V: ToOwned,
+    {
+        let encode = |bound: Bound<&KR>| bound.map(|key| key.borrow().as_bytes().as_ref().to_vec());
+        let range = (encode(range.start_bound()), encode(range.end_bound()));
+        Ok(TypedRangeIter {
+            inner: self.get_range_owned(range)?,
+            compression: self.compression,
+            error: None,
+            _value_type: Default::default(),
+        })
+    }
+
+    /// Iterates over the entries whose keys do not start with `prefix`, in ascending key order:
+    /// those before the prefix, then those after it. The prefix is of the encoded keys, so this
+    /// is meant for tables whose keys are ordered by their bytes, such as `[u8]` and `str`
+    pub fn scan_excluding_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<ExcludingPrefixIter<'_, K>, Error> {
+        let after = match prefix_successor(prefix) {
+            Some(successor) => Some(self.get_range_owned(successor..)?),
+            None => None,
+        };
+        Ok(ExcludingPrefixIter {
+            below: self.get_range_owned(..prefix.to_vec())?,
+            after,
+            in_below: true,
+        })
+    }
+
+    /// Iterates over every entry in the table, in ascending key order
+    pub fn iter(&self) -> Result<BinarytreeRangeIter<'_, RangeFull, K>, Error> {
+        self.get_range(..)
+    }
+
     pub fn get_range_reversed<'a, T: RangeBounds<&'a [u8]>>(
         &'a self,
         range: T,
-    ) -> Result<BinarytreeRangeIter<T, K>, Error> {
+    ) -> Result<BinarytreeRangeIter<'a, T, K>, Error> {
         self.storage
             .get_range_reversed(self.table_id, range, self.root_page)
     }
 
+    /// Returns a cursor over the range, which can scroll forward with `next()` and backward
+    /// with `prev()`
+    pub fn range_cursor<'a, T: RangeBounds<&'a [u8]>>(
+        &'a self,
+        range: T,
+    ) -> Result<RangeCursor<'a, T, K>, Error> {
+        self.storage
+            .get_range_cursor(self.table_id, range, self.root_page)
+    }
+
+    /// Writes every entry of the table to `w`, in order. Read it back with
+    /// `WriteTransaction::import_from`. Values are exported uncompressed
+    pub fn export_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        w.write_all(&EXPORT_MAGIC)?;
+        let mut iter =
+            self.storage
+                .get_range::<RangeFull, K, &[u8]>(self.table_id, .., self.root_page)?;
+        let mut entries: u64 = 0;
+        for entry in iter.by_ref() {
+            w.write_all(&[EXPORT_ENTRY])?;
+            w.write_all(&(entry.key().len() as u64).to_be_bytes())?;
+            w.write_all(entry.key())?;
+            if self.compression == Compression::None {
+                w.write_all(&(entry.value().len() as u64).to_be_bytes())?;
+                w.write_all(entry.value())?;
+            } else {
+                let value = decompress(entry.value())?;
+                w.write_all(&(value.len() as u64).to_be_bytes())?;
+                w.write_all(&value)?;
+            }
+            entries += 1;
+        }
+        iter.finish()?;
+        w.write_all(&[EXPORT_END])?;
+        w.write_all(&entries.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Returns a hash of every entry of the table, which depends only on the keys and values,
+    /// not on the order they were inserted in or on the shape of the tree. Values are hashed
+    /// uncompressed. The hash is stable across releases and platforms, so it can be compared
+    /// between databases, e.g. to check that a replica is up to date
+    pub fn content_hash(&self) -> Result<u64, Error> {
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut iter =
+            self.storage
+                .get_range::<RangeFull, K, &[u8]>(self.table_id, .., self.root_page)?;
+        for entry in iter.by_ref() {
+            let decompressed;
+            let value = if self.compression == Compression::None {
+                entry.value()
+            } else {
+                decompressed = decompress(entry.value())?;
+                &decompressed
+            };
+            // length-prefixed, so that bytes cannot move between a key and its value unnoticed
+            for data in [entry.key(), value] {
+                hash = fnv1a(hash, &(data.len() as u64).to_be_bytes());
+                hash = fnv1a(hash, data);
+            }
+        }
+        iter.finish()?;
+        Ok(hash)
+    }
+
+    /// Estimates the number of entries in the range, much more cheaply than counting them,
+    /// by only descending into the parts of the tree at the bounds of the range.
+    ///
+    /// The estimate is exact for small ranges. For a table written by a single commit, it is
+    /// never less than the exact count and at most twice it. Tables that have since been
+    /// modified by many small commits can have a less balanced tree, and a less accurate estimate
+    pub fn estimate_count<'a, T: RangeBounds<&'a [u8]>>(&self, range: T) -> Result<usize, Error> {
+        self.storage
+            .estimate_len::<T, K, &[u8]>(self.table_id, &range, self.root_page)
+    }
+
+    /// Counts the entries in the range exactly, with the same bounds as `get_range`.
+    /// Walks every entry in the range, but only the keys are compared, and no value is located
+    pub fn count_range<'a, T: RangeBounds<&'a [u8]>>(&'a self, range: T) -> Result<usize, Error> {
+        let mut iter = self.get_range(range)?;
+        let mut count = 0;
+        while iter.next_location().is_some() {
+            count += 1;
+        }
+        iter.finish()?;
+        Ok(count)
+    }
+
     pub fn len(&self) -> Result<usize, Error> {
         self.storage.len(self.table_id, self.root_page)
     }
@@ -1,5 +1,7 @@
 use std::cmp::Ordering;
+use std::convert::TryInto;
 use std::marker::PhantomData;
+use std::mem::size_of;
 
 pub trait WithLifetime<'a> {
     type Out;
@@ -10,33 +12,224 @@ impl<'a, T: 'a + ?Sized> WithLifetime<'a> for RefLifetime<T> {
     type Out = &'a T;
 }
 
-pub trait RadbKey {
+pub struct OwnedLifetime<T>(PhantomData<T>);
+impl<'a, T> WithLifetime<'a> for OwnedLifetime<T> {
+    type Out = T;
+}
+
+pub trait AsBytesWithLifetime<'a> {
+    type Out: AsRef<[u8]>;
+}
+
+pub struct RefAsBytesLifetime<T: ?Sized>(PhantomData<T>);
+impl<'a, T: 'a + AsRef<[u8]> + ?Sized> AsBytesWithLifetime<'a> for RefAsBytesLifetime<T> {
+    type Out = &'a T;
+}
+
+pub struct OwnedAsBytesLifetime<T>(PhantomData<T>);
+impl<'a, T: AsRef<[u8]> + 'a> AsBytesWithLifetime<'a> for OwnedAsBytesLifetime<T> {
+    type Out = T;
+}
+
+pub trait RadbValue {
     // TODO: need GATs, so that we can replace all this HRTB stuff
     type View: for<'a> WithLifetime<'a>;
+    type ToBytes: for<'a> AsBytesWithLifetime<'a>;
 
     /// Deserializes data
     /// Implementations may return a view over data, or an owned type
-    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime>::Out;
+    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out;
 
-    /// Serialize the key to a slice
-    fn as_bytes(&self) -> &[u8];
+    /// Serialize the value to a slice
+    /// Implementations may return a view over self, or an owned encoding
+    fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out;
+}
 
+pub trait RadbKey: RadbValue {
     /// Compare data1 with data2
     fn compare(data1: &[u8], data2: &[u8]) -> Ordering;
 }
 
+impl RadbValue for [u8] {
+    type View = RefLifetime<[u8]>;
+    type ToBytes = RefAsBytesLifetime<[u8]>;
+
+    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
+        data
+    }
+
+    fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
+        self
+    }
+}
+
 impl RadbKey for [u8] {
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        data1.cmp(data2)
+    }
+}
+
+impl RadbValue for Vec<u8> {
     type View = RefLifetime<[u8]>;
+    type ToBytes = RefAsBytesLifetime<[u8]>;
 
-    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime>::Out {
+    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
         data
     }
 
-    fn as_bytes(&self) -> &[u8] {
+    fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
         self
     }
+}
 
+impl RadbKey for Vec<u8> {
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        <[u8] as RadbKey>::compare(data1, data2)
+    }
+}
+
+impl RadbValue for str {
+    type View = RefLifetime<str>;
+    type ToBytes = RefAsBytesLifetime<str>;
+
+    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
+        std::str::from_utf8(data).unwrap()
+    }
+
+    fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
+        self
+    }
+}
+
+impl RadbKey for str {
+    // UTF-8 preserves code point order, so comparing the raw bytes is
+    // the same as comparing the decoded strings
     fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
         data1.cmp(data2)
     }
 }
+
+impl RadbValue for String {
+    type View = RefLifetime<str>;
+    type ToBytes = RefAsBytesLifetime<str>;
+
+    fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
+        <str as RadbValue>::from_bytes(data)
+    }
+
+    fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
+        self.as_str()
+    }
+}
+
+impl RadbKey for String {
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        <str as RadbKey>::compare(data1, data2)
+    }
+}
+
+// Integers are stored big-endian, so that unsigned keys also sort correctly as raw bytes
+macro_rules! be_type {
+    ($t:ty) => {
+        impl RadbValue for $t {
+            type View = OwnedLifetime<$t>;
+            type ToBytes = OwnedAsBytesLifetime<[u8; size_of::<$t>()]>;
+
+            fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
+                <$t>::from_be_bytes(data.try_into().unwrap())
+            }
+
+            fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
+                self.to_be_bytes()
+            }
+        }
+
+        impl RadbKey for $t {
+            fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+                Self::from_bytes(data1).cmp(&Self::from_bytes(data2))
+            }
+        }
+    };
+}
+
+be_type!(u8);
+be_type!(u16);
+be_type!(u32);
+be_type!(u64);
+be_type!(u128);
+be_type!(i8);
+be_type!(i16);
+be_type!(i32);
+be_type!(i64);
+be_type!(i128);
+
+// Appends a length-prefixed component to an encoded tuple
+// Also used by the code generated by `#[derive(RadbKey)]`
+#[doc(hidden)]
+pub fn encode_component(output: &mut Vec<u8>, component: &[u8]) {
+    output.extend_from_slice(&(component.len() as u32).to_be_bytes());
+    output.extend_from_slice(component);
+}
+
+// Splits the next length-prefixed component off the front of an encoded tuple
+// Also used by the code generated by `#[derive(RadbKey)]`
+#[doc(hidden)]
+pub fn split_component(data: &[u8]) -> (&[u8], &[u8]) {
+    let len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+    (&data[4..(4 + len)], &data[(4 + len)..])
+}
+
+// Tuple encoding is:
+// * for every component but the last: (4 bytes) length, big endian, then the component bytes
+// * the last component's bytes, unprefixed
+//
+// The raw bytes do not sort the same way as the tuple, so `compare` decodes the components
+// and compares them one by one with each component's own `compare`. This way a longer first
+// component (e.g. "ab") can never be misordered against a shorter one (e.g. "b") because
+// of its length prefix.
+macro_rules! tuple_type {
+    ($($name:ident $var:ident $idx:tt),+ ; $last:ident $last_idx:tt) => {
+        impl<'a, $($name: WithLifetime<'a>,)+ $last: WithLifetime<'a>> WithLifetime<'a>
+            for ($($name,)+ $last)
+        {
+            type Out = ($($name::Out,)+ $last::Out);
+        }
+
+        impl<$($name: RadbValue,)+ $last: RadbValue> RadbValue for ($($name,)+ $last) {
+            type View = ($($name::View,)+ $last::View);
+            type ToBytes = OwnedAsBytesLifetime<Vec<u8>>;
+
+            fn from_bytes(data: &[u8]) -> <Self::View as WithLifetime<'_>>::Out {
+                let rest = data;
+                $(let ($var, rest) = split_component(rest);)+
+                ($($name::from_bytes($var),)+ $last::from_bytes(rest))
+            }
+
+            fn as_bytes(&self) -> <Self::ToBytes as AsBytesWithLifetime<'_>>::Out {
+                let mut output = vec![];
+                $(encode_component(&mut output, self.$idx.as_bytes().as_ref());)+
+                output.extend_from_slice(self.$last_idx.as_bytes().as_ref());
+                output
+            }
+        }
+
+        impl<$($name: RadbKey,)+ $last: RadbKey> RadbKey for ($($name,)+ $last) {
+            fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+                let (rest1, rest2) = (data1, data2);
+                $(
+                    let (component1, rest1) = split_component(rest1);
+                    let (component2, rest2) = split_component(rest2);
+                    match $name::compare(component1, component2) {
+                        Ordering::Equal => {}
+                        ordering => return ordering,
+                    }
+                )+
+                $last::compare(rest1, rest2)
+            }
+        }
+    };
+}
+
+tuple_type!(T0 t0 0; T1 1);
+tuple_type!(T0 t0 0, T1 t1 1; T2 2);
+tuple_type!(T0 t0 0, T1 t1 1, T2 t2 2; T3 3);
@@ -45,3 +45,25 @@ fn test_insert_duplicate_key() {
     btree.insert("g", 7);
     assert_eq!(btree.search(&"g"), Some(&7));
 }
+
+#[test]
+fn test_borrowed_key_lookups() {
+    let mut btree: BTree<String, i32> = BTree::new();
+    for (i, key) in ["g", "m", "p", "x", "a", "c", "d", "f", "i"]
+        .iter()
+        .enumerate()
+    {
+        btree.insert(key.to_string(), i as i32);
+    }
+
+    // &str keys, without constructing a String
+    assert_eq!(btree.search("m"), Some(&1));
+    assert_eq!(btree.search("i"), Some(&8));
+    assert_eq!(btree.search("b"), None);
+    let mut cursor = btree.cursor();
+    assert_eq!(cursor.seek("e").map(|(_, value)| *value), Some(7));
+    assert_eq!(btree.delete("x"), Some(3));
+    assert_eq!(btree.delete("x"), None);
+    assert_eq!(btree.search("x"), None);
+    assert!(btree.check_invariants().is_ok());
+}
@@ -0,0 +1,75 @@
+use radarbase::btree::BTree;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+const SEEDS: u64 = 20;
+const OPERATIONS: usize = 2000;
+
+// Applies a seeded random mix of inserts, deletes and searches to both a `BTree` and a
+// `BTreeMap`, checking that they agree, and that the tree is valid, after every step.
+// `key` draws a key from a small space, so that keys collide and deletes find something.
+// Run a single seed with `BTREE_MODEL_SEED=<seed>` to reproduce a failure
+fn check_against_model<K: Ord + Clone + Debug>(key: impl Fn(&mut StdRng) -> K) {
+    let seeds: Vec<u64> = match std::env::var("BTREE_MODEL_SEED") {
+        Ok(seed) => vec![seed.parse().expect("BTREE_MODEL_SEED is not a number")],
+        Err(_) => (0..SEEDS).collect(),
+    };
+    for seed in seeds {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut btree = BTree::new();
+        let mut model = BTreeMap::new();
+        for step in 0..OPERATIONS {
+            let k = key(&mut rng);
+            match rng.gen_range(0..3) {
+                0 => {
+                    let value: u32 = rng.gen();
+                    // an existing key keeps its value
+                    btree.insert(k.clone(), value);
+                    model.entry(k).or_insert(value);
+                }
+                1 => {
+                    assert_eq!(
+                        btree.delete(&k),
+                        model.remove(&k),
+                        "delete of {:?} at step {} with seed {}",
+                        k,
+                        step,
+                        seed
+                    );
+                }
+                _ => {
+                    assert_eq!(
+                        btree.search(&k),
+                        model.get(&k),
+                        "search of {:?} at step {} with seed {}",
+                        k,
+                        step,
+                        seed
+                    );
+                }
+            }
+            if let Err(err) = btree.check_invariants() {
+                panic!("{} at step {} with seed {}", err, step, seed);
+            }
+        }
+        let expected: Vec<(K, u32)> = model.into_iter().collect();
+        assert_eq!(btree.to_vec(), expected, "contents with seed {}", seed);
+    }
+}
+
+#[test]
+fn model_integer_keys() {
+    check_against_model(|rng| rng.gen_range(0..200u32));
+}
+
+#[test]
+fn model_string_keys() {
+    check_against_model(|rng| {
+        let len = rng.gen_range(1..=3);
+        (0..len)
+            .map(|_| rng.gen_range(b'a'..=b'f') as char)
+            .collect::<String>()
+    });
+}
@@ -1,8 +1,10 @@
 use radarbase::btree::BTree; // also radarbase::BTree
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[test]
+#[allow(deprecated)]
 fn test_traverse() {
     let mut tree = BTree::<String, i32>::new();
     let keys = vec![
@@ -12,7 +14,7 @@ fn test_traverse() {
         "orange".to_string(),
         "kiwi".to_string(),
     ];
-    let values = vec![2, 3, 7, 5, 4];
+    let values = [2, 3, 7, 5, 4];
 
     for (key, value) in keys.iter().zip(values.iter()) {
         tree.insert(key.clone(), *value);
@@ -28,7 +30,7 @@ fn test_traverse() {
     assert_eq!(sorted_keys, expected_keys);
 
     for (key, value) in sorted_keys.iter().zip(sorted_values.iter()) {
-        assert_eq!(tree.search(&key), Some(value));
+        assert_eq!(tree.search(key), Some(value));
     }
 }
 
@@ -48,10 +50,95 @@ fn test_traverse_sorted_keys() {
         tree.insert(key.clone(), *value);
     }
 
-    let kv_pairs = tree.traverse();
-    let sorted_keys: Vec<String> = kv_pairs.iter().map(|(k, _)| k.clone()).collect();
+    let sorted_keys: Vec<String> = tree.entries().map(|(k, _)| k.clone()).collect();
     let mut expected_keys = keys.clone();
     expected_keys.sort();
 
     assert_eq!(sorted_keys, expected_keys);
 }
+
+#[test]
+fn test_cursor() {
+    let mut tree = BTree::<i32, i32>::new();
+    let mut keys: Vec<i32> = (0..500).map(|i| i * 2).collect();
+    keys.shuffle(&mut thread_rng());
+    for key in keys.iter() {
+        tree.insert(*key, -key);
+    }
+
+    let mut cursor = tree.cursor();
+    assert_eq!(cursor.seek(&100), Some((&100, &-100)));
+    let mut observed = vec![*cursor.key().unwrap()];
+    observed.push(*cursor.next().unwrap().0);
+    observed.push(*cursor.next().unwrap().0);
+    for _ in 0..3 {
+        observed.push(*cursor.prev().unwrap().0);
+    }
+    assert_eq!(observed, vec![100, 102, 104, 102, 100, 98]);
+    assert_eq!(cursor.value(), Some(&-98));
+
+    // a missing key seeks to the next larger key
+    assert_eq!(cursor.seek(&101).map(|(key, _)| *key), Some(102));
+    assert_eq!(cursor.seek(&1000), None);
+    assert_eq!(cursor.key(), None);
+
+    // a full walk in both directions, from past the end
+    let expected: Vec<i32> = tree.entries().map(|(key, _)| *key).collect();
+    let mut backward = Vec::new();
+    while let Some((key, _)) = cursor.prev() {
+        backward.push(*key);
+    }
+    backward.reverse();
+    assert_eq!(backward, expected);
+    let forward: Vec<i32> = cursor.by_ref().map(|(key, _)| *key).collect();
+    assert_eq!(forward, expected);
+    assert_eq!(cursor.next(), None);
+
+    let empty = BTree::<i32, i32>::new();
+    let mut cursor = empty.cursor();
+    assert_eq!(cursor.next(), None);
+    assert_eq!(cursor.prev(), None);
+    assert_eq!(cursor.seek(&1), None);
+}
+
+// A value that counts how many times it is cloned
+#[derive(Debug, PartialEq)]
+struct Counted(i32);
+
+static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        CLONES.fetch_add(1, Ordering::SeqCst);
+        Counted(self.0)
+    }
+}
+
+#[test]
+fn test_entries_borrow() {
+    let mut tree = BTree::<i32, Counted>::new();
+    let mut keys: Vec<i32> = (0..1000).collect();
+    keys.shuffle(&mut thread_rng());
+    for key in keys.iter() {
+        tree.insert(*key, Counted(-key));
+    }
+
+    // entries() hands out references into the tree, without cloning anything
+    CLONES.store(0, Ordering::SeqCst);
+    let mut count = 0;
+    for (expected, (key, value)) in (0..1000).zip(tree.entries()) {
+        assert_eq!(*key, expected);
+        assert!(std::ptr::eq(value, tree.search(key).unwrap()));
+        count += 1;
+    }
+    assert_eq!(count, 1000);
+    assert_eq!(CLONES.load(Ordering::SeqCst), 0);
+
+    // to_vec() makes owned copies of each value
+    let pairs = tree.to_vec();
+    assert_eq!(CLONES.load(Ordering::SeqCst), 1000);
+    let expected: Vec<(i32, Counted)> = (0..1000).map(|key| (key, Counted(-key))).collect();
+    assert_eq!(pairs, expected);
+
+    assert!(BTree::<i32, Counted>::new().entries().next().is_none());
+}
@@ -1,8 +1,12 @@
 use tempfile::NamedTempFile;
 
-use radarbase::{Database, Table};
+use radarbase::{
+    CompactProgress, Corruption, Database, Durability, Error, Index, NodeType, PageUsage, Table,
+};
 use rand::prelude::SliceRandom;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
 
 const ELEMENTS: usize = 100;
 
@@ -24,7 +28,7 @@ fn persistence() {
     let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
 
     let db = unsafe { Database::open(tmpfile.path()).unwrap() };
-    let mut table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
 
     let pairs = gen_data(100, 16, 20);
 
@@ -37,7 +41,6 @@ fn persistence() {
     }
     txn.commit().unwrap();
 
-    drop(table);
     drop(db);
     let db = unsafe { Database::open(tmpfile.path()).unwrap() };
     let table: Table<[u8]> = db.open_table(b"x").unwrap();
@@ -54,3 +57,2201 @@ fn persistence() {
         }
     }
 }
+
+#[test]
+fn reopen_reads_header() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let first: Table<u64, u64> = db.open_table(b"first").unwrap();
+    let mut txn = first.begin_write().unwrap();
+    for i in 0..100u64 {
+        txn.insert(&i, &(i * 10)).unwrap();
+    }
+    txn.commit().unwrap();
+    let mut txn = first.begin_write().unwrap();
+    txn.remove(&0).unwrap();
+    txn.commit().unwrap();
+    let version = db.current_version();
+    drop(db);
+
+    // the root page, the page size and the next table id are all read back from the header
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    assert_eq!(db.current_version(), version);
+    let first: Table<u64, u64> = db.open_table(b"first").unwrap();
+    let txn = first.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 99);
+    assert!(txn.get(&0).unwrap().is_none());
+    assert_eq!(txn.get(&42).unwrap().unwrap().to_value(), 420);
+    drop(txn);
+    let mut txn = first.begin_write().unwrap();
+    txn.remove(&42).unwrap();
+    txn.commit().unwrap();
+
+    // a table created after the reopen does not reuse the id of the first
+    let second: Table<u64, u64> = db.open_table(b"second").unwrap();
+    let mut txn = second.begin_write().unwrap();
+    txn.insert(&42, &1).unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let first: Table<u64, u64> = db.open_table(b"first").unwrap();
+    let second: Table<u64, u64> = db.open_table(b"second").unwrap();
+    assert_eq!(first.read_transaction().unwrap().len().unwrap(), 98);
+    assert!(first
+        .read_transaction()
+        .unwrap()
+        .get(&42)
+        .unwrap()
+        .is_none());
+    let txn = second.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 1);
+    assert_eq!(txn.get(&42).unwrap().unwrap().to_value(), 1);
+}
+
+#[test]
+fn get_multi() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    // entries of another table, on both sides of the keys in the tree
+    let other: Table<[u8]> = db.open_table(b"y").unwrap();
+    let mut txn = other.begin_write().unwrap();
+    txn.insert(b"hello", b"other").unwrap();
+    txn.commit().unwrap();
+
+    let pairs = gen_data(500, 16, 20);
+    let mut txn = table.begin_write().unwrap();
+    for (key, value) in pairs.iter() {
+        txn.insert(key, value).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let missing: Vec<Vec<u8>> = vec![b"hello".to_vec(), vec![], vec![0xff; 32]];
+    let mut keys: Vec<&[u8]> = pairs.iter().map(|(key, _)| key.as_slice()).collect();
+    keys.extend(missing.iter().map(|key| key.as_slice()));
+    // a key that is requested twice is returned twice
+    keys.push(pairs[0].0.as_slice());
+    keys.shuffle(&mut rand::thread_rng());
+
+    let txn = table.read_transaction().unwrap();
+    let found = txn.get_multi(&keys).unwrap();
+    assert_eq!(found.len(), keys.len());
+    for (key, value) in keys.iter().zip(found) {
+        let expected = txn.get(key).unwrap().map(|value| value.to_vec());
+        assert_eq!(value.map(|value| value.to_vec()), expected);
+    }
+    assert!(txn.get_multi(&[]).unwrap().is_empty());
+
+    let empty: Table<[u8]> = db.open_table(b"z").unwrap();
+    let txn = empty.read_transaction().unwrap();
+    assert!(txn
+        .get_multi(&keys[..2])
+        .unwrap()
+        .iter()
+        .all(Option::is_none));
+}
+
+#[test]
+fn get_multi_snapshot() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..100u64 {
+        txn.insert(&i, &i).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let read_txn = table.read_transaction().unwrap();
+    let keys: Vec<u64> = (0..200).rev().collect();
+    let keys: Vec<&u64> = keys.iter().collect();
+    let found = read_txn.get_multi(&keys).unwrap();
+
+    // the guards all borrow the snapshot of the read transaction, so later commits don't
+    // change them
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..200u64 {
+        txn.insert(&i, &(i + 1000)).unwrap();
+    }
+    txn.commit().unwrap();
+
+    for (key, value) in keys.iter().zip(found.iter()) {
+        match value {
+            Some(value) => assert_eq!(value.to_value(), **key),
+            None => assert!(**key >= 100),
+        }
+    }
+    assert_eq!(found.iter().flatten().count(), 100);
+}
+
+#[test]
+fn database_options() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe {
+        Database::builder()
+            .set_initial_size(64 * 1024)
+            .set_max_size(4 * 1024 * 1024)
+            .open(tmpfile.path())
+            .unwrap()
+    };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let pairs = gen_data(1000, 16, 100);
+    let mut txn = table.begin_write().unwrap();
+    for (key, value) in pairs.iter() {
+        txn.insert(key, value).unwrap();
+    }
+    txn.commit().unwrap();
+    // the file grew beyond its initial size, but not past the max size
+    let len = tmpfile.path().metadata().unwrap().len();
+    assert!(len > 64 * 1024);
+    assert!(len <= 4 * 1024 * 1024);
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    for (key, value) in pairs.iter() {
+        assert_eq!(value, txn.get(key).unwrap().unwrap().as_ref());
+    }
+}
+
+#[test]
+fn open_with_size() {
+    let page_size = page_size::get();
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    // rounded up to 64 pages
+    let db = unsafe { Database::open_with_size(tmpfile.path(), 63 * page_size + 1).unwrap() };
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..1000u64 {
+        txn.insert(&i, b"value").unwrap();
+    }
+    assert!(matches!(txn.commit(), Err(Error::OutOfSpace)));
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..10u64 {
+        txn.insert(&i, b"value").unwrap();
+    }
+    txn.commit().unwrap();
+    assert!(tmpfile.path().metadata().unwrap().len() <= 64 * page_size as u64);
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    assert_eq!(table.read_transaction().unwrap().len().unwrap(), 10);
+
+    // the smallest size fits a table with one entry
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open_with_size(tmpfile.path(), 3 * page_size).unwrap() };
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(&0, b"value").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(table.read_transaction().unwrap().len().unwrap(), 1);
+
+    for size in [page_size, 2 * page_size] {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let result = unsafe { Database::open_with_size(tmpfile.path(), size) };
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert!(matches!(Database::open_in_memory(size), Err(Error::Io(_))));
+    }
+    let db = Database::open_in_memory(3 * page_size).unwrap();
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(&0, b"value").unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn estimated_rebuild_size() {
+    let page_size = page_size::get();
+    let db = Database::open_in_memory(64 * page_size).unwrap();
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let free_space = |db: &Database| {
+        let stats = db.stats().unwrap();
+        stats.mapped_bytes - stats.next_free_page as usize * stats.page_size
+    };
+
+    // too large a commit is known to fail before it is attempted
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..1000u64 {
+        txn.insert(&i, b"value").unwrap();
+    }
+    let estimate = txn.estimated_rebuild_size().unwrap().unwrap();
+    assert!(estimate > free_space(&db), "{}", estimate);
+    assert!(matches!(txn.commit(), Err(Error::OutOfSpace)));
+
+    // and a smaller one fits, and allocates as much as estimated
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..20u64 {
+        txn.insert(&i, b"value").unwrap();
+    }
+    let estimate = txn.estimated_rebuild_size().unwrap().unwrap();
+    assert!(estimate <= free_space(&db), "{}", estimate);
+    let before = db.stats().unwrap().next_free_page;
+    txn.commit().unwrap();
+    let allocated = (db.stats().unwrap().next_free_page - before) as usize * page_size;
+    assert_eq!(allocated, estimate);
+
+    // a commit that is small compared to the database inserts the entries one by one
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(&100, b"value").unwrap();
+    assert_eq!(txn.estimated_rebuild_size().unwrap(), None);
+}
+
+#[test]
+fn invalid_database_options() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let result = unsafe {
+        Database::builder()
+            .set_initial_size(8 * 1024 * 1024)
+            .set_max_size(4 * 1024 * 1024)
+            .open(tmpfile.path())
+    };
+    assert!(matches!(result, Err(Error::Io(_))));
+
+    let result = unsafe {
+        Database::builder()
+            .set_page_size(page_size::get() + 1)
+            .open(tmpfile.path())
+    };
+    assert!(matches!(result, Err(Error::Io(_))));
+}
+
+#[test]
+fn usable_leaf_capacity() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let capacity = db.usable_leaf_capacity();
+    assert!(capacity < db.page_size());
+
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"k", &vec![1; capacity - 1]).unwrap();
+    assert!(matches!(
+        txn.insert(b"l", &vec![1; capacity]),
+        Err(Error::ValueTooLarge(_))
+    ));
+    txn.insert_reserve(b"m", capacity - 1).unwrap();
+    txn.commit().unwrap();
+
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.get(b"k").unwrap().unwrap().len(), capacity - 1);
+    assert_eq!(txn.get(b"m").unwrap().unwrap().len(), capacity - 1);
+}
+
+#[test]
+fn create_locks_file() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = Database::create(tmpfile.path()).unwrap();
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"world").unwrap();
+    txn.commit().unwrap();
+    assert!(matches!(
+        Database::create(tmpfile.path()),
+        Err(Error::AlreadyOpen)
+    ));
+    // the file is left untouched by the failed open
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(b"world", txn.get(b"hello").unwrap().unwrap().as_ref());
+    drop(txn);
+
+    // the lock is released when the database is closed
+    drop(db);
+    let db = Database::create(tmpfile.path()).unwrap();
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(b"world", txn.get(b"hello").unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn large_page_size() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let page_size = 4 * page_size::get();
+    let db = unsafe {
+        Database::builder()
+            .set_page_size(page_size)
+            .open(tmpfile.path())
+            .unwrap()
+    };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    // too large for a page of the default size
+    let value = vec![1u8; page_size::get()];
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", &value).unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    // the page size is stored in the database
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(value, txn.get(b"hello").unwrap().unwrap().to_vec());
+}
+
+#[test]
+fn reopen_keeps_page_size() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let page_size = 4 * page_size::get();
+    let db = unsafe {
+        Database::builder()
+            .set_page_size(page_size)
+            .set_initial_size(2 * page_size)
+            .open(tmpfile.path())
+            .unwrap()
+    };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"world").unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    // the sizes are rounded to the stored page size, not the one given to the builder,
+    // which would grow the file to a length that is not a whole number of pages
+    let db = unsafe {
+        Database::builder()
+            .set_initial_size(9 * page_size::get())
+            .open(tmpfile.path())
+            .unwrap()
+    };
+    assert_eq!(db.page_size(), page_size);
+    assert_eq!(
+        tmpfile.as_file().metadata().unwrap().len() as usize % page_size,
+        0
+    );
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(
+        b"world",
+        txn.get(b"hello").unwrap().unwrap().to_vec().as_slice()
+    );
+}
+
+#[test]
+fn stream_value() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    // a page must hold the whole value
+    let db = unsafe {
+        Database::builder()
+            .set_page_size(1024 * 1024)
+            .set_initial_size(8 * 1024 * 1024)
+            .open(tmpfile.path())
+            .unwrap()
+    };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let data: Vec<u8> = (0..256 * 1024).map(|_| rand::thread_rng().gen()).collect();
+    let mut txn = table.begin_write().unwrap();
+    let mut writer = txn.value_writer(b"blob").unwrap();
+    // io::copy writes in chunks
+    std::io::copy(&mut data.as_slice(), &mut writer).unwrap();
+    drop(writer);
+    txn.commit().unwrap();
+
+    let txn = table.read_transaction().unwrap();
+    let mut guard = txn.get(b"blob").unwrap().unwrap();
+    let mut output = vec![];
+    std::io::copy(&mut guard, &mut output).unwrap();
+    assert_eq!(output, data);
+    assert_eq!(guard.len(), data.len());
+
+    // the writer refuses a value larger than a page
+    let mut txn = table.begin_write().unwrap();
+    let mut writer = txn.value_writer(b"huge").unwrap();
+    let result = std::io::Write::write_all(&mut writer, &vec![0; 1024 * 1024]);
+    let err = result.unwrap_err().into_inner().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::ValueTooLarge(_))
+    ));
+}
+
+#[test]
+fn list_empty_tables() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    // opening a table registers it, even if nothing is ever written to it
+    for name in [b"c", b"a", b"b"] {
+        let _: Table<[u8]> = db.open_table(name).unwrap();
+    }
+    let table: Table<[u8]> = db.open_table(b"a").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"world").unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    assert_eq!(
+        db.list_tables().unwrap(),
+        vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+    );
+}
+
+#[test]
+fn list_and_delete_tables() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    for name in [b"a", b"b", b"c"] {
+        let table: Table<[u8]> = db.open_table(name).unwrap();
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(b"hello", name).unwrap();
+        txn.commit().unwrap();
+    }
+    assert_eq!(
+        db.list_tables().unwrap(),
+        vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+    );
+
+    assert!(db.delete_table(b"b").unwrap());
+    assert!(!db.delete_table(b"b").unwrap());
+    assert!(!db.delete_table(b"d").unwrap());
+    assert_eq!(
+        db.list_tables().unwrap(),
+        vec![b"a".to_vec(), b"c".to_vec()]
+    );
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    assert_eq!(
+        db.list_tables().unwrap(),
+        vec![b"a".to_vec(), b"c".to_vec()]
+    );
+    for name in [b"a", b"c"] {
+        let table: Table<[u8]> = db.open_table(name).unwrap();
+        let txn = table.read_transaction().unwrap();
+        assert_eq!(name, txn.get(b"hello").unwrap().unwrap().as_ref());
+    }
+    assert!(matches!(
+        db.open_existing_table::<[u8], [u8]>(b"b"),
+        Err(Error::TableNotFound(_))
+    ));
+
+    // a deleted table is created again empty, with a new id, rather than resurrected
+    let table: Table<[u8]> = db.open_table(b"b").unwrap();
+    assert!(table.read_transaction().unwrap().is_empty().unwrap());
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"new", b"b").unwrap();
+    txn.commit().unwrap();
+    let a: Table<[u8]> = db.open_table(b"a").unwrap();
+    assert!(a.read_transaction().unwrap().get(b"new").unwrap().is_none());
+
+    assert!(db.delete_table(b"a").unwrap());
+    assert!(db.delete_table(b"b").unwrap());
+    assert!(db.delete_table(b"c").unwrap());
+    assert!(db.list_tables().unwrap().is_empty());
+}
+
+#[test]
+fn delete_table_conflicts_with_write() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"committed", b"1").unwrap();
+    txn.commit().unwrap();
+
+    // the transaction would otherwise commit its entry to a table that no longer exists
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"staged", b"2").unwrap();
+    assert!(matches!(db.delete_table(b"x"), Err(Error::WriteConflict)));
+    txn.commit().unwrap();
+    assert_eq!(db.list_tables().unwrap(), vec![b"x".to_vec()]);
+    assert_eq!(table.read_transaction().unwrap().len().unwrap(), 2);
+
+    assert!(db.delete_table(b"x").unwrap());
+    assert!(db.list_tables().unwrap().is_empty());
+    assert_eq!(db.stats().unwrap().table_entries, vec![]);
+}
+
+#[test]
+fn read_only() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"world").unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    let mut permissions = tmpfile.path().metadata().unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(tmpfile.path(), permissions).unwrap();
+
+    let db = unsafe { Database::open_read_only(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(b"world", txn.get(b"hello").unwrap().unwrap().as_ref());
+    assert_eq!(txn.len().unwrap(), 1);
+
+    assert!(matches!(table.begin_write(), Err(Error::ReadOnly)));
+    assert!(matches!(db.delete_table(b"x"), Err(Error::ReadOnly)));
+    assert!(matches!(
+        db.open_table::<[u8], [u8]>(b"y"),
+        Err(Error::TableNotFound(_))
+    ));
+}
+
+#[test]
+fn in_memory() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let file_db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let memory_db = Database::open_in_memory(1024 * 1024 * 1024).unwrap();
+
+    let pairs = gen_data(1000, 16, 20);
+    let mut results = vec![];
+    for db in [&file_db, &memory_db] {
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for (key, value) in pairs.iter() {
+            txn.insert(key, value).unwrap();
+        }
+        txn.commit().unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for (key, _) in pairs.iter().step_by(3) {
+            txn.remove(key).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let txn = table.read_transaction().unwrap();
+        for (i, (key, value)) in pairs.iter().enumerate() {
+            let result = txn.get(key).unwrap();
+            if i % 3 == 0 {
+                assert!(result.is_none());
+            } else {
+                assert_eq!(value, result.unwrap().as_ref());
+            }
+        }
+        let mut range_len = 0;
+        let mut iter = txn.get_range(pairs[0].0.as_slice()..).unwrap();
+        while iter.next().is_some() {
+            range_len += 1;
+        }
+        results.push((txn.len().unwrap(), range_len));
+    }
+    assert_eq!(results[0], results[1]);
+
+    assert!(matches!(Database::open_in_memory(0), Err(Error::Io(_))));
+}
+
+#[test]
+fn eventual_durability() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let pairs = gen_data(100, 16, 20);
+    for (key, value) in pairs.iter() {
+        let mut txn = table.begin_write().unwrap();
+        txn.set_durability(Durability::Eventual);
+        txn.insert(key, value).unwrap();
+        txn.commit().unwrap();
+    }
+    db.flush().unwrap();
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), pairs.len());
+    for (key, value) in pairs.iter() {
+        assert_eq!(value, txn.get(key).unwrap().unwrap().as_ref());
+    }
+}
+
+#[test]
+fn verify() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for (key, value) in gen_data(100, 16, 20) {
+        txn.insert(&key, &value).unwrap();
+    }
+    txn.commit().unwrap();
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    assert!(report.pages_visited > 1);
+    drop(db);
+
+    // Point the first child of the root at a page that was never allocated
+    let mut data = std::fs::read(tmpfile.path()).unwrap();
+    let page_size = page_size::get();
+    // the root page number follows the magic number, format version and allocator state
+    let root = u64::from_be_bytes(data[13..21].try_into().unwrap()) as usize;
+    let root_page = &mut data[(root * page_size)..((root + 1) * page_size)];
+    assert_eq!(root_page[0], 2); // internal node
+    let key_len = u64::from_be_bytes(root_page[1..9].try_into().unwrap()) as usize;
+    let lte_offset = 17 + key_len;
+    root_page[lte_offset..(lte_offset + 8)].copy_from_slice(&u64::MAX.to_be_bytes());
+    std::fs::write(tmpfile.path(), &data).unwrap();
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let report = db.verify().unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(
+        report.errors,
+        vec![format!("page {} is out of bounds", u64::MAX)]
+    );
+}
+
+#[test]
+fn corrupted_node_type() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut pairs = gen_data(100, 16, 20);
+    let mut txn = table.begin_write().unwrap();
+    for (key, value) in pairs.iter() {
+        txn.insert(key, value).unwrap();
+    }
+    txn.commit().unwrap();
+    drop(db);
+
+    // Overwrite the type byte of the root's gt child, which holds the largest keys. The table's
+    // catalog entry sorts first, so it stays readable
+    let mut data = std::fs::read(tmpfile.path()).unwrap();
+    let page_size = page_size::get();
+    let root = u64::from_be_bytes(data[13..21].try_into().unwrap()) as usize;
+    let root_page = &data[(root * page_size)..((root + 1) * page_size)];
+    assert_eq!(root_page[0], 2); // internal node
+    let key_len = u64::from_be_bytes(root_page[1..9].try_into().unwrap()) as usize;
+    let gt_offset = 17 + key_len + 8;
+    let gt = u64::from_be_bytes(root_page[gt_offset..(gt_offset + 8)].try_into().unwrap());
+    data[gt as usize * page_size] = 0xff;
+    std::fs::write(tmpfile.path(), &data).unwrap();
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    pairs.sort();
+    let (largest, _) = pairs.last().unwrap();
+    let txn = table.read_transaction().unwrap();
+    let expected = Corruption::InvalidNodeType {
+        page: gt,
+        byte: 0xff,
+    };
+    assert!(
+        matches!(txn.get(largest), Err(Error::Corruption(corruption)) if corruption == expected)
+    );
+    assert!(matches!(txn.len(), Err(Error::Corruption(_))));
+
+    // iteration stops at the corrupted page, and reports it
+    let mut iter = txn.iter().unwrap();
+    let mut count = 0;
+    while iter.next().is_some() {
+        count += 1;
+    }
+    assert!(count < pairs.len());
+    assert!(matches!(iter.take_error(), Some(Error::Corruption(_))));
+
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(largest, b"value").unwrap();
+    assert!(matches!(txn.commit(), Err(Error::Corruption(_))));
+}
+
+// Returns the page number of the lte or gt child of an internal node in the file
+fn child_page(data: &[u8], page: u64, gt: bool) -> u64 {
+    let page_size = page_size::get();
+    let node = &data[(page as usize * page_size)..((page as usize + 1) * page_size)];
+    assert_eq!(node[0], 2); // internal node
+    let key_len = u64::from_be_bytes(node[1..9].try_into().unwrap()) as usize;
+    let offset = 17 + key_len + if gt { 8 } else { 0 };
+    u64::from_be_bytes(node[offset..(offset + 8)].try_into().unwrap())
+}
+
+#[test]
+fn unbounded_range_stops_at_table_end() {
+    let write = |table: &mut Table<u64, u64>, count: u64| {
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..count {
+            txn.insert(&i, &i).unwrap();
+        }
+        txn.commit().unwrap();
+    };
+    // corrupts the page found by following `path` from the root, and reopens the database
+    let corrupt = |tmpfile: &NamedTempFile, path: &[bool]| {
+        let mut data = std::fs::read(tmpfile.path()).unwrap();
+        let mut page = u64::from_be_bytes(data[13..21].try_into().unwrap());
+        for gt in path {
+            page = child_page(&data, page, *gt);
+        }
+        data[page as usize * page_size::get()] = 0xff;
+        std::fs::write(tmpfile.path(), &data).unwrap();
+        unsafe { Database::open(tmpfile.path()).unwrap() }
+    };
+
+    // the root's gt sub-tree only holds entries of the second table, which a scan of the
+    // first table must stop before
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    write(&mut db.open_table(b"first").unwrap(), 10);
+    write(&mut db.open_table(b"second").unwrap(), 100);
+    drop(db);
+    let db = corrupt(&tmpfile, &[true]);
+    let table: Table<u64, u64> = db.open_table(b"first").unwrap();
+    let txn = table.read_transaction().unwrap();
+    let mut iter = txn.get_range(..).unwrap();
+    let mut count = 0;
+    while iter.next().is_some() {
+        count += 1;
+    }
+    assert!(iter.take_error().is_none());
+    assert_eq!(count, 10);
+    let start = 5u64.to_be_bytes();
+    let mut iter = txn.get_range(&start[..]..).unwrap();
+    let mut count = 0;
+    while iter.next().is_some() {
+        count += 1;
+    }
+    assert!(iter.take_error().is_none());
+    assert_eq!(count, 5);
+
+    // and in reverse, a scan of the second table must stop before the first table's entries
+    // in the gt sub-tree of the root's lte child, which is clear of the table names
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    write(&mut db.open_table(b"first").unwrap(), 100);
+    write(&mut db.open_table(b"second").unwrap(), 10);
+    drop(db);
+    let db = corrupt(&tmpfile, &[false, true]);
+    let table: Table<u64, u64> = db.open_table(b"second").unwrap();
+    let txn = table.read_transaction().unwrap();
+    let mut iter = txn.get_range_reversed(..).unwrap();
+    let mut keys = vec![];
+    for entry in iter.by_ref() {
+        keys.push(u64::from_be_bytes(entry.key_value().0.try_into().unwrap()));
+    }
+    assert!(iter.take_error().is_none());
+    assert_eq!(keys, (0..10u64).rev().collect::<Vec<_>>());
+}
+
+#[test]
+fn export_import() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let pairs = gen_data(100, 16, 20);
+    let mut txn = table.begin_write().unwrap();
+    for (key, value) in pairs.iter() {
+        txn.insert(key, value).unwrap();
+    }
+    txn.commit().unwrap();
+    let mut exported = vec![];
+    table
+        .read_transaction()
+        .unwrap()
+        .export_to(&mut exported)
+        .unwrap();
+
+    let tmpfile2: NamedTempFile = NamedTempFile::new().unwrap();
+    let db2 = unsafe { Database::open(tmpfile2.path()).unwrap() };
+    let table2: Table<[u8]> = db2.open_table(b"y").unwrap();
+    let mut txn = table2.begin_write().unwrap();
+    txn.import_from(&mut exported.as_slice()).unwrap();
+    txn.commit().unwrap();
+
+    let txn = table2.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), pairs.len());
+    for (key, value) in pairs.iter() {
+        assert_eq!(value, txn.get(key).unwrap().unwrap().as_ref());
+    }
+    let mut reexported = vec![];
+    txn.export_to(&mut reexported).unwrap();
+    assert_eq!(exported, reexported);
+
+    // a truncated export is detected, and none of its entries are staged
+    let table3: Table<[u8]> = db2.open_table(b"z").unwrap();
+    let mut txn = table3.begin_write().unwrap();
+    txn.insert(b"staged", b"value").unwrap();
+    let truncated = &exported[..(exported.len() - 1)];
+    assert!(matches!(
+        txn.import_from(&mut &truncated[..]),
+        Err(Error::Corruption(Corruption::ExportTruncated))
+    ));
+    assert_eq!(txn.len().unwrap(), 1);
+    assert!(txn.get(&pairs[0].0).unwrap().is_none());
+    txn.commit().unwrap();
+    let txn = table3.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 1);
+    assert_eq!(b"value", txn.get(b"staged").unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn table_ids() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    // tables are not created in name order, so that the newest table is not the last by name
+    for name in [b"b", b"a", b"c"] {
+        let table: Table<[u8]> = db.open_table(name).unwrap();
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(b"hello", name).unwrap();
+        txn.commit().unwrap();
+    }
+    // a deleted table's id is not reused
+    assert!(db.delete_table(b"c").unwrap());
+    let table: Table<[u8]> = db.open_table(b"d").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"d").unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    for name in [b"a", b"b", b"d"] {
+        let table: Table<[u8]> = db.open_table(name).unwrap();
+        let txn = table.read_transaction().unwrap();
+        assert_eq!(txn.len().unwrap(), 1);
+        assert_eq!(name, txn.get(b"hello").unwrap().unwrap().as_ref());
+    }
+    let table: Table<[u8]> = db.open_table(b"c").unwrap();
+    assert!(table.read_transaction().unwrap().is_empty().unwrap());
+}
+
+#[test]
+fn stats() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let stats = db.stats().unwrap();
+    assert_eq!(stats.table_count, 0);
+    assert!(stats.mapped_bytes >= stats.file_bytes);
+
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let _: Table<[u8]> = db.open_table(b"y").unwrap();
+    let before = db.stats().unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"world").unwrap();
+    txn.insert(b"hello2", b"world").unwrap();
+    txn.commit().unwrap();
+
+    let stats = db.stats().unwrap();
+    assert!(stats.next_free_page > before.next_free_page);
+    assert_eq!(stats.table_count, 2);
+    assert_eq!(
+        stats.table_entries,
+        vec![(b"x".to_vec(), 2), (b"y".to_vec(), 0)]
+    );
+    assert!(stats.next_free_page * stats.page_size as u64 <= stats.file_bytes as u64);
+}
+
+#[test]
+fn stats_tree_height() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    assert_eq!(db.stats().unwrap().tree_height, 0);
+
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut height = db.stats().unwrap().tree_height;
+    assert_eq!(height, 1);
+    let mut key = 0u64;
+    for count in [10, 100, 1000] {
+        let mut txn = table.begin_write().unwrap();
+        while key < count {
+            txn.insert(&key, &key).unwrap();
+            key += 1;
+        }
+        txn.commit().unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.tree_height > height, "{}", stats.tree_height);
+        height = stats.tree_height;
+    }
+}
+
+#[test]
+fn invalid_database() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let mut data = vec![0u8; 3 * 4096 + 17];
+    rand::thread_rng().fill(data.as_mut_slice());
+    data[0] = b'x';
+    std::fs::write(tmpfile.path(), &data).unwrap();
+
+    assert!(matches!(
+        unsafe { Database::open(tmpfile.path()) },
+        Err(Error::InvalidDatabase)
+    ));
+    assert!(matches!(
+        unsafe { Database::open_read_only(tmpfile.path()) },
+        Err(Error::InvalidDatabase)
+    ));
+    // the file is left untouched
+    assert_eq!(std::fs::read(tmpfile.path()).unwrap(), data);
+
+    // a file shorter than the magic number
+    std::fs::write(tmpfile.path(), b"ra").unwrap();
+    assert!(matches!(
+        unsafe { Database::open(tmpfile.path()) },
+        Err(Error::InvalidDatabase)
+    ));
+
+    // a database written by an unknown version of the file format
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    drop(unsafe { Database::open(tmpfile.path()).unwrap() });
+    let mut data = std::fs::read(tmpfile.path()).unwrap();
+    assert_eq!(&data[0..4], b"radb");
+    data[4] += 1;
+    std::fs::write(tmpfile.path(), &data).unwrap();
+    assert!(matches!(
+        unsafe { Database::open(tmpfile.path()) },
+        Err(Error::Corruption(Corruption::UnsupportedFormatVersion(_)))
+    ));
+}
+
+#[test]
+fn compact_and_shrink() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let mut db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let pairs = gen_data(1000, 16, 20);
+    {
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        for chunk in pairs.chunks(100) {
+            let mut txn = table.begin_write().unwrap();
+            for (key, value) in chunk {
+                txn.insert(key, value).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        let mut txn = table.begin_write().unwrap();
+        for (key, _) in &pairs[10..] {
+            txn.remove(key).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    let len_before = tmpfile.path().metadata().unwrap().len();
+
+    db.compact_and_shrink().unwrap();
+    let len_after = tmpfile.path().metadata().unwrap().len();
+    assert!(len_after < len_before, "{} {}", len_after, len_before);
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.unreachable_pages, 0);
+
+    let check = |db: &Database| {
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let txn = table.read_transaction().unwrap();
+        assert_eq!(txn.len().unwrap(), 10);
+        for (key, value) in &pairs[..10] {
+            assert_eq!(value.as_slice(), txn.get(key).unwrap().unwrap().as_ref());
+        }
+    };
+    check(&db);
+
+    // the database can still grow, and survives a reopen
+    {
+        let table: Table<[u8]> = db.open_table(b"y").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for (key, value) in &pairs {
+            txn.insert(key, value).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    drop(db);
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    check(&db);
+    let table: Table<[u8]> = db.open_table(b"y").unwrap();
+    assert_eq!(table.read_transaction().unwrap().len().unwrap(), 1000);
+}
+
+#[test]
+fn compact_and_shrink_with_lower_fill_factor() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    {
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..2000u64 {
+            txn.insert(&i, b"value").unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    // the rebuilt tree needs about twice the pages of the densely packed one
+    let mut db = unsafe {
+        Database::builder()
+            .set_fill_factor(0.5)
+            .open(tmpfile.path())
+            .unwrap()
+    };
+    let before = db.stats().unwrap().next_free_page;
+    db.compact_and_shrink().unwrap();
+    assert!(db.stats().unwrap().next_free_page > before);
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 2000);
+    for i in 0..2000u64 {
+        assert_eq!(b"value", txn.get(&i).unwrap().unwrap().as_ref());
+    }
+}
+
+#[test]
+fn compact_and_shrink_out_of_space() {
+    let page_size = page_size::get();
+    let mut db = Database::open_in_memory(64 * page_size).unwrap();
+    {
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..40u64 {
+            txn.insert(&i, b"value").unwrap();
+        }
+        txn.commit().unwrap();
+        let mut txn = table.begin_write().unwrap();
+        txn.remove(&0).unwrap();
+        txn.commit().unwrap();
+    }
+    // a copy of the tree does not fit after the allocated pages
+    let before = db.stats().unwrap();
+    assert!(before.free_pages > 0);
+    assert!(matches!(db.compact_and_shrink(), Err(Error::OutOfSpace)));
+
+    // and the free pages are still reused
+    let after = db.stats().unwrap();
+    assert_eq!(after.next_free_page, before.next_free_page);
+    assert_eq!(after.free_pages, before.free_pages);
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.remove(&1).unwrap();
+    txn.commit().unwrap();
+    assert!(db.stats().unwrap().next_free_page <= before.next_free_page);
+    assert_eq!(table.read_transaction().unwrap().len().unwrap(), 38);
+}
+
+#[test]
+fn pages_are_reused() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..100u64 {
+        txn.insert(&i, &i).unwrap();
+    }
+    txn.commit().unwrap();
+    // each commit copies a path of the tree, and frees the path that it replaced
+    let overwrite = |rounds: std::ops::Range<u64>| {
+        for round in rounds {
+            let mut txn = table.begin_write().unwrap();
+            txn.insert(&(round % 100), &(round + 100)).unwrap();
+            txn.commit().unwrap();
+        }
+    };
+    overwrite(0..100);
+    let allocated = db.stats().unwrap().next_free_page;
+    overwrite(100..3000);
+    assert!(db.stats().unwrap().next_free_page <= allocated);
+
+    // the versions that a reader and a checkpoint hold keep their pages
+    let reader = table.read_transaction().unwrap();
+    let checkpoint = db.checkpoint();
+    overwrite(3000..3100);
+    for i in 0..100u64 {
+        assert_eq!(reader.get(&i).unwrap().unwrap().to_value(), 2900 + i + 100);
+    }
+    assert!(db.stats().unwrap().next_free_page > allocated);
+    drop(reader);
+    let at_checkpoint = db.read_at::<u64, u64>(b"x", checkpoint).unwrap();
+    assert_eq!(at_checkpoint.get(&0).unwrap().unwrap().to_value(), 3000);
+    drop(at_checkpoint);
+    assert!(db.release_checkpoint(checkpoint));
+    let allocated = db.stats().unwrap().next_free_page;
+    overwrite(3100..4000);
+    assert!(db.stats().unwrap().next_free_page <= allocated);
+
+    // a commit that is not flushed may still be read from disk after a crash, so the pages
+    // that it superseded are only freed once it is flushed
+    let free_pages = db.stats().unwrap().free_pages;
+    for round in 4000..4010u64 {
+        let mut txn = table.begin_write().unwrap();
+        txn.set_durability(Durability::Eventual);
+        txn.insert(&(round % 100), &(round + 100)).unwrap();
+        txn.commit().unwrap();
+    }
+    assert!(db.stats().unwrap().free_pages < free_pages);
+    db.flush().unwrap();
+    assert!(db.stats().unwrap().free_pages >= free_pages);
+
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    let txn = table.read_transaction().unwrap();
+    for i in 0..100u64 {
+        let round = if i < 10 { 4000 + i } else { 3900 + i };
+        assert_eq!(txn.get(&i).unwrap().unwrap().to_value(), round + 100);
+    }
+}
+
+#[test]
+fn free_pages_persist() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..100u64 {
+        txn.insert(&i, &i).unwrap();
+    }
+    txn.commit().unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..50u64 {
+        txn.remove(&i).unwrap();
+    }
+    txn.commit().unwrap();
+    let stats = db.stats().unwrap();
+    assert!(stats.free_pages > 0);
+    drop(db);
+
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let reopened = db.stats().unwrap();
+    assert_eq!(reopened.free_pages, stats.free_pages);
+    assert_eq!(reopened.next_free_page, stats.next_free_page);
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    // the free pages are reused, rather than growing the file
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(&0, &0).unwrap();
+    txn.commit().unwrap();
+    assert!(db.stats().unwrap().next_free_page <= stats.next_free_page);
+}
+
+#[test]
+fn compact() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..10_000u64 {
+        txn.insert(&i, &i).unwrap();
+    }
+    txn.commit().unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..9_000u64 {
+        txn.remove(&i).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let txn = table.begin_write().unwrap();
+    assert!(matches!(db.compact(), Err(Error::WriteConflict)));
+    drop(txn);
+
+    let before = db.stats().unwrap();
+    db.compact().unwrap();
+    let after = db.stats().unwrap();
+    assert!(
+        after.next_free_page * 4 < before.next_free_page,
+        "{} {}",
+        after.next_free_page,
+        before.next_free_page
+    );
+    assert!(after.file_bytes < before.file_bytes);
+
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 1000);
+    for i in 9_000..10_000u64 {
+        assert_eq!(txn.get(&i).unwrap().unwrap().to_value(), i);
+    }
+}
+
+#[test]
+fn compact_step() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let pairs = gen_data(1000, 16, 20);
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    for chunk in pairs.chunks(100) {
+        let mut txn = table.begin_write().unwrap();
+        for (key, value) in chunk {
+            txn.insert(key, value).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    // a reader of a version from before the deletes can still read it, all the way through
+    let read_txn = table.read_transaction().unwrap();
+    let snapshot: BTreeMap<Vec<u8>, Vec<u8>> = pairs.iter().cloned().collect();
+    for chunk in pairs[100..].chunks(50) {
+        let mut txn = table.begin_write().unwrap();
+        for (key, _) in chunk {
+            txn.remove(key).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    let mut expected: BTreeMap<Vec<u8>, Vec<u8>> = pairs[..100].iter().cloned().collect();
+    let pages_before = db.stats().unwrap().next_free_page;
+    let len_before = tmpfile.path().metadata().unwrap().len();
+
+    // a writer keeps compaction out, since it may have flushed pages that look unused
+    let txn = table.begin_write().unwrap();
+    assert!(matches!(db.compact_step(64), Err(Error::WriteConflict)));
+    txn.abort().unwrap();
+    // moving a page below the root takes copying the nodes above it too, so once the root
+    // has moved, a budget of one page is too small
+    let mut steps = 0;
+    let err = loop {
+        match db.compact_step(1) {
+            Ok(progress) => assert_eq!(progress, CompactProgress::Pending),
+            Err(err) => break err,
+        }
+        steps += 1;
+        assert!(steps < 2, "{}", steps);
+    };
+    assert!(matches!(err, Error::CompactBudgetTooSmall(1)));
+
+    let mut steps = 0;
+    while db.compact_step(16).unwrap() == CompactProgress::Pending {
+        steps += 1;
+        // writes can be interleaved with the steps
+        if steps % 3 == 0 {
+            let mut txn = table.begin_write().unwrap();
+            let key = (steps as u64).to_be_bytes();
+            txn.insert(&key, b"value").unwrap();
+            txn.commit().unwrap();
+            expected.insert(key.to_vec(), b"value".to_vec());
+        }
+        assert!(steps < 1000);
+    }
+    assert_eq!(read_txn.len().unwrap(), snapshot.len());
+    for (key, value) in &snapshot {
+        assert_eq!(
+            read_txn.get(key).unwrap().unwrap().as_ref(),
+            value.as_slice()
+        );
+    }
+    drop(read_txn);
+
+    // without the reader, its pages are reclaimed too
+    while db.compact_step(16).unwrap() == CompactProgress::Pending {
+        steps += 1;
+    }
+    // each step moves a bounded number of pages
+    assert!(steps > 3, "{}", steps);
+    let pages_after = db.stats().unwrap().next_free_page;
+    assert!(
+        pages_after < pages_before / 2,
+        "{} {}",
+        pages_after,
+        pages_before
+    );
+    assert!(tmpfile.path().metadata().unwrap().len() < len_before);
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+
+    let check = |db: &Database| {
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let txn = table.read_transaction().unwrap();
+        assert_eq!(txn.len().unwrap(), expected.len());
+        for (key, value) in &expected {
+            assert_eq!(txn.get(key).unwrap().unwrap().as_ref(), value.as_slice());
+        }
+    };
+    check(&db);
+    drop(db);
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    check(&db);
+}
+
+#[test]
+fn len_after_overwrites() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let mut db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    {
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        for i in 0..100u64 {
+            let mut txn = table.begin_write().unwrap();
+            txn.insert(b"key", &i.to_be_bytes()).unwrap();
+            txn.commit().unwrap();
+        }
+        let txn = table.read_transaction().unwrap();
+        assert_eq!(txn.len().unwrap(), 1);
+        let mut iter = txn.get_range(..).unwrap();
+        let mut count = 0;
+        while iter.next().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+    }
+
+    db.compact_and_shrink().unwrap();
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 1);
+    assert_eq!(
+        &99u64.to_be_bytes(),
+        txn.get(b"key").unwrap().unwrap().as_ref()
+    );
+}
+
+#[test]
+fn secondary_index() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let users: Table<u64, str> = db.open_table(b"users").unwrap();
+    let by_name: Table<[u8]> = db.open_table(b"users_by_name").unwrap();
+    let index: Index<u64, str, str> = Index::new(users, by_name, |name| name.to_string());
+
+    let mut txn = index.begin_write().unwrap();
+    for (id, name) in [(1, "alice"), (2, "bob"), (3, "alice"), (4, "carol")] {
+        txn.insert(&id, name).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let ids = |index: &Index<u64, str, str>, name: &str| -> Vec<u64> {
+        index
+            .lookup(name)
+            .unwrap()
+            .map(|key| key.to_value())
+            .collect()
+    };
+    assert_eq!(ids(&index, "alice"), vec![1, 3]);
+    assert_eq!(ids(&index, "bob"), vec![2]);
+    assert!(ids(&index, "al").is_empty());
+    assert!(ids(&index, "dave").is_empty());
+
+    // overwrites and removals move the index entries
+    let mut txn = index.begin_write().unwrap();
+    txn.insert(&3, "bob").unwrap();
+    txn.remove(&4).unwrap();
+    txn.insert(&5, "carol").unwrap();
+    txn.remove(&5).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(ids(&index, "alice"), vec![1]);
+    assert_eq!(ids(&index, "bob"), vec![2, 3]);
+    assert!(ids(&index, "carol").is_empty());
+
+    // aborted changes are not indexed
+    let mut txn = index.begin_write().unwrap();
+    txn.insert(&6, "alice").unwrap();
+    txn.abort().unwrap();
+    assert_eq!(ids(&index, "alice"), vec![1]);
+    let read_txn = index.primary().read_transaction().unwrap();
+    assert_eq!(read_txn.len().unwrap(), 3);
+
+    // the indexed value is encoded as an `IK`, here a number
+    let teams: Table<str, str> = db.open_table(b"teams").unwrap();
+    let by_len: Table<[u8]> = db.open_table(b"teams_by_len").unwrap();
+    let index: Index<str, str, u64> =
+        Index::new(teams, by_len, |members| members.split(',').count() as u64);
+    let mut txn = index.begin_write().unwrap();
+    txn.insert("red", "alice,bob").unwrap();
+    txn.insert("blue", "carol").unwrap();
+    txn.insert("green", "dave,erin").unwrap();
+    txn.commit().unwrap();
+    let names: Vec<String> = index
+        .lookup(&2)
+        .unwrap()
+        .map(|key| key.to_value().to_string())
+        .collect();
+    assert_eq!(names, vec!["green", "red"]);
+}
+
+#[test]
+fn secondary_index_commits_atomically() {
+    let page_size = page_size::get();
+    let db = Database::open_in_memory(64 * page_size).unwrap();
+    let users: Table<u64, str> = db.open_table(b"users").unwrap();
+    let by_name: Table<[u8]> = db.open_table(b"users_by_name").unwrap();
+    let index: Index<u64, str, str> = Index::new(users, by_name, |name| name.to_string());
+
+    // commit until the pages run out, which may happen while applying either table
+    let mut id = 0u64;
+    let err = loop {
+        let mut txn = index.begin_write().unwrap();
+        txn.insert(&id, &format!("user{}", id)).unwrap();
+        match txn.commit() {
+            Ok(()) => id += 1,
+            Err(err) => break err,
+        }
+        assert!(id < 1000, "the database never filled up");
+    };
+    assert!(matches!(err, Error::OutOfSpace));
+    // neither the primary table nor the index has the entry of the failed commit
+    let read_txn = index.primary().read_transaction().unwrap();
+    assert_eq!(read_txn.len().unwrap(), id as usize);
+    assert!(read_txn.get(&id).unwrap().is_none());
+    assert_eq!(index.lookup(&format!("user{}", id)).unwrap().count(), 0);
+    assert_eq!(index.lookup("user0").unwrap().count(), 1);
+}
+
+#[test]
+fn checkpoints() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let mut db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let empty = db.checkpoint();
+    let checkpoint = {
+        let table: Table<[u8]> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(b"hello", b"world").unwrap();
+        txn.insert(b"removed", b"1").unwrap();
+        txn.commit().unwrap();
+        let checkpoint = db.checkpoint();
+        // a second checkpoint of the same version holds it too
+        assert_eq!(db.checkpoint(), checkpoint);
+
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(b"hello", b"changed").unwrap();
+        txn.remove(b"removed").unwrap();
+        txn.insert(b"added", b"2").unwrap();
+        txn.commit().unwrap();
+        let other: Table<[u8]> = db.open_table(b"y").unwrap();
+        let mut txn = other.begin_write().unwrap();
+        txn.insert(b"a", b"b").unwrap();
+        txn.commit().unwrap();
+        checkpoint
+    };
+
+    let txn = db.read_at::<[u8], [u8]>(b"x", checkpoint).unwrap();
+    assert_eq!(txn.version(), checkpoint);
+    assert_eq!(b"world", txn.get(b"hello").unwrap().unwrap().as_ref());
+    assert_eq!(b"1", txn.get(b"removed").unwrap().unwrap().as_ref());
+    assert!(txn.get(b"added").unwrap().is_none());
+    assert_eq!(txn.len().unwrap(), 2);
+    drop(txn);
+    assert!(matches!(
+        db.read_at::<[u8], [u8]>(b"y", checkpoint),
+        Err(Error::TableNotFound(_))
+    ));
+    assert!(matches!(
+        db.read_at::<[u8], [u8]>(b"x", empty),
+        Err(Error::TableNotFound(_))
+    ));
+
+    // compaction would overwrite the checkpoints
+    assert!(matches!(
+        db.compact_and_shrink(),
+        Err(Error::CheckpointHeld)
+    ));
+    assert!(db.release_checkpoint(checkpoint));
+    let txn = db.read_at::<[u8], [u8]>(b"x", checkpoint).unwrap();
+    assert_eq!(b"world", txn.get(b"hello").unwrap().unwrap().as_ref());
+    drop(txn);
+    assert!(db.release_checkpoint(checkpoint));
+    assert!(!db.release_checkpoint(checkpoint));
+    assert!(matches!(
+        db.read_at::<[u8], [u8]>(b"x", checkpoint),
+        Err(Error::CheckpointNotFound(_))
+    ));
+    assert!(db.release_checkpoint(empty));
+    db.compact_and_shrink().unwrap();
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(b"changed", txn.get(b"hello").unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn out_of_space() {
+    let page_size = page_size::get();
+    let db = Database::open_in_memory(64 * page_size).unwrap();
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..1000u64 {
+        txn.insert(&i, b"value").unwrap();
+    }
+    // the rebuilt tree would not fit, which is detected before anything is written
+    assert!(matches!(txn.commit(), Err(Error::OutOfSpace)));
+
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..10u64 {
+        txn.insert(&i, b"value").unwrap();
+    }
+    txn.commit().unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 10);
+}
+
+#[test]
+fn out_of_space_on_insert() {
+    let page_size = page_size::get();
+    let db = Database::open_in_memory(64 * page_size).unwrap();
+    let table: Table<u64> = db.open_table(b"x").unwrap();
+    // small commits insert into the tree, instead of rebuilding it, and each allocates a
+    // new path to the root, until the pages run out
+    let mut committed = 0;
+    let err = loop {
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(&committed, b"value").unwrap();
+        match txn.commit() {
+            Ok(()) => committed += 1,
+            Err(err) => break err,
+        }
+        assert!(committed < 1000, "the database never filled up");
+    };
+    assert!(matches!(err, Error::OutOfSpace));
+
+    // the failed commit left the committed tree untouched
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), committed as usize);
+    assert!(txn.get(&committed).unwrap().is_none());
+    assert!(db.verify().unwrap().errors.is_empty());
+}
+
+#[test]
+fn failed_flush_keeps_staged_changes() {
+    let page_size = page_size::get();
+    let db = Database::open_in_memory(64 * page_size).unwrap();
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let stage = || {
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..5000u64 {
+            txn.insert(&i, &i).unwrap();
+        }
+        assert!(matches!(txn.flush_staged(), Err(Error::OutOfSpace)));
+        // the changes are still staged, rather than dropped by the failed flush
+        assert_eq!(txn.get(&42).unwrap().unwrap().to_value(), 42);
+        assert_eq!(txn.len().unwrap(), 5000);
+        txn
+    };
+    // so committing them fails too
+    assert!(matches!(stage().commit(), Err(Error::OutOfSpace)));
+    assert!(table.read_transaction().unwrap().is_empty().unwrap());
+
+    // unless enough of them are undone
+    let mut txn = stage();
+    for i in 10..5000u64 {
+        txn.remove(&i).unwrap();
+    }
+    txn.commit().unwrap();
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), 10);
+    assert_eq!(txn.get(&9).unwrap().unwrap().to_value(), 9);
+}
+
+#[test]
+fn single_key_commit_pages() {
+    // pages allocated by a commit of one key, into a table of the given size
+    let pages_per_commit = |len: u64| {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..len {
+            txn.insert(&(2 * i), b"value").unwrap();
+        }
+        txn.commit().unwrap();
+
+        // pages that are allocated and not free. A reader of the previous version keeps the
+        // pages that a commit supersedes from being freed, so this only counts the new ones
+        let pages_in_use = || {
+            let stats = db.stats().unwrap();
+            stats.next_free_page - stats.free_pages as u64
+        };
+        let mut max_pages = 0;
+        for i in 0..10 {
+            let _reader = table.read_transaction().unwrap();
+            let before = pages_in_use();
+            let mut txn = table.begin_write().unwrap();
+            txn.insert(&(2 * i * len / 10 + 1), b"value").unwrap();
+            txn.commit().unwrap();
+            max_pages = max_pages.max(pages_in_use() - before);
+        }
+
+        // the first commit into a new table must not rebuild the other tables
+        let other: Table<u64> = db.open_table(b"y").unwrap();
+        // creating the table is not flushed, so flush it, for the pages that it superseded
+        // to be freed before measuring
+        db.flush().unwrap();
+        let _reader = table.read_transaction().unwrap();
+        let before = pages_in_use();
+        let mut txn = other.begin_write().unwrap();
+        txn.insert(&1, b"value").unwrap();
+        txn.commit().unwrap();
+        max_pages.max(pages_in_use() - before)
+    };
+
+    let small = pages_per_commit(256);
+    let large = pages_per_commit(4096);
+    // a balanced tree of n entries is about log2(n) deep, and a commit copies one path of it
+    assert!(large <= 2 * 12 + 4, "{}", large);
+    assert!(large <= small + 8, "{} {}", small, large);
+}
+
+#[test]
+fn fill_factor() {
+    // pages allocated by single key commits into a table bulk loaded with the fill factor
+    let pages_for_inserts = |fill_factor: f32| {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = unsafe {
+            Database::builder()
+                .set_fill_factor(fill_factor)
+                .open(tmpfile.path())
+                .unwrap()
+        };
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..1000u64 {
+            txn.insert(&(4 * i), b"value").unwrap();
+        }
+        txn.commit().unwrap();
+
+        let before = db.stats().unwrap().next_free_page;
+        for i in 0..50u64 {
+            let mut txn = table.begin_write().unwrap();
+            txn.insert(&(80 * i + 1), b"value").unwrap();
+            txn.commit().unwrap();
+        }
+        let txn = table.read_transaction().unwrap();
+        assert_eq!(txn.len().unwrap(), 1050);
+        db.stats().unwrap().next_free_page - before
+    };
+
+    let tight = pages_for_inserts(1.0);
+    let slack = pages_for_inserts(0.5);
+    assert!(slack < tight, "{} {}", slack, tight);
+
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    for fill_factor in [0.0, 1.5, f32::NAN] {
+        let result = unsafe {
+            Database::builder()
+                .set_fill_factor(fill_factor)
+                .open(tmpfile.path())
+        };
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+}
+
+#[test]
+fn commit_reuses_superseded_pages() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..1000u64 {
+        txn.insert(&(i * 2), &i).unwrap();
+    }
+    txn.commit().unwrap();
+    let before = db.verify().unwrap();
+
+    // small enough compared to the tree to be applied one key at a time, so each insert
+    // copies the path that the previous one built
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..400u64 {
+        txn.insert(&(i * 5 + 1), &i).unwrap();
+    }
+    for i in 0..100u64 {
+        txn.remove(&(i * 6)).unwrap();
+    }
+    txn.commit().unwrap();
+    let after = db.verify().unwrap();
+    assert!(after.errors.is_empty(), "{:?}", after.errors);
+    // only pages of the tree before the commit are left unreachable, rather than a copy of
+    // every path that was built along the way
+    assert!(after.unreachable_pages - before.unreachable_pages <= before.pages_visited);
+
+    let mut expected = BTreeMap::new();
+    for i in 0..1000u64 {
+        expected.insert(i * 2, i);
+    }
+    for i in 0..400u64 {
+        expected.insert(i * 5 + 1, i);
+    }
+    for i in 0..100u64 {
+        expected.remove(&(i * 6));
+    }
+    let txn = table.read_transaction().unwrap();
+    assert_eq!(txn.len().unwrap(), expected.len());
+    for (key, value) in expected {
+        assert_eq!(txn.get(&key).unwrap().unwrap().to_value(), value);
+    }
+}
+
+#[test]
+fn scan_excluding_prefix() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let keys: Vec<&[u8]> = vec![
+        b"a",
+        b"ap",
+        b"apple",
+        b"apples",
+        b"apricot",
+        b"ap\xff",
+        b"aq",
+        b"b",
+        b"\xff\xff",
+        b"\xff\xff\x01",
+    ];
+    let mut txn = table.begin_write().unwrap();
+    for key in keys.iter() {
+        txn.insert(key, b"value").unwrap();
+    }
+    txn.commit().unwrap();
+
+    let scan = |prefix: &[u8]| -> Vec<Vec<u8>> {
+        let txn = table.read_transaction().unwrap();
+        let mut iter = txn.scan_excluding_prefix(prefix).unwrap();
+        let mut found = vec![];
+        for entry in iter.by_ref() {
+            found.push(entry.key_value().0.to_vec());
+        }
+        assert!(iter.take_error().is_none());
+        found
+    };
+    let expected: Vec<Vec<u8>> = [&b"a"[..], b"aq", b"b", b"\xff\xff", b"\xff\xff\x01"]
+        .iter()
+        .map(|key| key.to_vec())
+        .collect();
+    assert_eq!(scan(b"ap"), expected);
+    // no key can follow a prefix of 0xFF bytes
+    assert_eq!(scan(b"\xff\xff"), keys[..8].to_vec());
+    // every key starts with the empty prefix
+    assert!(scan(b"").is_empty());
+    assert_eq!(scan(b"c"), keys);
+}
+
+#[test]
+fn range_iterator_adapters() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..20u8 {
+        txn.insert(&[i], &[i * 2]).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let read_txn = table.read_transaction().unwrap();
+    let mut keys = vec![];
+    for entry in read_txn.get_range(..).unwrap() {
+        keys.push(entry.key_value().0[0]);
+    }
+    assert_eq!(keys, (0..20).collect::<Vec<u8>>());
+
+    // entries borrow from the database, so they can be collected and outlive the iterator
+    let entries: Vec<_> = read_txn
+        .get_range([5u8].as_slice()..)
+        .unwrap()
+        .filter(|entry| entry.key_value().0[0] % 2 == 1)
+        .take(3)
+        .collect();
+    let pairs: Vec<(&[u8], &[u8])> = entries.iter().map(|entry| entry.key_value()).collect();
+    assert_eq!(
+        pairs,
+        vec![
+            ([5u8].as_slice(), [10u8].as_slice()),
+            (&[7], &[14]),
+            (&[9], &[18])
+        ]
+    );
+    assert_eq!(read_txn.get_range(..[10u8].as_slice()).unwrap().count(), 10);
+}
+
+#[test]
+fn range_typed() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u32, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    // inserted out of order, and spanning byte boundaries
+    for i in [300u32, 7, 65536, 0, 255, 256, 1000] {
+        txn.insert(&i, &(u64::from(i) * 3)).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let txn = table.read_transaction().unwrap();
+    let all: Vec<(u32, u64)> = txn.range_typed::<u32, _>(..).unwrap().collect();
+    let expected: Vec<(u32, u64)> = [0, 7, 255, 256, 300, 1000, 65536]
+        .iter()
+        .map(|i| (*i, u64::from(*i) * 3))
+        .collect();
+    assert_eq!(all, expected);
+    let mut iter = txn.range_typed(255u32..1000).unwrap();
+    let keys: Vec<u32> = iter.by_ref().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![255, 256, 300]);
+    assert!(iter.take_error().is_none());
+    drop(iter);
+    drop(txn);
+
+    // the bytes of negative keys sort after those of positive keys, but the key type's
+    // comparator orders them first
+    let table: Table<i32, u64> = db.open_table(b"y").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in [5i32, -1, 0, -300, 2] {
+        txn.insert(&i, &0).unwrap();
+    }
+    txn.commit().unwrap();
+    let txn = table.read_transaction().unwrap();
+    let keys: Vec<i32> = txn
+        .range_typed(-300i32..=2)
+        .unwrap()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec![-300, -1, 0, 2]);
+}
+
+// Number of levels of the tree, from the indentation of each node in `dump_tree`
+fn tree_height(dump: &str) -> usize {
+    dump.lines()
+        .map(|line| (line.len() - line.trim_start().len()) / 2 + 1)
+        .max()
+        .unwrap()
+}
+
+// The greatest height of a balanced tree of `entries` entries. A tree of height h, balanced as
+// an AVL tree, has at least Fibonacci(h + 1) leaves, so its height is at most
+// log_phi(leaves) + 1, and each leaf holds at least one entry
+fn balanced_height_bound(entries: usize) -> usize {
+    (1.45 * (entries as f64).log2()) as usize + 1
+}
+
+#[test]
+fn deletes_keep_tree_balanced() {
+    let mut rng = StdRng::seed_from_u64(615);
+    let mut keys: Vec<u32> = (0..2000).collect();
+    keys.shuffle(&mut rng);
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = Database::create(tmpfile.path()).unwrap();
+    let table: Table<u32> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for key in 0..2000u32 {
+        txn.insert(&key, b"value").unwrap();
+    }
+    txn.commit().unwrap();
+    let dump = table.read_transaction().unwrap().dump_tree().unwrap();
+    // a balanced tree of 1000 full leaves, and the catalog's leaf
+    assert_eq!(tree_height(&dump), 11);
+
+    for (i, chunk) in keys.chunks(100).enumerate() {
+        let mut txn = table.begin_write().unwrap();
+        for key in chunk {
+            txn.remove(key).unwrap();
+        }
+        txn.commit().unwrap();
+        let dump = table.read_transaction().unwrap().dump_tree().unwrap();
+        // the height shrinks along with the remaining entries, and the catalog's
+        let entries = 2000 - 100 * (i + 1) + 1;
+        let height = tree_height(&dump);
+        assert!(
+            height <= balanced_height_bound(entries),
+            "height {} for {} entries",
+            height,
+            entries
+        );
+        // and sibling leaves that are left with one entry each are merged, so the remaining
+        // entries take few nodes
+        let nodes = dump.lines().count();
+        assert!(
+            2 * nodes <= 3 * entries,
+            "{} nodes for {} entries",
+            nodes,
+            entries
+        );
+    }
+
+    let txn = table.read_transaction().unwrap();
+    for key in keys.iter() {
+        assert!(txn.get(key).unwrap().is_none());
+    }
+    assert!(txn.is_empty().unwrap());
+    drop(txn);
+
+    // deletes that leave one entry in each sub-tree along the left edge of the tree, whose
+    // sub-trees each hold twice as many keys as the one below, would leave a tall tree of few
+    // entries without rotations
+    let mut txn = table.begin_write().unwrap();
+    for key in 0..2000u32 {
+        txn.insert(&key, b"value").unwrap();
+    }
+    txn.commit().unwrap();
+    let kept = |key: &u32| *key == 0 || key.is_power_of_two() && *key > 1;
+    let mut removed: Vec<u32> = (0..2000).filter(|key| !kept(key)).collect();
+    removed.shuffle(&mut rng);
+    for chunk in removed.chunks(100) {
+        let mut txn = table.begin_write().unwrap();
+        for key in chunk {
+            txn.remove(key).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    let txn = table.read_transaction().unwrap();
+    let entries = txn.len().unwrap() + 1;
+    assert_eq!(entries, 12);
+    let height = tree_height(&txn.dump_tree().unwrap());
+    assert!(
+        height <= balanced_height_bound(entries),
+        "height {} for {} entries",
+        height,
+        entries
+    );
+}
+
+#[test]
+fn small_commits_keep_tree_balanced() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = Database::create(tmpfile.path()).unwrap();
+    let table: Table<u32> = db.open_table(b"x").unwrap();
+    // inserts in key order all go to the right edge of the tree, which is rotated to balance it
+    for key in 0..1000u32 {
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(&key, b"value").unwrap();
+        txn.commit().unwrap();
+    }
+    let height = tree_height(&table.read_transaction().unwrap().dump_tree().unwrap());
+    assert!(height <= balanced_height_bound(1001), "height {}", height);
+
+    // and so are deletes that empty the left edge
+    for key in 0..900u32 {
+        let mut txn = table.begin_write().unwrap();
+        txn.remove(&key).unwrap();
+        txn.commit().unwrap();
+    }
+    let txn = table.read_transaction().unwrap();
+    let height = tree_height(&txn.dump_tree().unwrap());
+    assert!(height <= balanced_height_bound(101), "height {}", height);
+    assert_eq!(txn.len().unwrap(), 100);
+    assert_eq!(txn.first().unwrap().unwrap().0.to_value(), 900);
+}
+
+#[test]
+fn content_hash() {
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..500u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; (i % 7) as usize]))
+        .collect();
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    // written in one commit, which builds a balanced tree
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for (key, value) in entries.iter() {
+        txn.insert(key, value).unwrap();
+    }
+    txn.commit().unwrap();
+    let hash = table.read_transaction().unwrap().content_hash().unwrap();
+
+    // written in reverse, one commit at a time, into another database
+    let tmpfile2: NamedTempFile = NamedTempFile::new().unwrap();
+    let db2 = unsafe { Database::open(tmpfile2.path()).unwrap() };
+    let table2: Table<[u8]> = db2.open_table(b"x").unwrap();
+    for (key, value) in entries.iter().rev() {
+        let mut txn = table2.begin_write().unwrap();
+        txn.insert(key, value).unwrap();
+        txn.commit().unwrap();
+    }
+    assert_ne!(
+        table.read_transaction().unwrap().dump_tree().unwrap(),
+        table2.read_transaction().unwrap().dump_tree().unwrap()
+    );
+    assert_eq!(
+        table2.read_transaction().unwrap().content_hash().unwrap(),
+        hash
+    );
+
+    // values are hashed uncompressed
+    #[cfg(feature = "lz4")]
+    {
+        let options = radarbase::TableOptions {
+            compression: radarbase::Compression::Lz4,
+        };
+        let compressed: Table<[u8]> = db2.open_table_with_options(b"y", options).unwrap();
+        let mut txn = compressed.begin_write().unwrap();
+        for (key, value) in entries.iter() {
+            txn.insert(key, value).unwrap();
+        }
+        txn.commit().unwrap();
+        assert_eq!(
+            compressed
+                .read_transaction()
+                .unwrap()
+                .content_hash()
+                .unwrap(),
+            hash
+        );
+    }
+
+    // a changed value changes the hash
+    let mut txn = table2.begin_write().unwrap();
+    txn.insert(&entries[250].0, b"changed").unwrap();
+    txn.commit().unwrap();
+    assert_ne!(
+        table2.read_transaction().unwrap().content_hash().unwrap(),
+        hash
+    );
+
+    // so does moving a byte from a key to its value
+    let a: Table<[u8]> = db.open_table(b"a").unwrap();
+    let mut txn = a.begin_write().unwrap();
+    txn.insert(b"ab", b"c").unwrap();
+    txn.commit().unwrap();
+    let b: Table<[u8]> = db.open_table(b"b").unwrap();
+    let mut txn = b.begin_write().unwrap();
+    txn.insert(b"a", b"bc").unwrap();
+    txn.commit().unwrap();
+    assert_ne!(
+        a.read_transaction().unwrap().content_hash().unwrap(),
+        b.read_transaction().unwrap().content_hash().unwrap()
+    );
+    // while other tables do not change a table's hash
+    assert_eq!(
+        table.read_transaction().unwrap().content_hash().unwrap(),
+        hash
+    );
+}
+
+#[test]
+fn flush_staged() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<u64, u64> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in 0..100 {
+        txn.insert(&i, &i).unwrap();
+    }
+    txn.commit().unwrap();
+    let committed = table.read_transaction().unwrap().content_hash().unwrap();
+
+    let mut txn = table.begin_write().unwrap();
+    for round in 0..5u64 {
+        for i in 0..100 {
+            txn.insert(&(round * 100 + i), &round).unwrap();
+        }
+        txn.remove(&(round * 10)).unwrap();
+        txn.flush_staged().unwrap();
+        // the transaction reads what it flushed, while readers still see the committed data
+        assert_eq!(
+            txn.get(&(round * 100 + 1)).unwrap().unwrap().to_value(),
+            round
+        );
+        assert!(txn.get(&(round * 10)).unwrap().is_none());
+        let read_txn = table.read_transaction().unwrap();
+        assert_eq!(read_txn.len().unwrap(), 100);
+        assert_eq!(read_txn.content_hash().unwrap(), committed);
+    }
+    txn.abort().unwrap();
+    let read_txn = table.read_transaction().unwrap();
+    assert_eq!(read_txn.content_hash().unwrap(), committed);
+    assert_eq!(read_txn.get(&10).unwrap().unwrap().to_value(), 10);
+    drop(read_txn);
+    assert!(db.verify().unwrap().errors.is_empty());
+
+    // a flushed transaction commits both its flushed and its staged changes
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(&1000, &1).unwrap();
+    txn.flush_staged().unwrap();
+    txn.clear().unwrap();
+    txn.insert(&2000, &2).unwrap();
+    txn.flush_staged().unwrap();
+    txn.insert(&3000, &3).unwrap();
+    txn.commit().unwrap();
+    let read_txn = table.read_transaction().unwrap();
+    let mut iter = read_txn.range_typed::<u64, _>(..).unwrap();
+    let entries: Vec<(u64, u64)> = iter.by_ref().collect();
+    assert_eq!(entries, vec![(2000, 2), (3000, 3)]);
+    drop(iter);
+    drop(read_txn);
+
+    // committing would discard a table created since the first flush
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(&4000, &4).unwrap();
+    txn.flush_staged().unwrap();
+    let _other: Table<u64, u64> = db.open_table(b"y").unwrap();
+    assert!(matches!(txn.commit(), Err(Error::WriteConflict)));
+}
+
+#[test]
+fn page_utilization() {
+    // the pages of a table of small entries, bulk loaded with the fill factor
+    let usage = |fill_factor: f32| {
+        let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+        let db = Database::builder()
+            .set_fill_factor(fill_factor)
+            .create(tmpfile.path())
+            .unwrap();
+        let table: Table<u64> = db.open_table(b"x").unwrap();
+        let mut txn = table.begin_write().unwrap();
+        for i in 0..1000u64 {
+            txn.insert(&i, b"value").unwrap();
+        }
+        txn.commit().unwrap();
+        let usage = db.page_utilization().unwrap();
+        assert_eq!(usage.len() as u64, db.verify().unwrap().pages_visited);
+        usage
+    };
+
+    let packed = usage(1.0);
+    let sparse = usage(0.5);
+    for page in packed.iter().chain(sparse.iter()) {
+        // every node takes a whole page, of which small entries use a sliver
+        assert!(page.used_bytes * 10 < page.page_size, "{:?}", page);
+    }
+    let leaves = |usage: &[PageUsage]| -> (usize, usize) {
+        let leaves = usage.iter().filter(|page| page.node_type == NodeType::Leaf);
+        (
+            leaves.clone().count(),
+            leaves.map(|page| page.used_bytes).sum(),
+        )
+    };
+    let (packed_leaves, packed_bytes) = leaves(&packed);
+    let (sparse_leaves, sparse_bytes) = leaves(&sparse);
+    // both hold the same entries, but packing takes half the leaves, each twice as full
+    assert_eq!(packed_leaves * 2, sparse_leaves + 1);
+    assert!(packed_bytes < sparse_bytes);
+    assert!(packed_bytes / packed_leaves > sparse_bytes / sparse_leaves);
+    assert!(packed.len() < sparse.len());
+}
+
+#[test]
+fn iterate_read_transaction() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut expected = BTreeMap::new();
+    let mut txn = table.begin_write().unwrap();
+    for i in [5u8, 3, 200, 0, 17] {
+        txn.insert(&[i, i], &[i]).unwrap();
+        expected.insert(vec![i, i], vec![i]);
+    }
+    txn.commit().unwrap();
+
+    let read_txn = table.read_transaction().unwrap();
+    let mut entries = vec![];
+    for (key, value) in &read_txn {
+        entries.push((key.to_vec(), value.to_vec()));
+    }
+    assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+
+    // values are decoded, and ordered by the key type
+    let table: Table<i64, str> = db.open_table(b"y").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    for i in [3i64, -20, 7, -1] {
+        txn.insert(&i, &i.to_string()).unwrap();
+    }
+    txn.commit().unwrap();
+    let read_txn = table.read_transaction().unwrap();
+    let mut iter = (&read_txn).into_iter();
+    let entries: Vec<(i64, String)> = iter
+        .by_ref()
+        .map(|(key, value)| (key.to_value(), value.to_value().to_string()))
+        .collect();
+    assert!(iter.take_error().is_none());
+    let expected: Vec<(i64, String)> = [-20i64, -1, 3, 7]
+        .iter()
+        .map(|i| (*i, i.to_string()))
+        .collect();
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn write_while_reading() {
+    let tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
+    let db = unsafe { Database::open(tmpfile.path()).unwrap() };
+    let table: Table<[u8]> = db.open_table(b"x").unwrap();
+    let mut txn = table.begin_write().unwrap();
+    txn.insert(b"hello", b"world").unwrap();
+    txn.insert(b"hello2", b"world2").unwrap();
+    txn.commit().unwrap();
+
+    // pages are allocated and written while a guard and an iterator still point into the mmap
+    let read_txn = table.read_transaction().unwrap();
+    let guard = read_txn.get(b"hello").unwrap().unwrap();
+    let mut iter = (&read_txn).into_iter();
+    assert_eq!(iter.next().unwrap().0.as_ref(), b"hello");
+    for i in 0..100u32 {
+        let mut txn = table.begin_write().unwrap();
+        txn.insert(&i.to_be_bytes(), b"value").unwrap();
+        txn.remove(b"hello2").unwrap();
+        txn.commit().unwrap();
+    }
+    assert_eq!(guard.as_ref(), b"world");
+    assert_eq!(iter.next().unwrap().1.as_ref(), b"world2");
+    assert!(iter.next().is_none());
+    drop(iter);
+    drop(guard);
+    drop(read_txn);
+
+    let read_txn = table.read_transaction().unwrap();
+    assert_eq!(read_txn.len().unwrap(), 101);
+    assert_eq!(read_txn.get(b"hello").unwrap().unwrap().as_ref(), b"world");
+    assert!(read_txn.get(b"hello2").unwrap().is_none());
+}